@@ -0,0 +1,20 @@
+create table session
+(
+    session_id         uuid primary key default uuid_generate_v1mc(),
+    user_id            uuid references "user" (user_id) on delete cascade,
+    restaurant_id      uuid references restaurant (restaurant_id) on delete cascade,
+    -- sha256 of the current refresh token secret; rotated on every refresh
+    refresh_token_hash text        not null,
+    user_agent         text,
+    created_at         timestamptz not null default now(),
+    last_used_at       timestamptz not null default now(),
+    expires_at         timestamptz not null,
+    revoked_at         timestamptz,
+    updated_at         timestamptz,
+    constraint session_single_owner check (num_nonnulls(user_id, restaurant_id) = 1)
+);
+
+create index session_user_id_idx on session (user_id);
+create index session_restaurant_id_idx on session (restaurant_id);
+
+select trigger_updated_at('session');
@@ -0,0 +1,36 @@
+create table admin
+(
+    admin_id      uuid primary key                                default uuid_generate_v1mc(),
+    username      text collate "case_insensitive" unique not null,
+    password_hash text                                   not null,
+    created_at    timestamptz                            not null default now(),
+    updated_at    timestamptz
+);
+
+SELECT trigger_updated_at('admin');
+
+alter table session add column admin_id uuid references admin (admin_id) on delete cascade;
+alter table session drop constraint session_single_owner;
+alter table session add constraint session_single_owner
+    check (num_nonnulls(user_id, restaurant_id, admin_id) = 1);
+
+create index session_admin_id_idx on session (admin_id);
+
+alter table restaurant add column status text not null default 'active';
+alter table restaurant add constraint restaurant_status_check
+    check (status in ('active', 'suspended'));
+
+alter table "user" add column suspended_at timestamptz;
+
+-- append-only record of everything done through the admin API
+create table admin_action
+(
+    admin_action_id uuid primary key     default uuid_generate_v1mc(),
+    admin_id        uuid references admin (admin_id) on delete set null,
+    action          text        not null,
+    target_id       uuid,
+    details         jsonb       not null default '{}',
+    created_at      timestamptz not null default now()
+);
+
+create index admin_action_created_at_idx on admin_action (created_at);
@@ -0,0 +1,39 @@
+create table staff
+(
+    staff_id       uuid primary key                                default uuid_generate_v1mc(),
+    restaurant_id  uuid references restaurant (restaurant_id) on delete cascade not null,
+    username       text collate "case_insensitive" unique not null,
+    name           text                                   not null,
+    password_hash  text                                   not null,
+    role           text                                   not null,
+    -- staff are deactivated rather than deleted so that their past actions stay attributed
+    deactivated_at timestamptz,
+    created_at     timestamptz                            not null default now(),
+    updated_at     timestamptz,
+    constraint staff_role_check check (role in ('owner', 'manager', 'cashier', 'kitchen'))
+);
+
+SELECT trigger_updated_at('staff');
+
+create index staff_restaurant_id_idx on staff (restaurant_id);
+
+alter table session add column staff_id uuid references staff (staff_id) on delete cascade;
+alter table session drop constraint session_single_owner;
+alter table session add constraint session_single_owner
+    check (num_nonnulls(user_id, restaurant_id, admin_id, staff_id) = 1);
+
+create index session_staff_id_idx on session (staff_id);
+
+-- who did what on behalf of a restaurant; a null staff_id means the restaurant's own account
+create table restaurant_action
+(
+    restaurant_action_id uuid primary key     default uuid_generate_v1mc(),
+    restaurant_id        uuid references restaurant (restaurant_id) on delete cascade not null,
+    staff_id             uuid references staff (staff_id) on delete set null,
+    action               text        not null,
+    target_id            uuid,
+    details              jsonb       not null default '{}',
+    created_at           timestamptz not null default now()
+);
+
+create index restaurant_action_restaurant_id_idx on restaurant_action (restaurant_id, created_at);
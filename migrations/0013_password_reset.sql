@@ -0,0 +1,21 @@
+-- where password reset codes are sent
+alter table "user" add column email text collate "case_insensitive" unique;
+alter table restaurant add column email text collate "case_insensitive" unique;
+
+create table password_reset
+(
+    password_reset_id uuid primary key     default uuid_generate_v1mc(),
+    user_id           uuid references "user" (user_id) on delete cascade,
+    restaurant_id     uuid references restaurant (restaurant_id) on delete cascade,
+    -- sha256 of the code that was sent; the code itself is never stored
+    code_hash         text        not null,
+    -- wrong guesses so far; the code is burnt once this reaches the limit
+    attempts          int         not null default 0,
+    expires_at        timestamptz not null,
+    used_at           timestamptz,
+    created_at        timestamptz not null default now(),
+    constraint password_reset_single_owner check (num_nonnulls(user_id, restaurant_id) = 1)
+);
+
+create index password_reset_user_id_idx on password_reset (user_id);
+create index password_reset_restaurant_id_idx on password_reset (restaurant_id);
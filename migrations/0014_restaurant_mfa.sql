@@ -0,0 +1,30 @@
+-- RFC 6238 TOTP for restaurant logins.
+-- totp_secret is set as soon as enrolment starts, but only counts once totp_enabled_at is set.
+alter table restaurant add column totp_secret text;
+alter table restaurant add column totp_enabled_at timestamptz;
+-- the last time step a code was accepted for, so that a code can't be replayed
+alter table restaurant add column totp_last_step bigint;
+
+create table restaurant_recovery_code
+(
+    restaurant_id uuid references restaurant (restaurant_id) on delete cascade not null,
+    -- sha256 of the code
+    code_hash     text        not null,
+    used_at       timestamptz,
+    created_at    timestamptz not null default now()
+);
+
+create index restaurant_recovery_code_restaurant_id_idx on restaurant_recovery_code (restaurant_id);
+
+-- platform wide settings managed through the admin API; there is only ever one row
+create table platform_settings
+(
+    id                     boolean primary key default true,
+    require_restaurant_mfa boolean not null    default false,
+    updated_at             timestamptz,
+    constraint platform_settings_single_row check (id)
+);
+
+insert into platform_settings default values;
+
+SELECT trigger_updated_at('platform_settings');
@@ -0,0 +1,12 @@
+-- recent failed logins, counted per account name and per client address so guessing can be slowed down
+create table login_failure
+(
+    -- what was attempted, e.g. `user:alice` or `ip:203.0.113.7`
+    key             text primary key,
+    failures        int         not null default 0,
+    -- no more attempts are allowed for this key until then
+    locked_until    timestamptz,
+    last_failure_at timestamptz not null default now()
+);
+
+create index login_failure_last_failure_at_idx on login_failure (last_failure_at);
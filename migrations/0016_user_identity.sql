@@ -0,0 +1,24 @@
+-- accounts at an external OpenID Connect provider that users sign in with
+create table user_identity
+(
+    issuer        text        not null,
+    -- the provider's `sub` claim, which unlike the email never changes
+    subject       text        not null,
+    user_id       uuid        not null references "user" (user_id) on delete cascade,
+    created_at    timestamptz not null default now(),
+    last_login_at timestamptz not null default now(),
+    primary key (issuer, subject)
+);
+
+create index user_identity_user_id_idx on user_identity (user_id);
+
+-- single sign-on logins waiting for the user to come back from the provider
+create table oidc_login
+(
+    -- the `state` parameter sent to the provider
+    state         text primary key,
+    pkce_verifier text        not null,
+    nonce         text        not null,
+    expires_at    timestamptz not null,
+    created_at    timestamptz not null default now()
+);
@@ -0,0 +1,4 @@
+-- restaurants that sign themselves up wait for an administrator before they are listed or take orders
+alter table restaurant drop constraint restaurant_status_check;
+alter table restaurant add constraint restaurant_status_check
+    check (status in ('pending_approval', 'active', 'suspended'));
@@ -0,0 +1,22 @@
+-- keys for a restaurant's own integrations, e.g. a POS or a receipt printer,
+-- sent in the `X-Api-Key` header instead of a login token
+create table api_key
+(
+    api_key_id    uuid primary key     default uuid_generate_v1mc(),
+    restaurant_id uuid references restaurant (restaurant_id) on delete cascade not null,
+    name          text        not null,
+    -- sha256 of the key, which is only ever shown when it is created
+    key_hash      text unique not null,
+    -- the start of the key, so it can be told apart from the others
+    key_prefix    text        not null,
+    scopes        text[]      not null,
+    expires_at    timestamptz,
+    last_used_at  timestamptz,
+    revoked_at    timestamptz,
+    created_at    timestamptz not null default now()
+);
+
+create index api_key_restaurant_id_idx on api_key (restaurant_id);
+
+-- a null staff_id and api_key_id means the restaurant's own account
+alter table restaurant_action add column api_key_id uuid references api_key (api_key_id) on delete set null;
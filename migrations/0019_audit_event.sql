@@ -0,0 +1,43 @@
+-- security relevant events, such as logins and password changes, kept for investigating incidents.
+-- There are no foreign keys so that events outlive whatever they are about.
+create table audit_event
+(
+    audit_event_id uuid primary key     default uuid_generate_v1mc(),
+    event          text        not null,
+    -- user, restaurant, staff, api_key or admin; null if nobody was signed in, e.g. for a failed login
+    actor_kind     text,
+    actor_id       uuid,
+    -- the restaurant and user the event concerns, so their owners can see it
+    restaurant_id  uuid,
+    user_id        uuid,
+    target_id      uuid,
+    ip             text,
+    user_agent     text,
+    -- what changed, as {"field": {"from": ..., "to": ...}}; secrets are only ever recorded as changed
+    changes        jsonb       not null default '{}',
+    created_at     timestamptz not null default now()
+);
+
+create index audit_event_created_at_idx on audit_event (created_at);
+create index audit_event_restaurant_id_idx on audit_event (restaurant_id, created_at);
+create index audit_event_user_id_idx on audit_event (user_id, created_at);
+
+create function audit_event_append_only()
+    returns trigger as
+$$
+begin
+    raise exception 'audit_event is append-only';
+end;
+$$ language plpgsql;
+
+create trigger audit_event_append_only
+    before update or delete
+    on audit_event
+    for each row
+execute function audit_event_append_only();
+
+create trigger audit_event_no_truncate
+    before truncate
+    on audit_event
+    for each statement
+execute function audit_event_append_only();
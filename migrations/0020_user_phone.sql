@@ -0,0 +1,29 @@
+-- a verified phone number users can log in with, and restaurants can call them on about an order;
+-- in E.164 format, e.g. `+919876543210`
+alter table "user" add column phone_number text constraint user_phone_number_key unique;
+alter table "user" add column phone_verified_at timestamptz;
+
+-- one-time codes sent by SMS, both to log in and to verify a number before it is added to a user
+create table phone_otp
+(
+    phone_otp_id uuid primary key     default uuid_generate_v1mc(),
+    phone_number text        not null,
+    -- `login` or `verify`
+    purpose      text        not null,
+    -- who the code is for; null if nobody has the number, in which case nothing is sent
+    user_id      uuid references "user" (user_id) on delete cascade,
+    -- sha256 of the code that was sent; the code itself is never stored
+    code_hash    text        not null,
+    -- wrong guesses so far; the code is burnt once this reaches the limit
+    attempts     int         not null default 0,
+    -- the address the code was requested from, to limit how many codes one client can ask for
+    ip           text,
+    expires_at   timestamptz not null,
+    used_at      timestamptz,
+    created_at   timestamptz not null default now(),
+    constraint phone_otp_purpose check (purpose in ('login', 'verify'))
+);
+
+create index phone_otp_phone_number_idx on phone_otp (phone_number, created_at);
+create index phone_otp_ip_idx on phone_otp (ip, created_at);
+create index phone_otp_user_id_idx on phone_otp (user_id);
@@ -0,0 +1,7 @@
+-- deleted users are anonymised rather than removed, so restaurants keep their order history
+alter table "user" add column deleted_at timestamptz;
+
+-- removing a user outright would take the restaurants' revenue history with it
+alter table "order" drop constraint order_user_id_fkey;
+alter table "order" add constraint order_user_id_fkey
+    foreign key (user_id) references "user" (user_id) on delete restrict;
@@ -0,0 +1,27 @@
+-- devices users get push notifications on, since one user can be logged in on several
+create table push_device
+(
+    push_device_id  uuid primary key     default uuid_generate_v1mc(),
+    user_id         uuid        not null references "user" (user_id) on delete cascade,
+    -- the session the device registered under; pushes stop once it ends.
+    -- null for tokens carried over from `user.expo_push_token`, until the device registers again
+    session_id      uuid references session (session_id) on delete cascade,
+    -- a device belongs to whoever last registered it
+    expo_push_token text        not null unique,
+    -- as reported by the app, e.g. `ios` or `android`
+    platform        text,
+    app_version     text,
+    last_seen_at    timestamptz not null default now(),
+    created_at      timestamptz not null default now()
+);
+
+create index push_device_user_id_idx on push_device (user_id);
+create index push_device_session_id_idx on push_device (session_id);
+
+insert into push_device (user_id, expo_push_token)
+select user_id, expo_push_token
+from "user"
+where expo_push_token is not null
+on conflict (expo_push_token) do nothing;
+
+alter table "user" drop column expo_push_token;
@@ -0,0 +1,19 @@
+-- restaurants and menu items users have marked as favourites, to find them quickly again
+create table favourite_restaurant
+(
+    user_id       uuid        not null references "user" (user_id) on delete cascade,
+    restaurant_id uuid        not null references restaurant (restaurant_id) on delete cascade,
+    created_at    timestamptz not null default now(),
+    primary key (user_id, restaurant_id)
+);
+
+create table favourite_item
+(
+    user_id    uuid        not null references "user" (user_id) on delete cascade,
+    item_id    uuid        not null references item (item_id) on delete cascade,
+    created_at timestamptz not null default now(),
+    primary key (user_id, item_id)
+);
+
+create index favourite_item_item_id_idx on favourite_item (item_id);
+create index favourite_restaurant_restaurant_id_idx on favourite_restaurant (restaurant_id);
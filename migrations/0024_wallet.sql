@@ -0,0 +1,157 @@
+-- prepaid wallets, kept as a double-entry ledger: every transaction moves money between accounts
+-- and its entries sum to zero, so the balances of all accounts together are always zero.
+-- Amounts are whole rupees, like order totals.
+create table ledger_account
+(
+    ledger_account_id uuid primary key     default uuid_generate_v1mc(),
+    -- `wallet`: what the platform owes a user;
+    -- `restaurant`: what the platform owes a restaurant for orders paid from wallets;
+    -- `phonepe`: money received through PhonePe, negative as it was paid out into wallets;
+    -- `adjustments`: the other side of corrections made by administrators
+    kind              text        not null,
+    user_id           uuid references "user" (user_id) on delete restrict,
+    restaurant_id     uuid references restaurant (restaurant_id) on delete restrict,
+    -- the sum of the account's entries, kept up to date by `ledger_entry_apply`
+    balance           bigint      not null default 0,
+    created_at        timestamptz not null default now(),
+    constraint ledger_account_kind check (kind in ('wallet', 'restaurant', 'phonepe', 'adjustments')),
+    constraint ledger_account_owner check (
+        case kind
+            when 'wallet' then user_id is not null and restaurant_id is null
+            when 'restaurant' then restaurant_id is not null and user_id is null
+            else user_id is null and restaurant_id is null
+        end
+    ),
+    constraint ledger_account_wallet_not_overdrawn check (kind <> 'wallet' or balance >= 0)
+);
+
+create unique index ledger_account_user_id_key on ledger_account (user_id) where kind = 'wallet';
+create unique index ledger_account_restaurant_id_key on ledger_account (restaurant_id) where kind = 'restaurant';
+create unique index ledger_account_system_key on ledger_account (kind) where kind in ('phonepe', 'adjustments');
+
+insert into ledger_account (kind) values ('phonepe'), ('adjustments');
+
+create table ledger_transaction
+(
+    ledger_transaction_id uuid primary key     default uuid_generate_v1mc(),
+    -- `top_up`, `debit`, `refund` or `adjustment`
+    kind                  text        not null,
+    -- the order paid for or refunded
+    order_id              uuid references "order" (order_id) on delete restrict,
+    -- the administrator that made an adjustment
+    admin_id              uuid references admin (admin_id) on delete restrict,
+    description           text        not null,
+    created_at            timestamptz not null default now(),
+    constraint ledger_transaction_kind check (kind in ('top_up', 'debit', 'refund', 'adjustment'))
+);
+
+-- an order is paid from a wallet at most once, and refunded at most once
+create unique index ledger_transaction_order_id_key on ledger_transaction (order_id, kind)
+    where kind in ('debit', 'refund');
+
+create table ledger_entry
+(
+    ledger_entry_id       uuid primary key     default uuid_generate_v1mc(),
+    ledger_transaction_id uuid        not null references ledger_transaction (ledger_transaction_id) on delete restrict,
+    ledger_account_id     uuid        not null references ledger_account (ledger_account_id) on delete restrict,
+    -- added to the account's balance; negative to take money out of it
+    amount                bigint      not null,
+    -- the account's balance after this entry, for statements
+    balance_after         bigint      not null default 0,
+    created_at            timestamptz not null default now(),
+    constraint ledger_entry_amount_nonzero check (amount <> 0)
+);
+
+create index ledger_entry_transaction_id_idx on ledger_entry (ledger_transaction_id);
+create index ledger_entry_account_id_idx on ledger_entry (ledger_account_id, created_at);
+
+-- top-ups paid through PhonePe, credited to the wallet once the payment succeeds
+create table wallet_top_up
+(
+    wallet_top_up_id      uuid primary key     default uuid_generate_v1mc(),
+    user_id               uuid        not null references "user" (user_id) on delete restrict,
+    amount                bigint      not null,
+    -- `payment_pending`, `paid` or `payment_failed`
+    status                text        not null default 'payment_pending',
+    payment_url           text,
+    ledger_transaction_id uuid references ledger_transaction (ledger_transaction_id) on delete restrict,
+    created_at            timestamptz not null default now(),
+    updated_at            timestamptz,
+    constraint wallet_top_up_amount_positive check (amount > 0)
+);
+
+create index wallet_top_up_user_id_idx on wallet_top_up (user_id, created_at);
+
+select trigger_updated_at('wallet_top_up');
+
+-- keep every account's balance equal to the sum of its entries
+create function ledger_entry_apply()
+    returns trigger as
+$$
+begin
+    update ledger_account
+    set balance = balance + NEW.amount
+    where ledger_account_id = NEW.ledger_account_id
+    returning balance into NEW.balance_after;
+
+    return NEW;
+end;
+$$ language plpgsql;
+
+create trigger ledger_entry_apply
+    before insert
+    on ledger_entry
+    for each row
+execute function ledger_entry_apply();
+
+-- checked at commit, once all of a transaction's entries are in
+create function ledger_transaction_balanced()
+    returns trigger as
+$$
+begin
+    if (select sum(amount) from ledger_entry where ledger_transaction_id = NEW.ledger_transaction_id) <> 0 then
+        raise exception 'ledger transaction % does not balance', NEW.ledger_transaction_id;
+    end if;
+
+    return null;
+end;
+$$ language plpgsql;
+
+create constraint trigger ledger_transaction_balanced
+    after insert
+    on ledger_entry
+    deferrable initially deferred
+    for each row
+execute function ledger_transaction_balanced();
+
+create function ledger_append_only()
+    returns trigger as
+$$
+begin
+    raise exception '% is append-only', TG_TABLE_NAME;
+end;
+$$ language plpgsql;
+
+create trigger ledger_transaction_append_only
+    before update or delete
+    on ledger_transaction
+    for each row
+execute function ledger_append_only();
+
+create trigger ledger_entry_append_only
+    before update or delete
+    on ledger_entry
+    for each row
+execute function ledger_append_only();
+
+create trigger ledger_transaction_no_truncate
+    before truncate
+    on ledger_transaction
+    for each statement
+execute function ledger_append_only();
+
+create trigger ledger_entry_no_truncate
+    before truncate
+    on ledger_entry
+    for each statement
+execute function ledger_append_only();
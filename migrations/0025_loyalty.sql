@@ -0,0 +1,67 @@
+-- loyalty programmes restaurants run for their regulars: points earned per rupee spent and redeemed as a discount,
+-- and stamp cards filled by buying qualifying items and redeemed for one of them free
+create table loyalty_programme
+(
+    restaurant_id       uuid primary key references restaurant (restaurant_id) on delete cascade,
+    -- points earned for every rupee paid for a completed order, 0 to earn none
+    points_per_rupee    int         not null default 0 check (points_per_rupee >= 0),
+    -- points redeemed for every rupee of discount
+    points_per_discount int         not null default 100 check (points_per_discount > 0),
+    -- stamps that fill a card, null if the restaurant has no stamp card
+    stamps_per_card     int check (stamps_per_card > 0),
+    created_at          timestamptz not null default now(),
+    updated_at          timestamptz
+);
+
+SELECT trigger_updated_at('loyalty_programme');
+
+-- items that earn stamps, and which one a full card gets free
+create table loyalty_stamp_item
+(
+    item_id       uuid primary key references item (item_id) on delete cascade,
+    restaurant_id uuid        not null references restaurant (restaurant_id) on delete cascade,
+    -- stamps earned for each one bought
+    stamps        int         not null check (stamps > 0),
+    created_at    timestamptz not null default now()
+);
+
+create index loyalty_stamp_item_restaurant_id_idx on loyalty_stamp_item (restaurant_id);
+
+-- what each user has collected at each restaurant
+create table loyalty_balance
+(
+    user_id       uuid        not null references "user" (user_id) on delete cascade,
+    restaurant_id uuid        not null references restaurant (restaurant_id) on delete cascade,
+    points        int         not null default 0 check (points >= 0),
+    stamps        int         not null default 0 check (stamps >= 0),
+    created_at    timestamptz not null default now(),
+    updated_at    timestamptz,
+    primary key (user_id, restaurant_id)
+);
+
+SELECT trigger_updated_at('loyalty_balance');
+
+-- every change to a balance, so that cancelling an order can undo exactly what it did
+create table loyalty_event
+(
+    loyalty_event_id uuid primary key     default uuid_generate_v1mc(),
+    user_id          uuid        not null references "user" (user_id) on delete cascade,
+    restaurant_id    uuid        not null references restaurant (restaurant_id) on delete cascade,
+    order_id         uuid        not null references "order" (order_id) on delete cascade,
+    -- `earn` when the order is completed, `redeem` when it is made, `reverse` when it is cancelled or its payment fails
+    kind             text        not null check (kind in ('earn', 'redeem', 'reverse')),
+    -- added to the balance, negative when redeeming
+    points           int         not null,
+    stamps           int         not null,
+    created_at       timestamptz not null default now()
+);
+
+create index loyalty_event_order_id_idx on loyalty_event (order_id);
+create index loyalty_event_user_id_idx on loyalty_event (user_id, restaurant_id);
+
+alter table "order"
+    -- taken off the total by redeeming points or a stamp card
+    add column loyalty_discount int not null default 0,
+    -- earned once the order is completed, under the programme's rules when it was made
+    add column loyalty_points   int not null default 0,
+    add column loyalty_stamps   int not null default 0;
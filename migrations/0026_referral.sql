@@ -0,0 +1,45 @@
+-- short codes users share to invite others, leaving out characters that are easily confused
+create function generate_referral_code()
+    returns text as
+$$
+select string_agg(substr('ABCDEFGHJKMNPQRSTUVWXYZ23456789', floor(random() * 31)::int + 1, 1), '')
+from generate_series(1, 8)
+$$ language sql volatile;
+
+alter table "user"
+    add column referral_code text not null default generate_referral_code()
+        constraint user_referral_code_key unique;
+
+-- who invited whom; both are rewarded once the referee completes a qualifying order
+create table referral
+(
+    referral_id       uuid primary key     default uuid_generate_v1mc(),
+    referrer_id       uuid        not null references "user" (user_id) on delete cascade,
+    -- a user can only be referred once, when signing up
+    referee_id        uuid        not null references "user" (user_id) on delete cascade
+        constraint referral_referee_id_key unique,
+    -- `pending` until the referee's first qualifying order is completed, then `rewarded`
+    status            text        not null default 'pending' check (status in ('pending', 'rewarded')),
+    -- the order that earned the rewards
+    order_id          uuid references "order" (order_id) on delete set null,
+    -- false if the referrer had already reached the cap, or can no longer use the app
+    referrer_rewarded bool        not null default false,
+    created_at        timestamptz not null default now(),
+    rewarded_at       timestamptz
+);
+
+create index referral_referrer_id_idx on referral (referrer_id, created_at);
+
+-- rewards are paid into wallets from a marketing account
+alter table ledger_account
+    drop constraint ledger_account_kind,
+    add constraint ledger_account_kind check (kind in ('wallet', 'restaurant', 'phonepe', 'adjustments', 'rewards'));
+
+drop index ledger_account_system_key;
+create unique index ledger_account_system_key on ledger_account (kind) where kind in ('phonepe', 'adjustments', 'rewards');
+
+insert into ledger_account (kind) values ('rewards');
+
+alter table ledger_transaction
+    drop constraint ledger_transaction_kind,
+    add constraint ledger_transaction_kind check (kind in ('top_up', 'debit', 'refund', 'adjustment', 'reward'));
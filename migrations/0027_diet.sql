@@ -0,0 +1,19 @@
+-- dietary labels on menu items, and what users eat, so menus can flag what they shouldn't order.
+-- `diet` is `non_veg`, `egg`, `veg`, `vegan` or `jain`, and null on items that haven't been labelled
+-- or users who eat anything
+alter table item
+    add column diet      text
+        constraint item_diet check (diet in ('non_veg', 'egg', 'veg', 'vegan', 'jain')),
+    add column allergens text[] not null default '{}'
+        constraint item_allergens check (
+            allergens <@ array ['dairy', 'egg', 'fish', 'gluten', 'nuts', 'sesame', 'shellfish', 'soy']
+            );
+
+alter table "user"
+    add column diet            text
+        constraint user_diet check (diet in ('non_veg', 'egg', 'veg', 'vegan', 'jain')),
+    -- allergens the user avoids
+    add column avoid_allergens text[] not null default '{}'
+        constraint user_avoid_allergens check (
+            avoid_allergens <@ array ['dairy', 'egg', 'fish', 'gluten', 'nuts', 'sesame', 'shellfish', 'soy']
+            );
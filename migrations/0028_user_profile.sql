@@ -0,0 +1,15 @@
+-- details restaurants can use to find the user picking up an order, and which of them the user lets restaurants see.
+-- The phone number is `phone_number`, added when the user verifies it
+alter table "user"
+    add column display_name       text,
+    add column hostel             text,
+    -- only set along with `hostel`
+    add column room               text,
+    -- the college roll or ID card number
+    add column roll_number        text,
+    add column share_display_name bool not null default true,
+    -- restaurants saw phone numbers before they could be hidden, so keep showing them until the user says otherwise
+    add column share_phone_number bool not null default true,
+    add column share_hostel       bool not null default false,
+    add column share_roll_number  bool not null default false,
+    add constraint user_room_needs_hostel check (room is null or hostel is not null);
@@ -0,0 +1,25 @@
+-- how each user has behaved at each restaurant, and whether the restaurant has blocked them
+create table restaurant_customer
+(
+    restaurant_id uuid        not null references restaurant (restaurant_id) on delete cascade,
+    user_id       uuid        not null references "user" (user_id) on delete cascade,
+    -- paid orders the user never collected, as marked by the restaurant
+    no_shows      int         not null default 0,
+    -- paid orders the user cancelled
+    cancellations int         not null default 0,
+    -- set while the restaurant won't take orders from the user
+    blocked_at    timestamptz,
+    -- the restaurant's note on why, never shown to the user
+    block_reason  text,
+    created_at    timestamptz not null default now(),
+    updated_at    timestamptz,
+    primary key (restaurant_id, user_id)
+);
+
+SELECT trigger_updated_at('restaurant_customer');
+
+create index restaurant_customer_user_id_idx on restaurant_customer (user_id);
+
+alter table "order"
+    -- when the restaurant marked the order as never collected
+    add column no_show_at timestamptz;
@@ -0,0 +1,49 @@
+-- the IANA timezone the restaurant's opening hours are in
+alter table restaurant
+    add column timezone text not null default 'Asia/Kolkata';
+
+-- dates a restaurant keeps different hours on, or closes for, like a holiday
+create table opening_exception
+(
+    restaurant_id uuid        not null references restaurant (restaurant_id) on delete cascade,
+    -- in the restaurant's timezone
+    date          date        not null,
+    -- shown to users, e.g. "Diwali"
+    reason        text,
+    created_at    timestamptz not null default now(),
+    updated_at    timestamptz,
+    primary key (restaurant_id, date)
+);
+
+SELECT trigger_updated_at('opening_exception');
+
+-- when a restaurant is open, each row one interval of either its weekly schedule or an exception.
+-- An exception without any intervals means the restaurant is closed all day
+create table opening_hours
+(
+    opening_hours_id uuid primary key default uuid_generate_v1mc(),
+    restaurant_id    uuid        not null references restaurant (restaurant_id) on delete cascade,
+    -- ISO weekday, 1 for Monday to 7 for Sunday, for the weekly schedule
+    weekday          smallint check (weekday between 1 and 7),
+    -- for an exception instead
+    date             date,
+    -- local times in the restaurant's timezone. An interval closing before it opens runs past midnight
+    opens            time        not null,
+    closes           time        not null,
+    created_at       timestamptz not null default now(),
+    foreign key (restaurant_id, date) references opening_exception (restaurant_id, date) on delete cascade,
+    constraint opening_hours_weekday_or_date check ((weekday is null) <> (date is null)),
+    constraint opening_hours_not_empty check (opens <> closes)
+);
+
+create index opening_hours_restaurant_id_idx on opening_hours (restaurant_id);
+
+-- open_time and close_time only ever meant the time of day, the same every day
+insert into opening_hours (restaurant_id, weekday, opens, closes)
+select restaurant_id, weekday, (open_time at time zone 'Asia/Kolkata')::time, (close_time at time zone 'Asia/Kolkata')::time
+from restaurant, generate_series(1, 7) weekday
+where (open_time at time zone 'Asia/Kolkata')::time <> (close_time at time zone 'Asia/Kolkata')::time;
+
+alter table restaurant
+    drop column open_time,
+    drop column close_time;
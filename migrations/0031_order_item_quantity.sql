@@ -0,0 +1,4 @@
+-- orders could be made with negative quantities, and so negative totals. Not validated, so old orders don't stop
+-- the migration, but enforced for every new item
+alter table order_item
+    add constraint order_item_quantity_positive check (quantity > 0) not valid;
@@ -0,0 +1,9 @@
+-- when the user proved they own `email`, either through a confirmation code or a provider that verified it.
+-- Only verified emails are trusted to link single sign-on accounts
+alter table "user"
+    add column email_verified_at timestamptz;
+
+-- everyone who signed in with single sign-on got their email from the provider, which only shares verified ones
+update "user" u set email_verified_at = i.created_at
+from (select user_id, min(created_at) as created_at from user_identity group by user_id) i
+where i.user_id = u.user_id and u.email is not null;
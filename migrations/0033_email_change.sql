@@ -0,0 +1,19 @@
+-- new email addresses waiting for their owner to confirm them with the code sent there
+create table email_change
+(
+    email_change_id uuid primary key     default uuid_generate_v1mc(),
+    user_id         uuid references "user" (user_id) on delete cascade,
+    restaurant_id   uuid references restaurant (restaurant_id) on delete cascade,
+    email           text collate "case_insensitive" not null,
+    -- sha256 of the code that was sent; the code itself is never stored
+    code_hash       text        not null,
+    -- wrong guesses so far; the code is burnt once this reaches the limit
+    attempts        int         not null default 0,
+    expires_at      timestamptz not null,
+    used_at         timestamptz,
+    created_at      timestamptz not null default now(),
+    constraint email_change_single_owner check (num_nonnulls(user_id, restaurant_id) = 1)
+);
+
+create index email_change_user_id_idx on email_change (user_id);
+create index email_change_restaurant_id_idx on email_change (restaurant_id);
@@ -0,0 +1,18 @@
+-- RFC 6238 TOTP for staff logins, the same as for restaurants.
+-- totp_secret is set as soon as enrolment starts, but only counts once totp_enabled_at is set.
+alter table staff
+    add column totp_secret     text,
+    add column totp_enabled_at timestamptz,
+    -- the last time step a code was accepted for, so that a code can't be replayed
+    add column totp_last_step  bigint;
+
+create table staff_recovery_code
+(
+    staff_id   uuid references staff (staff_id) on delete cascade not null,
+    -- sha256 of the code
+    code_hash  text        not null,
+    used_at    timestamptz,
+    created_at timestamptz not null default now()
+);
+
+create index staff_recovery_code_staff_id_idx on staff_recovery_code (staff_id);
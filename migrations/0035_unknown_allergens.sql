@@ -0,0 +1,8 @@
+-- null when the restaurant hasn't said which allergens an item contains, rather than an empty list, which claims it
+-- contains none
+alter table item
+    alter column allergens drop not null,
+    alter column allergens drop default;
+
+-- items were never asked for their allergens before, so an empty list can't be told apart from an unlabelled item
+update item set allergens = null where allergens = '{}';
@@ -0,0 +1,8 @@
+-- what a suspended restaurant goes back to when it is reinstated, so suspending a restaurant that was never approved
+-- can't be used to skip approval
+alter table restaurant
+    add column status_before_suspension text
+        constraint restaurant_status_before_suspension check (status_before_suspension in ('pending_approval', 'active'));
+
+-- it isn't known whether restaurants suspended before now were approved, so they need approving again
+update restaurant set status_before_suspension = 'pending_approval' where status = 'suspended';
@@ -0,0 +1,12 @@
+-- deleted restaurants are anonymised rather than removed, so users and the ledger keep their order history
+alter table restaurant
+    add column deleted_at timestamptz;
+
+alter table restaurant drop constraint restaurant_status_check;
+alter table restaurant add constraint restaurant_status_check
+    check (status in ('pending_approval', 'active', 'suspended', 'deleted'));
+
+-- removing a restaurant outright would take its orders and their payments with it
+alter table "order" drop constraint order_restaurant_id_fkey;
+alter table "order" add constraint order_restaurant_id_fkey
+    foreign key (restaurant_id) references restaurant (restaurant_id) on delete restrict;
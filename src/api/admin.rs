@@ -0,0 +1,1061 @@
+use axum::extract::{Path, Query, State};
+use axum::routing::{delete, get, post, put};
+use axum::{Json, Router};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::{query, PgConnection};
+use uuid::Uuid;
+
+use crate::api::audit::{change, AuditEvent, SECRET_CHANGED};
+use crate::api::auth::AuthAdmin;
+use crate::api::login_throttle::LoginAttempt;
+use crate::api::mfa;
+use crate::api::opening_hours::set_default_hours;
+use crate::api::restaurants::{validate_new_restaurant, PhonepeDetails};
+use crate::api::sessions::{create_session, end_session, end_sessions, SessionOwner};
+use crate::api::util::{hash_password, ClientInfo};
+use crate::api::{AppContext, Error, Result, ResultExt};
+
+/// Page size used when a listing doesn't ask for one.
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 500;
+
+pub(crate) fn router() -> Router<AppContext> {
+    Router::new()
+        .route("/api/admin/login", post(login_admin))
+        .route("/api/admin/logout", post(logout_admin))
+        .route(
+            "/api/admin/restaurants",
+            get(get_restaurants).post(create_restaurant),
+        )
+        .route("/api/admin/restaurants/:id", delete(delete_restaurant))
+        .route(
+            "/api/admin/restaurants/:id/approve",
+            post(approve_restaurant),
+        )
+        .route(
+            "/api/admin/restaurants/:id/suspend",
+            post(suspend_restaurant),
+        )
+        .route(
+            "/api/admin/restaurants/:id/reinstate",
+            post(reinstate_restaurant),
+        )
+        .route("/api/admin/restaurants/:id/phonepe", put(set_phonepe))
+        .route(
+            "/api/admin/restaurants/:id/password",
+            put(reset_restaurant_password),
+        )
+        .route(
+            "/api/admin/restaurants/:id/mfa",
+            delete(reset_restaurant_mfa),
+        )
+        .route("/api/admin/users", get(get_users))
+        .route("/api/admin/users/:id/suspend", post(suspend_user))
+        .route("/api/admin/users/:id/reinstate", post(reinstate_user))
+        .route("/api/admin/users/:id/password", put(reset_user_password))
+        .route("/api/admin/orders", get(get_orders))
+        .route("/api/admin/actions", get(get_actions))
+        .route(
+            "/api/admin/settings",
+            get(get_settings).put(update_settings),
+        )
+}
+
+/// Create the administrator from `config` if there are none yet,
+/// so a fresh deployment can be managed without touching the database.
+pub(crate) async fn bootstrap(ctx: &AppContext) -> anyhow::Result<()> {
+    let (Some(username), Some(password)) = (&ctx.config.admin_username, &ctx.config.admin_password)
+    else {
+        return Ok(());
+    };
+
+    let mut tx = ctx.db.begin().await?;
+
+    // Serialise concurrent startups so only one of them creates the account.
+    query!("lock table admin in exclusive mode")
+        .execute(&mut *tx)
+        .await?;
+
+    let exists = sqlx::query_scalar!(r#"select exists(select 1 from admin) as "exists!""#)
+        .fetch_one(&mut *tx)
+        .await?;
+
+    if !exists {
+        let hash = hash_password(ctx, password.clone()).await?;
+        query!(
+            "insert into admin (username, password_hash) values ($1, $2)",
+            username,
+            hash
+        )
+        .execute(&mut *tx)
+        .await?;
+        log::info!("created bootstrap administrator {:?}", username);
+    }
+
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Append an entry to the admin audit trail, in the same transaction as the action itself.
+pub(super) async fn record_action(
+    tx: &mut PgConnection,
+    auth_admin: &AuthAdmin,
+    action: &str,
+    target_id: Option<Uuid>,
+    details: serde_json::Value,
+) -> Result<()> {
+    query!(
+        r#"
+            insert into admin_action (admin_id, action, target_id, details)
+            values ($1, $2, $3, $4)
+        "#,
+        auth_admin.admin_id,
+        action,
+        target_id,
+        details
+    )
+    .execute(tx)
+    .await?;
+
+    Ok(())
+}
+
+#[derive(Deserialize)]
+pub(super) struct Page {
+    #[serde(default, deserialize_with = "number_from_query")]
+    limit: Option<i64>,
+    #[serde(default, deserialize_with = "number_from_query")]
+    offset: Option<i64>,
+}
+
+/// Query strings are all text once `Page` is flattened into another struct, so parse numbers by hand.
+fn number_from_query<'de, D>(deserializer: D) -> Result<Option<i64>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|value| value.parse().map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+impl Page {
+    pub(super) fn limit(&self) -> i64 {
+        self.limit
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .clamp(1, MAX_PAGE_SIZE)
+    }
+
+    pub(super) fn offset(&self) -> i64 {
+        self.offset.unwrap_or(0).max(0)
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct AdminBody<T> {
+    admin: T,
+}
+
+#[derive(serde::Deserialize)]
+struct LoginAdmin {
+    username: String,
+    password: String,
+}
+
+#[derive(serde::Serialize)]
+struct Admin {
+    id: Uuid,
+    username: String,
+    token: String,
+    refresh_token: String,
+}
+
+async fn login_admin(
+    ctx: State<AppContext>,
+    client: ClientInfo,
+    Json(req): Json<AdminBody<LoginAdmin>>,
+) -> Result<Json<AdminBody<Admin>>> {
+    let attempt = LoginAttempt::start(&ctx, "admin", &req.admin.username, &client).await?;
+
+    let admin = query!(
+        r#"select admin_id, username, password_hash from admin where username = $1"#,
+        req.admin.username
+    )
+    .fetch_optional(&ctx.db)
+    .await?;
+
+    let (admin, new_hash) = attempt
+        .verify(&ctx, admin, req.admin.password, |admin| {
+            &admin.password_hash
+        })
+        .await?;
+
+    if let Some(hash) = new_hash {
+        query!(
+            r#"update admin set password_hash = $1 where admin_id = $2"#,
+            hash,
+            admin.admin_id
+        )
+        .execute(&ctx.db)
+        .await?;
+    }
+
+    let session = create_session(&ctx, SessionOwner::Admin(admin.admin_id), &client).await?;
+
+    Ok(Json(AdminBody {
+        admin: Admin {
+            id: admin.admin_id,
+            username: admin.username,
+            token: AuthAdmin {
+                admin_id: admin.admin_id,
+                session_id: session.session_id,
+            }
+            .to_jwt(&ctx),
+            refresh_token: session.refresh_token,
+        },
+    }))
+}
+
+async fn logout_admin(auth_admin: AuthAdmin, ctx: State<AppContext>) -> Result<()> {
+    end_session(&ctx, auth_admin.session_id).await
+}
+
+// ========= restaurants
+
+#[derive(serde::Serialize)]
+struct Restaurants {
+    restaurants: Vec<RestaurantSummary>,
+}
+
+#[derive(serde::Serialize)]
+struct RestaurantSummary {
+    id: Uuid,
+    username: String,
+    name: String,
+    status: String,
+    phonepe_id: String,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+struct RestaurantFilter {
+    /// Only list restaurants with this status, e.g. `pending_approval`.
+    status: Option<String>,
+    #[serde(flatten)]
+    page: Page,
+}
+
+async fn get_restaurants(
+    _auth_admin: AuthAdmin,
+    ctx: State<AppContext>,
+    Query(req): Query<RestaurantFilter>,
+) -> Result<Json<Restaurants>> {
+    let restaurants = query!(
+        r#"
+            select restaurant_id, username, name, status, phonepe_id, created_at
+            from restaurant
+            where ($1::text is null or status = $1)
+            order by created_at
+            limit $2 offset $3
+        "#,
+        req.status,
+        req.page.limit(),
+        req.page.offset()
+    )
+    .fetch_all(&ctx.db)
+    .await?
+    .into_iter()
+    .map(|row| RestaurantSummary {
+        id: row.restaurant_id,
+        username: row.username,
+        name: row.name,
+        status: row.status,
+        phonepe_id: row.phonepe_id,
+        created_at: row.created_at,
+    })
+    .collect();
+
+    Ok(Json(Restaurants { restaurants }))
+}
+
+#[derive(Deserialize)]
+struct RestaurantBody<T> {
+    restaurant: T,
+}
+
+#[derive(Deserialize)]
+struct NewRestaurant {
+    username: String,
+    name: String,
+    password: String,
+    email: Option<String>,
+    phonepe: PhonepeDetails,
+}
+
+async fn create_restaurant(
+    auth_admin: AuthAdmin,
+    ctx: State<AppContext>,
+    Json(req): Json<RestaurantBody<NewRestaurant>>,
+) -> Result<Json<Uuid>> {
+    let req = req.restaurant;
+    validate_new_restaurant(&req.username, &req.name, req.email.as_deref())?;
+    ctx.password_policy.check("password", &req.password)?;
+
+    let hash = hash_password(&ctx, req.password).await?;
+
+    let mut tx = ctx.db.begin().await?;
+
+    let restaurant_id = sqlx::query_scalar!(
+        r#"
+            insert into restaurant
+                (username, name, password_hash, email, phonepe_id, phonepe_key, phonepe_key_id)
+            values ($1, $2, $3, $4, $5, $6, $7)
+            returning restaurant_id
+        "#,
+        req.username,
+        req.name,
+        hash,
+        req.email,
+        req.phonepe.id,
+        req.phonepe.key,
+        req.phonepe.key_id
+    )
+    .fetch_one(&mut *tx)
+    .await
+    .on_constraint("restaurant_username_key", |_| {
+        Error::unprocessable_entity([("username", "username taken")])
+    })
+    .on_constraint("restaurant_name_key", |_| {
+        Error::unprocessable_entity([("name", "name taken")])
+    })
+    .on_constraint("restaurant_email_key", |_| {
+        Error::unprocessable_entity([("email", "email taken")])
+    })?;
+
+    set_default_hours(&mut tx, restaurant_id).await?;
+
+    record_action(
+        &mut tx,
+        &auth_admin,
+        "create_restaurant",
+        Some(restaurant_id),
+        json!({ "username": req.username, "name": req.name }),
+    )
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(Json(restaurant_id))
+}
+
+/// Delete a restaurant.
+///
+/// Its orders are kept for the users who placed them and for the ledger, so instead of removing the restaurant
+/// we stop it being listed or logged in to, strip its credentials and free its username and email for others.
+async fn delete_restaurant(
+    auth_admin: AuthAdmin,
+    ctx: State<AppContext>,
+    Path(restaurant_id): Path<Uuid>,
+) -> Result<()> {
+    let mut tx = ctx.db.begin().await?;
+
+    // The password hash and PhonePe details can't be null, and an empty hash matches no password.
+    let restaurant = query!(
+        r#"
+            update restaurant r
+            set username = 'deleted-' || r.restaurant_id,
+                password_hash = '',
+                email = null,
+                image = null,
+                phonepe_id = '',
+                phonepe_key = '',
+                phonepe_key_id = '',
+                totp_secret = null,
+                totp_enabled_at = null,
+                totp_last_step = null,
+                status = 'deleted',
+                status_before_suspension = null,
+                deleted_at = now()
+            from restaurant old
+            where r.restaurant_id = $1 and old.restaurant_id = r.restaurant_id and old.status <> 'deleted'
+            returning old.username, old.name
+        "#,
+        restaurant_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    query!(
+        r#"
+            update staff
+            set username = 'deleted-' || staff_id,
+                password_hash = '',
+                totp_secret = null,
+                totp_enabled_at = null,
+                totp_last_step = null,
+                deactivated_at = coalesce(deactivated_at, now())
+            where restaurant_id = $1
+        "#,
+        restaurant_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    query!(
+        r#"
+            delete from staff_recovery_code
+            where staff_id in (select staff_id from staff where restaurant_id = $1)
+        "#,
+        restaurant_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    query!(
+        r#"delete from restaurant_recovery_code where restaurant_id = $1"#,
+        restaurant_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    query!(
+        r#"update api_key set revoked_at = now() where restaurant_id = $1 and revoked_at is null"#,
+        restaurant_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    query!(
+        r#"delete from password_reset where restaurant_id = $1"#,
+        restaurant_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    query!(
+        r#"delete from email_change where restaurant_id = $1"#,
+        restaurant_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    query!(
+        r#"delete from favourite_restaurant where restaurant_id = $1"#,
+        restaurant_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    query!(
+        r#"update item set available = false where restaurant_id = $1"#,
+        restaurant_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    query!(
+        r#"
+            update session set revoked_at = now()
+            where (restaurant_id = $1 or staff_id in (select staff_id from staff where restaurant_id = $1))
+              and revoked_at is null
+        "#,
+        restaurant_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    record_action(
+        &mut tx,
+        &auth_admin,
+        "delete_restaurant",
+        Some(restaurant_id),
+        json!({ "username": restaurant.username, "name": restaurant.name }),
+    )
+    .await?;
+
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Move a restaurant to `status`, if it currently has one of the statuses in `from`, or back to the status
+/// it had before it was suspended if `status` is `None`.
+async fn set_restaurant_status(
+    auth_admin: &AuthAdmin,
+    ctx: &AppContext,
+    restaurant_id: Uuid,
+    from: &[&str],
+    status: Option<&str>,
+) -> Result<()> {
+    let mut tx = ctx.db.begin().await?;
+
+    let current = query!(
+        r#"select status, status_before_suspension from restaurant where restaurant_id = $1 for update"#,
+        restaurant_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    if !from.contains(&current.status.as_str()) {
+        return Err(Error::unprocessable_entity([(
+            "status",
+            format!("restaurant is {}", current.status),
+        )]));
+    }
+
+    let status = match status {
+        Some(status) => status,
+        None => current
+            .status_before_suspension
+            .as_deref()
+            .unwrap_or("pending_approval"),
+    };
+
+    query!(
+        r#"
+            update restaurant
+            set status = $1,
+                status_before_suspension = case when $1 = 'suspended' then $2 end
+            where restaurant_id = $3
+        "#,
+        status,
+        current.status,
+        restaurant_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    record_action(
+        &mut tx,
+        auth_admin,
+        "set_restaurant_status",
+        Some(restaurant_id),
+        json!({ "status": status }),
+    )
+    .await?;
+
+    tx.commit().await?;
+    Ok(())
+}
+
+async fn suspend_restaurant(
+    auth_admin: AuthAdmin,
+    ctx: State<AppContext>,
+    Path(restaurant_id): Path<Uuid>,
+) -> Result<()> {
+    set_restaurant_status(
+        &auth_admin,
+        &ctx,
+        restaurant_id,
+        &["pending_approval", "active"],
+        Some("suspended"),
+    )
+    .await?;
+    end_sessions(&ctx, SessionOwner::Restaurant(restaurant_id), None).await
+}
+
+/// Undo a suspension, which only makes the restaurant active again if it had been approved.
+async fn reinstate_restaurant(
+    auth_admin: AuthAdmin,
+    ctx: State<AppContext>,
+    Path(restaurant_id): Path<Uuid>,
+) -> Result<()> {
+    set_restaurant_status(&auth_admin, &ctx, restaurant_id, &["suspended"], None).await
+}
+
+/// Let a restaurant that registered itself be listed and take orders.
+async fn approve_restaurant(
+    auth_admin: AuthAdmin,
+    ctx: State<AppContext>,
+    Path(restaurant_id): Path<Uuid>,
+) -> Result<()> {
+    set_restaurant_status(
+        &auth_admin,
+        &ctx,
+        restaurant_id,
+        &["pending_approval"],
+        Some("active"),
+    )
+    .await
+}
+
+async fn set_phonepe(
+    auth_admin: AuthAdmin,
+    ctx: State<AppContext>,
+    client: ClientInfo,
+    Path(restaurant_id): Path<Uuid>,
+    Json(req): Json<PhonepeDetails>,
+) -> Result<()> {
+    let mut tx = ctx.db.begin().await?;
+
+    let old = query!(
+        r#"
+            update restaurant set phonepe_id = $1, phonepe_key = $2, phonepe_key_id = $3
+            from (
+                select restaurant_id, phonepe_id, phonepe_key, phonepe_key_id from restaurant
+                where restaurant_id = $4
+                for update
+            ) old
+            where restaurant.restaurant_id = old.restaurant_id
+            returning old.phonepe_id, old.phonepe_key, old.phonepe_key_id
+        "#,
+        req.id,
+        req.key,
+        req.key_id,
+        restaurant_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    let mut changes = serde_json::Map::new();
+
+    if old.phonepe_id != req.id {
+        changes.insert("phonepe_id".into(), change(&old.phonepe_id, &req.id));
+    }
+
+    if old.phonepe_key_id != req.key_id {
+        changes.insert(
+            "phonepe_key_id".into(),
+            change(&old.phonepe_key_id, &req.key_id),
+        );
+    }
+
+    // Never put the key itself in the audit trail.
+    if old.phonepe_key != req.key {
+        changes.insert("phonepe_key".into(), SECRET_CHANGED.into());
+    }
+
+    AuditEvent::new("phonepe_changed", &auth_admin)
+        .restaurant(restaurant_id)
+        .changes(changes.into())
+        .record(&mut *tx, &client)
+        .await?;
+
+    record_action(
+        &mut tx,
+        &auth_admin,
+        "set_restaurant_phonepe",
+        Some(restaurant_id),
+        json!({ "phonepe_id": req.id, "phonepe_key_id": req.key_id }),
+    )
+    .await?;
+
+    tx.commit().await?;
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct NewPassword {
+    new_password: String,
+}
+
+async fn reset_restaurant_password(
+    auth_admin: AuthAdmin,
+    ctx: State<AppContext>,
+    client: ClientInfo,
+    Path(restaurant_id): Path<Uuid>,
+    Json(req): Json<NewPassword>,
+) -> Result<()> {
+    ctx.password_policy
+        .check("new_password", &req.new_password)?;
+    let hash = hash_password(&ctx, req.new_password).await?;
+
+    let mut tx = ctx.db.begin().await?;
+
+    let result = query!(
+        r#"update restaurant set password_hash = $1 where restaurant_id = $2"#,
+        hash,
+        restaurant_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(Error::NotFound);
+    }
+
+    record_action(
+        &mut tx,
+        &auth_admin,
+        "reset_restaurant_password",
+        Some(restaurant_id),
+        json!({}),
+    )
+    .await?;
+
+    AuditEvent::new("password_changed", &auth_admin)
+        .restaurant(restaurant_id)
+        .changes(json!({ "password": SECRET_CHANGED }))
+        .record(&mut *tx, &client)
+        .await?;
+
+    tx.commit().await?;
+
+    end_sessions(&ctx, SessionOwner::Restaurant(restaurant_id), None).await
+}
+
+/// Turn off a restaurant's second factor, for when it has lost both its authenticator and recovery codes.
+async fn reset_restaurant_mfa(
+    auth_admin: AuthAdmin,
+    ctx: State<AppContext>,
+    Path(restaurant_id): Path<Uuid>,
+) -> Result<()> {
+    let mut tx = ctx.db.begin().await?;
+
+    query!(
+        r#"select restaurant_id from restaurant where restaurant_id = $1 for update"#,
+        restaurant_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    mfa::clear_totp(&mut tx, mfa::MfaAccount::Restaurant(restaurant_id)).await?;
+
+    record_action(
+        &mut tx,
+        &auth_admin,
+        "reset_restaurant_mfa",
+        Some(restaurant_id),
+        json!({}),
+    )
+    .await?;
+
+    tx.commit().await?;
+
+    end_sessions(&ctx, SessionOwner::Restaurant(restaurant_id), None).await
+}
+
+// ========= users
+
+#[derive(Deserialize)]
+struct UserSearch {
+    /// Case-insensitive substring of the username.
+    q: Option<String>,
+    #[serde(flatten)]
+    page: Page,
+}
+
+#[derive(serde::Serialize)]
+struct Users {
+    users: Vec<UserSummary>,
+}
+
+#[derive(serde::Serialize)]
+struct UserSummary {
+    id: Uuid,
+    username: String,
+    created_at: DateTime<Utc>,
+    suspended_at: Option<DateTime<Utc>>,
+    /// Set once the user has deleted their account, which leaves it anonymised.
+    deleted_at: Option<DateTime<Utc>>,
+}
+
+async fn get_users(
+    _auth_admin: AuthAdmin,
+    ctx: State<AppContext>,
+    Query(search): Query<UserSearch>,
+) -> Result<Json<Users>> {
+    // `like` is not supported on nondeterministic collations, so search on a "C" copy.
+    let users = query!(
+        r#"
+            select user_id, username, created_at, suspended_at, deleted_at
+            from "user"
+            where $1::text is null
+               or (username collate "C") ilike '%' || $1 || '%'
+            order by created_at
+            limit $2 offset $3
+        "#,
+        search.q,
+        search.page.limit(),
+        search.page.offset()
+    )
+    .fetch_all(&ctx.db)
+    .await?
+    .into_iter()
+    .map(|row| UserSummary {
+        id: row.user_id,
+        username: row.username,
+        created_at: row.created_at,
+        suspended_at: row.suspended_at,
+        deleted_at: row.deleted_at,
+    })
+    .collect();
+
+    Ok(Json(Users { users }))
+}
+
+async fn set_user_suspended(
+    auth_admin: &AuthAdmin,
+    ctx: &AppContext,
+    user_id: Uuid,
+    suspended: bool,
+) -> Result<()> {
+    let mut tx = ctx.db.begin().await?;
+
+    let result = query!(
+        r#"
+            update "user"
+            set suspended_at = case when $1 then coalesce(suspended_at, now()) end
+            where user_id = $2
+        "#,
+        suspended,
+        user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(Error::NotFound);
+    }
+
+    record_action(
+        &mut tx,
+        auth_admin,
+        if suspended {
+            "suspend_user"
+        } else {
+            "reinstate_user"
+        },
+        Some(user_id),
+        json!({}),
+    )
+    .await?;
+
+    tx.commit().await?;
+    Ok(())
+}
+
+async fn suspend_user(
+    auth_admin: AuthAdmin,
+    ctx: State<AppContext>,
+    Path(user_id): Path<Uuid>,
+) -> Result<()> {
+    set_user_suspended(&auth_admin, &ctx, user_id, true).await?;
+    end_sessions(&ctx, SessionOwner::User(user_id), None).await
+}
+
+async fn reinstate_user(
+    auth_admin: AuthAdmin,
+    ctx: State<AppContext>,
+    Path(user_id): Path<Uuid>,
+) -> Result<()> {
+    set_user_suspended(&auth_admin, &ctx, user_id, false).await
+}
+
+async fn reset_user_password(
+    auth_admin: AuthAdmin,
+    ctx: State<AppContext>,
+    client: ClientInfo,
+    Path(user_id): Path<Uuid>,
+    Json(req): Json<NewPassword>,
+) -> Result<()> {
+    ctx.password_policy
+        .check("new_password", &req.new_password)?;
+    let hash = hash_password(&ctx, req.new_password).await?;
+
+    let mut tx = ctx.db.begin().await?;
+
+    let result = query!(
+        r#"update "user" set password_hash = $1 where user_id = $2"#,
+        hash,
+        user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(Error::NotFound);
+    }
+
+    record_action(
+        &mut tx,
+        &auth_admin,
+        "reset_user_password",
+        Some(user_id),
+        json!({}),
+    )
+    .await?;
+
+    AuditEvent::new("password_changed", &auth_admin)
+        .user(user_id)
+        .changes(json!({ "password": SECRET_CHANGED }))
+        .record(&mut *tx, &client)
+        .await?;
+
+    tx.commit().await?;
+
+    end_sessions(&ctx, SessionOwner::User(user_id), None).await
+}
+
+// ========= orders
+
+#[derive(Deserialize)]
+struct OrderSearch {
+    restaurant_id: Option<Uuid>,
+    user_id: Option<Uuid>,
+    status: Option<String>,
+    #[serde(flatten)]
+    page: Page,
+}
+
+#[derive(serde::Serialize)]
+struct Orders {
+    orders: Vec<OrderSummary>,
+}
+
+#[derive(serde::Serialize)]
+struct OrderSummary {
+    id: Uuid,
+    restaurant_id: Uuid,
+    restaurant_name: String,
+    user_id: Uuid,
+    user_name: String,
+    total: i32,
+    status: String,
+    created_at: DateTime<Utc>,
+    order_placed_time: Option<DateTime<Utc>>,
+    order_completed_time: Option<DateTime<Utc>>,
+}
+
+async fn get_orders(
+    _auth_admin: AuthAdmin,
+    ctx: State<AppContext>,
+    Query(search): Query<OrderSearch>,
+) -> Result<Json<Orders>> {
+    let orders = query!(
+        r#"
+            select o.order_id, o.restaurant_id, r.name as restaurant_name,
+                   o.user_id, u.username as user_name, o.total, o.status, o.created_at,
+                   o.order_placed_time, o.order_completed_time
+            from "order" o
+            join restaurant r using (restaurant_id)
+            join "user" u using (user_id)
+            where ($1::uuid is null or o.restaurant_id = $1)
+              and ($2::uuid is null or o.user_id = $2)
+              and ($3::text is null or o.status = $3)
+            order by o.created_at desc
+            limit $4 offset $5
+        "#,
+        search.restaurant_id,
+        search.user_id,
+        search.status,
+        search.page.limit(),
+        search.page.offset()
+    )
+    .fetch_all(&ctx.db)
+    .await?
+    .into_iter()
+    .map(|row| OrderSummary {
+        id: row.order_id,
+        restaurant_id: row.restaurant_id,
+        restaurant_name: row.restaurant_name,
+        user_id: row.user_id,
+        user_name: row.user_name,
+        total: row.total,
+        status: row.status,
+        created_at: row.created_at,
+        order_placed_time: row.order_placed_time,
+        order_completed_time: row.order_completed_time,
+    })
+    .collect();
+
+    Ok(Json(Orders { orders }))
+}
+
+// ========= audit trail
+
+#[derive(serde::Serialize)]
+struct Actions {
+    actions: Vec<Action>,
+}
+
+#[derive(serde::Serialize)]
+struct Action {
+    id: Uuid,
+    admin_id: Option<Uuid>,
+    admin_username: Option<String>,
+    action: String,
+    target_id: Option<Uuid>,
+    details: serde_json::Value,
+    created_at: DateTime<Utc>,
+}
+
+async fn get_actions(
+    _auth_admin: AuthAdmin,
+    ctx: State<AppContext>,
+    Query(page): Query<Page>,
+) -> Result<Json<Actions>> {
+    let actions = query!(
+        r#"
+            select a.admin_action_id, a.admin_id, ad.username as "admin_username?",
+                   a.action, a.target_id, a.details, a.created_at
+            from admin_action a
+            left join admin ad using (admin_id)
+            order by a.created_at desc
+            limit $1 offset $2
+        "#,
+        page.limit(),
+        page.offset()
+    )
+    .fetch_all(&ctx.db)
+    .await?
+    .into_iter()
+    .map(|row| Action {
+        id: row.admin_action_id,
+        admin_id: row.admin_id,
+        admin_username: row.admin_username,
+        action: row.action,
+        target_id: row.target_id,
+        details: row.details,
+        created_at: row.created_at,
+    })
+    .collect();
+
+    Ok(Json(Actions { actions }))
+}
+
+// ========= settings
+
+#[derive(serde::Serialize, Deserialize)]
+struct Settings {
+    /// Make every restaurant set up two-factor authentication the next time it logs in.
+    require_restaurant_mfa: bool,
+}
+
+async fn get_settings(_: AuthAdmin, ctx: State<AppContext>) -> Result<Json<Settings>> {
+    Ok(Json(Settings {
+        require_restaurant_mfa: mfa::restaurant_mfa_required(&ctx.db).await?,
+    }))
+}
+
+async fn update_settings(
+    auth_admin: AuthAdmin,
+    ctx: State<AppContext>,
+    Json(req): Json<Settings>,
+) -> Result<Json<Settings>> {
+    let mut tx = ctx.db.begin().await?;
+
+    query!(
+        r#"update platform_settings set require_restaurant_mfa = $1"#,
+        req.require_restaurant_mfa
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    record_action(
+        &mut tx,
+        &auth_admin,
+        "update_settings",
+        None,
+        json!({ "require_restaurant_mfa": req.require_restaurant_mfa }),
+    )
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(Json(req))
+}
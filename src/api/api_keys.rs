@@ -0,0 +1,230 @@
+use axum::extract::{Path, State};
+use axum::routing::{delete, get};
+use axum::{Json, Router};
+use chrono::{DateTime, Utc};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use sqlx::query;
+use uuid::Uuid;
+
+use crate::api::auth::{ApiKeyScope, AuthRestaurant, Permission};
+use crate::api::restaurants::record_action;
+use crate::api::{AppContext, Error, Result};
+
+/// Every key starts with this, so they're easy to recognise in configs and leaked secret scans.
+const KEY_PREFIX: &str = "kg_";
+
+/// How much of a key is kept in the clear, to tell keys apart when listing them.
+const SHOWN_KEY_LENGTH: usize = 11;
+
+pub(crate) fn router() -> Router<AppContext> {
+    Router::new()
+        .route(
+            "/api/restaurants/api_keys",
+            get(get_api_keys).post(create_api_key),
+        )
+        .route("/api/restaurants/api_keys/:id", delete(revoke_api_key))
+}
+
+fn generate_key() -> String {
+    let secret: [u8; 32] = rand::random();
+    format!("{}{}", KEY_PREFIX, hex::encode(secret))
+}
+
+/// Keys are random enough that a plain hash can't be brute forced,
+/// so they can be looked up by hash rather than checked one by one with a slow password hash.
+pub(super) fn hash_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct ApiKeyBody<T> {
+    api_key: T,
+}
+
+#[derive(serde::Serialize)]
+struct ApiKey {
+    id: Uuid,
+    name: String,
+    /// The start of the key.
+    prefix: String,
+    scopes: Vec<ApiKeyScope>,
+    expires_at: Option<DateTime<Utc>>,
+    last_used_at: Option<DateTime<Utc>>,
+    created_at: DateTime<Utc>,
+    /// Only returned when the key is created, it can't be looked up again.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+struct ApiKeyList {
+    api_keys: Vec<ApiKey>,
+}
+
+/// List the restaurant's keys that haven't been revoked, including expired ones.
+async fn get_api_keys(
+    auth_restaurant: AuthRestaurant,
+    ctx: State<AppContext>,
+) -> Result<Json<ApiKeyList>> {
+    auth_restaurant.require(Permission::ManageRestaurant)?;
+
+    let api_keys = query!(
+        r#"
+            select api_key_id, name, key_prefix, scopes, expires_at, last_used_at, created_at
+            from api_key
+            where restaurant_id = $1 and revoked_at is null
+            order by created_at
+        "#,
+        auth_restaurant.restaurant_id
+    )
+    .fetch_all(&ctx.db)
+    .await?
+    .into_iter()
+    .map(|row| {
+        Ok(ApiKey {
+            id: row.api_key_id,
+            name: row.name,
+            prefix: row.key_prefix,
+            scopes: row
+                .scopes
+                .iter()
+                .map(|scope| scope.parse())
+                .collect::<anyhow::Result<_>>()?,
+            expires_at: row.expires_at,
+            last_used_at: row.last_used_at,
+            created_at: row.created_at,
+            key: None,
+        })
+    })
+    .collect::<Result<_>>()?;
+
+    Ok(Json(ApiKeyList { api_keys }))
+}
+
+#[derive(serde::Deserialize)]
+struct NewApiKey {
+    name: String,
+    scopes: Vec<ApiKeyScope>,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+/// Create a key, returning it in full this one time.
+async fn create_api_key(
+    auth_restaurant: AuthRestaurant,
+    ctx: State<AppContext>,
+    Json(req): Json<ApiKeyBody<NewApiKey>>,
+) -> Result<Json<ApiKeyBody<ApiKey>>> {
+    auth_restaurant.require(Permission::ManageRestaurant)?;
+
+    let mut errors = Vec::new();
+
+    if req.api_key.name.trim().is_empty() {
+        errors.push(("name", "name is required"));
+    }
+
+    if req.api_key.scopes.is_empty() {
+        errors.push(("scopes", "at least one scope is required"));
+    }
+
+    if req.api_key.expires_at.is_some_and(|at| at <= Utc::now()) {
+        errors.push(("expires_at", "must be in the future"));
+    }
+
+    if !errors.is_empty() {
+        return Err(Error::unprocessable_entity(errors));
+    }
+
+    let mut scopes = req.api_key.scopes;
+    scopes.sort_by_key(|scope| scope.as_str());
+    scopes.dedup();
+
+    let key = generate_key();
+    let prefix = key[..SHOWN_KEY_LENGTH].to_owned();
+
+    let mut tx = ctx.db.begin().await?;
+
+    let api_key = query!(
+        r#"
+            insert into api_key (restaurant_id, name, key_hash, key_prefix, scopes, expires_at)
+            values ($1, $2, $3, $4, $5, $6)
+            returning api_key_id, created_at
+        "#,
+        auth_restaurant.restaurant_id,
+        req.api_key.name,
+        hash_key(&key),
+        prefix,
+        &scopes
+            .iter()
+            .map(|scope| scope.as_str().to_owned())
+            .collect::<Vec<_>>(),
+        req.api_key.expires_at
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    record_action(
+        &mut *tx,
+        &auth_restaurant,
+        "create_api_key",
+        Some(api_key.api_key_id),
+        json!({ "name": req.api_key.name, "scopes": scopes }),
+    )
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(Json(ApiKeyBody {
+        api_key: ApiKey {
+            id: api_key.api_key_id,
+            name: req.api_key.name,
+            prefix,
+            scopes,
+            expires_at: req.api_key.expires_at,
+            last_used_at: None,
+            created_at: api_key.created_at,
+            key: Some(key),
+        },
+    }))
+}
+
+/// Revoke a key, which stops working immediately.
+///
+/// Revoked keys are kept so that what was done with them stays attributed.
+async fn revoke_api_key(
+    auth_restaurant: AuthRestaurant,
+    ctx: State<AppContext>,
+    Path(api_key_id): Path<Uuid>,
+) -> Result<()> {
+    auth_restaurant.require(Permission::ManageRestaurant)?;
+
+    let mut tx = ctx.db.begin().await?;
+
+    let result = query!(
+        r#"
+            update api_key set revoked_at = now()
+            where api_key_id = $1 and restaurant_id = $2 and revoked_at is null
+        "#,
+        api_key_id,
+        auth_restaurant.restaurant_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(Error::NotFound);
+    }
+
+    record_action(
+        &mut *tx,
+        &auth_restaurant,
+        "revoke_api_key",
+        Some(api_key_id),
+        json!({}),
+    )
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(())
+}
@@ -0,0 +1,275 @@
+use axum::extract::{Query, State};
+use axum::routing::get;
+use axum::{Json, Router};
+use chrono::{DateTime, Utc};
+use serde_json::json;
+use sqlx::{query, query_as, PgExecutor};
+use uuid::Uuid;
+
+use crate::api::admin::Page;
+use crate::api::auth::{AuthAdmin, AuthIntegration, AuthRestaurant, AuthUser, Permission};
+use crate::api::util::ClientInfo;
+use crate::api::{AppContext, Result};
+
+pub(crate) fn router() -> Router<AppContext> {
+    Router::new()
+        .route("/api/admin/audit_events", get(get_admin_audit_events))
+        .route(
+            "/api/restaurants/audit_events",
+            get(get_restaurant_audit_events),
+        )
+}
+
+/// Who did something recorded in the audit log.
+#[derive(Clone, Copy, Debug)]
+pub(super) enum Actor {
+    /// Nobody was signed in, e.g. for a failed login or a password reset with a code.
+    Anonymous,
+    User(Uuid),
+    Restaurant(Uuid),
+    Staff(Uuid),
+    ApiKey(Uuid),
+    Admin(Uuid),
+}
+
+impl Actor {
+    fn kind(self) -> Option<&'static str> {
+        match self {
+            Self::Anonymous => None,
+            Self::User(_) => Some("user"),
+            Self::Restaurant(_) => Some("restaurant"),
+            Self::Staff(_) => Some("staff"),
+            Self::ApiKey(_) => Some("api_key"),
+            Self::Admin(_) => Some("admin"),
+        }
+    }
+
+    fn id(self) -> Option<Uuid> {
+        match self {
+            Self::Anonymous => None,
+            Self::User(id)
+            | Self::Restaurant(id)
+            | Self::Staff(id)
+            | Self::ApiKey(id)
+            | Self::Admin(id) => Some(id),
+        }
+    }
+}
+
+impl From<&AuthUser> for Actor {
+    fn from(auth_user: &AuthUser) -> Self {
+        Self::User(auth_user.user_id)
+    }
+}
+
+impl From<&AuthRestaurant> for Actor {
+    fn from(auth_restaurant: &AuthRestaurant) -> Self {
+        match auth_restaurant.staff_id {
+            Some(staff_id) => Self::Staff(staff_id),
+            None => Self::Restaurant(auth_restaurant.restaurant_id),
+        }
+    }
+}
+
+impl From<&AuthIntegration> for Actor {
+    fn from(auth: &AuthIntegration) -> Self {
+        match auth {
+            AuthIntegration::Restaurant(auth_restaurant) => auth_restaurant.into(),
+            AuthIntegration::ApiKey(auth_api_key) => Self::ApiKey(auth_api_key.api_key_id),
+        }
+    }
+}
+
+impl From<&AuthAdmin> for Actor {
+    fn from(auth_admin: &AuthAdmin) -> Self {
+        Self::Admin(auth_admin.admin_id)
+    }
+}
+
+/// An entry for the audit log, which can't be changed once recorded.
+pub(super) struct AuditEvent {
+    event: &'static str,
+    actor: Actor,
+    restaurant_id: Option<Uuid>,
+    user_id: Option<Uuid>,
+    target_id: Option<Uuid>,
+    changes: serde_json::Value,
+}
+
+impl AuditEvent {
+    pub fn new(event: &'static str, actor: impl Into<Actor>) -> Self {
+        Self {
+            event,
+            actor: actor.into(),
+            restaurant_id: None,
+            user_id: None,
+            target_id: None,
+            changes: json!({}),
+        }
+    }
+
+    /// The restaurant the event concerns, whose owner can then see it.
+    pub fn restaurant(mut self, restaurant_id: impl Into<Option<Uuid>>) -> Self {
+        self.restaurant_id = restaurant_id.into();
+        self
+    }
+
+    /// The user the event concerns.
+    pub fn user(mut self, user_id: impl Into<Option<Uuid>>) -> Self {
+        self.user_id = user_id.into();
+        self
+    }
+
+    pub fn target(mut self, target_id: Uuid) -> Self {
+        self.target_id = Some(target_id);
+        self
+    }
+
+    /// What changed, see [`change`] and [`SECRET_CHANGED`].
+    pub fn changes(mut self, changes: serde_json::Value) -> Self {
+        self.changes = changes;
+        self
+    }
+
+    pub async fn record(self, db: impl PgExecutor<'_>, client: &ClientInfo) -> Result<()> {
+        query!(
+            r#"
+                insert into audit_event
+                    (event, actor_kind, actor_id, restaurant_id, user_id, target_id, ip, user_agent, changes)
+                values ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+            "#,
+            self.event,
+            self.actor.kind(),
+            self.actor.id(),
+            self.restaurant_id,
+            self.user_id,
+            self.target_id,
+            client.ip.map(|ip| ip.to_string()),
+            client.user_agent,
+            self.changes
+        )
+        .execute(db)
+        .await?;
+
+        Ok(())
+    }
+}
+
+/// A field of [`AuditEvent::changes`] that went from `from` to `to`.
+pub(super) fn change(from: impl serde::Serialize, to: impl serde::Serialize) -> serde_json::Value {
+    json!({ "from": from, "to": to })
+}
+
+/// A field of [`AuditEvent::changes`] whose value must never be recorded, such as a password.
+pub(super) const SECRET_CHANGED: &str = "changed";
+
+#[derive(serde::Serialize)]
+struct AuditEvents {
+    audit_events: Vec<AuditEventRow>,
+}
+
+#[derive(serde::Serialize)]
+struct AuditEventRow {
+    id: Uuid,
+    event: String,
+    actor_kind: Option<String>,
+    actor_id: Option<Uuid>,
+    restaurant_id: Option<Uuid>,
+    user_id: Option<Uuid>,
+    target_id: Option<Uuid>,
+    ip: Option<String>,
+    user_agent: Option<String>,
+    changes: serde_json::Value,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(serde::Deserialize)]
+struct AdminFilter {
+    event: Option<String>,
+    actor_id: Option<Uuid>,
+    restaurant_id: Option<Uuid>,
+    user_id: Option<Uuid>,
+    #[serde(flatten)]
+    page: Page,
+}
+
+/// Search every event, newest first.
+async fn get_admin_audit_events(
+    _auth_admin: AuthAdmin,
+    ctx: State<AppContext>,
+    Query(filter): Query<AdminFilter>,
+) -> Result<Json<AuditEvents>> {
+    let audit_events = query_as!(
+        AuditEventRow,
+        r#"
+            select audit_event_id as id, event, actor_kind, actor_id, restaurant_id, user_id,
+                   target_id, ip, user_agent, changes, created_at
+            from audit_event
+            where ($1::text is null or event = $1)
+              and ($2::uuid is null or actor_id = $2)
+              and ($3::uuid is null or restaurant_id = $3)
+              and ($4::uuid is null or user_id = $4)
+            order by created_at desc
+            limit $5 offset $6
+        "#,
+        filter.event,
+        filter.actor_id,
+        filter.restaurant_id,
+        filter.user_id,
+        filter.page.limit(),
+        filter.page.offset()
+    )
+    .fetch_all(&ctx.db)
+    .await?;
+
+    Ok(Json(AuditEvents { audit_events }))
+}
+
+#[derive(serde::Deserialize)]
+struct RestaurantFilter {
+    event: Option<String>,
+    #[serde(flatten)]
+    page: Page,
+}
+
+/// The events concerning the owner's own restaurant, newest first.
+///
+/// Events can concern a restaurant without it or its staff doing them, like a user cancelling an order,
+/// and where those came from is the user's business only.
+async fn get_restaurant_audit_events(
+    auth_restaurant: AuthRestaurant,
+    ctx: State<AppContext>,
+    Query(filter): Query<RestaurantFilter>,
+) -> Result<Json<AuditEvents>> {
+    auth_restaurant.require(Permission::ManageRestaurant)?;
+
+    let audit_events = query_as!(
+        AuditEventRow,
+        r#"
+            with own_actor as (
+                select 'restaurant' as kind, $1 as id
+                union all
+                select 'staff', staff_id from staff where restaurant_id = $1
+                union all
+                select 'api_key', api_key_id from api_key where restaurant_id = $1
+            )
+            select audit_event_id as id, event, actor_kind, actor_id, restaurant_id, user_id, target_id,
+                   case when own_actor.id is not null then ip end as ip,
+                   case when own_actor.id is not null then user_agent end as user_agent,
+                   changes, created_at
+            from audit_event
+            left join own_actor on own_actor.kind = actor_kind and own_actor.id = actor_id
+            where restaurant_id = $1 and ($2::text is null or event = $2)
+            order by created_at desc
+            limit $3 offset $4
+        "#,
+        auth_restaurant.restaurant_id,
+        filter.event,
+        filter.page.limit(),
+        filter.page.offset()
+    )
+    .fetch_all(&ctx.db)
+    .await?;
+
+    Ok(Json(AuditEvents { audit_events }))
+}
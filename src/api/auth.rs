@@ -1,44 +1,95 @@
+use std::str::FromStr;
+
 use crate::api::Error;
 use axum::extract::{FromRef, FromRequestParts};
 use axum::http::request::Parts;
 use chrono::Utc;
 
+use crate::api::api_keys::hash_key;
+use crate::api::restaurants::can_log_in;
+use crate::api::sessions::ensure_session_active;
 use crate::api::AppContext;
 use async_trait::async_trait;
 use axum::http::header::AUTHORIZATION;
-use axum::http::HeaderValue;
-use hmac::{Hmac, NewMac};
-use jwt::{SignWithKey, VerifyWithKey};
-use sha2::Sha384;
+use axum::http::{HeaderName, HeaderValue};
 use uuid::Uuid;
 
-const DEFAULT_SESSION_LENGTH: chrono::Duration = chrono::Duration::weeks(1);
+/// Access tokens are short-lived; clients renew them with the refresh token of their session.
+const ACCESS_TOKEN_LENGTH: chrono::Duration = chrono::Duration::minutes(15);
+
+/// How far in the future an `iat` claim may be before we assume the token is bogus,
+/// to allow for clock skew between us and services minting tokens with our keys.
+const ISSUED_AT_LEEWAY: chrono::Duration = chrono::Duration::minutes(1);
+
+/// Value of the `iss` claim of every token we issue.
+const TOKEN_ISSUER: &str = "kg-rust";
+
+/// Value of the `aud` claim of access tokens for this API.
+const TOKEN_AUDIENCE: &str = "kg-rust:api";
+
+/// Value of the `aud` claim of tokens handed out between the password and second factor steps of a login,
+/// so they can't be used as access tokens.
+const MFA_TOKEN_AUDIENCE: &str = "kg-rust:mfa";
+
+/// How long a client has to provide the second factor after getting the password right.
+const MFA_TOKEN_LENGTH: chrono::Duration = chrono::Duration::minutes(5);
 
 const SCHEME_PREFIX: &str = "Bearer ";
 
-pub struct AuthUser {
-    pub user_id: Uuid,
+/// Header restaurant integrations send their API key in, see [`AuthIntegration`].
+pub(in crate::api) const API_KEY_HEADER: HeaderName = HeaderName::from_static("x-api-key");
+
+/// The kinds of account a token can be issued to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PrincipalKind {
+    User,
+    Restaurant,
+    Admin,
+    Staff,
+}
+
+/// Whoever the access token in the `Authorization` header was issued to.
+///
+/// Handlers should normally ask for one of the role guards below, such as [`AuthUser`],
+/// which reject tokens of any other kind.
+pub struct Principal {
+    pub kind: PrincipalKind,
+    /// The id of the user, restaurant, admin or staff member, depending on `kind`.
+    pub id: Uuid,
+    pub session_id: Uuid,
 }
-pub struct MaybeAuthUser(pub Option<AuthUser>);
 
 #[derive(serde::Serialize, serde::Deserialize)]
-struct AuthUserClaims {
-    user_id: Uuid,
+struct Claims {
+    /// Standard JWT `sub` claim.
+    sub: Uuid,
+    kind: PrincipalKind,
+    /// The session this token was issued under.
+    sid: Uuid,
+    /// Standard JWT `iss` claim.
+    iss: String,
+    /// Standard JWT `aud` claim.
+    aud: String,
+    /// Standard JWT `iat` claim.
+    iat: i64,
     /// Standard JWT `exp` claim.
     exp: i64,
 }
 
-impl AuthUser {
+impl Principal {
     pub(in crate::api) fn to_jwt(&self, ctx: &AppContext) -> String {
-        let hmac = Hmac::<Sha384>::new_from_slice(ctx.config.hmac_key.as_bytes())
-            .expect("HMAC-SHA-384 can accept any key length");
-
-        AuthUserClaims {
-            user_id: self.user_id,
-            exp: (Utc::now() + DEFAULT_SESSION_LENGTH).timestamp(),
-        }
-        .sign_with_key(&hmac)
-        .expect("HMAC signing should be infallible")
+        let now = Utc::now();
+
+        ctx.jwt_keys.sign(Claims {
+            sub: self.id,
+            kind: self.kind,
+            sid: self.session_id,
+            iss: TOKEN_ISSUER.into(),
+            aud: TOKEN_AUDIENCE.into(),
+            iat: now.timestamp(),
+            exp: (now + ACCESS_TOKEN_LENGTH).timestamp(),
+        })
     }
 
     /// Attempt to parse `Self` from an `Authorization` header.
@@ -58,39 +109,46 @@ impl AuthUser {
 
         let token = &auth_header[SCHEME_PREFIX.len()..];
 
-        let jwt = jwt::Token::<jwt::Header, AuthUserClaims, _>::parse_unverified(token)
-            .map_err(|_| Error::Unauthorized)?;
-
-        let hmac = Hmac::<Sha384>::new_from_slice(ctx.config.hmac_key.as_bytes())
-            .expect("HMAC-SHA-384 can accept any key length");
-
-        let jwt = jwt.verify_with_key(&hmac).map_err(|e| {
+        let claims: Claims = ctx.jwt_keys.verify(token).map_err(|e| {
             log::debug!("JWT failed to verify: {}", e);
             Error::Unauthorized
         })?;
 
-        let (_header, claims) = jwt.into();
-
-        if claims.exp < Utc::now().timestamp() {
-            log::debug!("token expired");
-            return Err(Error::Unauthorized);
-        }
+        check_registered_claims(
+            &claims.iss,
+            &claims.aud,
+            claims.iat,
+            claims.exp,
+            TOKEN_AUDIENCE,
+        )?;
 
         Ok(Self {
-            user_id: claims.user_id,
+            kind: claims.kind,
+            id: claims.sub,
+            session_id: claims.sid,
         })
     }
-}
 
-impl MaybeAuthUser {
-    /// If this is `Self(Some(AuthUser))`, return `AuthUser::user_id`
-    pub fn restaurant_id(&self) -> Option<Uuid> {
-        self.0.as_ref().map(|auth_user| auth_user.user_id)
+    /// Parse `Self` from an `Authorization` header and check that its session is still active.
+    async fn authenticate(ctx: &AppContext, auth_header: &HeaderValue) -> Result<Self, Error> {
+        let principal = Self::from_authorization(ctx, auth_header)?;
+        ensure_session_active(ctx, principal.session_id).await?;
+        Ok(principal)
+    }
+
+    /// Role guard: reject the request unless this is a principal of `kind`.
+    pub fn require(self, kind: PrincipalKind) -> Result<Self, Error> {
+        if self.kind != kind {
+            log::debug!("expected a {:?} token, got a {:?} token", kind, self.kind);
+            return Err(Error::Forbidden);
+        }
+
+        Ok(self)
     }
 }
 
 #[async_trait]
-impl<S> FromRequestParts<S> for AuthUser
+impl<S> FromRequestParts<S> for Principal
 where
     S: Send + Sync,
     AppContext: FromRef<S>,
@@ -106,12 +164,119 @@ where
             .get(AUTHORIZATION)
             .ok_or(Error::Unauthorized)?;
 
-        Self::from_authorization(&ctx, auth_header)
+        Self::authenticate(&ctx, auth_header).await
+    }
+}
+
+/// Check the standard claims every token we issue carries.
+fn check_registered_claims(
+    iss: &str,
+    aud: &str,
+    iat: i64,
+    exp: i64,
+    expected_audience: &str,
+) -> Result<(), Error> {
+    if iss != TOKEN_ISSUER || aud != expected_audience {
+        log::debug!(
+            "token has the wrong issuer or audience: {:?} {:?}",
+            iss,
+            aud
+        );
+        return Err(Error::Unauthorized);
+    }
+
+    let now = Utc::now();
+
+    if exp < now.timestamp() {
+        log::debug!("token expired");
+        return Err(Error::Unauthorized);
+    }
+
+    if iat > (now + ISSUED_AT_LEEWAY).timestamp() {
+        log::debug!("token issued in the future");
+        return Err(Error::Unauthorized);
+    }
+
+    Ok(())
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct MfaClaims {
+    /// Standard JWT `sub` claim.
+    sub: Uuid,
+    kind: PrincipalKind,
+    /// Standard JWT `iss` claim.
+    iss: String,
+    /// Standard JWT `aud` claim.
+    aud: String,
+    /// Standard JWT `iat` claim.
+    iat: i64,
+    /// Standard JWT `exp` claim.
+    exp: i64,
+}
+
+/// A token proving that a restaurant or member of staff got its password right but still has to pass its second factor.
+pub(in crate::api) fn mfa_pending_token(ctx: &AppContext, kind: PrincipalKind, id: Uuid) -> String {
+    let now = Utc::now();
+
+    ctx.jwt_keys.sign(MfaClaims {
+        sub: id,
+        kind,
+        iss: TOKEN_ISSUER.into(),
+        aud: MFA_TOKEN_AUDIENCE.into(),
+        iat: now.timestamp(),
+        exp: (now + MFA_TOKEN_LENGTH).timestamp(),
+    })
+}
+
+/// Check a token from [`mfa_pending_token`], returning the restaurant or staff member of `kind` it was issued to.
+pub(in crate::api) fn verify_mfa_pending_token(
+    ctx: &AppContext,
+    token: &str,
+    kind: PrincipalKind,
+) -> Result<Uuid, Error> {
+    let claims: MfaClaims = ctx.jwt_keys.verify(token).map_err(|e| {
+        log::debug!("MFA token failed to verify: {}", e);
+        Error::Unauthorized
+    })?;
+
+    check_registered_claims(
+        &claims.iss,
+        &claims.aud,
+        claims.iat,
+        claims.exp,
+        MFA_TOKEN_AUDIENCE,
+    )?;
+
+    if claims.kind != kind {
+        log::debug!("MFA token issued to a {:?}", claims.kind);
+        return Err(Error::Unauthorized);
+    }
+
+    Ok(claims.sub)
+}
+
+// =========
+
+/// Role guard for endpoints only users may call.
+pub struct AuthUser {
+    pub user_id: Uuid,
+    pub session_id: Uuid,
+}
+
+impl AuthUser {
+    pub(in crate::api) fn to_jwt(&self, ctx: &AppContext) -> String {
+        Principal {
+            kind: PrincipalKind::User,
+            id: self.user_id,
+            session_id: self.session_id,
+        }
+        .to_jwt(ctx)
     }
 }
 
 #[async_trait]
-impl<S> FromRequestParts<S> for MaybeAuthUser
+impl<S> FromRequestParts<S> for AuthUser
 where
     S: Send + Sync,
     AppContext: FromRef<S>,
@@ -119,105 +284,353 @@ where
     type Rejection = Error;
 
     async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
-        let ctx: AppContext = AppContext::from_ref(state);
+        let principal = Principal::from_request_parts(parts, state)
+            .await?
+            .require(PrincipalKind::User)?;
 
-        Ok(Self(
-            // Get the value of the `Authorization` header, if it was sent at all.
-            parts
-                .headers
-                .get(AUTHORIZATION)
-                .map(|auth_header| AuthUser::from_authorization(&ctx, auth_header))
-                .transpose()?,
-        ))
+        Ok(Self {
+            user_id: principal.id,
+            session_id: principal.session_id,
+        })
     }
 }
 
 // =========
 
-pub struct AuthRestaurant {
-    pub restaurant_id: Uuid,
+/// The role of a member of a restaurant's staff, which decides what they may do.
+///
+/// The restaurant's own account is treated as [`StaffRole::Owner`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StaffRole {
+    Owner,
+    Manager,
+    Cashier,
+    Kitchen,
 }
 
-pub struct MaybeAuthRestaurant(pub Option<AuthRestaurant>);
+impl StaffRole {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Owner => "owner",
+            Self::Manager => "manager",
+            Self::Cashier => "cashier",
+            Self::Kitchen => "kitchen",
+        }
+    }
+
+    pub fn can(self, permission: Permission) -> bool {
+        use Permission::*;
 
-#[derive(serde::Serialize, serde::Deserialize)]
-struct AuthRestaurantClaims {
-    restaurant_id: Uuid,
-    exp: i64,
+        match self {
+            Self::Owner => true,
+            Self::Manager => !matches!(permission, ManageRestaurant | ManageStaff),
+            Self::Cashier => matches!(permission, ViewOrders | CompleteOrders | CancelOrders),
+            Self::Kitchen => matches!(permission, ViewOrders | CompleteOrders),
+        }
+    }
+}
+
+impl FromStr for StaffRole {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "owner" => Ok(Self::Owner),
+            "manager" => Ok(Self::Manager),
+            "cashier" => Ok(Self::Cashier),
+            "kitchen" => Ok(Self::Kitchen),
+            _ => Err(anyhow::anyhow!("unknown staff role {:?}", s)),
+        }
+    }
+}
+
+/// Things a restaurant account may or may not be allowed to do, depending on its [`StaffRole`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Permission {
+    /// Change the restaurant's name, password, opening hours and picture.
+    ManageRestaurant,
+    /// Add, edit and remove menu items.
+    ManageMenu,
+    /// Add, edit and deactivate staff accounts, and see what they did.
+    ManageStaff,
+    /// Send and delete broadcast notifications.
+    SendNotifications,
+    ViewOrders,
+    CompleteOrders,
+    CancelOrders,
+    ViewStats,
+}
+
+/// Role guard for endpoints only restaurants, or members of their staff, may call.
+///
+/// Handlers should check the [`Permission`] they need with [`AuthRestaurant::require`].
+pub struct AuthRestaurant {
+    pub restaurant_id: Uuid,
+    pub session_id: Uuid,
+    /// Set when a member of staff is signed in, rather than the restaurant's own account.
+    pub staff_id: Option<Uuid>,
+    pub role: StaffRole,
 }
 
 impl AuthRestaurant {
     pub(in crate::api) fn to_jwt(&self, ctx: &AppContext) -> String {
-        let hmac = Hmac::<Sha384>::new_from_slice(ctx.config.hmac_key.as_bytes())
-            .expect("HMAC-SHA-384 can accept any key length");
+        let (kind, id) = match self.staff_id {
+            Some(staff_id) => (PrincipalKind::Staff, staff_id),
+            None => (PrincipalKind::Restaurant, self.restaurant_id),
+        };
+
+        Principal {
+            kind,
+            id,
+            session_id: self.session_id,
+        }
+        .to_jwt(ctx)
+    }
 
-        AuthRestaurantClaims {
-            restaurant_id: self.restaurant_id,
-            exp: (Utc::now() + DEFAULT_SESSION_LENGTH).timestamp(),
+    /// Look up the restaurant and role of a restaurant or staff principal.
+    ///
+    /// Staff are looked up on every request so that role changes and deactivation apply immediately.
+    async fn from_principal(ctx: &AppContext, principal: Principal) -> Result<Self, Error> {
+        match principal.kind {
+            PrincipalKind::Restaurant => Ok(Self {
+                restaurant_id: principal.id,
+                session_id: principal.session_id,
+                staff_id: None,
+                role: StaffRole::Owner,
+            }),
+            PrincipalKind::Staff => {
+                let staff = sqlx::query!(
+                    r#"
+                        select restaurant_id, role, status from staff
+                        join restaurant using (restaurant_id)
+                        where staff_id = $1 and deactivated_at is null
+                    "#,
+                    principal.id
+                )
+                .fetch_optional(&ctx.db)
+                .await?
+                .filter(|staff| can_log_in(&staff.status))
+                .ok_or_else(|| {
+                    log::debug!(
+                        "staff {} or their restaurant is no longer active",
+                        principal.id
+                    );
+                    Error::Unauthorized
+                })?;
+
+                Ok(Self {
+                    restaurant_id: staff.restaurant_id,
+                    session_id: principal.session_id,
+                    staff_id: Some(principal.id),
+                    role: staff.role.parse()?,
+                })
+            }
+            kind => {
+                log::debug!(
+                    "expected a restaurant or staff token, got a {:?} token",
+                    kind
+                );
+                Err(Error::Forbidden)
+            }
         }
-        .sign_with_key(&hmac)
-        .expect("HMAC signing should be infallible")
     }
 
-    /// Attempt to parse `Self` from an `Authorization` header.
-    fn from_authorization(ctx: &AppContext, auth_header: &HeaderValue) -> Result<Self, Error> {
-        let auth_header = auth_header.to_str().map_err(|_| {
-            log::debug!("Authorization header is not UTF-8");
+    /// Permission guard: reject the request unless our role allows `permission`.
+    pub fn require(&self, permission: Permission) -> Result<(), Error> {
+        if !self.role.can(permission) {
+            log::debug!("{:?} staff may not {:?}", self.role, permission);
+            return Err(Error::Forbidden);
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for AuthRestaurant
+where
+    S: Send + Sync,
+    AppContext: FromRef<S>,
+{
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let ctx = AppContext::from_ref(state);
+        let principal = Principal::from_request_parts(parts, state).await?;
+
+        Self::from_principal(&ctx, principal).await
+    }
+}
+
+// =========
+
+/// What an API key may be used for, each allowing one [`Permission`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum ApiKeyScope {
+    #[serde(rename = "orders:read")]
+    OrdersRead,
+    #[serde(rename = "orders:complete")]
+    OrdersComplete,
+    #[serde(rename = "orders:cancel")]
+    OrdersCancel,
+    #[serde(rename = "menu:write")]
+    MenuWrite,
+    #[serde(rename = "stats:read")]
+    StatsRead,
+}
+
+impl ApiKeyScope {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::OrdersRead => "orders:read",
+            Self::OrdersComplete => "orders:complete",
+            Self::OrdersCancel => "orders:cancel",
+            Self::MenuWrite => "menu:write",
+            Self::StatsRead => "stats:read",
+        }
+    }
+
+    pub fn permission(self) -> Permission {
+        match self {
+            Self::OrdersRead => Permission::ViewOrders,
+            Self::OrdersComplete => Permission::CompleteOrders,
+            Self::OrdersCancel => Permission::CancelOrders,
+            Self::MenuWrite => Permission::ManageMenu,
+            Self::StatsRead => Permission::ViewStats,
+        }
+    }
+}
+
+impl FromStr for ApiKeyScope {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "orders:read" => Ok(Self::OrdersRead),
+            "orders:complete" => Ok(Self::OrdersComplete),
+            "orders:cancel" => Ok(Self::OrdersCancel),
+            "menu:write" => Ok(Self::MenuWrite),
+            "stats:read" => Ok(Self::StatsRead),
+            _ => Err(anyhow::anyhow!("unknown API key scope {:?}", s)),
+        }
+    }
+}
+
+/// A restaurant's integration, authenticated with one of its API keys.
+pub struct AuthApiKey {
+    pub api_key_id: Uuid,
+    pub restaurant_id: Uuid,
+    pub scopes: Vec<ApiKeyScope>,
+}
+
+impl AuthApiKey {
+    /// Look up an API key, recording that it was used.
+    ///
+    /// Revoked and expired keys, and keys of restaurants that can't log in, are rejected.
+    async fn from_key(ctx: &AppContext, key: &HeaderValue) -> Result<Self, Error> {
+        let key = key.to_str().map_err(|_| {
+            log::debug!("API key is not valid ASCII");
             Error::Unauthorized
         })?;
 
-        if !auth_header.starts_with(SCHEME_PREFIX) {
+        let api_key = sqlx::query!(
+            r#"
+                update api_key set last_used_at = now()
+                from restaurant
+                where api_key.restaurant_id = restaurant.restaurant_id
+                  and key_hash = $1
+                  and revoked_at is null
+                  and (expires_at is null or expires_at > now())
+                  -- the statuses `can_log_in` accepts, checked here so rejected keys aren't marked as used
+                  and status in ('active', 'pending_approval')
+                returning api_key_id, api_key.restaurant_id, scopes
+            "#,
+            hash_key(key)
+        )
+        .fetch_optional(&ctx.db)
+        .await?
+        .ok_or_else(|| {
+            log::debug!("unknown, revoked or expired API key, or its restaurant can't log in");
+            Error::Unauthorized
+        })?;
+
+        Ok(Self {
+            api_key_id: api_key.api_key_id,
+            restaurant_id: api_key.restaurant_id,
+            scopes: api_key
+                .scopes
+                .iter()
+                .map(|scope| scope.parse())
+                .collect::<anyhow::Result<_>>()?,
+        })
+    }
+
+    /// Permission guard: reject the request unless one of our scopes allows `permission`.
+    pub fn require(&self, permission: Permission) -> Result<(), Error> {
+        if !self
+            .scopes
+            .iter()
+            .any(|scope| scope.permission() == permission)
+        {
             log::debug!(
-                "Authorization header is using the wrong scheme: {:?}",
-                auth_header
+                "API key {} has no scope for {:?}",
+                self.api_key_id,
+                permission
             );
-            return Err(Error::Unauthorized);
+            return Err(Error::Forbidden);
         }
 
-        let token = &auth_header[SCHEME_PREFIX.len()..];
-
-        let jwt = jwt::Token::<jwt::Header, AuthRestaurantClaims, _>::parse_unverified(token)
-            .map_err(|e| {
-                log::debug!(
-                    "failed to parse Authorization header {:?}: {}",
-                    auth_header,
-                    e
-                );
-                Error::Unauthorized
-            })?;
+        Ok(())
+    }
+}
 
-        let hmac = Hmac::<Sha384>::new_from_slice(ctx.config.hmac_key.as_bytes())
-            .expect("HMAC-SHA-384 can accept any key length");
+#[async_trait]
+impl<S> FromRequestParts<S> for AuthApiKey
+where
+    S: Send + Sync,
+    AppContext: FromRef<S>,
+{
+    type Rejection = Error;
 
-        let jwt = jwt.verify_with_key(&hmac).map_err(|e| {
-            log::debug!("JWT failed to verify: {}", e);
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let ctx = AppContext::from_ref(state);
+        let key = parts.headers.get(API_KEY_HEADER).ok_or_else(|| {
+            log::debug!("missing {} header", API_KEY_HEADER);
             Error::Unauthorized
         })?;
 
-        let (_header, claims) = jwt.into();
+        Self::from_key(&ctx, key).await
+    }
+}
 
-        if claims.exp < Utc::now().timestamp() {
-            log::debug!("token expired");
-            return Err(Error::Unauthorized);
-        }
+/// Role guard for the restaurant endpoints integrations may call,
+/// accepting an API key in the `X-Api-Key` header as well as a restaurant or staff token.
+///
+/// Handlers should check the [`Permission`] they need with [`AuthIntegration::require`].
+pub enum AuthIntegration {
+    Restaurant(AuthRestaurant),
+    ApiKey(AuthApiKey),
+}
 
-        Ok(Self {
-            restaurant_id: claims.restaurant_id,
-        })
+impl AuthIntegration {
+    pub fn restaurant_id(&self) -> Uuid {
+        match self {
+            Self::Restaurant(auth_restaurant) => auth_restaurant.restaurant_id,
+            Self::ApiKey(auth_api_key) => auth_api_key.restaurant_id,
+        }
     }
-}
 
-impl MaybeAuthRestaurant {
-    /// If this is `Self(Some(AuthUser))`, return `AuthUser::user_id`
-    pub fn user_id(&self) -> Option<Uuid> {
-        self.0
-            .as_ref()
-            .map(|auth_restaurant: &AuthRestaurant| auth_restaurant.restaurant_id)
+    pub fn require(&self, permission: Permission) -> Result<(), Error> {
+        match self {
+            Self::Restaurant(auth_restaurant) => auth_restaurant.require(permission),
+            Self::ApiKey(auth_api_key) => auth_api_key.require(permission),
+        }
     }
 }
 
 #[async_trait]
-impl<S> FromRequestParts<S> for AuthRestaurant
+impl<S> FromRequestParts<S> for AuthIntegration
 where
     S: Send + Sync,
     AppContext: FromRef<S>,
@@ -225,20 +638,39 @@ where
     type Rejection = Error;
 
     async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
-        let ctx: AppContext = AppContext::from_ref(state);
+        if parts.headers.contains_key(API_KEY_HEADER) {
+            return Ok(Self::ApiKey(
+                AuthApiKey::from_request_parts(parts, state).await?,
+            ));
+        }
 
-        // Get the value of the `Authorization` header, if it was sent at all.
-        let auth_header = parts
-            .headers
-            .get(AUTHORIZATION)
-            .ok_or(Error::Unauthorized)?;
+        Ok(Self::Restaurant(
+            AuthRestaurant::from_request_parts(parts, state).await?,
+        ))
+    }
+}
 
-        Self::from_authorization(&ctx, auth_header)
+// =========
+
+/// Role guard for the platform administration endpoints.
+pub struct AuthAdmin {
+    pub admin_id: Uuid,
+    pub session_id: Uuid,
+}
+
+impl AuthAdmin {
+    pub(in crate::api) fn to_jwt(&self, ctx: &AppContext) -> String {
+        Principal {
+            kind: PrincipalKind::Admin,
+            id: self.admin_id,
+            session_id: self.session_id,
+        }
+        .to_jwt(ctx)
     }
 }
 
 #[async_trait]
-impl<S> FromRequestParts<S> for MaybeAuthRestaurant
+impl<S> FromRequestParts<S> for AuthAdmin
 where
     S: Send + Sync,
     AppContext: FromRef<S>,
@@ -246,22 +678,25 @@ where
     type Rejection = Error;
 
     async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
-        let ctx: AppContext = AppContext::from_ref(state);
+        let principal = Principal::from_request_parts(parts, state)
+            .await?
+            .require(PrincipalKind::Admin)?;
 
-        Ok(Self(
-            // Get the value of the `Authorization` header, if it was sent at all.
-            parts
-                .headers
-                .get(AUTHORIZATION)
-                .map(|auth_header| AuthRestaurant::from_authorization(&ctx, auth_header))
-                .transpose()?,
-        ))
+        Ok(Self {
+            admin_id: principal.id,
+            session_id: principal.session_id,
+        })
     }
 }
 
+// =========
+
+/// Role guard for endpoints that both users and restaurants may call, with different behaviour.
+///
+/// Restaurants may also call them with an API key, see [`AuthIntegration`].
 pub enum Auth {
     User(AuthUser),
-    Restaurant(AuthRestaurant),
+    Restaurant(AuthIntegration),
 }
 
 #[async_trait]
@@ -273,13 +708,30 @@ where
     type Rejection = Error;
 
     async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
-        if let Ok(user) = AuthUser::from_request_parts(parts, state).await {
-            return Ok(Self::User(user));
-        }
-        if let Ok(restaurant) = AuthRestaurant::from_request_parts(parts, state).await {
-            return Ok(Self::Restaurant(restaurant));
+        if parts.headers.contains_key(API_KEY_HEADER) {
+            return Ok(Self::Restaurant(AuthIntegration::ApiKey(
+                AuthApiKey::from_request_parts(parts, state).await?,
+            )));
         }
 
-        Err(Error::Unauthorized)
+        let ctx = AppContext::from_ref(state);
+        let principal = Principal::from_request_parts(parts, state).await?;
+
+        match principal.kind {
+            PrincipalKind::User => Ok(Self::User(AuthUser {
+                user_id: principal.id,
+                session_id: principal.session_id,
+            })),
+            PrincipalKind::Restaurant | PrincipalKind::Staff => Ok(Self::Restaurant(
+                AuthIntegration::Restaurant(AuthRestaurant::from_principal(&ctx, principal).await?),
+            )),
+            kind => {
+                log::debug!(
+                    "expected a user or restaurant token, got a {:?} token",
+                    kind
+                );
+                Err(Error::Forbidden)
+            }
+        }
     }
 }
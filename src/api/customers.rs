@@ -0,0 +1,290 @@
+use axum::extract::{Path, Query, State};
+use axum::routing::{get, post, put};
+use axum::{Json, Router};
+use chrono::{DateTime, Utc};
+use serde_json::json;
+use sqlx::{query, PgConnection};
+use uuid::Uuid;
+
+use crate::api::admin::Page;
+use crate::api::auth::{AuthIntegration, AuthRestaurant, Permission};
+use crate::api::restaurants::record_action;
+use crate::api::{AppContext, Error, Result};
+
+const MAX_BLOCK_REASON_LENGTH: usize = 500;
+
+pub(crate) fn router() -> Router<AppContext> {
+    Router::new()
+        .route("/api/restaurants/customers", get(get_customers))
+        .route(
+            "/api/restaurants/customers/:user_id/block",
+            put(block_customer).delete(unblock_customer),
+        )
+        .route("/api/orders/no_show/:order_id", post(mark_no_show))
+}
+
+/// Refuse orders from users the restaurant has blocked, in the same transaction as making the order.
+pub(super) async fn check_not_blocked(
+    tx: &mut PgConnection,
+    restaurant_id: Uuid,
+    user_id: Uuid,
+) -> Result<()> {
+    let blocked = sqlx::query_scalar!(
+        r#"
+            select exists(
+                select 1 from restaurant_customer
+                where restaurant_id = $1 and user_id = $2 and blocked_at is not null
+            ) as "blocked!"
+        "#,
+        restaurant_id,
+        user_id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    if blocked {
+        log::debug!(
+            "user {} is blocked at restaurant {}",
+            user_id,
+            restaurant_id
+        );
+        return Err(Error::unprocessable_entity([(
+            "restaurant_id",
+            "this restaurant is no longer taking orders from you, please contact them to sort it out",
+        )]));
+    }
+
+    Ok(())
+}
+
+/// Count a paid order the user cancelled, in the same transaction as cancelling it.
+pub(super) async fn count_cancellation(
+    tx: &mut PgConnection,
+    restaurant_id: Uuid,
+    user_id: Uuid,
+) -> Result<()> {
+    query!(
+        r#"
+            insert into restaurant_customer (restaurant_id, user_id, cancellations) values ($1, $2, 1)
+            on conflict (restaurant_id, user_id) do update
+            set cancellations = restaurant_customer.cancellations + 1
+        "#,
+        restaurant_id,
+        user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    Ok(())
+}
+
+/// Mark a paid order as never collected, counting it against the user.
+async fn mark_no_show(
+    auth_restaurant: AuthIntegration,
+    ctx: State<AppContext>,
+    Path(order_id): Path<Uuid>,
+) -> Result<()> {
+    auth_restaurant.require(Permission::CompleteOrders)?;
+
+    let mut tx = ctx.db.begin().await?;
+
+    let user_id = sqlx::query_scalar!(
+        r#"
+            update "order" set no_show_at = now()
+            where order_id = $1 and restaurant_id = $2 and status in ('paid', 'completed') and no_show_at is null
+            returning user_id
+        "#,
+        order_id,
+        auth_restaurant.restaurant_id()
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    // Already marked, or never paid for.
+    let Some(user_id) = user_id else {
+        return Err(Error::unprocessable_entity([(
+            "order",
+            "can only mark paid orders as not collected, once",
+        )]));
+    };
+
+    query!(
+        r#"
+            insert into restaurant_customer (restaurant_id, user_id, no_shows) values ($1, $2, 1)
+            on conflict (restaurant_id, user_id) do update
+            set no_shows = restaurant_customer.no_shows + 1
+        "#,
+        auth_restaurant.restaurant_id(),
+        user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    record_action(
+        &mut *tx,
+        &auth_restaurant,
+        "mark_no_show",
+        Some(order_id),
+        json!({ "user_id": user_id }),
+    )
+    .await?;
+
+    tx.commit().await?;
+    Ok(())
+}
+
+#[derive(serde::Serialize)]
+struct Customers {
+    customers: Vec<Customer>,
+}
+
+#[derive(serde::Serialize)]
+struct Customer {
+    user_id: Uuid,
+    username: String,
+    no_shows: i32,
+    cancellations: i32,
+    blocked_at: Option<DateTime<Utc>>,
+    block_reason: Option<String>,
+}
+
+/// Users with no-shows or cancellations at the restaurant, or that it has blocked, blocked ones first.
+async fn get_customers(
+    auth_restaurant: AuthRestaurant,
+    ctx: State<AppContext>,
+    Query(page): Query<Page>,
+) -> Result<Json<Customers>> {
+    auth_restaurant.require(Permission::ViewOrders)?;
+
+    let customers = sqlx::query_as!(
+        Customer,
+        r#"
+            select c.user_id, u.username, c.no_shows, c.cancellations, c.blocked_at, c.block_reason
+            from restaurant_customer c
+            join "user" u using (user_id)
+            where c.restaurant_id = $1
+            order by c.blocked_at is null, c.no_shows + c.cancellations desc, u.username
+            limit $2 offset $3
+        "#,
+        auth_restaurant.restaurant_id,
+        page.limit(),
+        page.offset()
+    )
+    .fetch_all(&ctx.db)
+    .await?;
+
+    Ok(Json(Customers { customers }))
+}
+
+#[derive(serde::Deserialize)]
+struct Block {
+    reason: Option<String>,
+}
+
+/// Stop taking orders from a user, who only needs to have ordered from the restaurant before.
+///
+/// Blocking is a sanction by the restaurant as a whole, so only owners may do it: it needs
+/// [`Permission::ManageRestaurant`], which managers don't have.
+async fn block_customer(
+    auth_restaurant: AuthRestaurant,
+    ctx: State<AppContext>,
+    Path(user_id): Path<Uuid>,
+    Json(req): Json<Block>,
+) -> Result<()> {
+    auth_restaurant.require(Permission::ManageRestaurant)?;
+
+    let reason = req
+        .reason
+        .map(|reason| reason.trim().to_owned())
+        .filter(|reason| !reason.is_empty());
+
+    if reason
+        .as_ref()
+        .is_some_and(|reason| reason.chars().count() > MAX_BLOCK_REASON_LENGTH)
+    {
+        return Err(Error::unprocessable_entity([(
+            "reason",
+            format!("must be at most {} characters", MAX_BLOCK_REASON_LENGTH),
+        )]));
+    }
+
+    let mut tx = ctx.db.begin().await?;
+
+    // Restaurants don't get to look up users that have never ordered from them.
+    let ordered = sqlx::query_scalar!(
+        r#"select exists(select 1 from "order" where restaurant_id = $1 and user_id = $2) as "ordered!""#,
+        auth_restaurant.restaurant_id,
+        user_id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    if !ordered {
+        return Err(Error::NotFound);
+    }
+
+    query!(
+        r#"
+            insert into restaurant_customer (restaurant_id, user_id, blocked_at, block_reason)
+            values ($1, $2, now(), $3)
+            on conflict (restaurant_id, user_id) do update
+            set blocked_at = coalesce(restaurant_customer.blocked_at, now()), block_reason = $3
+        "#,
+        auth_restaurant.restaurant_id,
+        user_id,
+        reason
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    record_action(
+        &mut *tx,
+        &auth_restaurant,
+        "block_customer",
+        Some(user_id),
+        json!({ "reason": reason }),
+    )
+    .await?;
+
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Only owners may lift a block, the same as placing one.
+async fn unblock_customer(
+    auth_restaurant: AuthRestaurant,
+    ctx: State<AppContext>,
+    Path(user_id): Path<Uuid>,
+) -> Result<()> {
+    auth_restaurant.require(Permission::ManageRestaurant)?;
+
+    let mut tx = ctx.db.begin().await?;
+
+    let unblocked = query!(
+        r#"
+            update restaurant_customer set blocked_at = null, block_reason = null
+            where restaurant_id = $1 and user_id = $2 and blocked_at is not null
+        "#,
+        auth_restaurant.restaurant_id,
+        user_id
+    )
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+
+    if unblocked == 0 {
+        return Err(Error::NotFound);
+    }
+
+    record_action(
+        &mut *tx,
+        &auth_restaurant,
+        "unblock_customer",
+        Some(user_id),
+        json!({}),
+    )
+    .await?;
+
+    tx.commit().await?;
+    Ok(())
+}
@@ -0,0 +1,220 @@
+use std::str::FromStr;
+
+use axum::extract::State;
+use axum::routing::get;
+use axum::{Json, Router};
+use sqlx::{query, PgExecutor};
+use uuid::Uuid;
+
+use crate::api::auth::AuthUser;
+use crate::api::{AppContext, Result};
+
+pub(crate) fn router() -> Router<AppContext> {
+    Router::new().route(
+        "/api/users/dietary_preferences",
+        get(get_preferences).put(update_preferences),
+    )
+}
+
+/// What a menu item is, or what a user eats, as labelled in India.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Diet {
+    NonVeg,
+    /// Vegetarian, plus eggs.
+    Egg,
+    Veg,
+    Vegan,
+    /// Vegetarian without root vegetables such as onion, garlic and potato.
+    Jain,
+}
+
+impl Diet {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::NonVeg => "non_veg",
+            Self::Egg => "egg",
+            Self::Veg => "veg",
+            Self::Vegan => "vegan",
+            Self::Jain => "jain",
+        }
+    }
+
+    /// Whether someone who eats this can eat food labelled `food`.
+    pub fn allows(self, food: Diet) -> bool {
+        match self {
+            Self::NonVeg => true,
+            Self::Egg => food != Self::NonVeg,
+            Self::Veg => matches!(food, Self::Veg | Self::Vegan | Self::Jain),
+            // Jain food may have dairy, and vegan food onion or garlic, so neither allows the other.
+            Self::Vegan => food == Self::Vegan,
+            Self::Jain => food == Self::Jain,
+        }
+    }
+}
+
+impl FromStr for Diet {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "non_veg" => Ok(Self::NonVeg),
+            "egg" => Ok(Self::Egg),
+            "veg" => Ok(Self::Veg),
+            "vegan" => Ok(Self::Vegan),
+            "jain" => Ok(Self::Jain),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Allergen {
+    Dairy,
+    Egg,
+    Fish,
+    Gluten,
+    /// Peanuts and tree nuts.
+    Nuts,
+    Sesame,
+    Shellfish,
+    Soy,
+}
+
+impl Allergen {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Dairy => "dairy",
+            Self::Egg => "egg",
+            Self::Fish => "fish",
+            Self::Gluten => "gluten",
+            Self::Nuts => "nuts",
+            Self::Sesame => "sesame",
+            Self::Shellfish => "shellfish",
+            Self::Soy => "soy",
+        }
+    }
+}
+
+impl FromStr for Allergen {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dairy" => Ok(Self::Dairy),
+            "egg" => Ok(Self::Egg),
+            "fish" => Ok(Self::Fish),
+            "gluten" => Ok(Self::Gluten),
+            "nuts" => Ok(Self::Nuts),
+            "sesame" => Ok(Self::Sesame),
+            "shellfish" => Ok(Self::Shellfish),
+            "soy" => Ok(Self::Soy),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Parse allergens read from the database, which only ever has known ones.
+pub(super) fn parse_allergens(allergens: &[String]) -> Vec<Allergen> {
+    allergens
+        .iter()
+        .filter_map(|allergen| allergen.parse().ok())
+        .collect()
+}
+
+/// Store allergens as the `text[]` the database has them as.
+pub(super) fn allergen_strs(allergens: &[Allergen]) -> Vec<String> {
+    allergens
+        .iter()
+        .map(|allergen| allergen.as_str().to_owned())
+        .collect()
+}
+
+#[derive(Default, serde::Serialize, serde::Deserialize)]
+pub(super) struct DietaryPreferences {
+    /// What the user eats, or none if they eat anything.
+    pub diet: Option<Diet>,
+    /// Allergens the user avoids.
+    #[serde(default)]
+    pub avoid: Vec<Allergen>,
+}
+
+impl DietaryPreferences {
+    /// Why someone with these preferences shouldn't eat an item: `diet` if it doesn't suit their diet,
+    /// including when the item isn't labelled, and any allergens they avoid it contains, or `allergens`
+    /// if they avoid some and the item's allergens aren't known.
+    pub fn conflicts(
+        &self,
+        diet: Option<Diet>,
+        allergens: Option<&[Allergen]>,
+    ) -> Vec<&'static str> {
+        let mut conflicts = Vec::new();
+
+        // Anything suits someone who eats non-veg, even if it isn't labelled.
+        if let Some(eats) = self.diet.filter(|&eats| eats != Diet::NonVeg) {
+            if !diet.is_some_and(|diet| eats.allows(diet)) {
+                conflicts.push("diet");
+            }
+        }
+
+        match allergens {
+            Some(allergens) => conflicts.extend(
+                allergens
+                    .iter()
+                    .filter(|allergen| self.avoid.contains(allergen))
+                    .map(|allergen| allergen.as_str()),
+            ),
+            None if !self.avoid.is_empty() => conflicts.push("allergens"),
+            None => {}
+        }
+
+        conflicts
+    }
+}
+
+/// The user's saved preferences, which are empty until they set some.
+pub(super) async fn dietary_preferences(
+    db: impl PgExecutor<'_>,
+    user_id: Uuid,
+) -> Result<DietaryPreferences> {
+    let user = query!(
+        r#"select diet, avoid_allergens from "user" where user_id = $1"#,
+        user_id
+    )
+    .fetch_one(db)
+    .await?;
+
+    Ok(DietaryPreferences {
+        diet: user.diet.and_then(|diet| diet.parse().ok()),
+        avoid: parse_allergens(&user.avoid_allergens),
+    })
+}
+
+async fn get_preferences(
+    auth_user: AuthUser,
+    ctx: State<AppContext>,
+) -> Result<Json<DietaryPreferences>> {
+    Ok(Json(dietary_preferences(&ctx.db, auth_user.user_id).await?))
+}
+
+/// Replace the user's preferences, which `/api/restaurants/menu/:restaurant_id` checks items against.
+async fn update_preferences(
+    auth_user: AuthUser,
+    ctx: State<AppContext>,
+    Json(mut req): Json<DietaryPreferences>,
+) -> Result<Json<DietaryPreferences>> {
+    req.avoid.sort_by_key(|allergen| allergen.as_str());
+    req.avoid.dedup();
+
+    query!(
+        r#"update "user" set diet = $1, avoid_allergens = $2 where user_id = $3"#,
+        req.diet.map(Diet::as_str),
+        &allergen_strs(&req.avoid),
+        auth_user.user_id
+    )
+    .execute(&ctx.db)
+    .await?;
+
+    Ok(Json(req))
+}
@@ -0,0 +1,509 @@
+use axum::extract::State;
+use axum::routing::post;
+use axum::{Json, Router};
+use chrono::Utc;
+use serde_json::json;
+use sqlx::{query, PgConnection};
+use uuid::Uuid;
+
+use crate::api::audit::{change, AuditEvent};
+use crate::api::auth::{AuthRestaurant, AuthUser, Permission};
+use crate::api::login_throttle::throttle;
+use crate::api::messages::Message;
+use crate::api::password_reset::{generate_code, hash_code};
+use crate::api::util::ClientInfo;
+use crate::api::{AppContext, Error, Result, ResultExt};
+
+/// How long a confirmation code can be used for after it was sent.
+const CONFIRMATION_CODE_LENGTH: chrono::Duration = chrono::Duration::hours(1);
+
+/// Wrong guesses allowed before a confirmation code stops working.
+const MAX_CONFIRMATION_ATTEMPTS: i32 = 5;
+
+/// Changes one account can request before it is throttled, see [`throttle`].
+const MAX_CHANGE_REQUESTS: i32 = 3;
+
+pub(crate) fn router() -> Router<AppContext> {
+    Router::new()
+        .route("/api/users/email/confirm", post(confirm_user_email))
+        .route(
+            "/api/restaurants/email/confirm",
+            post(confirm_restaurant_email),
+        )
+}
+
+/// The account whose email is changing.
+#[derive(Clone, Copy)]
+pub(super) enum Account {
+    User(Uuid),
+    Restaurant(Uuid),
+}
+
+impl Account {
+    /// The `user_id` and `restaurant_id` columns of this account's `email_change` rows.
+    fn columns(self) -> (Option<Uuid>, Option<Uuid>) {
+        match self {
+            Self::User(user_id) => (Some(user_id), None),
+            Self::Restaurant(restaurant_id) => (None, Some(restaurant_id)),
+        }
+    }
+
+    fn throttle_key(self) -> String {
+        match self {
+            Self::User(user_id) => format!("email_change:user:{}", user_id),
+            Self::Restaurant(restaurant_id) => format!("email_change:restaurant:{}", restaurant_id),
+        }
+    }
+}
+
+fn invalid_code() -> Error {
+    Error::unprocessable_entity([("code", "invalid or expired confirmation code")])
+}
+
+/// Start changing the account's email to `email`, in the same transaction as updating the account.
+/// The address only replaces the current one once its owner confirms it with the code sent there.
+///
+/// Returns the message to [`send`] once the transaction is committed: the code, or if another account
+/// already has the address, a notice to its owner instead. The caller can't tell which, so this can't
+/// be used to find out which addresses are registered.
+pub(super) async fn request_change(
+    ctx: &AppContext,
+    tx: &mut PgConnection,
+    account: Account,
+    email: &str,
+) -> Result<Message> {
+    if !email.contains('@') {
+        return Err(Error::unprocessable_entity([(
+            "email",
+            "is not an email address",
+        )]));
+    }
+
+    throttle(ctx, &account.throttle_key(), MAX_CHANGE_REQUESTS).await?;
+
+    let taken = match account {
+        Account::User(user_id) => {
+            sqlx::query_scalar!(
+                r#"select exists(select 1 from "user" where email = $1 and user_id <> $2) as "taken!""#,
+                email,
+                user_id
+            )
+            .fetch_one(&mut *tx)
+            .await?
+        }
+        Account::Restaurant(restaurant_id) => {
+            sqlx::query_scalar!(
+                r#"select exists(select 1 from restaurant where email = $1 and restaurant_id <> $2) as "taken!""#,
+                email,
+                restaurant_id
+            )
+            .fetch_one(&mut *tx)
+            .await?
+        }
+    };
+
+    if taken {
+        log::debug!("email change to an address that is already taken");
+        return Ok(Message {
+            to: email.to_owned(),
+            subject: "Someone tried to use your email address".into(),
+            body: "Someone tried to add this email address to an account, but it already belongs to one.\n\n\
+                   If that was you, you can reset the password of the account it belongs to instead. \
+                   Otherwise, you can ignore this message."
+                .into(),
+        });
+    }
+
+    let code = generate_code();
+    let (user_id, restaurant_id) = account.columns();
+
+    // Only the most recent code is ever valid.
+    query!(
+        r#"
+            update email_change set used_at = now()
+            where user_id is not distinct from $1
+              and restaurant_id is not distinct from $2
+              and used_at is null
+        "#,
+        user_id,
+        restaurant_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    query!(
+        r#"
+            insert into email_change (user_id, restaurant_id, email, code_hash, expires_at)
+            values ($1, $2, $3, $4, $5)
+        "#,
+        user_id,
+        restaurant_id,
+        email,
+        hash_code(&code),
+        Utc::now() + CONFIRMATION_CODE_LENGTH
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    Ok(Message {
+        to: email.to_owned(),
+        subject: "Confirm your email address".into(),
+        body: format!(
+            "Use the code {} to confirm this email address for your account. It expires in {} minutes.\n\n\
+             If you didn't ask to change your email address, you can ignore this message.",
+            code,
+            CONFIRMATION_CODE_LENGTH.num_minutes()
+        ),
+    })
+}
+
+/// Send a message in the background, so the response time doesn't give away which message it was.
+pub(super) fn send(ctx: &AppContext, message: Message) {
+    let messages = ctx.messages.clone();
+    tokio::spawn(async move {
+        if let Err(e) = messages.send(message).await {
+            log::error!("failed to send email confirmation message: {:?}", e);
+        }
+    });
+}
+
+#[derive(serde::Deserialize)]
+struct ConfirmEmail {
+    code: String,
+}
+
+async fn confirm_user_email(
+    auth_user: AuthUser,
+    ctx: State<AppContext>,
+    client: ClientInfo,
+    Json(req): Json<ConfirmEmail>,
+) -> Result<()> {
+    let event = AuditEvent::new("email_changed", &auth_user).user(auth_user.user_id);
+    confirm(
+        &ctx,
+        Account::User(auth_user.user_id),
+        &req.code,
+        event,
+        &client,
+    )
+    .await
+}
+
+async fn confirm_restaurant_email(
+    auth_restaurant: AuthRestaurant,
+    ctx: State<AppContext>,
+    client: ClientInfo,
+    Json(req): Json<ConfirmEmail>,
+) -> Result<()> {
+    auth_restaurant.require(Permission::ManageRestaurant)?;
+
+    let event = AuditEvent::new("email_changed", &auth_restaurant)
+        .restaurant(auth_restaurant.restaurant_id);
+    let account = Account::Restaurant(auth_restaurant.restaurant_id);
+    confirm(&ctx, account, &req.code, event, &client).await
+}
+
+/// Replace the account's email with the one waiting for `code`, letting the old address know.
+async fn confirm(
+    ctx: &AppContext,
+    account: Account,
+    code: &str,
+    event: AuditEvent,
+    client: &ClientInfo,
+) -> Result<()> {
+    let (user_id, restaurant_id) = account.columns();
+
+    let mut tx = ctx.db.begin().await?;
+
+    let pending = query!(
+        r#"
+            select email_change_id, email, code_hash
+            from email_change
+            where user_id is not distinct from $1
+              and restaurant_id is not distinct from $2
+              and used_at is null
+              and expires_at > now()
+            order by created_at desc
+            limit 1
+            for update
+        "#,
+        user_id,
+        restaurant_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or_else(invalid_code)?;
+
+    if pending.code_hash != hash_code(code) {
+        query!(
+            r#"
+                update email_change
+                set attempts = attempts + 1,
+                    used_at = case when attempts + 1 >= $2 then now() end
+                where email_change_id = $1
+            "#,
+            pending.email_change_id,
+            MAX_CONFIRMATION_ATTEMPTS
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        return Err(invalid_code());
+    }
+
+    // Only taken if someone else confirmed the address since, and whoever has this code owns it anyway.
+    let old_email = match account {
+        Account::User(user_id) => {
+            let old_email =
+                sqlx::query_scalar!(r#"select email from "user" where user_id = $1"#, user_id)
+                    .fetch_one(&mut *tx)
+                    .await?;
+
+            query!(
+                r#"update "user" set email = $1, email_verified_at = now() where user_id = $2"#,
+                pending.email,
+                user_id
+            )
+            .execute(&mut *tx)
+            .await
+            .on_constraint("user_email_key", |_| {
+                Error::unprocessable_entity([("email", "email taken")])
+            })?;
+
+            old_email
+        }
+        Account::Restaurant(restaurant_id) => {
+            let old_email = sqlx::query_scalar!(
+                r#"select email from restaurant where restaurant_id = $1"#,
+                restaurant_id
+            )
+            .fetch_one(&mut *tx)
+            .await?;
+
+            query!(
+                r#"update restaurant set email = $1 where restaurant_id = $2"#,
+                pending.email,
+                restaurant_id
+            )
+            .execute(&mut *tx)
+            .await
+            .on_constraint("restaurant_email_key", |_| {
+                Error::unprocessable_entity([("email", "email taken")])
+            })?;
+
+            old_email
+        }
+    };
+
+    query!(
+        r#"update email_change set used_at = now() where email_change_id = $1"#,
+        pending.email_change_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    event
+        .changes(json!({ "email": change(&old_email, &pending.email) }))
+        .record(&mut *tx, client)
+        .await?;
+
+    tx.commit().await?;
+
+    // Whoever lost control of the account should at least hear about it.
+    if let Some(old_email) = old_email.filter(|old_email| *old_email != pending.email) {
+        send(
+            ctx,
+            Message {
+                to: old_email,
+                subject: "Your email address was changed".into(),
+                body: format!(
+                    "The email address of your account was changed to {}.\n\n\
+                     If you didn't do this, please contact us right away.",
+                    pending.email
+                ),
+            },
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use clap::Parser;
+    use sqlx::PgPool;
+
+    use super::*;
+    use crate::config::Config;
+
+    /// A context whose messages are written to a fresh directory, see `message_transport` in the config.
+    fn context(db: PgPool) -> (AppContext, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("email-change-{:016x}", rand::random::<u64>()));
+        let config = Config::parse_from([
+            "kg",
+            "--database-url",
+            "postgres://",
+            "--hmac-key",
+            "secret",
+            "--message-transport",
+            "file",
+            "--message-dir",
+            dir.to_str().unwrap(),
+        ]);
+
+        (AppContext::new(config, db).unwrap(), dir)
+    }
+
+    async fn user(ctx: &AppContext, email: &str) -> Uuid {
+        sqlx::query_scalar!(
+            r#"
+                insert into "user" (username, password_hash, email, email_verified_at)
+                values ($1, 'not a hash', $1, now())
+                returning user_id
+            "#,
+            email
+        )
+        .fetch_one(&ctx.db)
+        .await
+        .unwrap()
+    }
+
+    /// Request a change the way the handlers do, returning the code from the message it sent.
+    async fn request(ctx: &AppContext, dir: &Path, user_id: Uuid, email: &str) -> Option<String> {
+        let mut tx = ctx.db.begin().await.unwrap();
+        let message = request_change(ctx, &mut tx, Account::User(user_id), email)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+        ctx.messages.send(message).await.unwrap();
+
+        let contents = next_message(dir).await;
+        assert!(contents.starts_with(&format!("To: {}\n", email)));
+        let code = contents.split("Use the code ").nth(1)?.split(' ').next()?;
+        Some(code.to_owned())
+    }
+
+    /// Wait for a message to be written to `dir`, removing it once read.
+    async fn next_message(dir: &Path) -> String {
+        for _ in 0..100 {
+            let entry = std::fs::read_dir(dir).unwrap().next();
+            if let Some(entry) = entry {
+                let path = entry.unwrap().path();
+                let contents = std::fs::read_to_string(&path).unwrap();
+                // Messages sent in the background may still be being written.
+                if contents.ends_with('\n') {
+                    std::fs::remove_file(path).unwrap();
+                    return contents;
+                }
+            }
+
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        panic!("no message was sent");
+    }
+
+    async fn confirm_user(ctx: &AppContext, user_id: Uuid, code: &str) -> Result<()> {
+        let auth_user = AuthUser {
+            user_id,
+            session_id: Uuid::nil(),
+        };
+        let event = AuditEvent::new("email_changed", &auth_user).user(user_id);
+        let client = ClientInfo {
+            user_agent: None,
+            ip: None,
+        };
+        confirm(ctx, Account::User(user_id), code, event, &client).await
+    }
+
+    async fn email(ctx: &AppContext, user_id: Uuid) -> Option<String> {
+        sqlx::query_scalar!(r#"select email from "user" where user_id = $1"#, user_id)
+            .fetch_one(&ctx.db)
+            .await
+            .unwrap()
+    }
+
+    fn is_invalid_code(result: Result<()>) -> bool {
+        matches!(result, Err(Error::UnprocessableEntity { errors }) if errors.contains_key("code"))
+    }
+
+    #[sqlx::test]
+    async fn confirmed_code_changes_email_once(db: PgPool) {
+        let (ctx, dir) = context(db);
+        let user_id = user(&ctx, "old@example.com").await;
+
+        let code = request(&ctx, &dir, user_id, "new@example.com")
+            .await
+            .unwrap();
+        assert_eq!(email(&ctx, user_id).await.unwrap(), "old@example.com");
+
+        confirm_user(&ctx, user_id, &code).await.unwrap();
+        assert_eq!(email(&ctx, user_id).await.unwrap(), "new@example.com");
+        assert!(next_message(&dir)
+            .await
+            .starts_with("To: old@example.com\nSubject: Your email address was changed\n"));
+
+        assert!(is_invalid_code(confirm_user(&ctx, user_id, &code).await));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[sqlx::test]
+    async fn new_request_replaces_old_code(db: PgPool) {
+        let (ctx, dir) = context(db);
+        let user_id = user(&ctx, "old@example.com").await;
+
+        let old_code = request(&ctx, &dir, user_id, "first@example.com")
+            .await
+            .unwrap();
+        let new_code = request(&ctx, &dir, user_id, "second@example.com")
+            .await
+            .unwrap();
+
+        assert!(is_invalid_code(
+            confirm_user(&ctx, user_id, &old_code).await
+        ));
+        assert_eq!(email(&ctx, user_id).await.unwrap(), "old@example.com");
+
+        confirm_user(&ctx, user_id, &new_code).await.unwrap();
+        assert_eq!(email(&ctx, user_id).await.unwrap(), "second@example.com");
+        next_message(&dir).await;
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[sqlx::test]
+    async fn code_stops_working_after_too_many_wrong_guesses(db: PgPool) {
+        let (ctx, dir) = context(db);
+        let user_id = user(&ctx, "old@example.com").await;
+
+        let code = request(&ctx, &dir, user_id, "new@example.com")
+            .await
+            .unwrap();
+
+        for _ in 0..MAX_CONFIRMATION_ATTEMPTS {
+            assert!(is_invalid_code(confirm_user(&ctx, user_id, "wrong").await));
+        }
+
+        assert!(is_invalid_code(confirm_user(&ctx, user_id, &code).await));
+        assert_eq!(email(&ctx, user_id).await.unwrap(), "old@example.com");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[sqlx::test]
+    async fn taken_address_gets_a_notice_instead_of_a_code(db: PgPool) {
+        let (ctx, dir) = context(db);
+        let user_id = user(&ctx, "old@example.com").await;
+        user(&ctx, "taken@example.com").await;
+
+        assert_eq!(
+            request(&ctx, &dir, user_id, "taken@example.com").await,
+            None
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
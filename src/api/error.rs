@@ -1,7 +1,7 @@
 use std::borrow::Cow;
 use std::collections::HashMap;
 
-use axum::http::header::WWW_AUTHENTICATE;
+use axum::http::header::{RETRY_AFTER, WWW_AUTHENTICATE};
 use axum::http::StatusCode;
 use axum::response::{IntoResponse, Response};
 use axum::Json;
@@ -15,6 +15,11 @@ pub enum Error {
     Forbidden,
     #[error("request path not found")]
     NotFound,
+    #[error("too many failed attempts, try again later")]
+    TooManyRequests {
+        /// Seconds until the client may try again.
+        retry_after: u64,
+    },
     #[error("error in the request body")]
     UnprocessableEntity {
         errors: HashMap<Cow<'static, str>, Vec<Cow<'static, str>>>,
@@ -47,6 +52,7 @@ impl Error {
             Self::Unauthorized => StatusCode::UNAUTHORIZED,
             Self::Forbidden => StatusCode::FORBIDDEN,
             Self::NotFound => StatusCode::NOT_FOUND,
+            Self::TooManyRequests { .. } => StatusCode::TOO_MANY_REQUESTS,
             Self::UnprocessableEntity { .. } => StatusCode::UNPROCESSABLE_ENTITY,
             Self::Sqlx(_) | Self::Anyhow(_) => StatusCode::INTERNAL_SERVER_ERROR,
         }
@@ -72,6 +78,14 @@ impl IntoResponse for Error {
                 )
                     .into_response();
             }
+            Self::TooManyRequests { retry_after } => {
+                return (
+                    self.status_code(),
+                    [(RETRY_AFTER, retry_after.to_string())],
+                    self.to_string(),
+                )
+                    .into_response();
+            }
 
             Self::Sqlx(ref e) => {
                 // TODO: we probably want to use `tracing` instead
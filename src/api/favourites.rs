@@ -0,0 +1,185 @@
+use axum::extract::{Path, State};
+use axum::routing::{get, put};
+use axum::{Json, Router};
+use chrono::{DateTime, Utc};
+use sqlx::query;
+use uuid::Uuid;
+
+use crate::api::auth::AuthUser;
+use crate::api::opening_hours::schedule;
+use crate::api::{AppContext, Error, Result, ResultExt};
+
+pub(crate) fn router() -> Router<AppContext> {
+    Router::new()
+        .route("/api/users/favourites", get(get_favourites))
+        .route(
+            "/api/users/favourites/restaurants/:id",
+            put(add_favourite_restaurant).delete(remove_favourite_restaurant),
+        )
+        .route(
+            "/api/users/favourites/items/:id",
+            put(add_favourite_item).delete(remove_favourite_item),
+        )
+}
+
+#[derive(serde::Serialize)]
+pub(super) struct Favourites {
+    restaurants: Vec<FavouriteRestaurant>,
+    items: Vec<FavouriteItem>,
+}
+
+#[derive(serde::Serialize)]
+struct FavouriteRestaurant {
+    id: Uuid,
+    name: String,
+    is_open_now: bool,
+    /// When the restaurant next opens, if it's closed and opens within two weeks.
+    next_opening: Option<DateTime<Utc>>,
+    /// Whether the restaurant is taking orders at all, e.g. not suspended.
+    active: bool,
+    favourited_at: DateTime<Utc>,
+}
+
+#[derive(serde::Serialize)]
+struct FavouriteItem {
+    id: Uuid,
+    restaurant_id: Uuid,
+    restaurant_name: String,
+    name: String,
+    description: String,
+    /// The current price, which may have changed since the item was favourited.
+    price: i32,
+    /// Whether the item can be ordered right now: it is on the menu and its restaurant is active.
+    available: bool,
+    favourited_at: DateTime<Utc>,
+}
+
+async fn get_favourites(auth_user: AuthUser, ctx: State<AppContext>) -> Result<Json<Favourites>> {
+    Ok(Json(favourites(&ctx, auth_user.user_id).await?))
+}
+
+/// The user's favourite restaurants and items, most recently favourited first.
+pub(super) async fn favourites(ctx: &AppContext, user_id: Uuid) -> Result<Favourites> {
+    let mut conn = ctx.db.acquire().await?;
+
+    let records = query!(
+        r#"
+            select r.restaurant_id as id, r.name,
+                   r.status = 'active' as "active!", f.created_at as favourited_at
+            from favourite_restaurant f
+            join restaurant r using (restaurant_id)
+            where f.user_id = $1
+            order by f.created_at desc
+        "#,
+        user_id
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    let mut restaurants = vec![];
+    for restaurant in records {
+        let (is_open_now, next_opening) = schedule(&mut conn, restaurant.id).await?.status();
+
+        restaurants.push(FavouriteRestaurant {
+            id: restaurant.id,
+            name: restaurant.name,
+            is_open_now,
+            next_opening,
+            active: restaurant.active,
+            favourited_at: restaurant.favourited_at,
+        });
+    }
+
+    let items = sqlx::query_as!(
+        FavouriteItem,
+        r#"
+            select i.item_id as id, r.restaurant_id, r.name as restaurant_name, i.name, i.description,
+                   i.price, i.available and r.status = 'active' as "available!",
+                   f.created_at as favourited_at
+            from favourite_item f
+            join item i using (item_id)
+            join restaurant r on r.restaurant_id = i.restaurant_id
+            where f.user_id = $1
+            order by f.created_at desc
+        "#,
+        user_id
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    Ok(Favourites { restaurants, items })
+}
+
+async fn add_favourite_restaurant(
+    auth_user: AuthUser,
+    ctx: State<AppContext>,
+    Path(restaurant_id): Path<Uuid>,
+) -> Result<()> {
+    query!(
+        r#"
+            insert into favourite_restaurant (user_id, restaurant_id) values ($1, $2)
+            on conflict do nothing
+        "#,
+        auth_user.user_id,
+        restaurant_id
+    )
+    .execute(&ctx.db)
+    .await
+    .on_constraint("favourite_restaurant_restaurant_id_fkey", |_| {
+        Error::NotFound
+    })?;
+
+    Ok(())
+}
+
+async fn remove_favourite_restaurant(
+    auth_user: AuthUser,
+    ctx: State<AppContext>,
+    Path(restaurant_id): Path<Uuid>,
+) -> Result<()> {
+    query!(
+        r#"delete from favourite_restaurant where user_id = $1 and restaurant_id = $2"#,
+        auth_user.user_id,
+        restaurant_id
+    )
+    .execute(&ctx.db)
+    .await?;
+
+    Ok(())
+}
+
+async fn add_favourite_item(
+    auth_user: AuthUser,
+    ctx: State<AppContext>,
+    Path(item_id): Path<Uuid>,
+) -> Result<()> {
+    query!(
+        r#"
+            insert into favourite_item (user_id, item_id) values ($1, $2)
+            on conflict do nothing
+        "#,
+        auth_user.user_id,
+        item_id
+    )
+    .execute(&ctx.db)
+    .await
+    .on_constraint("favourite_item_item_id_fkey", |_| Error::NotFound)?;
+
+    Ok(())
+}
+
+async fn remove_favourite_item(
+    auth_user: AuthUser,
+    ctx: State<AppContext>,
+    Path(item_id): Path<Uuid>,
+) -> Result<()> {
+    query!(
+        r#"delete from favourite_item where user_id = $1 and item_id = $2"#,
+        auth_user.user_id,
+        item_id
+    )
+    .execute(&ctx.db)
+    .await?;
+
+    Ok(())
+}
@@ -0,0 +1,223 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::{bail, Context};
+use axum::extract::State;
+use axum::routing::get;
+use axum::{Json, Router};
+use base64::prelude::*;
+use hmac::{Hmac, NewMac};
+use jwt::{
+    AlgorithmType, Header, PKeyWithDigest, SignWithKey, SigningAlgorithm, Token, VerifyWithStore,
+    VerifyingAlgorithm,
+};
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Private, Public};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sha2::Sha384;
+
+use crate::api::AppContext;
+use crate::config::{Config, JwtAlgorithm};
+
+pub(crate) fn router() -> Router<AppContext> {
+    Router::new().route("/.well-known/jwks.json", get(get_jwks))
+}
+
+/// The keys used to sign and verify our JWTs.
+///
+/// Tokens are always signed with the current key and carry its id in the `kid` header,
+/// verification picks the key by that id so retired keys keep working until their tokens expire.
+pub(crate) struct JwtKeys {
+    current_id: String,
+    current: SigningKey,
+    verifying: BTreeMap<String, VerifyingKey>,
+    /// The public half of every RSA key we accept, as published at `/.well-known/jwks.json`.
+    jwks: JwkSet,
+}
+
+impl JwtKeys {
+    pub fn from_config(config: &Config) -> anyhow::Result<Self> {
+        let mut keys = Self {
+            current_id: config.hmac_key_id.clone(),
+            current: SigningKey::Hmac(hmac_key(&config.hmac_key)),
+            verifying: BTreeMap::new(),
+            jwks: JwkSet { keys: vec![] },
+        };
+
+        keys.add_verifying_key(
+            &config.hmac_key_id,
+            VerifyingKey::Hmac(hmac_key(&config.hmac_key)),
+        )?;
+        for previous in &config.hmac_previous_keys {
+            keys.add_verifying_key(&previous.id, VerifyingKey::Hmac(hmac_key(&previous.key)))?;
+        }
+
+        if let Some(ref path) = config.jwt_rsa_private_key {
+            let private = read_rsa_private_key(path)?;
+            let public = PKey::public_key_from_pem(&private.public_key_to_pem()?)
+                .context("failed to derive RSA public key")?;
+
+            keys.add_verifying_key(&config.jwt_rsa_key_id, VerifyingKey::Rsa(rs256(public)))?;
+
+            if config.jwt_algorithm == JwtAlgorithm::Rs256 {
+                keys.current_id = config.jwt_rsa_key_id.clone();
+                keys.current = SigningKey::Rsa(rs256(private));
+            }
+        }
+        for previous in &config.jwt_rsa_previous_keys {
+            let public = read_rsa_public_key(Path::new(&previous.key))?;
+            keys.add_verifying_key(&previous.id, VerifyingKey::Rsa(rs256(public)))?;
+        }
+
+        Ok(keys)
+    }
+
+    fn add_verifying_key(&mut self, id: &str, key: VerifyingKey) -> anyhow::Result<()> {
+        if let VerifyingKey::Rsa(ref rsa) = key {
+            self.jwks.keys.push(Jwk::rs256(id, &rsa.key)?);
+        }
+
+        if self.verifying.insert(id.to_owned(), key).is_some() {
+            bail!("JWT key id {:?} is used more than once", id);
+        }
+
+        Ok(())
+    }
+
+    pub fn sign<C: Serialize>(&self, claims: C) -> String {
+        let header = Header {
+            algorithm: self.current.algorithm_type(),
+            key_id: Some(self.current_id.clone()),
+            ..Default::default()
+        };
+
+        Token::new(header, claims)
+            .sign_with_key(&self.current)
+            .expect("JWT signing should be infallible")
+            .into()
+    }
+
+    pub fn verify<C: DeserializeOwned>(&self, token: &str) -> Result<C, jwt::Error> {
+        let jwt: Token<Header, C, _> = Token::parse_unverified(token)?;
+        let (_header, claims) = jwt.verify_with_store(&self.verifying)?.into();
+        Ok(claims)
+    }
+}
+
+enum SigningKey {
+    Hmac(Box<Hmac<Sha384>>),
+    Rsa(PKeyWithDigest<Private>),
+}
+
+impl SigningAlgorithm for SigningKey {
+    fn algorithm_type(&self) -> AlgorithmType {
+        match self {
+            Self::Hmac(key) => SigningAlgorithm::algorithm_type(key.as_ref()),
+            Self::Rsa(key) => SigningAlgorithm::algorithm_type(key),
+        }
+    }
+
+    fn sign(&self, header: &str, claims: &str) -> Result<String, jwt::Error> {
+        match self {
+            Self::Hmac(key) => key.sign(header, claims),
+            Self::Rsa(key) => key.sign(header, claims),
+        }
+    }
+}
+
+enum VerifyingKey {
+    Hmac(Box<Hmac<Sha384>>),
+    Rsa(PKeyWithDigest<Public>),
+}
+
+impl VerifyingAlgorithm for VerifyingKey {
+    fn algorithm_type(&self) -> AlgorithmType {
+        match self {
+            Self::Hmac(key) => VerifyingAlgorithm::algorithm_type(key.as_ref()),
+            Self::Rsa(key) => VerifyingAlgorithm::algorithm_type(key),
+        }
+    }
+
+    fn verify_bytes(
+        &self,
+        header: &str,
+        claims: &str,
+        signature: &[u8],
+    ) -> Result<bool, jwt::Error> {
+        match self {
+            Self::Hmac(key) => key.verify_bytes(header, claims, signature),
+            Self::Rsa(key) => key.verify_bytes(header, claims, signature),
+        }
+    }
+}
+
+fn hmac_key(key: &str) -> Box<Hmac<Sha384>> {
+    Box::new(
+        Hmac::<Sha384>::new_from_slice(key.as_bytes())
+            .expect("HMAC-SHA-384 can accept any key length"),
+    )
+}
+
+fn rs256<T>(key: PKey<T>) -> PKeyWithDigest<T> {
+    PKeyWithDigest {
+        digest: MessageDigest::sha256(),
+        key,
+    }
+}
+
+fn read_rsa_private_key(path: &Path) -> anyhow::Result<PKey<Private>> {
+    let pem = std::fs::read(path)
+        .with_context(|| format!("failed to read RSA private key {}", path.display()))?;
+    let key = PKey::private_key_from_pem(&pem)
+        .with_context(|| format!("invalid RSA private key {}", path.display()))?;
+    key.rsa()
+        .with_context(|| format!("{} is not an RSA key", path.display()))?;
+    Ok(key)
+}
+
+fn read_rsa_public_key(path: &Path) -> anyhow::Result<PKey<Public>> {
+    let pem = std::fs::read(path)
+        .with_context(|| format!("failed to read RSA public key {}", path.display()))?;
+    let key = PKey::public_key_from_pem(&pem)
+        .with_context(|| format!("invalid RSA public key {}", path.display()))?;
+    key.rsa()
+        .with_context(|| format!("{} is not an RSA key", path.display()))?;
+    Ok(key)
+}
+
+/// A JSON Web Key Set, see RFC 7517.
+#[derive(Clone, Serialize)]
+struct JwkSet {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Clone, Serialize)]
+struct Jwk {
+    kty: &'static str,
+    #[serde(rename = "use")]
+    use_: &'static str,
+    alg: &'static str,
+    kid: String,
+    n: String,
+    e: String,
+}
+
+impl Jwk {
+    fn rs256(kid: &str, key: &PKey<Public>) -> anyhow::Result<Self> {
+        let rsa = key.rsa().context("JWKS can only publish RSA keys")?;
+
+        Ok(Self {
+            kty: "RSA",
+            use_: "sig",
+            alg: "RS256",
+            kid: kid.to_owned(),
+            n: BASE64_URL_SAFE_NO_PAD.encode(rsa.n().to_vec()),
+            e: BASE64_URL_SAFE_NO_PAD.encode(rsa.e().to_vec()),
+        })
+    }
+}
+
+async fn get_jwks(ctx: State<AppContext>) -> Json<JwkSet> {
+    Json(ctx.jwt_keys.jwks.clone())
+}
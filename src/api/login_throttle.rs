@@ -0,0 +1,290 @@
+use chrono::{Duration, Utc};
+use serde_json::json;
+use sqlx::query;
+use uuid::Uuid;
+
+use crate::api::audit::{Actor, AuditEvent};
+use crate::api::util::{
+    dummy_verify_password, hash_password, password_needs_rehash, verify_password, ClientInfo,
+};
+use crate::api::{AppContext, Error, Result};
+
+/// Failures allowed for one account name before it is locked out.
+const MAX_ACCOUNT_FAILURES: i32 = 5;
+
+/// Failures allowed from one address before it is locked out,
+/// higher than for accounts since many people can share an address.
+const MAX_ADDRESS_FAILURES: i32 = 50;
+
+/// The first lockout, doubled for every failure after that.
+const BASE_LOCKOUT: Duration = Duration::seconds(30);
+const MAX_LOCKOUT: Duration = Duration::hours(1);
+
+/// Failure counts start over once there has been none for this long.
+const FAILURE_WINDOW: Duration = Duration::days(1);
+
+/// A login attempt, checked against and counted towards the recent failures
+/// of both the account name and the client's address.
+///
+/// Attempts are counted as failures as soon as they start, so that concurrent attempts can't all get in before
+/// the first of them fails, and uncounted again if they succeed.
+pub(super) struct LoginAttempt {
+    account: &'static str,
+    username: String,
+    account_key: String,
+    address_key: Option<String>,
+    client: ClientInfo,
+}
+
+impl LoginAttempt {
+    /// Start an attempt to log into `account` (e.g. `user`) as `username`,
+    /// failing with [`Error::TooManyRequests`] if either is locked out.
+    ///
+    /// Names are counted whether or not an account exists with them, so lockouts don't give that away.
+    pub(super) async fn start(
+        ctx: &AppContext,
+        account: &'static str,
+        username: &str,
+        client: &ClientInfo,
+    ) -> Result<Self> {
+        let account_key = format!("{}:{}", account, username.to_lowercase());
+        reserve(ctx, &account_key, MAX_ACCOUNT_FAILURES).await?;
+
+        let address_key = client.ip.map(|ip| format!("ip:{}", ip));
+        if let Some(ref address_key) = address_key {
+            reserve(ctx, address_key, MAX_ADDRESS_FAILURES).await?;
+        }
+
+        Ok(Self {
+            account,
+            username: username.to_owned(),
+            account_key,
+            address_key,
+            client: client.clone(),
+        })
+    }
+
+    /// Check `password` against the account's hash, or against a dummy hash if there is no
+    /// such account so that both cases take as long, and record the outcome.
+    ///
+    /// Along with the account, returns a new hash to store if the current one is outdated,
+    /// see [`password_needs_rehash`].
+    ///
+    /// Every failure is [`Error::Unauthorized`], whether the account exists or not.
+    pub(super) async fn verify<T>(
+        self,
+        ctx: &AppContext,
+        account: Option<T>,
+        password: String,
+        password_hash: impl FnOnce(&T) -> &str,
+    ) -> Result<(T, Option<String>)> {
+        let Some(account) = account else {
+            dummy_verify_password(ctx, password).await?;
+            return Err(self.failed(ctx).await);
+        };
+
+        let password_hash = password_hash(&account).to_owned();
+        let needs_rehash = password_needs_rehash(&ctx.config, &password_hash);
+
+        match verify_password(ctx, password.clone(), password_hash).await {
+            Ok(()) => {
+                self.succeeded(ctx).await?;
+
+                let new_hash = match needs_rehash {
+                    true => Some(hash_password(ctx, password).await?),
+                    false => None,
+                };
+
+                Ok((account, new_hash))
+            }
+            Err(Error::Unauthorized) => Err(self.failed(ctx).await),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Record a failed attempt, which was already counted when it started, returning the error to respond with.
+    pub(super) async fn failed(self, ctx: &AppContext) -> Error {
+        log::debug!(
+            "failed login to {} from {:?}",
+            self.account_key,
+            self.address_key
+        );
+
+        match self.record_audit_event(ctx).await {
+            Ok(()) => Error::Unauthorized,
+            Err(e) => e,
+        }
+    }
+
+    /// Record the failure in the audit log, against the account it was for if there is one.
+    async fn record_audit_event(&self, ctx: &AppContext) -> Result<()> {
+        let (restaurant_id, user_id) = match self.account {
+            "user" => {
+                let user_id = sqlx::query_scalar!(
+                    r#"select user_id from "user" where username = $1"#,
+                    self.username
+                )
+                .fetch_optional(&ctx.db)
+                .await?;
+                (None, user_id)
+            }
+            "phone" => {
+                let user_id = sqlx::query_scalar!(
+                    r#"select user_id from "user" where phone_number = $1"#,
+                    self.username
+                )
+                .fetch_optional(&ctx.db)
+                .await?;
+                (None, user_id)
+            }
+            "restaurant" => {
+                let restaurant_id = sqlx::query_scalar!(
+                    r#"select restaurant_id from restaurant where username = $1"#,
+                    self.username
+                )
+                .fetch_optional(&ctx.db)
+                .await?;
+                (restaurant_id, None)
+            }
+            "staff" => {
+                let restaurant_id = sqlx::query_scalar!(
+                    r#"select restaurant_id from staff where username = $1"#,
+                    self.username
+                )
+                .fetch_optional(&ctx.db)
+                .await?;
+                (restaurant_id, None)
+            }
+            // The second step of a restaurant login, which is counted by restaurant rather than name.
+            "restaurant_mfa" => (self.username.parse::<Uuid>().ok(), None),
+            // Likewise for staff, counted by staff member.
+            "staff_mfa" => {
+                let restaurant_id = match self.username.parse::<Uuid>() {
+                    Ok(staff_id) => {
+                        sqlx::query_scalar!(
+                            r#"select restaurant_id from staff where staff_id = $1"#,
+                            staff_id
+                        )
+                        .fetch_optional(&ctx.db)
+                        .await?
+                    }
+                    Err(_) => None,
+                };
+                (restaurant_id, None)
+            }
+            _ => (None, None),
+        };
+
+        AuditEvent::new("login_failed", Actor::Anonymous)
+            .restaurant(restaurant_id)
+            .user(user_id)
+            .changes(json!({ "account": self.account, "username": self.username }))
+            .record(&ctx.db, &self.client)
+            .await
+    }
+
+    /// Forget the account's failures, along with any that have expired.
+    ///
+    /// The address's other failures are left to expire, since they may have been someone else's.
+    pub(super) async fn succeeded(self, ctx: &AppContext) -> Result<()> {
+        query!(
+            r#"
+                delete from login_failure
+                where key = $1
+                   or (last_failure_at < $2 and (locked_until is null or locked_until < now()))
+            "#,
+            self.account_key,
+            Utc::now() - FAILURE_WINDOW
+        )
+        .execute(&ctx.db)
+        .await?;
+
+        // Along with the lockout this attempt started, if it was the one to reach the limit.
+        query!(
+            r#"
+                update login_failure
+                set failures = greatest(failures - 1, 0),
+                    locked_until = case when failures - 1 < $2 then null else locked_until end
+                where key = $1
+            "#,
+            self.address_key,
+            MAX_ADDRESS_FAILURES
+        )
+        .execute(&ctx.db)
+        .await?;
+
+        Ok(())
+    }
+}
+
+/// Count a request that can be abused even when it succeeds, such as asking for a password reset code,
+/// failing with [`Error::TooManyRequests`] once `key` has made more than `max_requests` in a row.
+///
+/// Shares the lockouts of failed logins, so keys should be prefixed with what they are throttling.
+pub(super) async fn throttle(ctx: &AppContext, key: &str, max_requests: i32) -> Result<()> {
+    reserve(ctx, key, max_requests).await
+}
+
+/// Count an attempt for `key` before it is made, failing with [`Error::TooManyRequests`] if `key` is locked out.
+///
+/// The attempt that reaches `max_failures` locks `key` out straight away, rather than once it has failed,
+/// so that no other attempt can start in the meantime. Attempts for the same key are counted one at a time.
+async fn reserve(ctx: &AppContext, key: &str, max_failures: i32) -> Result<()> {
+    let mut tx = ctx.db.begin().await?;
+
+    query!(
+        r#"insert into login_failure (key) values ($1) on conflict (key) do nothing"#,
+        key
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    let current = query!(
+        r#"select failures, locked_until, last_failure_at from login_failure where key = $1 for update"#,
+        key
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    let now = Utc::now();
+
+    if let Some(locked_until) = current.locked_until.filter(|&until| until > now) {
+        log::debug!("{} is locked until {}", key, locked_until);
+        return Err(Error::TooManyRequests {
+            retry_after: (locked_until - now).num_seconds().max(1) as u64,
+        });
+    }
+
+    let failures = match current.last_failure_at < now - FAILURE_WINDOW {
+        true => 1,
+        false => current.failures + 1,
+    };
+
+    let locked_until = (failures >= max_failures).then(|| {
+        let lockout = lockout(failures, max_failures);
+        log::info!("locking out {} for {}s", key, lockout.num_seconds());
+        now + lockout
+    });
+
+    query!(
+        r#"
+            update login_failure
+            set failures = $1, locked_until = $2, last_failure_at = now()
+            where key = $3
+        "#,
+        failures,
+        locked_until,
+        key
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+    Ok(())
+}
+
+/// How long to lock a key out for after its `failures`th failure in a row,
+/// doubling with every failure past the limit, up to `MAX_LOCKOUT`.
+fn lockout(failures: i32, max_failures: i32) -> Duration {
+    (BASE_LOCKOUT * 2i32.pow((failures - max_failures).min(16) as u32)).min(MAX_LOCKOUT)
+}
@@ -0,0 +1,921 @@
+use std::collections::HashMap;
+
+use axum::extract::{Path, State};
+use axum::routing::{get, put};
+use axum::{Json, Router};
+use serde_json::json;
+use sqlx::{query, PgConnection};
+use uuid::Uuid;
+
+use crate::api::auth::{Auth, AuthRestaurant, AuthUser, Permission};
+use crate::api::restaurants::record_action;
+use crate::api::{AppContext, Error, Result};
+
+/// Keeps points earned on even the largest orders well within an `int`.
+const MAX_POINTS_PER_RUPEE: i32 = 100;
+
+pub(crate) fn router() -> Router<AppContext> {
+    Router::new()
+        .route("/api/users/loyalty", get(get_balances))
+        .route(
+            "/api/restaurants/loyalty",
+            put(update_programme).delete(delete_programme),
+        )
+        .route(
+            "/api/restaurants/loyalty/:restaurant_id",
+            get(get_programme),
+        )
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Programme {
+    /// Points earned for every rupee paid for a completed order.
+    points_per_rupee: i32,
+    /// Points redeemed for every rupee of discount.
+    points_per_discount: i32,
+    /// Stamps that fill a card, or none if the restaurant has no stamp card.
+    stamps_per_card: Option<i32>,
+    /// The items that earn stamps, any one of which a full card gets free.
+    stamp_items: Vec<StampItem>,
+}
+
+impl Programme {
+    /// Check the rules make sense, and that points earned under them fit in an order's `loyalty_points`.
+    fn validate(&self) -> Result<()> {
+        if self.points_per_rupee < 0 {
+            return Err(Error::unprocessable_entity([(
+                "points_per_rupee",
+                "can't be negative",
+            )]));
+        }
+
+        if self.points_per_rupee > MAX_POINTS_PER_RUPEE {
+            return Err(Error::unprocessable_entity([(
+                "points_per_rupee",
+                format!("must be at most {}", MAX_POINTS_PER_RUPEE),
+            )]));
+        }
+
+        if self.points_per_discount < 1 {
+            return Err(Error::unprocessable_entity([(
+                "points_per_discount",
+                "must be at least 1",
+            )]));
+        }
+
+        if self.stamps_per_card.is_some_and(|stamps| stamps < 1) {
+            return Err(Error::unprocessable_entity([(
+                "stamps_per_card",
+                "must be at least 1",
+            )]));
+        }
+
+        if self.stamps_per_card.is_none() && !self.stamp_items.is_empty() {
+            return Err(Error::unprocessable_entity([(
+                "stamp_items",
+                "need stamps_per_card to be set",
+            )]));
+        }
+
+        if self.stamp_items.iter().any(|item| item.stamps < 1) {
+            return Err(Error::unprocessable_entity([(
+                "stamp_items",
+                "must earn at least 1 stamp",
+            )]));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct StampItem {
+    item_id: Uuid,
+    /// Only filled in responses.
+    #[serde(default, skip_deserializing)]
+    name: String,
+    /// Stamps earned for each one bought.
+    stamps: i32,
+}
+
+async fn get_programme(
+    _auth: Auth,
+    ctx: State<AppContext>,
+    Path(restaurant_id): Path<Uuid>,
+) -> Result<Json<Programme>> {
+    let programme = query!(
+        r#"
+            select points_per_rupee, points_per_discount, stamps_per_card
+            from loyalty_programme where restaurant_id = $1
+        "#,
+        restaurant_id
+    )
+    .fetch_optional(&ctx.db)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    let stamp_items = sqlx::query_as!(
+        StampItem,
+        r#"
+            select s.item_id, i.name, s.stamps
+            from loyalty_stamp_item s
+            join item i using (item_id)
+            where s.restaurant_id = $1
+            order by i.name
+        "#,
+        restaurant_id
+    )
+    .fetch_all(&ctx.db)
+    .await?;
+
+    Ok(Json(Programme {
+        points_per_rupee: programme.points_per_rupee,
+        points_per_discount: programme.points_per_discount,
+        stamps_per_card: programme.stamps_per_card,
+        stamp_items,
+    }))
+}
+
+/// Start the restaurant's loyalty programme, or change its rules.
+///
+/// Orders already made keep earning under the rules they were made with.
+async fn update_programme(
+    auth_restaurant: AuthRestaurant,
+    ctx: State<AppContext>,
+    Json(req): Json<Programme>,
+) -> Result<Json<Programme>> {
+    auth_restaurant.require(Permission::ManageRestaurant)?;
+
+    req.validate()?;
+
+    let mut tx = ctx.db.begin().await?;
+
+    query!(
+        r#"
+            insert into loyalty_programme (restaurant_id, points_per_rupee, points_per_discount, stamps_per_card)
+            values ($1, $2, $3, $4)
+            on conflict (restaurant_id) do update
+            set points_per_rupee = excluded.points_per_rupee,
+                points_per_discount = excluded.points_per_discount,
+                stamps_per_card = excluded.stamps_per_card
+        "#,
+        auth_restaurant.restaurant_id,
+        req.points_per_rupee,
+        req.points_per_discount,
+        req.stamps_per_card
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    query!(
+        r#"delete from loyalty_stamp_item where restaurant_id = $1"#,
+        auth_restaurant.restaurant_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    let mut stamp_items = Vec::with_capacity(req.stamp_items.len());
+
+    for item in req.stamp_items {
+        // Only the restaurant's own items, which also gives us the name to respond with.
+        let name = sqlx::query_scalar!(
+            r#"
+                insert into loyalty_stamp_item (item_id, restaurant_id, stamps)
+                select item_id, restaurant_id, $3 from item where item_id = $1 and restaurant_id = $2
+                on conflict (item_id) do update set stamps = excluded.stamps
+                returning (select name from item where item_id = $1) as "name!"
+            "#,
+            item.item_id,
+            auth_restaurant.restaurant_id,
+            item.stamps
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or_else(|| {
+            Error::unprocessable_entity([("stamp_items", format!("no such item {}", item.item_id))])
+        })?;
+
+        stamp_items.push(StampItem {
+            item_id: item.item_id,
+            name,
+            stamps: item.stamps,
+        });
+    }
+
+    let logged_items: Vec<_> = stamp_items
+        .iter()
+        .map(|item| json!({ "item_id": item.item_id, "stamps": item.stamps }))
+        .collect();
+
+    record_action(
+        &mut *tx,
+        &auth_restaurant,
+        "update_loyalty_programme",
+        None,
+        json!({
+            "points_per_rupee": req.points_per_rupee,
+            "points_per_discount": req.points_per_discount,
+            "stamps_per_card": req.stamps_per_card,
+            "stamp_items": logged_items,
+        }),
+    )
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(Json(Programme {
+        points_per_rupee: req.points_per_rupee,
+        points_per_discount: req.points_per_discount,
+        stamps_per_card: req.stamps_per_card,
+        stamp_items,
+    }))
+}
+
+/// End the restaurant's loyalty programme. Users keep their balances, but can't redeem them unless it starts again.
+async fn delete_programme(auth_restaurant: AuthRestaurant, ctx: State<AppContext>) -> Result<()> {
+    auth_restaurant.require(Permission::ManageRestaurant)?;
+
+    let mut tx = ctx.db.begin().await?;
+
+    query!(
+        r#"delete from loyalty_stamp_item where restaurant_id = $1"#,
+        auth_restaurant.restaurant_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    let deleted = query!(
+        r#"delete from loyalty_programme where restaurant_id = $1"#,
+        auth_restaurant.restaurant_id
+    )
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+
+    if deleted == 0 {
+        return Err(Error::NotFound);
+    }
+
+    record_action(
+        &mut *tx,
+        &auth_restaurant,
+        "delete_loyalty_programme",
+        None,
+        json!({}),
+    )
+    .await?;
+
+    tx.commit().await?;
+    Ok(())
+}
+
+#[derive(serde::Serialize)]
+pub(super) struct LoyaltyBalance {
+    restaurant_id: Uuid,
+    restaurant_name: String,
+    points: i32,
+    stamps: i32,
+    /// Whether the restaurant still runs its programme; balances can only be redeemed while it does.
+    active: bool,
+    /// The discount `points` can be redeemed for, in rupees.
+    points_value: Option<i32>,
+    stamps_per_card: Option<i32>,
+}
+
+async fn get_balances(
+    auth_user: AuthUser,
+    ctx: State<AppContext>,
+) -> Result<Json<Vec<LoyaltyBalance>>> {
+    Ok(Json(balances(&ctx, auth_user.user_id).await?))
+}
+
+/// The user's points and stamps at every restaurant they have collected any at.
+pub(super) async fn balances(ctx: &AppContext, user_id: Uuid) -> Result<Vec<LoyaltyBalance>> {
+    let balances = sqlx::query_as!(
+        LoyaltyBalance,
+        r#"
+            select b.restaurant_id, r.name as restaurant_name, b.points, b.stamps,
+                   p.restaurant_id is not null as "active!",
+                   b.points / p.points_per_discount as "points_value?",
+                   p.stamps_per_card as "stamps_per_card?"
+            from loyalty_balance b
+            join restaurant r using (restaurant_id)
+            left join loyalty_programme p using (restaurant_id)
+            where b.user_id = $1 and (b.points > 0 or b.stamps > 0)
+            order by r.name
+        "#,
+        user_id
+    )
+    .fetch_all(&ctx.db)
+    .await?;
+
+    Ok(balances)
+}
+
+/// What the user asked to redeem on a new order.
+#[derive(Default, serde::Deserialize)]
+pub(super) struct Redeem {
+    /// Points to redeem, a multiple of the programme's `points_per_discount`.
+    #[serde(default)]
+    pub points: i32,
+    /// Whether to redeem a full stamp card for the cheapest stamp item in the order.
+    #[serde(default)]
+    pub stamp_card: bool,
+}
+
+/// How loyalty changes an order.
+pub(super) struct OrderLoyalty {
+    /// Taken off the order's total.
+    pub discount: i32,
+    /// Earned once the order is completed.
+    pub points: i32,
+    pub stamps: i32,
+}
+
+/// Redeem what the user asked to on a new order and work out what it earns, in the same transaction as making it.
+///
+/// `items` are the order's `(item_id, price, quantity)`, and `total` what it comes to before the discount.
+pub(super) async fn redeem(
+    tx: &mut PgConnection,
+    user_id: Uuid,
+    restaurant_id: Uuid,
+    order_id: Uuid,
+    items: &[(Uuid, i32, i32)],
+    total: i32,
+    redeem: &Redeem,
+) -> Result<OrderLoyalty> {
+    let redeeming = redeem.points != 0 || redeem.stamp_card;
+
+    let Some(rules) = sqlx::query_as!(
+        Rules,
+        r#"
+            select points_per_rupee, points_per_discount, stamps_per_card
+            from loyalty_programme where restaurant_id = $1
+        "#,
+        restaurant_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    else {
+        if redeeming {
+            return Err(Error::unprocessable_entity([(
+                "redeem",
+                "restaurant has no loyalty programme",
+            )]));
+        }
+
+        return Ok(OrderLoyalty {
+            discount: 0,
+            points: 0,
+            stamps: 0,
+        });
+    };
+
+    let stamp_items: HashMap<Uuid, i32> = query!(
+        r#"select item_id, stamps from loyalty_stamp_item where restaurant_id = $1"#,
+        restaurant_id
+    )
+    .fetch_all(&mut *tx)
+    .await?
+    .into_iter()
+    .map(|item| (item.item_id, item.stamps))
+    .collect();
+
+    if !redeeming {
+        let (loyalty, _) = order_loyalty(&rules, &stamp_items, items, total, redeem, (0, 0))?;
+        return Ok(loyalty);
+    }
+
+    // Locks the balance, so two orders can't both redeem the same points.
+    let balance = query!(
+        r#"
+            select points, stamps from loyalty_balance
+            where user_id = $1 and restaurant_id = $2
+            for update
+        "#,
+        user_id,
+        restaurant_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .map_or((0, 0), |balance| (balance.points, balance.stamps));
+
+    let (loyalty, card_stamps) =
+        order_loyalty(&rules, &stamp_items, items, total, redeem, balance)?;
+
+    add_to_balance(tx, user_id, restaurant_id, -redeem.points, -card_stamps).await?;
+    record_event(
+        tx,
+        user_id,
+        restaurant_id,
+        order_id,
+        "redeem",
+        -redeem.points,
+        -card_stamps,
+    )
+    .await?;
+
+    Ok(loyalty)
+}
+
+/// The rules of a restaurant's programme that orders are made under.
+struct Rules {
+    points_per_rupee: i32,
+    points_per_discount: i32,
+    stamps_per_card: Option<i32>,
+}
+
+/// Work out what [`redeem`] takes off an order and what the order earns.
+///
+/// `stamp_items` are the stamps each of the programme's items earns, and `balance` the user's `(points, stamps)`,
+/// only looked at when redeeming. Also returns the stamps a full card takes off the balance.
+fn order_loyalty(
+    rules: &Rules,
+    stamp_items: &HashMap<Uuid, i32>,
+    items: &[(Uuid, i32, i32)],
+    total: i32,
+    redeem: &Redeem,
+    (balance_points, balance_stamps): (i32, i32),
+) -> Result<(OrderLoyalty, i32)> {
+    let mut stamps = items
+        .iter()
+        .filter_map(|(item_id, _, quantity)| Some(stamp_items.get(item_id)?.checked_mul(*quantity)))
+        .try_fold(0i32, |stamps, item_stamps| stamps.checked_add(item_stamps?))
+        .ok_or_else(|| Error::unprocessable_entity([("quantity", "is too large")]))?;
+
+    let mut discount = 0;
+    let mut card_stamps = 0;
+
+    if redeem.points != 0 || redeem.stamp_card {
+        if redeem.points < 0 || redeem.points % rules.points_per_discount != 0 {
+            return Err(Error::unprocessable_entity([(
+                "points",
+                format!("must be a multiple of {}", rules.points_per_discount),
+            )]));
+        }
+
+        if redeem.points > balance_points {
+            return Err(Error::unprocessable_entity([(
+                "points",
+                format!("only {} points available", balance_points),
+            )]));
+        }
+
+        discount += redeem.points / rules.points_per_discount;
+
+        if redeem.stamp_card {
+            let Some(stamps_per_card) = rules.stamps_per_card else {
+                return Err(Error::unprocessable_entity([(
+                    "stamp_card",
+                    "restaurant has no stamp card",
+                )]));
+            };
+
+            if balance_stamps < stamps_per_card {
+                return Err(Error::unprocessable_entity([(
+                    "stamp_card",
+                    format!(
+                        "only {} of {} stamps collected",
+                        balance_stamps, stamps_per_card
+                    ),
+                )]));
+            }
+
+            let (free_item_stamps, free_item_price) = items
+                .iter()
+                .filter(|(_, _, quantity)| *quantity > 0)
+                .filter_map(|(item_id, price, _)| Some((*stamp_items.get(item_id)?, *price)))
+                .min_by_key(|(_, price)| *price)
+                .ok_or_else(|| {
+                    Error::unprocessable_entity([(
+                        "stamp_card",
+                        "order has no item the card can be redeemed for",
+                    )])
+                })?;
+
+            // The free item doesn't earn stamps of its own.
+            discount += free_item_price;
+            stamps -= free_item_stamps;
+            card_stamps = stamps_per_card;
+        }
+
+        if discount > total {
+            return Err(Error::unprocessable_entity([(
+                "points",
+                "discount can't be more than the order's total",
+            )]));
+        }
+    }
+
+    // Programmes from before points_per_rupee was bounded may still overflow.
+    let points = (total - discount)
+        .checked_mul(rules.points_per_rupee)
+        .ok_or_else(|| {
+            Error::unprocessable_entity([("total", "is too large to earn points on")])
+        })?;
+
+    let loyalty = OrderLoyalty {
+        discount,
+        points,
+        stamps: stamps.max(0),
+    };
+
+    Ok((loyalty, card_stamps))
+}
+
+/// Credit what a completed order earned, in the same transaction as completing it.
+pub(super) async fn earn(tx: &mut PgConnection, order_id: Uuid) -> Result<()> {
+    let order = query!(
+        r#"select user_id, restaurant_id, loyalty_points, loyalty_stamps from "order" where order_id = $1"#,
+        order_id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    if order.loyalty_points == 0 && order.loyalty_stamps == 0 {
+        return Ok(());
+    }
+
+    add_to_balance(
+        tx,
+        order.user_id,
+        order.restaurant_id,
+        order.loyalty_points,
+        order.loyalty_stamps,
+    )
+    .await?;
+
+    record_event(
+        tx,
+        order.user_id,
+        order.restaurant_id,
+        order_id,
+        "earn",
+        order.loyalty_points,
+        order.loyalty_stamps,
+    )
+    .await
+}
+
+/// Undo whatever an order earned or redeemed, when it is cancelled or its payment fails.
+///
+/// Points already spent elsewhere can't be taken back, so balances stop at zero.
+pub(super) async fn reverse(tx: &mut PgConnection, order_id: Uuid) -> Result<()> {
+    let totals = query!(
+        r#"
+            select user_id, restaurant_id, sum(points)::int as "points!", sum(stamps)::int as "stamps!"
+            from loyalty_event
+            where order_id = $1
+            group by user_id, restaurant_id
+        "#,
+        order_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let Some(totals) = totals else {
+        return Ok(());
+    };
+
+    if totals.points == 0 && totals.stamps == 0 {
+        return Ok(());
+    }
+
+    add_to_balance(
+        tx,
+        totals.user_id,
+        totals.restaurant_id,
+        -totals.points,
+        -totals.stamps,
+    )
+    .await?;
+
+    record_event(
+        tx,
+        totals.user_id,
+        totals.restaurant_id,
+        order_id,
+        "reverse",
+        -totals.points,
+        -totals.stamps,
+    )
+    .await
+}
+
+async fn add_to_balance(
+    tx: &mut PgConnection,
+    user_id: Uuid,
+    restaurant_id: Uuid,
+    points: i32,
+    stamps: i32,
+) -> Result<()> {
+    query!(
+        r#"
+            insert into loyalty_balance (user_id, restaurant_id, points, stamps)
+            values ($1, $2, greatest($3, 0), greatest($4, 0))
+            on conflict (user_id, restaurant_id) do update
+            set points = greatest(loyalty_balance.points + $3, 0),
+                stamps = greatest(loyalty_balance.stamps + $4, 0)
+        "#,
+        user_id,
+        restaurant_id,
+        points,
+        stamps
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    Ok(())
+}
+
+async fn record_event(
+    tx: &mut PgConnection,
+    user_id: Uuid,
+    restaurant_id: Uuid,
+    order_id: Uuid,
+    kind: &str,
+    points: i32,
+    stamps: i32,
+) -> Result<()> {
+    query!(
+        r#"
+            insert into loyalty_event (user_id, restaurant_id, order_id, kind, points, stamps)
+            values ($1, $2, $3, $4, $5, $6)
+        "#,
+        user_id,
+        restaurant_id,
+        order_id,
+        kind,
+        points,
+        stamps
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn programme(points_per_rupee: i32) -> Programme {
+        Programme {
+            points_per_rupee,
+            points_per_discount: 10,
+            stamps_per_card: Some(5),
+            stamp_items: Vec::new(),
+        }
+    }
+
+    fn rules() -> Rules {
+        Rules {
+            points_per_rupee: 2,
+            points_per_discount: 10,
+            stamps_per_card: Some(5),
+        }
+    }
+
+    fn redeem(points: i32, stamp_card: bool) -> Redeem {
+        Redeem { points, stamp_card }
+    }
+
+    /// The field and message of a rejected request.
+    fn rejected<T>(result: Result<T>) -> (String, String) {
+        match result {
+            Err(Error::UnprocessableEntity { errors }) => {
+                let (field, messages) = errors.into_iter().next().unwrap();
+                (field.into_owned(), messages[0].to_string())
+            }
+            _ => panic!("request was not rejected"),
+        }
+    }
+
+    #[test]
+    fn points_per_rupee_must_be_within_bounds() {
+        assert!(programme(0).validate().is_ok());
+        assert!(programme(MAX_POINTS_PER_RUPEE).validate().is_ok());
+        assert_eq!(
+            rejected(programme(-1).validate()),
+            ("points_per_rupee".into(), "can't be negative".into())
+        );
+        assert_eq!(
+            rejected(programme(MAX_POINTS_PER_RUPEE + 1).validate()),
+            ("points_per_rupee".into(), "must be at most 100".into())
+        );
+    }
+
+    #[test]
+    fn points_that_overflow_are_rejected() {
+        let rules = Rules {
+            points_per_rupee: MAX_POINTS_PER_RUPEE,
+            ..rules()
+        };
+        let largest = i32::MAX / MAX_POINTS_PER_RUPEE;
+
+        let (loyalty, _) = order_loyalty(
+            &rules,
+            &HashMap::new(),
+            &[],
+            largest,
+            &Redeem::default(),
+            (0, 0),
+        )
+        .unwrap();
+        assert_eq!(loyalty.points, largest * MAX_POINTS_PER_RUPEE);
+
+        assert_eq!(
+            rejected(order_loyalty(
+                &rules,
+                &HashMap::new(),
+                &[],
+                largest + 1,
+                &Redeem::default(),
+                (0, 0)
+            )),
+            ("total".into(), "is too large to earn points on".into())
+        );
+    }
+
+    #[test]
+    fn stamps_that_overflow_are_rejected() {
+        let item = Uuid::from_u128(1);
+        let stamp_items = HashMap::from([(item, 2)]);
+
+        assert_eq!(
+            rejected(order_loyalty(
+                &rules(),
+                &stamp_items,
+                &[(item, 100, i32::MAX)],
+                100,
+                &Redeem::default(),
+                (0, 0)
+            )),
+            ("quantity".into(), "is too large".into())
+        );
+    }
+
+    #[test]
+    fn redeemed_points_are_discounted_and_not_earned_on() {
+        let (loyalty, card_stamps) = order_loyalty(
+            &rules(),
+            &HashMap::new(),
+            &[],
+            100,
+            &redeem(30, false),
+            (50, 0),
+        )
+        .unwrap();
+
+        assert_eq!(loyalty.discount, 3);
+        assert_eq!(loyalty.points, 97 * 2);
+        assert_eq!(card_stamps, 0);
+    }
+
+    #[test]
+    fn redeemed_points_must_be_available_multiples() {
+        let order = |points, balance| {
+            order_loyalty(
+                &rules(),
+                &HashMap::new(),
+                &[],
+                100,
+                &redeem(points, false),
+                (balance, 0),
+            )
+        };
+
+        assert_eq!(
+            rejected(order(15, 50)),
+            ("points".into(), "must be a multiple of 10".into())
+        );
+        assert_eq!(
+            rejected(order(-10, 50)),
+            ("points".into(), "must be a multiple of 10".into())
+        );
+        assert_eq!(
+            rejected(order(60, 50)),
+            ("points".into(), "only 50 points available".into())
+        );
+    }
+
+    #[test]
+    fn discount_can_not_be_more_than_total() {
+        assert_eq!(
+            rejected(order_loyalty(
+                &rules(),
+                &HashMap::new(),
+                &[],
+                5,
+                &redeem(60, false),
+                (100, 0)
+            )),
+            (
+                "points".into(),
+                "discount can't be more than the order's total".into()
+            )
+        );
+
+        let (loyalty, _) = order_loyalty(
+            &rules(),
+            &HashMap::new(),
+            &[],
+            5,
+            &redeem(50, false),
+            (100, 0),
+        )
+        .unwrap();
+        assert_eq!(loyalty.discount, 5);
+        assert_eq!(loyalty.points, 0);
+    }
+
+    #[test]
+    fn stamp_card_gets_cheapest_stamp_item_free() {
+        let (coffee, cake, bread) = (Uuid::from_u128(1), Uuid::from_u128(2), Uuid::from_u128(3));
+        let stamp_items = HashMap::from([(coffee, 1), (cake, 3)]);
+        let items = [(coffee, 150, 2), (cake, 120, 1), (bread, 50, 1)];
+
+        let (loyalty, card_stamps) = order_loyalty(
+            &rules(),
+            &stamp_items,
+            &items,
+            470,
+            &redeem(0, true),
+            (0, 7),
+        )
+        .unwrap();
+
+        assert_eq!(loyalty.discount, 120);
+        // The free cake's own stamps aren't earned.
+        assert_eq!(loyalty.stamps, 2);
+        assert_eq!(loyalty.points, 350 * 2);
+        assert_eq!(card_stamps, 5);
+    }
+
+    #[test]
+    fn stamp_card_must_be_full_and_have_an_item() {
+        let (coffee, bread) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        let stamp_items = HashMap::from([(coffee, 1)]);
+
+        assert_eq!(
+            rejected(order_loyalty(
+                &rules(),
+                &stamp_items,
+                &[(coffee, 150, 1)],
+                150,
+                &redeem(0, true),
+                (0, 4)
+            )),
+            ("stamp_card".into(), "only 4 of 5 stamps collected".into())
+        );
+        assert_eq!(
+            rejected(order_loyalty(
+                &rules(),
+                &stamp_items,
+                &[(bread, 50, 1), (coffee, 150, 0)],
+                50,
+                &redeem(0, true),
+                (0, 5)
+            )),
+            (
+                "stamp_card".into(),
+                "order has no item the card can be redeemed for".into()
+            )
+        );
+        assert_eq!(
+            rejected(order_loyalty(
+                &Rules {
+                    stamps_per_card: None,
+                    ..rules()
+                },
+                &stamp_items,
+                &[(coffee, 150, 1)],
+                150,
+                &redeem(0, true),
+                (0, 5)
+            )),
+            ("stamp_card".into(), "restaurant has no stamp card".into())
+        );
+    }
+
+    #[test]
+    fn nothing_is_redeemed_unless_asked() {
+        let coffee = Uuid::from_u128(1);
+        let stamp_items = HashMap::from([(coffee, 1)]);
+
+        let (loyalty, card_stamps) = order_loyalty(
+            &rules(),
+            &stamp_items,
+            &[(coffee, 150, 3)],
+            450,
+            &Redeem::default(),
+            (100, 10),
+        )
+        .unwrap();
+
+        assert_eq!(loyalty.discount, 0);
+        assert_eq!(loyalty.stamps, 3);
+        assert_eq!(loyalty.points, 900);
+        assert_eq!(card_stamps, 0);
+    }
+}
@@ -0,0 +1,111 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::Context;
+use async_trait::async_trait;
+use chrono::Utc;
+use lettre::message::Mailbox;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Tokio1Executor};
+
+use crate::config::{Config, MessageTransportKind};
+
+/// A message to someone outside the app, such as a password reset code.
+pub(crate) struct Message {
+    /// The email address to deliver to.
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// Delivers [`Message`]s, see `message_transport` in the config.
+#[async_trait]
+pub(crate) trait MessageTransport: Send + Sync {
+    async fn send(&self, message: Message) -> anyhow::Result<()>;
+}
+
+pub(crate) fn from_config(config: &Config) -> anyhow::Result<Arc<dyn MessageTransport>> {
+    Ok(match config.message_transport {
+        MessageTransportKind::Smtp => {
+            let url = config.smtp_url.as_deref().context(
+                "smtp_url is required, or set message_transport=file during development",
+            )?;
+            let from = config
+                .smtp_from
+                .as_deref()
+                .context("smtp_from is required")?;
+
+            Arc::new(SmtpTransport {
+                mailer: AsyncSmtpTransport::<Tokio1Executor>::from_url(url)
+                    .context("invalid smtp_url")?
+                    .build(),
+                from: from.parse().context("invalid smtp_from")?,
+            })
+        }
+        MessageTransportKind::File => {
+            log::warn!(
+                "writing messages to {} instead of sending them, don't use this in production",
+                config.message_dir.display()
+            );
+
+            std::fs::create_dir_all(&config.message_dir)
+                .with_context(|| format!("failed to create {}", config.message_dir.display()))?;
+
+            Arc::new(FileTransport {
+                dir: config.message_dir.clone(),
+            })
+        }
+    })
+}
+
+struct SmtpTransport {
+    mailer: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+#[async_trait]
+impl MessageTransport for SmtpTransport {
+    async fn send(&self, message: Message) -> anyhow::Result<()> {
+        let email = lettre::Message::builder()
+            .from(self.from.clone())
+            .to(message.to.parse().context("invalid recipient address")?)
+            .subject(message.subject)
+            .body(message.body)
+            .context("failed to build email")?;
+
+        self.mailer
+            .send(email)
+            .await
+            .context("failed to send email")?;
+
+        Ok(())
+    }
+}
+
+/// Writes every message to its own file instead of sending it,
+/// so flows like password reset can be exercised without a mail server.
+struct FileTransport {
+    dir: PathBuf,
+}
+
+#[async_trait]
+impl MessageTransport for FileTransport {
+    async fn send(&self, message: Message) -> anyhow::Result<()> {
+        let path = self.dir.join(format!(
+            "{}-{:016x}.txt",
+            Utc::now().format("%Y%m%dT%H%M%S"),
+            rand::random::<u64>()
+        ));
+
+        let contents = format!(
+            "To: {}\nSubject: {}\n\n{}\n",
+            message.to, message.subject, message.body
+        );
+
+        tokio::fs::write(&path, contents)
+            .await
+            .with_context(|| format!("failed to write message to {}", path.display()))?;
+
+        log::info!("wrote message for {} to {}", message.to, path.display());
+        Ok(())
+    }
+}
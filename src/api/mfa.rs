@@ -0,0 +1,691 @@
+use axum::extract::State;
+use axum::routing::post;
+use axum::{Json, Router};
+use chrono::Utc;
+use rand::seq::SliceRandom;
+use rand::RngCore;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use sqlx::{query, PgConnection, PgExecutor};
+use totp_rs::{Algorithm, TOTP};
+use uuid::Uuid;
+
+use crate::api::auth::{verify_mfa_pending_token, AuthRestaurant, PrincipalKind};
+use crate::api::login_throttle::LoginAttempt;
+use crate::api::restaurants::{
+    can_log_in, record_action, start_restaurant_session, Restaurant, RestaurantBody,
+};
+use crate::api::staff::{start_staff_session, Staff, StaffBody};
+use crate::api::util::ClientInfo;
+use crate::api::{AppContext, Error, Result};
+
+/// Shown as the account's issuer in authenticator apps.
+const TOTP_ISSUER: &str = "Khaogalli";
+
+/// Length of a TOTP time step in seconds, as recommended by RFC 6238.
+const TOTP_STEP: i64 = 30;
+
+const RECOVERY_CODE_COUNT: usize = 10;
+
+/// Recovery codes are written down by hand, so leave out characters that are easily confused.
+const RECOVERY_CODE_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+
+pub(crate) fn router() -> Router<AppContext> {
+    Router::new()
+        .route("/api/restaurants/login/mfa", post(login_mfa))
+        .route("/api/restaurants/staff/login/mfa", post(login_staff_mfa))
+        .route(
+            "/api/restaurants/mfa/totp",
+            post(start_enrolment).delete(disable_totp),
+        )
+        .route("/api/restaurants/mfa/totp/enable", post(enable_totp))
+        .route(
+            "/api/restaurants/mfa/recovery_codes",
+            post(regenerate_recovery_codes),
+        )
+}
+
+/// What an authenticator app needs to start generating codes.
+#[derive(serde::Serialize)]
+pub(super) struct TotpSetup {
+    /// The base32 secret, for typing in by hand.
+    secret: String,
+    /// An `otpauth://` URI, usually shown as a QR code.
+    otpauth_uri: String,
+}
+
+/// Whose second factor it is: a restaurant's own login, or a member of its staff.
+#[derive(Clone, Copy)]
+pub(super) enum MfaAccount {
+    Restaurant(Uuid),
+    Staff(Uuid),
+}
+
+impl MfaAccount {
+    /// The account signed in, whose second factor it manages.
+    fn of(auth_restaurant: &AuthRestaurant) -> Self {
+        match auth_restaurant.staff_id {
+            Some(staff_id) => Self::Staff(staff_id),
+            None => Self::Restaurant(auth_restaurant.restaurant_id),
+        }
+    }
+
+    /// The account name second factor attempts are counted against, see [`LoginAttempt`].
+    fn login_account(self) -> (&'static str, Uuid) {
+        match self {
+            Self::Restaurant(restaurant_id) => ("restaurant_mfa", restaurant_id),
+            Self::Staff(staff_id) => ("staff_mfa", staff_id),
+        }
+    }
+}
+
+/// Whether the platform administrators require every restaurant to use a second factor.
+pub(super) async fn restaurant_mfa_required(db: impl PgExecutor<'_>) -> Result<bool> {
+    Ok(
+        sqlx::query_scalar!(r#"select require_restaurant_mfa from platform_settings"#)
+            .fetch_one(db)
+            .await?,
+    )
+}
+
+/// Whether a member of staff has to use a second factor: owners and managers can do everything the
+/// restaurant's own login can, so they do whenever it has to.
+pub(super) async fn staff_mfa_required(db: impl PgExecutor<'_>, staff_id: Uuid) -> Result<bool> {
+    Ok(sqlx::query_scalar!(
+        r#"
+            select s.role in ('owner', 'manager')
+                   and (r.totp_enabled_at is not null or p.require_restaurant_mfa) as "required!"
+            from staff s
+            join restaurant r using (restaurant_id)
+            cross join platform_settings p
+            where s.staff_id = $1
+        "#,
+        staff_id
+    )
+    .fetch_one(db)
+    .await?)
+}
+
+/// Give an account a new TOTP secret, which only takes effect once a code generated from it is confirmed.
+pub(super) async fn begin_enrolment(
+    db: impl PgExecutor<'_>,
+    account: MfaAccount,
+    username: &str,
+) -> Result<TotpSetup> {
+    let mut secret = [0u8; 20];
+    rand::thread_rng().fill_bytes(&mut secret);
+    let totp = totp(secret.to_vec(), username);
+
+    match account {
+        MfaAccount::Restaurant(restaurant_id) => query!(
+            r#"
+                update restaurant set totp_secret = $1, totp_last_step = null
+                where restaurant_id = $2 and totp_enabled_at is null
+            "#,
+            totp.get_secret_base32(),
+            restaurant_id
+        ),
+        MfaAccount::Staff(staff_id) => query!(
+            r#"
+                update staff set totp_secret = $1, totp_last_step = null
+                where staff_id = $2 and totp_enabled_at is null
+            "#,
+            totp.get_secret_base32(),
+            staff_id
+        ),
+    }
+    .execute(db)
+    .await?;
+
+    Ok(TotpSetup {
+        secret: totp.get_secret_base32(),
+        otpauth_uri: totp.get_url(),
+    })
+}
+
+fn totp(secret: Vec<u8>, username: &str) -> TOTP {
+    // `new_unchecked` because restaurant usernames may contain characters `otpauth` URIs reject,
+    // we generate the secret ourselves so it is always long enough.
+    TOTP::new_unchecked(
+        Algorithm::SHA1,
+        6,
+        0,
+        TOTP_STEP as u64,
+        secret,
+        Some(TOTP_ISSUER.into()),
+        username.to_owned(),
+    )
+}
+
+fn stored_totp(secret: &str, username: &str) -> Result<TOTP> {
+    let secret = totp_rs::Secret::Encoded(secret.to_owned())
+        .to_bytes()
+        .map_err(|e| anyhow::anyhow!("invalid stored TOTP secret: {:?}", e))?;
+
+    Ok(totp(secret, username))
+}
+
+/// Return the time step `code` was generated for, if it is valid now and newer than `last_step`.
+///
+/// Codes from the steps either side of the current one are accepted to allow for clock drift.
+fn check_totp(totp: &TOTP, code: &str, last_step: Option<i64>) -> Option<i64> {
+    let current = Utc::now().timestamp() / TOTP_STEP;
+
+    (current - 1..=current + 1)
+        .filter(|step| last_step.is_none_or(|last| *step > last))
+        .find(|step| totp.check(code.trim(), (step * TOTP_STEP) as u64))
+}
+
+fn hash_recovery_code(code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+
+    hex::encode(Sha256::digest(normalized.as_bytes()))
+}
+
+fn generate_recovery_code() -> String {
+    let mut rng = rand::thread_rng();
+    let mut code = String::with_capacity(11);
+
+    for i in 0..10 {
+        if i == 5 {
+            code.push('-');
+        }
+        code.push(*RECOVERY_CODE_ALPHABET.choose(&mut rng).unwrap() as char);
+    }
+
+    code
+}
+
+/// Replace all of an account's recovery codes with new ones, returning them.
+async fn replace_recovery_codes(tx: &mut PgConnection, account: MfaAccount) -> Result<Vec<String>> {
+    let codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+        .map(|_| generate_recovery_code())
+        .collect();
+    let hashes: Vec<String> = codes.iter().map(|code| hash_recovery_code(code)).collect();
+
+    match account {
+        MfaAccount::Restaurant(restaurant_id) => {
+            query!(
+                r#"delete from restaurant_recovery_code where restaurant_id = $1"#,
+                restaurant_id
+            )
+            .execute(&mut *tx)
+            .await?;
+
+            query!(
+                r#"
+                    insert into restaurant_recovery_code (restaurant_id, code_hash)
+                    select $1, unnest($2::text[])
+                "#,
+                restaurant_id,
+                &hashes
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        MfaAccount::Staff(staff_id) => {
+            query!(
+                r#"delete from staff_recovery_code where staff_id = $1"#,
+                staff_id
+            )
+            .execute(&mut *tx)
+            .await?;
+
+            query!(
+                r#"
+                    insert into staff_recovery_code (staff_id, code_hash)
+                    select $1, unnest($2::text[])
+                "#,
+                staff_id,
+                &hashes
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+    }
+
+    Ok(codes)
+}
+
+/// Mark `code` as used if it is one of the account's unused recovery codes.
+async fn use_recovery_code(tx: &mut PgConnection, account: MfaAccount, code: &str) -> Result<bool> {
+    let result = match account {
+        MfaAccount::Restaurant(restaurant_id) => query!(
+            r#"
+                update restaurant_recovery_code set used_at = now()
+                where restaurant_id = $1 and code_hash = $2 and used_at is null
+            "#,
+            restaurant_id,
+            hash_recovery_code(code)
+        ),
+        MfaAccount::Staff(staff_id) => query!(
+            r#"
+                update staff_recovery_code set used_at = now()
+                where staff_id = $1 and code_hash = $2 and used_at is null
+            "#,
+            staff_id,
+            hash_recovery_code(code)
+        ),
+    }
+    .execute(&mut *tx)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+fn invalid_code() -> Error {
+    Error::unprocessable_entity([("code", "invalid code")])
+}
+
+struct TotpState {
+    username: String,
+    /// Whether the account may log in at all, e.g. its restaurant isn't suspended.
+    can_log_in: bool,
+    totp_secret: Option<String>,
+    totp_enabled: bool,
+    totp_last_step: Option<i64>,
+}
+
+async fn lock_totp_state(tx: &mut PgConnection, account: MfaAccount) -> Result<TotpState> {
+    Ok(match account {
+        MfaAccount::Restaurant(restaurant_id) => {
+            let row = query!(
+                r#"
+                    select username, status, totp_secret, totp_enabled_at, totp_last_step
+                    from restaurant where restaurant_id = $1
+                    for update
+                "#,
+                restaurant_id
+            )
+            .fetch_optional(&mut *tx)
+            .await?
+            .ok_or(Error::NotFound)?;
+
+            TotpState {
+                username: row.username,
+                can_log_in: can_log_in(&row.status),
+                totp_secret: row.totp_secret,
+                totp_enabled: row.totp_enabled_at.is_some(),
+                totp_last_step: row.totp_last_step,
+            }
+        }
+        MfaAccount::Staff(staff_id) => {
+            let row = query!(
+                r#"
+                    select s.username, r.status, s.deactivated_at, s.totp_secret, s.totp_enabled_at, s.totp_last_step
+                    from staff s join restaurant r using (restaurant_id)
+                    where s.staff_id = $1
+                    for update of s
+                "#,
+                staff_id
+            )
+            .fetch_optional(&mut *tx)
+            .await?
+            .ok_or(Error::NotFound)?;
+
+            TotpState {
+                username: row.username,
+                can_log_in: row.deactivated_at.is_none() && can_log_in(&row.status),
+                totp_secret: row.totp_secret,
+                totp_enabled: row.totp_enabled_at.is_some(),
+                totp_last_step: row.totp_last_step,
+            }
+        }
+    })
+}
+
+/// Accept a TOTP code for `state`, recording its time step so it can't be used again.
+async fn accept_totp(
+    tx: &mut PgConnection,
+    account: MfaAccount,
+    state: &TotpState,
+    code: &str,
+) -> Result<bool> {
+    let Some(ref secret) = state.totp_secret else {
+        return Ok(false);
+    };
+
+    let Some(step) = check_totp(
+        &stored_totp(secret, &state.username)?,
+        code,
+        state.totp_last_step,
+    ) else {
+        return Ok(false);
+    };
+
+    match account {
+        MfaAccount::Restaurant(restaurant_id) => query!(
+            r#"update restaurant set totp_last_step = $1 where restaurant_id = $2"#,
+            step,
+            restaurant_id
+        ),
+        MfaAccount::Staff(staff_id) => query!(
+            r#"update staff set totp_last_step = $1 where staff_id = $2"#,
+            step,
+            staff_id
+        ),
+    }
+    .execute(&mut *tx)
+    .await?;
+
+    Ok(true)
+}
+
+/// Turn on the second factor of an account whose enrolment was just confirmed, returning its recovery codes.
+async fn enable(tx: &mut PgConnection, account: MfaAccount) -> Result<Vec<String>> {
+    match account {
+        MfaAccount::Restaurant(restaurant_id) => query!(
+            r#"update restaurant set totp_enabled_at = now() where restaurant_id = $1"#,
+            restaurant_id
+        ),
+        MfaAccount::Staff(staff_id) => query!(
+            r#"update staff set totp_enabled_at = now() where staff_id = $1"#,
+            staff_id
+        ),
+    }
+    .execute(&mut *tx)
+    .await?;
+
+    replace_recovery_codes(tx, account).await
+}
+
+#[derive(serde::Deserialize)]
+struct MfaLogin {
+    /// The token returned by `/api/restaurants/login`.
+    mfa_token: String,
+    /// A code from the authenticator app, or one of the recovery codes.
+    code: String,
+}
+
+#[derive(serde::Serialize)]
+struct MfaLoginResponse<T> {
+    #[serde(flatten)]
+    login: T,
+    /// Only returned when this login completed TOTP enrolment.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    recovery_codes: Option<Vec<String>>,
+}
+
+/// Second step of a restaurant login, see `login_restaurant`.
+async fn login_mfa(
+    ctx: State<AppContext>,
+    client: ClientInfo,
+    Json(req): Json<MfaLogin>,
+) -> Result<Json<MfaLoginResponse<RestaurantBody<Restaurant>>>> {
+    let restaurant_id = verify_mfa_pending_token(&ctx, &req.mfa_token, PrincipalKind::Restaurant)?;
+    let recovery_codes = check_second_factor(
+        &ctx,
+        &client,
+        MfaAccount::Restaurant(restaurant_id),
+        &req.code,
+    )
+    .await?;
+
+    let login = start_restaurant_session(&ctx, restaurant_id, &client).await?;
+
+    Ok(Json(MfaLoginResponse {
+        login,
+        recovery_codes,
+    }))
+}
+
+/// Second step of a staff login, see `login_staff`.
+async fn login_staff_mfa(
+    ctx: State<AppContext>,
+    client: ClientInfo,
+    Json(req): Json<MfaLogin>,
+) -> Result<Json<MfaLoginResponse<StaffBody<Staff>>>> {
+    let staff_id = verify_mfa_pending_token(&ctx, &req.mfa_token, PrincipalKind::Staff)?;
+    let recovery_codes =
+        check_second_factor(&ctx, &client, MfaAccount::Staff(staff_id), &req.code).await?;
+
+    let login = start_staff_session(&ctx, staff_id, &client).await?;
+
+    Ok(Json(MfaLoginResponse {
+        login,
+        recovery_codes,
+    }))
+}
+
+/// Check the code given for the second step of a login, returning recovery codes if it completed enrolment.
+async fn check_second_factor(
+    ctx: &AppContext,
+    client: &ClientInfo,
+    account: MfaAccount,
+    code: &str,
+) -> Result<Option<Vec<String>>> {
+    let (login_account, id) = account.login_account();
+    let attempt = LoginAttempt::start(ctx, login_account, &id.to_string(), client).await?;
+
+    let mut tx = ctx.db.begin().await?;
+    let state = lock_totp_state(&mut tx, account).await?;
+
+    if !state.can_log_in {
+        log::debug!("{} {} may not log in", login_account, id);
+        return Err(Error::Forbidden);
+    }
+
+    let mut recovery_codes = None;
+
+    if accept_totp(&mut tx, account, &state, code).await? {
+        if !state.totp_enabled {
+            // Enrolment was forced on this login, and the first code completes it.
+            recovery_codes = Some(enable(&mut tx, account).await?);
+        }
+    } else if state.totp_enabled && use_recovery_code(&mut tx, account, code).await? {
+        log::info!("{} {} logged in with a recovery code", login_account, id);
+    } else {
+        log::debug!("wrong second factor for {} {}", login_account, id);
+        drop(tx);
+        return Err(match attempt.failed(ctx).await {
+            Error::Unauthorized => invalid_code(),
+            e => e,
+        });
+    }
+
+    tx.commit().await?;
+    attempt.succeeded(ctx).await?;
+
+    Ok(recovery_codes)
+}
+
+async fn start_enrolment(
+    auth_restaurant: AuthRestaurant,
+    ctx: State<AppContext>,
+) -> Result<Json<TotpSetup>> {
+    let account = MfaAccount::of(&auth_restaurant);
+
+    let mut tx = ctx.db.begin().await?;
+    let state = lock_totp_state(&mut tx, account).await?;
+
+    if state.totp_enabled {
+        return Err(Error::unprocessable_entity([(
+            "totp",
+            "two-factor authentication is already enabled",
+        )]));
+    }
+
+    let setup = begin_enrolment(&mut *tx, account, &state.username).await?;
+
+    tx.commit().await?;
+
+    Ok(Json(setup))
+}
+
+#[derive(serde::Deserialize)]
+struct TotpCode {
+    code: String,
+}
+
+#[derive(serde::Serialize)]
+struct RecoveryCodes {
+    recovery_codes: Vec<String>,
+}
+
+async fn enable_totp(
+    auth_restaurant: AuthRestaurant,
+    ctx: State<AppContext>,
+    Json(req): Json<TotpCode>,
+) -> Result<Json<RecoveryCodes>> {
+    let account = MfaAccount::of(&auth_restaurant);
+
+    let mut tx = ctx.db.begin().await?;
+    let state = lock_totp_state(&mut tx, account).await?;
+
+    if state.totp_enabled {
+        return Err(Error::unprocessable_entity([(
+            "totp",
+            "two-factor authentication is already enabled",
+        )]));
+    }
+
+    if state.totp_secret.is_none() {
+        return Err(Error::unprocessable_entity([(
+            "totp",
+            "enrolment has not been started",
+        )]));
+    }
+
+    if !accept_totp(&mut tx, account, &state, &req.code).await? {
+        return Err(invalid_code());
+    }
+
+    let recovery_codes = enable(&mut tx, account).await?;
+
+    record_action(&mut *tx, &auth_restaurant, "enable_totp", None, json!({})).await?;
+
+    tx.commit().await?;
+
+    Ok(Json(RecoveryCodes { recovery_codes }))
+}
+
+async fn disable_totp(
+    auth_restaurant: AuthRestaurant,
+    ctx: State<AppContext>,
+    Json(req): Json<TotpCode>,
+) -> Result<()> {
+    let account = MfaAccount::of(&auth_restaurant);
+
+    let mut tx = ctx.db.begin().await?;
+
+    let required = match account {
+        MfaAccount::Restaurant(_) => restaurant_mfa_required(&mut *tx).await?,
+        MfaAccount::Staff(staff_id) => staff_mfa_required(&mut *tx, staff_id).await?,
+    };
+
+    if required {
+        return Err(Error::unprocessable_entity([(
+            "totp",
+            "two-factor authentication is required for this account",
+        )]));
+    }
+
+    let state = lock_totp_state(&mut tx, account).await?;
+
+    if !state.totp_enabled {
+        return Err(Error::unprocessable_entity([(
+            "totp",
+            "two-factor authentication is not enabled",
+        )]));
+    }
+
+    if !accept_totp(&mut tx, account, &state, &req.code).await?
+        && !use_recovery_code(&mut tx, account, &req.code).await?
+    {
+        return Err(invalid_code());
+    }
+
+    clear_totp(&mut tx, account).await?;
+
+    record_action(&mut *tx, &auth_restaurant, "disable_totp", None, json!({})).await?;
+
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Turn off an account's second factor and forget its secret and recovery codes.
+pub(super) async fn clear_totp(tx: &mut PgConnection, account: MfaAccount) -> Result<()> {
+    match account {
+        MfaAccount::Restaurant(restaurant_id) => {
+            query!(
+                r#"
+                    update restaurant
+                    set totp_secret = null, totp_enabled_at = null, totp_last_step = null
+                    where restaurant_id = $1
+                "#,
+                restaurant_id
+            )
+            .execute(&mut *tx)
+            .await?;
+
+            query!(
+                r#"delete from restaurant_recovery_code where restaurant_id = $1"#,
+                restaurant_id
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        MfaAccount::Staff(staff_id) => {
+            query!(
+                r#"
+                    update staff
+                    set totp_secret = null, totp_enabled_at = null, totp_last_step = null
+                    where staff_id = $1
+                "#,
+                staff_id
+            )
+            .execute(&mut *tx)
+            .await?;
+
+            query!(
+                r#"delete from staff_recovery_code where staff_id = $1"#,
+                staff_id
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn regenerate_recovery_codes(
+    auth_restaurant: AuthRestaurant,
+    ctx: State<AppContext>,
+    Json(req): Json<TotpCode>,
+) -> Result<Json<RecoveryCodes>> {
+    let account = MfaAccount::of(&auth_restaurant);
+
+    let mut tx = ctx.db.begin().await?;
+    let state = lock_totp_state(&mut tx, account).await?;
+
+    if !state.totp_enabled {
+        return Err(Error::unprocessable_entity([(
+            "totp",
+            "two-factor authentication is not enabled",
+        )]));
+    }
+
+    if !accept_totp(&mut tx, account, &state, &req.code).await? {
+        return Err(invalid_code());
+    }
+
+    let recovery_codes = replace_recovery_codes(&mut tx, account).await?;
+
+    record_action(
+        &mut *tx,
+        &auth_restaurant,
+        "regenerate_recovery_codes",
+        None,
+        json!({}),
+    )
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(Json(RecoveryCodes { recovery_codes }))
+}
@@ -15,16 +15,43 @@ use tower_http::{
     sensitive_headers::SetSensitiveHeadersLayer, timeout::TimeoutLayer, trace::TraceLayer,
 };
 
+use crate::api::keys::JwtKeys;
+use crate::api::messages::MessageTransport;
+use crate::api::oidc::OidcProvider;
+use crate::api::sms::SmsGateway;
+use crate::api::util::PasswordPolicy;
 use crate::config::Config;
 
+mod admin;
+mod api_keys;
+mod audit;
 mod auth;
+mod customers;
+mod diet;
+mod email_change;
 mod error;
+mod favourites;
+mod keys;
+mod login_throttle;
+mod loyalty;
+mod messages;
+mod mfa;
 mod notifications;
+mod oidc;
+mod opening_hours;
 mod orders;
+mod password_reset;
+mod phone;
+mod profile;
+mod referrals;
 mod restaurants;
+mod sessions;
+mod sms;
+mod staff;
 mod stats;
 mod users;
 mod util;
+mod wallet;
 
 pub use error::{Error, ResultExt};
 
@@ -34,36 +61,88 @@ pub type Result<T, E = Error> = std::result::Result<T, E>;
 pub(crate) struct AppContext {
     config: Arc<Config>,
     db: PgPool,
+    jwt_keys: Arc<JwtKeys>,
+    messages: Arc<dyn MessageTransport>,
+    oidc: Option<Arc<OidcProvider>>,
+    password_policy: Arc<PasswordPolicy>,
+    sms: Option<Arc<dyn SmsGateway>>,
+}
+
+impl AppContext {
+    fn new(config: Config, db: PgPool) -> anyhow::Result<Self> {
+        let jwt_keys = JwtKeys::from_config(&config).context("invalid JWT signing keys")?;
+        let messages = messages::from_config(&config).context("invalid message transport")?;
+        let oidc = OidcProvider::from_config(&config).context("invalid OpenID Connect provider")?;
+        let password_policy =
+            PasswordPolicy::from_config(&config).context("invalid password settings")?;
+        let sms = sms::from_config(&config).context("invalid SMS gateway")?;
+
+        Ok(Self {
+            config: Arc::new(config),
+            db,
+            jwt_keys: Arc::new(jwt_keys),
+            messages,
+            oidc: oidc.map(Arc::new),
+            password_policy: Arc::new(password_policy),
+            sms,
+        })
+    }
 }
 
 pub async fn serve(config: Config, db: PgPool) -> anyhow::Result<()> {
-    let app_context = AppContext {
-        config: Arc::new(config),
-        db,
-    };
+    let app_context = AppContext::new(config, db)?;
+
+    admin::bootstrap(&app_context)
+        .await
+        .context("failed to create the bootstrap administrator")?;
+
+    wallet::check_ledger_on_startup(&app_context)
+        .await
+        .context("failed to check the wallet ledger")?;
 
     let app = routes(app_context);
 
     // TODO: we use 8080 as default port, but we should allow the user to specify it
     let addr = SocketAddr::from((Ipv4Addr::UNSPECIFIED, 8080));
     let listener = TcpListener::bind(addr).await?;
-    axum::serve(listener, app)
-        .with_graceful_shutdown(shutdown_signal())
-        .await
-        .context("error running HTTP server")
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(shutdown_signal())
+    .await
+    .context("error running HTTP server")
 }
 
 fn routes(app_context: AppContext) -> Router {
     Router::new()
+        .merge(admin::router())
         .merge(users::router())
+        .merge(oidc::router())
+        .merge(phone::router())
+        .merge(favourites::router())
+        .merge(diet::router())
+        .merge(loyalty::router())
+        .merge(referrals::router())
         .merge(restaurants::router())
+        .merge(opening_hours::router())
+        .merge(staff::router())
+        .merge(api_keys::router())
+        .merge(audit::router())
+        .merge(mfa::router())
+        .merge(sessions::router())
+        .merge(password_reset::router())
+        .merge(email_change::router())
         .merge(orders::router())
+        .merge(customers::router())
         .merge(stats::router())
         .merge(notifications::router())
+        .merge(wallet::router())
+        .merge(keys::router())
         .nest_service("/static", ServeDir::new("static"))
         .layer((
             DefaultBodyLimit::disable(),
-            SetSensitiveHeadersLayer::new([AUTHORIZATION]),
+            SetSensitiveHeadersLayer::new([AUTHORIZATION, auth::API_KEY_HEADER]),
             CompressionLayer::new(),
             TraceLayer::new_for_http().on_failure(()),
             TimeoutLayer::new(Duration::from_secs(30)),
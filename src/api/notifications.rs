@@ -2,11 +2,13 @@ use anyhow::Context;
 use axum::extract::{Path, State};
 use axum::routing::{delete, get, post};
 use axum::{Json, Router};
-use expo_push_notification_client::{Expo, ExpoPushMessage};
+use expo_push_notification_client::{
+    Details, DetailsErrorType, Expo, ExpoPushErrorReceipt, ExpoPushMessage, ExpoPushTicket,
+};
 use serde::{Deserialize, Serialize};
 use sqlx::query;
 
-use crate::api::auth::{AuthRestaurant, AuthUser};
+use crate::api::auth::{AuthRestaurant, AuthUser, Permission};
 use crate::api::{AppContext, Result};
 
 pub(crate) fn router() -> Router<AppContext> {
@@ -72,33 +74,38 @@ async fn get_user_notifications(
     auth_user: AuthUser,
     ctx: State<AppContext>,
 ) -> Result<Json<Vec<NotificationUser>>> {
-    let user_id = auth_user.user_id;
+    Ok(Json(user_notifications(&ctx, auth_user.user_id).await?))
+}
+
+/// The notifications a user can see: those sent to them and those broadcast by restaurants.
+pub(super) async fn user_notifications(
+    ctx: &AppContext,
+    user_id: uuid::Uuid,
+) -> Result<Vec<NotificationUser>> {
     // fetch notification from database
-    Ok(Json(
-        query!(
-            r#"
+    Ok(query!(
+        r#"
         select n.notification_id, n.title, n.body, n.ttl_minutes, n.created_at,
         r.restaurant_id, r.name as restaurant_name
         from notification n
         join restaurant r on n.sender_id = r.restaurant_id
         where (recipient_id = $1 or recipient_id is null) and sender_id is not null
         "#,
-            user_id
-        )
-        .fetch_all(&ctx.db)
-        .await?
-        .into_iter()
-        .map(|row| NotificationUser {
-            id: row.notification_id,
-            title: row.title.clone(),
-            body: row.body.clone(),
-            restaurant_name: row.restaurant_name.clone(),
-            restaurant_id: row.restaurant_id,
-            ttl_minutes: row.ttl_minutes,
-            created_at: row.created_at,
-        })
-        .collect(),
-    ))
+        user_id
+    )
+    .fetch_all(&ctx.db)
+    .await?
+    .into_iter()
+    .map(|row| NotificationUser {
+        id: row.notification_id,
+        title: row.title.clone(),
+        body: row.body.clone(),
+        restaurant_name: row.restaurant_name.clone(),
+        restaurant_id: row.restaurant_id,
+        ttl_minutes: row.ttl_minutes,
+        created_at: row.created_at,
+    })
+    .collect())
 }
 
 #[derive(Deserialize)]
@@ -113,6 +120,8 @@ async fn send_notification(
     ctx: State<AppContext>,
     Json(req): Json<NewNotification>,
 ) -> Result<()> {
+    auth_restaurant.require(Permission::SendNotifications)?;
+
     let restaurant_id = auth_restaurant.restaurant_id;
 
     let notification = Notification {
@@ -132,6 +141,8 @@ async fn delete_notification(
     ctx: State<AppContext>,
     Path(notification_id): Path<uuid::Uuid>,
 ) -> Result<()> {
+    auth_restaurant.require(Permission::SendNotifications)?;
+
     let restaurant_id = auth_restaurant.restaurant_id;
     // delete notification from database
     query!(
@@ -183,47 +194,78 @@ pub(crate) async fn new_notification(
     Ok(())
 }
 
+/// Expo accepts at most this many recipients in one request.
+const MAX_PUSH_RECIPIENTS: usize = 100;
+
+/// Push the notification to every active device of its recipient, or of every user for a broadcast.
+///
+/// A device is active while the session it registered under is. Devices carried over from before there
+/// were sessions stay active for 30 days unless they register again.
 async fn send_expo_notification(ctx: State<AppContext>, notification: Notification) -> Result<()> {
-    let expo_push_tokens = match notification.recipient_id {
-        Some(recipient_id) => query!(
-            r#"
-            select expo_push_token as "expo_push_token!: String"
-            from "user"
-            where user_id = $1 and expo_push_token is not null
-            "#,
-            recipient_id
-        )
-        .fetch_all(&ctx.db)
-        .await?
-        .into_iter()
-        .map(|row| row.expo_push_token)
-        .collect::<Vec<String>>(),
-        None => query!(
-            r#"
-            select expo_push_token as "expo_push_token!: String"
-            from "user"
-            where expo_push_token is not null
-            "#,
-        )
-        .fetch_all(&ctx.db)
-        .await?
-        .into_iter()
-        .map(|row| row.expo_push_token)
-        .collect::<Vec<String>>(),
-    };
+    let expo_push_tokens = query!(
+        r#"
+            select d.expo_push_token
+            from push_device d
+            left join session s on s.session_id = d.session_id
+            where ($1::uuid is null or d.user_id = $1)
+              and case
+                      when d.session_id is null then d.last_seen_at > now() - interval '30 days'
+                      else s.revoked_at is null and s.expires_at > now()
+                  end
+        "#,
+        notification.recipient_id
+    )
+    .fetch_all(&ctx.db)
+    .await?
+    .into_iter()
+    .map(|row| row.expo_push_token)
+    .collect::<Vec<String>>();
+
     if expo_push_tokens.is_empty() {
         return Ok(());
     }
 
     let expo = Expo::new(Default::default());
-    let message = ExpoPushMessage::builder(expo_push_tokens)
-        .title(notification.title)
-        .body(notification.body)
-        .build();
-
-    expo.send_push_notifications(message)
-        .await
-        .context("failed to send push notification")?;
+
+    for chunk in expo_push_tokens.chunks(MAX_PUSH_RECIPIENTS) {
+        let message = ExpoPushMessage::builder(chunk.to_vec())
+            .title(notification.title.clone())
+            .body(notification.body.clone())
+            .build();
+
+        let tickets = expo
+            .send_push_notifications(message)
+            .await
+            .context("failed to send push notification")?;
+
+        // Tickets come back in the order of the tokens; uninstalled apps won't come back, so forget them.
+        let unregistered = chunk
+            .iter()
+            .zip(tickets)
+            .filter(|(_, ticket)| {
+                matches!(
+                    ticket,
+                    ExpoPushTicket::Error(ExpoPushErrorReceipt {
+                        details: Some(Details {
+                            error: Some(DetailsErrorType::DeviceNotRegistered)
+                        }),
+                        ..
+                    })
+                )
+            })
+            .map(|(token, _)| token.clone())
+            .collect::<Vec<String>>();
+
+        if !unregistered.is_empty() {
+            log::debug!("removing {} unregistered push devices", unregistered.len());
+            query!(
+                r#"delete from push_device where expo_push_token = any($1)"#,
+                &unregistered
+            )
+            .execute(&ctx.db)
+            .await?;
+        }
+    }
 
     Ok(())
 }
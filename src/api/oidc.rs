@@ -0,0 +1,387 @@
+use anyhow::Context;
+use axum::extract::State;
+use axum::routing::post;
+use axum::{Json, Router};
+use chrono::Utc;
+use openidconnect::core::{CoreAuthenticationFlow, CoreClient, CoreProviderMetadata};
+use openidconnect::{
+    AuthorizationCode, ClientId, ClientSecret, CsrfToken, IssuerUrl, Nonce, OAuth2TokenResponse,
+    PkceCodeChallenge, PkceCodeVerifier, RedirectUrl, Scope, TokenResponse,
+};
+use rand::Rng;
+use sqlx::{query, PgConnection};
+use uuid::Uuid;
+
+use crate::api::users::{start_user_session, User, UserBody};
+use crate::api::util::{hash_password, ClientInfo};
+use crate::api::{AppContext, Error, Result};
+use crate::config::Config;
+
+/// How long a user has to sign in at the provider and come back.
+const LOGIN_LENGTH: chrono::Duration = chrono::Duration::minutes(10);
+
+pub(crate) fn router() -> Router<AppContext> {
+    Router::new()
+        .route("/api/users/oidc/authorize", post(authorize))
+        .route("/api/users/oidc/callback", post(callback))
+}
+
+/// The OpenID Connect provider users can sign in with, see `oidc_issuer_url` in the config.
+pub(crate) struct OidcProvider {
+    issuer_url: IssuerUrl,
+    client_id: ClientId,
+    client_secret: Option<ClientSecret>,
+    redirect_url: RedirectUrl,
+    http: reqwest::Client,
+}
+
+impl OidcProvider {
+    pub fn from_config(config: &Config) -> anyhow::Result<Option<Self>> {
+        let Some(ref issuer_url) = config.oidc_issuer_url else {
+            return Ok(None);
+        };
+
+        let client_id = config
+            .oidc_client_id
+            .clone()
+            .context("oidc_client_id is required")?;
+        let redirect_url = config
+            .oidc_redirect_url
+            .clone()
+            .context("oidc_redirect_url is required")?;
+
+        Ok(Some(Self {
+            issuer_url: IssuerUrl::new(issuer_url.clone()).context("invalid oidc_issuer_url")?,
+            client_id: ClientId::new(client_id),
+            client_secret: config.oidc_client_secret.clone().map(ClientSecret::new),
+            redirect_url: RedirectUrl::new(redirect_url).context("invalid oidc_redirect_url")?,
+            // Following redirects would let a misbehaving provider point us at internal services.
+            http: reqwest::Client::builder()
+                .redirect(reqwest::redirect::Policy::none())
+                .build()
+                .context("failed to build HTTP client")?,
+        }))
+    }
+
+    /// Fetch the provider's endpoints and signing keys.
+    ///
+    /// This is done for every login rather than once on startup,
+    /// so the app starts while the provider is down and picks up rotated keys.
+    async fn metadata(&self) -> Result<CoreProviderMetadata> {
+        Ok(
+            CoreProviderMetadata::discover_async(self.issuer_url.clone(), &self.http)
+                .await
+                .with_context(|| {
+                    format!(
+                        "failed to discover OpenID Connect provider {}",
+                        self.issuer_url.as_str()
+                    )
+                })?,
+        )
+    }
+}
+
+fn provider(ctx: &AppContext) -> Result<&OidcProvider> {
+    ctx.oidc.as_deref().ok_or_else(|| {
+        log::debug!("single sign-on is not configured");
+        Error::NotFound
+    })
+}
+
+#[derive(serde::Serialize)]
+struct Authorization {
+    /// Where to send the user to sign in, they come back to `oidc_redirect_url` with a `code` and `state`.
+    authorization_url: String,
+}
+
+/// Start a single sign-on login, with PKCE so the code is useless to anyone who intercepts it.
+async fn authorize(ctx: State<AppContext>) -> Result<Json<Authorization>> {
+    let provider = provider(&ctx)?;
+
+    let client = CoreClient::from_provider_metadata(
+        provider.metadata().await?,
+        provider.client_id.clone(),
+        provider.client_secret.clone(),
+    )
+    .set_redirect_uri(provider.redirect_url.clone());
+
+    let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
+
+    let (url, state, nonce) = client
+        .authorize_url(
+            CoreAuthenticationFlow::AuthorizationCode,
+            CsrfToken::new_random,
+            Nonce::new_random,
+        )
+        .add_scope(Scope::new("email".into()))
+        .add_scope(Scope::new("profile".into()))
+        .set_pkce_challenge(pkce_challenge)
+        .url();
+
+    let mut tx = ctx.db.begin().await?;
+
+    query!(r#"delete from oidc_login where expires_at < now()"#)
+        .execute(&mut *tx)
+        .await?;
+
+    query!(
+        r#"
+            insert into oidc_login (state, pkce_verifier, nonce, expires_at)
+            values ($1, $2, $3, $4)
+        "#,
+        state.secret(),
+        pkce_verifier.secret(),
+        nonce.secret(),
+        Utc::now() + LOGIN_LENGTH
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(Json(Authorization {
+        authorization_url: url.to_string(),
+    }))
+}
+
+#[derive(serde::Deserialize)]
+struct Callback {
+    code: String,
+    state: String,
+}
+
+fn sign_in_failed() -> Error {
+    Error::unprocessable_entity([("code", "could not sign in with the identity provider")])
+}
+
+/// Finish a single sign-on login with what the provider sent back to `oidc_redirect_url`,
+/// starting a session for the linked user and creating one if this is their first login.
+async fn callback(
+    ctx: State<AppContext>,
+    client_info: ClientInfo,
+    Json(req): Json<Callback>,
+) -> Result<Json<UserBody<User>>> {
+    let provider = provider(&ctx)?;
+
+    // Each login can only be finished once.
+    let login = query!(
+        r#"
+            delete from oidc_login where state = $1 and expires_at > now()
+            returning pkce_verifier, nonce
+        "#,
+        req.state
+    )
+    .fetch_optional(&ctx.db)
+    .await?
+    .ok_or_else(|| Error::unprocessable_entity([("state", "unknown or expired login")]))?;
+
+    let client = CoreClient::from_provider_metadata(
+        provider.metadata().await?,
+        provider.client_id.clone(),
+        provider.client_secret.clone(),
+    )
+    .set_redirect_uri(provider.redirect_url.clone());
+
+    let token = client
+        .exchange_code(AuthorizationCode::new(req.code))
+        .context("provider has no token endpoint")?
+        .set_pkce_verifier(PkceCodeVerifier::new(login.pkce_verifier))
+        .request_async(&provider.http)
+        .await
+        .map_err(|e| {
+            log::debug!("failed to exchange authorization code: {:?}", e);
+            sign_in_failed()
+        })?;
+
+    let claims = token
+        .id_token()
+        .ok_or_else(|| {
+            log::debug!(
+                "provider returned no ID token with {:?}",
+                token.token_type()
+            );
+            sign_in_failed()
+        })?
+        .claims(&client.id_token_verifier(), &Nonce::new(login.nonce))
+        .map_err(|e| {
+            log::debug!("invalid ID token: {:?}", e);
+            sign_in_failed()
+        })?;
+
+    let identity = Identity {
+        issuer: claims.issuer().to_string(),
+        subject: claims.subject().to_string(),
+        // An address the provider hasn't checked could belong to someone else's account here.
+        email: claims
+            .email()
+            .filter(|_| claims.email_verified() == Some(true))
+            .map(|email| email.to_string()),
+        preferred_username: claims.preferred_username().map(|name| name.to_string()),
+    };
+
+    let mut tx = ctx.db.begin().await?;
+    let user_id = find_or_create_user(&ctx, &mut tx, &identity).await?;
+    tx.commit().await?;
+
+    let suspended = sqlx::query_scalar!(
+        r#"select suspended_at is not null as "suspended!" from "user" where user_id = $1"#,
+        user_id
+    )
+    .fetch_one(&ctx.db)
+    .await?;
+
+    if suspended {
+        log::debug!("user {} is suspended", user_id);
+        return Err(Error::Forbidden);
+    }
+
+    Ok(Json(start_user_session(&ctx, user_id, &client_info).await?))
+}
+
+/// Who the provider says signed in.
+struct Identity {
+    issuer: String,
+    subject: String,
+    /// Only set if the provider has verified it.
+    email: Option<String>,
+    preferred_username: Option<String>,
+}
+
+/// Find the user linked to `identity`, otherwise link the user who verified the same email,
+/// otherwise create a new user.
+async fn find_or_create_user(
+    ctx: &AppContext,
+    tx: &mut PgConnection,
+    identity: &Identity,
+) -> Result<Uuid> {
+    let linked = sqlx::query_scalar!(
+        r#"
+            update user_identity set last_login_at = now()
+            where issuer = $1 and subject = $2
+            returning user_id
+        "#,
+        identity.issuer,
+        identity.subject
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    if let Some(user_id) = linked {
+        return Ok(user_id);
+    }
+
+    let existing = match identity.email {
+        Some(ref email) => {
+            sqlx::query!(
+                r#"select user_id, email_verified_at is not null as "verified!" from "user" where email = $1"#,
+                email
+            )
+            .fetch_optional(&mut *tx)
+            .await?
+        }
+        None => None,
+    };
+
+    let user_id = match existing {
+        Some(user) if user.verified => {
+            log::info!(
+                "linking {} at {} to user {} by verified email",
+                identity.subject,
+                identity.issuer,
+                user.user_id
+            );
+            user.user_id
+        }
+        // Anyone can register with an email they don't own, so neither hand that account over
+        // nor take the address from it.
+        Some(user) => {
+            log::info!(
+                "not linking {} at {} to user {}, who hasn't verified their email",
+                identity.subject,
+                identity.issuer,
+                user.user_id
+            );
+            create_user(ctx, tx, identity, None).await?
+        }
+        None => create_user(ctx, tx, identity, identity.email.as_deref()).await?,
+    };
+
+    query!(
+        r#"insert into user_identity (issuer, subject, user_id) values ($1, $2, $3)"#,
+        identity.issuer,
+        identity.subject,
+        user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    Ok(user_id)
+}
+
+/// Create a user for someone signing in for the first time, with the `email` the provider verified.
+///
+/// They get a random password nobody knows, and can set one with a password reset if they want.
+async fn create_user(
+    ctx: &AppContext,
+    tx: &mut PgConnection,
+    identity: &Identity,
+    email: Option<&str>,
+) -> Result<Uuid> {
+    let base = username_from(identity);
+    let password: [u8; 32] = rand::random();
+    let hash = hash_password(ctx, hex::encode(password)).await?;
+
+    // Usernames are first come first served, so add a number if someone already has this one.
+    for attempt in 0..10 {
+        let username = match attempt {
+            0 => base.clone(),
+            _ => format!("{}{}", base, rand::thread_rng().gen_range(1000..10000)),
+        };
+
+        let user_id = sqlx::query_scalar!(
+            r#"
+                insert into "user" (username, password_hash, email, email_verified_at)
+                values ($1, $2, $3::text, case when $3 is null then null else now() end)
+                on conflict do nothing
+                returning user_id
+            "#,
+            username,
+            hash,
+            email
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        if let Some(user_id) = user_id {
+            log::info!(
+                "created user {:?} for {} at {}",
+                username,
+                identity.subject,
+                identity.issuer
+            );
+            return Ok(user_id);
+        }
+    }
+
+    Err(anyhow::anyhow!("failed to find a free username based on {:?}", base).into())
+}
+
+fn username_from(identity: &Identity) -> String {
+    let name = identity
+        .preferred_username
+        .as_deref()
+        .or_else(|| identity.email.as_deref()?.split('@').next())
+        .unwrap_or_default();
+
+    let username: String = name
+        .chars()
+        .filter(|c| c.is_alphanumeric() || matches!(c, '.' | '_' | '-'))
+        .take(32)
+        .collect();
+
+    if username.is_empty() {
+        "student".into()
+    } else {
+        username
+    }
+}
@@ -0,0 +1,711 @@
+use std::collections::HashMap;
+
+use axum::extract::{Path, State};
+use axum::routing::{get, put};
+use axum::{Json, Router};
+use chrono::{
+    DateTime, Datelike, Days, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc, Weekday,
+};
+use chrono_tz::Tz;
+use serde_json::json;
+use sqlx::{query, PgConnection};
+use uuid::Uuid;
+
+use crate::api::auth::{Auth, AuthRestaurant, Permission};
+use crate::api::restaurants::record_action;
+use crate::api::{AppContext, Error, Result};
+
+const MAX_INTERVALS_PER_DAY: usize = 6;
+const MAX_REASON_LENGTH: usize = 100;
+/// How far ahead to look for the next opening, so a restaurant closed for the summer shows as closed without a date.
+const LOOKAHEAD_DAYS: u64 = 14;
+
+pub(crate) fn router() -> Router<AppContext> {
+    Router::new()
+        .route("/api/restaurants/opening_hours", put(update_opening_hours))
+        .route(
+            "/api/restaurants/opening_hours/:restaurant_id",
+            get(get_opening_hours),
+        )
+        .route(
+            "/api/restaurants/opening_exceptions/:date",
+            put(set_exception).delete(delete_exception),
+        )
+}
+
+/// One stretch of time the restaurant is open, in its timezone.
+/// If it closes before it opens, it runs past midnight into the next day.
+#[derive(Clone, Copy, serde::Serialize, serde::Deserialize)]
+pub(super) struct Interval {
+    opens: NaiveTime,
+    closes: NaiveTime,
+}
+
+impl Interval {
+    /// When the interval starts and ends if it starts on `date`.
+    fn on(self, date: NaiveDate) -> (NaiveDateTime, NaiveDateTime) {
+        let closes_on = if self.closes > self.opens {
+            date
+        } else {
+            date + Days::new(1)
+        };
+
+        (date.and_time(self.opens), closes_on.and_time(self.closes))
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct WeeklyInterval {
+    /// `Mon` to `Sun`; full names are accepted too.
+    weekday: Weekday,
+    #[serde(flatten)]
+    interval: Interval,
+}
+
+#[derive(serde::Serialize)]
+struct Exception {
+    date: NaiveDate,
+    reason: Option<String>,
+    /// Replaces the weekly schedule for the day; closed all day if empty.
+    hours: Vec<Interval>,
+}
+
+/// A restaurant's weekly schedule and upcoming exceptions, enough to tell whether it's open.
+pub(super) struct Schedule {
+    timezone: Tz,
+    /// Indexed by days from Monday.
+    weekly: [Vec<Interval>; 7],
+    exceptions: HashMap<NaiveDate, Vec<Interval>>,
+}
+
+impl Schedule {
+    /// The intervals starting on `date`, local to the restaurant.
+    fn intervals_on(
+        &self,
+        date: NaiveDate,
+    ) -> impl Iterator<Item = (NaiveDateTime, NaiveDateTime)> + '_ {
+        self.exceptions
+            .get(&date)
+            .unwrap_or(&self.weekly[date.weekday().num_days_from_monday() as usize])
+            .iter()
+            .map(move |interval| interval.on(date))
+    }
+
+    fn to_utc(&self, local: NaiveDateTime) -> Option<DateTime<Utc>> {
+        // Skips times that don't exist locally, like those in a daylight saving gap.
+        self.timezone
+            .from_local_datetime(&local)
+            .earliest()
+            .map(|time| time.with_timezone(&Utc))
+    }
+
+    pub fn is_open_at(&self, time: DateTime<Utc>) -> bool {
+        let local = time.with_timezone(&self.timezone).naive_local();
+        let today = local.date();
+
+        // Last night's intervals may still be running.
+        [today - Days::new(1), today].into_iter().any(|date| {
+            self.intervals_on(date)
+                .any(|(opens, closes)| opens <= local && local < closes)
+        })
+    }
+
+    /// When the restaurant next opens after `time`, if within [`LOOKAHEAD_DAYS`].
+    pub fn next_opening(&self, time: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let local = time.with_timezone(&self.timezone).naive_local();
+
+        (0..=LOOKAHEAD_DAYS)
+            .flat_map(|days| self.intervals_on(local.date() + Days::new(days)))
+            .map(|(opens, _)| opens)
+            .filter(|opens| *opens > local)
+            .min()
+            .and_then(|opens| self.to_utc(opens))
+    }
+
+    /// Whether the restaurant is open now, and otherwise when it next opens.
+    pub fn status(&self) -> (bool, Option<DateTime<Utc>>) {
+        let now = Utc::now();
+
+        if self.is_open_at(now) {
+            (true, None)
+        } else {
+            (false, self.next_opening(now))
+        }
+    }
+}
+
+/// Load the schedule of a restaurant, with the exceptions that could still matter.
+pub(super) async fn schedule(db: &mut PgConnection, restaurant_id: Uuid) -> Result<Schedule> {
+    let timezone = sqlx::query_scalar!(
+        "select timezone from restaurant where restaurant_id = $1",
+        restaurant_id
+    )
+    .fetch_optional(&mut *db)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    // Only ever set from a valid timezone, see `parse_timezone`.
+    let timezone: Tz = timezone
+        .parse()
+        .map_err(|e| anyhow::anyhow!("invalid timezone {:?}: {}", timezone, e))?;
+
+    // Yesterday's intervals may still be running, and exception dates are local to the restaurant.
+    let since = Utc::now().with_timezone(&timezone).date_naive() - Days::new(1);
+
+    let rows = query!(
+        r#"
+            select weekday, date, opens, closes from opening_hours
+            where restaurant_id = $1 and (date is null or date >= $2)
+        "#,
+        restaurant_id,
+        since
+    )
+    .fetch_all(&mut *db)
+    .await?;
+
+    let exception_dates = sqlx::query_scalar!(
+        "select date from opening_exception where restaurant_id = $1 and date >= $2",
+        restaurant_id,
+        since
+    )
+    .fetch_all(&mut *db)
+    .await?;
+
+    let mut schedule = Schedule {
+        timezone,
+        weekly: Default::default(),
+        exceptions: exception_dates
+            .into_iter()
+            .map(|date| (date, vec![]))
+            .collect(),
+    };
+
+    for row in rows {
+        let interval = Interval {
+            opens: row.opens,
+            closes: row.closes,
+        };
+
+        match (row.weekday, row.date) {
+            (Some(weekday), _) => schedule.weekly[weekday as usize - 1].push(interval),
+            (None, Some(date)) => schedule.exceptions.entry(date).or_default().push(interval),
+            (None, None) => {}
+        }
+    }
+
+    Ok(schedule)
+}
+
+/// Refuse orders while the restaurant is closed, in the same transaction as making the order.
+pub(super) async fn check_open(tx: &mut PgConnection, restaurant_id: Uuid) -> Result<()> {
+    let schedule = schedule(tx, restaurant_id).await?;
+    let now = Utc::now();
+
+    if schedule.is_open_at(now) {
+        return Ok(());
+    }
+
+    let message = match schedule.next_opening(now) {
+        Some(opens) => format!(
+            "restaurant is closed, it opens at {}",
+            opens
+                .with_timezone(&schedule.timezone)
+                .format("%H:%M on %a %-d %b")
+        ),
+        None => "restaurant is closed".to_owned(),
+    };
+
+    Err(Error::unprocessable_entity([("restaurant_id", message)]))
+}
+
+/// Give a new restaurant the hours every restaurant had before they could set their own.
+pub(super) async fn set_default_hours(tx: &mut PgConnection, restaurant_id: Uuid) -> Result<()> {
+    query!(
+        r#"
+            insert into opening_hours (restaurant_id, weekday, opens, closes)
+            select $1, weekday, '09:00', '21:00' from generate_series(1, 7) weekday
+        "#,
+        restaurant_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    Ok(())
+}
+
+fn parse_timezone(timezone: &str) -> Result<Tz> {
+    timezone.parse().map_err(|_| {
+        Error::unprocessable_entity([("timezone", "must be an IANA timezone like Asia/Kolkata")])
+    })
+}
+
+/// Check a day's intervals don't overlap, and that there aren't too many of them.
+fn validate_day<'a>(
+    field: &'static str,
+    intervals: impl IntoIterator<Item = &'a Interval>,
+) -> Result<()> {
+    let intervals: Vec<_> = intervals.into_iter().collect();
+
+    if intervals.len() > MAX_INTERVALS_PER_DAY {
+        return Err(Error::unprocessable_entity([(
+            field,
+            format!("can have at most {} intervals a day", MAX_INTERVALS_PER_DAY),
+        )]));
+    }
+
+    // Checked before `Interval::on`, which would take these to run for a whole day.
+    if intervals
+        .iter()
+        .any(|interval| interval.opens == interval.closes)
+    {
+        return Err(Error::unprocessable_entity([(
+            field,
+            "intervals must close at a different time than they open",
+        )]));
+    }
+
+    // Any day will do, only the order of the intervals matters.
+    let mut intervals: Vec<_> = intervals
+        .into_iter()
+        .map(|interval| interval.on(NaiveDate::MIN))
+        .collect();
+
+    intervals.sort();
+    if intervals.windows(2).any(|pair| pair[0].1 > pair[1].0) {
+        return Err(Error::unprocessable_entity([(
+            field,
+            "intervals on the same day can't overlap",
+        )]));
+    }
+
+    Ok(())
+}
+
+#[derive(serde::Serialize)]
+struct OpeningHours {
+    timezone: String,
+    hours: Vec<WeeklyInterval>,
+    /// Exceptions from today on.
+    exceptions: Vec<Exception>,
+    is_open_now: bool,
+    next_opening: Option<DateTime<Utc>>,
+}
+
+async fn get_opening_hours(
+    _auth: Auth,
+    ctx: State<AppContext>,
+    Path(restaurant_id): Path<Uuid>,
+) -> Result<Json<OpeningHours>> {
+    let mut conn = ctx.db.acquire().await?;
+
+    let schedule = schedule(&mut conn, restaurant_id).await?;
+    let (is_open_now, next_opening) = schedule.status();
+    let today = Utc::now().with_timezone(&schedule.timezone).date_naive();
+
+    let hours = query!(
+        r#"
+            select weekday as "weekday!", opens, closes from opening_hours
+            where restaurant_id = $1 and weekday is not null
+            order by weekday, opens
+        "#,
+        restaurant_id
+    )
+    .fetch_all(&mut *conn)
+    .await?
+    .into_iter()
+    .filter_map(|row| {
+        Some(WeeklyInterval {
+            weekday: Weekday::try_from(row.weekday as u8 - 1).ok()?,
+            interval: Interval {
+                opens: row.opens,
+                closes: row.closes,
+            },
+        })
+    })
+    .collect();
+
+    let mut exceptions: Vec<_> = query!(
+        r#"
+            select date, reason from opening_exception
+            where restaurant_id = $1 and date >= $2
+            order by date
+        "#,
+        restaurant_id,
+        today
+    )
+    .fetch_all(&mut *conn)
+    .await?
+    .into_iter()
+    .map(|row| Exception {
+        date: row.date,
+        reason: row.reason,
+        hours: vec![],
+    })
+    .collect();
+
+    for exception in &mut exceptions {
+        exception.hours = schedule
+            .exceptions
+            .get(&exception.date)
+            .cloned()
+            .unwrap_or_default();
+        exception.hours.sort_by_key(|interval| interval.opens);
+    }
+
+    Ok(Json(OpeningHours {
+        timezone: schedule.timezone.name().to_owned(),
+        hours,
+        exceptions,
+        is_open_now,
+        next_opening,
+    }))
+}
+
+#[derive(serde::Deserialize)]
+struct UpdateOpeningHours {
+    timezone: Option<String>,
+    /// Replaces the whole weekly schedule; days without intervals are closed.
+    hours: Vec<WeeklyInterval>,
+}
+
+async fn update_opening_hours(
+    auth_restaurant: AuthRestaurant,
+    ctx: State<AppContext>,
+    Json(req): Json<UpdateOpeningHours>,
+) -> Result<()> {
+    auth_restaurant.require(Permission::ManageRestaurant)?;
+
+    let timezone = req.timezone.as_deref().map(parse_timezone).transpose()?;
+
+    for weekday in (0..7).filter_map(|day| Weekday::try_from(day).ok()) {
+        validate_day(
+            "hours",
+            req.hours
+                .iter()
+                .filter(|hours| hours.weekday == weekday)
+                .map(|hours| &hours.interval),
+        )?;
+    }
+
+    let mut tx = ctx.db.begin().await?;
+
+    if let Some(timezone) = timezone {
+        query!(
+            "update restaurant set timezone = $1 where restaurant_id = $2",
+            timezone.name(),
+            auth_restaurant.restaurant_id
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    query!(
+        "delete from opening_hours where restaurant_id = $1 and weekday is not null",
+        auth_restaurant.restaurant_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    for hours in &req.hours {
+        query!(
+            "insert into opening_hours (restaurant_id, weekday, opens, closes) values ($1, $2, $3, $4)",
+            auth_restaurant.restaurant_id,
+            hours.weekday.number_from_monday() as i16,
+            hours.interval.opens,
+            hours.interval.closes
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    record_action(
+        &mut *tx,
+        &auth_restaurant,
+        "update_opening_hours",
+        None,
+        json!({ "timezone": req.timezone, "hours": req.hours }),
+    )
+    .await?;
+
+    tx.commit().await?;
+    Ok(())
+}
+
+#[derive(serde::Deserialize)]
+struct SetException {
+    reason: Option<String>,
+    /// Leave out or empty to close all day.
+    #[serde(default)]
+    hours: Vec<Interval>,
+}
+
+/// Keep different hours on a date, or close for it, replacing any exception already set for it.
+async fn set_exception(
+    auth_restaurant: AuthRestaurant,
+    ctx: State<AppContext>,
+    Path(date): Path<NaiveDate>,
+    Json(req): Json<SetException>,
+) -> Result<()> {
+    auth_restaurant.require(Permission::ManageRestaurant)?;
+
+    let reason = req
+        .reason
+        .map(|reason| reason.trim().to_owned())
+        .filter(|reason| !reason.is_empty());
+
+    if reason
+        .as_ref()
+        .is_some_and(|reason| reason.chars().count() > MAX_REASON_LENGTH)
+    {
+        return Err(Error::unprocessable_entity([(
+            "reason",
+            format!("must be at most {} characters", MAX_REASON_LENGTH),
+        )]));
+    }
+
+    validate_day("hours", &req.hours)?;
+
+    let mut tx = ctx.db.begin().await?;
+
+    let schedule = schedule(&mut tx, auth_restaurant.restaurant_id).await?;
+    if date < Utc::now().with_timezone(&schedule.timezone).date_naive() {
+        return Err(Error::unprocessable_entity([(
+            "date",
+            "can't be in the past",
+        )]));
+    }
+
+    // Replacing the exception drops its old hours with it.
+    query!(
+        "delete from opening_exception where restaurant_id = $1 and date = $2",
+        auth_restaurant.restaurant_id,
+        date
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    query!(
+        "insert into opening_exception (restaurant_id, date, reason) values ($1, $2, $3)",
+        auth_restaurant.restaurant_id,
+        date,
+        reason
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    for interval in &req.hours {
+        query!(
+            "insert into opening_hours (restaurant_id, date, opens, closes) values ($1, $2, $3, $4)",
+            auth_restaurant.restaurant_id,
+            date,
+            interval.opens,
+            interval.closes
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    record_action(
+        &mut *tx,
+        &auth_restaurant,
+        "set_opening_exception",
+        None,
+        json!({ "date": date, "reason": reason, "hours": req.hours }),
+    )
+    .await?;
+
+    tx.commit().await?;
+    Ok(())
+}
+
+async fn delete_exception(
+    auth_restaurant: AuthRestaurant,
+    ctx: State<AppContext>,
+    Path(date): Path<NaiveDate>,
+) -> Result<()> {
+    auth_restaurant.require(Permission::ManageRestaurant)?;
+
+    let mut tx = ctx.db.begin().await?;
+
+    let deleted = query!(
+        "delete from opening_exception where restaurant_id = $1 and date = $2",
+        auth_restaurant.restaurant_id,
+        date
+    )
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+
+    if deleted == 0 {
+        return Err(Error::NotFound);
+    }
+
+    record_action(
+        &mut *tx,
+        &auth_restaurant,
+        "delete_opening_exception",
+        None,
+        json!({ "date": date }),
+    )
+    .await?;
+
+    tx.commit().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn interval(opens: &str, closes: &str) -> Interval {
+        Interval {
+            opens: opens.parse().unwrap(),
+            closes: closes.parse().unwrap(),
+        }
+    }
+
+    /// Open only on the given weekdays, in India.
+    fn schedule(weekly: &[(Weekday, Interval)]) -> Schedule {
+        let mut schedule = Schedule {
+            timezone: chrono_tz::Asia::Kolkata,
+            weekly: Default::default(),
+            exceptions: HashMap::new(),
+        };
+
+        for &(weekday, interval) in weekly {
+            schedule.weekly[weekday.num_days_from_monday() as usize].push(interval);
+        }
+
+        schedule
+    }
+
+    fn date(date: &str) -> NaiveDate {
+        date.parse().unwrap()
+    }
+
+    /// A local time in the schedule's timezone. 2024-01-05 is a Friday.
+    fn at(schedule: &Schedule, time: &str) -> DateTime<Utc> {
+        let local: NaiveDateTime = time.parse().unwrap();
+        schedule.to_utc(local).unwrap()
+    }
+
+    #[test]
+    fn overnight_interval_runs_past_midnight() {
+        let schedule = schedule(&[(Weekday::Fri, interval("22:00:00", "02:00:00"))]);
+
+        assert!(!schedule.is_open_at(at(&schedule, "2024-01-05T21:59:00")));
+        assert!(schedule.is_open_at(at(&schedule, "2024-01-05T22:00:00")));
+        assert!(schedule.is_open_at(at(&schedule, "2024-01-06T01:59:00")));
+        assert!(!schedule.is_open_at(at(&schedule, "2024-01-06T02:00:00")));
+        // Saturday's own hours are closed, the interval only spills over from Friday.
+        assert!(!schedule.is_open_at(at(&schedule, "2024-01-06T22:30:00")));
+    }
+
+    #[test]
+    fn exception_on_spillover_day_keeps_last_night_open() {
+        let mut schedule = schedule(&[
+            (Weekday::Fri, interval("22:00:00", "02:00:00")),
+            (Weekday::Sat, interval("09:00:00", "17:00:00")),
+        ]);
+        schedule.exceptions.insert(date("2024-01-06"), vec![]);
+
+        assert!(schedule.is_open_at(at(&schedule, "2024-01-06T01:00:00")));
+        assert!(!schedule.is_open_at(at(&schedule, "2024-01-06T10:00:00")));
+    }
+
+    #[test]
+    fn exception_on_opening_day_closes_the_spillover() {
+        let mut schedule = schedule(&[(Weekday::Fri, interval("22:00:00", "02:00:00"))]);
+        schedule.exceptions.insert(date("2024-01-05"), vec![]);
+
+        assert!(!schedule.is_open_at(at(&schedule, "2024-01-05T23:00:00")));
+        assert!(!schedule.is_open_at(at(&schedule, "2024-01-06T01:00:00")));
+    }
+
+    #[test]
+    fn exception_hours_replace_the_weekly_ones() {
+        let mut schedule = schedule(&[(Weekday::Fri, interval("09:00:00", "17:00:00"))]);
+        schedule
+            .exceptions
+            .insert(date("2024-01-05"), vec![interval("12:00:00", "14:00:00")]);
+
+        assert!(!schedule.is_open_at(at(&schedule, "2024-01-05T10:00:00")));
+        assert!(schedule.is_open_at(at(&schedule, "2024-01-05T13:00:00")));
+        assert_eq!(
+            schedule.next_opening(at(&schedule, "2024-01-05T08:00:00")),
+            Some(at(&schedule, "2024-01-05T12:00:00"))
+        );
+    }
+
+    #[test]
+    fn next_opening_crosses_the_week_boundary() {
+        let schedule = schedule(&[(Weekday::Mon, interval("09:00:00", "17:00:00"))]);
+
+        // From Sunday evening to Monday morning.
+        assert_eq!(
+            schedule.next_opening(at(&schedule, "2024-01-07T20:00:00")),
+            Some(at(&schedule, "2024-01-08T09:00:00"))
+        );
+        // From Monday after closing to the Monday after.
+        assert_eq!(
+            schedule.next_opening(at(&schedule, "2024-01-08T17:30:00")),
+            Some(at(&schedule, "2024-01-15T09:00:00"))
+        );
+    }
+
+    #[test]
+    fn next_opening_gives_up_after_the_lookahead() {
+        let mut schedule = schedule(&[(Weekday::Mon, interval("09:00:00", "17:00:00"))]);
+        for days in 0..=LOOKAHEAD_DAYS {
+            schedule
+                .exceptions
+                .insert(date("2024-01-05") + Days::new(days), vec![]);
+        }
+
+        assert_eq!(
+            schedule.next_opening(at(&schedule, "2024-01-05T08:00:00")),
+            None
+        );
+    }
+
+    #[test]
+    fn validate_day_accepts_separate_and_overnight_intervals() {
+        let intervals = [
+            interval("08:00:00", "11:00:00"),
+            interval("12:00:00", "15:00:00"),
+            interval("22:00:00", "02:00:00"),
+        ];
+
+        assert!(validate_day("hours", &intervals).is_ok());
+    }
+
+    #[test]
+    fn validate_day_rejects_overlaps() {
+        let intervals = [
+            interval("08:00:00", "12:00:00"),
+            interval("11:00:00", "15:00:00"),
+        ];
+        assert!(validate_day("hours", &intervals).is_err());
+
+        let intervals = [
+            interval("20:00:00", "23:00:00"),
+            interval("22:00:00", "02:00:00"),
+        ];
+        assert!(validate_day("hours", &intervals).is_err());
+    }
+
+    #[test]
+    fn validate_day_rejects_empty_and_too_many_intervals() {
+        assert!(validate_day("hours", &[interval("09:00:00", "09:00:00")]).is_err());
+
+        let intervals: Vec<_> = (0..=MAX_INTERVALS_PER_DAY)
+            .map(|hour| {
+                interval(
+                    &format!("{:02}:00:00", hour * 2),
+                    &format!("{:02}:30:00", hour * 2),
+                )
+            })
+            .collect();
+        assert!(validate_day("hours", &intervals).is_err());
+    }
+}
@@ -10,13 +10,22 @@ use serde::{Deserialize, Serialize};
 use serde_json::json;
 use sha2::Digest;
 
-use crate::api::auth::{Auth, AuthRestaurant, AuthUser};
+use crate::api::audit::{change, AuditEvent};
+use crate::api::auth::{Auth, AuthIntegration, AuthUser, Permission};
+use crate::api::customers::{check_not_blocked, count_cancellation};
+use crate::api::loyalty::{self, redeem, Redeem};
 use crate::api::notifications::{new_notification, Notification};
+use crate::api::opening_hours::check_open;
+use crate::api::profile::{customer, Customer};
+use crate::api::referrals::reward_referral;
 use crate::api::restaurants::{
-    get_restaurant_name, get_restaurant_phonpe_details, PhonepeMerchant,
+    get_restaurant_name, get_restaurant_phonpe_details, record_action, PhonepeMerchant,
 };
 use crate::api::users::get_username;
+use crate::api::util::ClientInfo;
+use crate::api::wallet::refund_order;
 use crate::api::AppContext;
+use crate::api::Error;
 use crate::api::Result;
 use bigdecimal::ToPrimitive;
 
@@ -43,8 +52,13 @@ pub(super) struct Order {
     restaurant_name: String,
     user_id: uuid::Uuid,
     user_name: String,
+    /// Only shown to the restaurant, with the details the user shares so they can find them at pickup.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    customer: Option<Customer>,
     items: Vec<Item>,
     total: i32,
+    /// Taken off the total by redeeming loyalty points or a stamp card.
+    loyalty_discount: i32,
     status: String,
     created_at: chrono::DateTime<Utc>,
     order_placed_time: Option<chrono::DateTime<Utc>>,
@@ -64,6 +78,8 @@ struct Item {
 struct NewOrder {
     restaurant_id: uuid::Uuid,
     items: Vec<NewItem>,
+    #[serde(default)]
+    redeem: Redeem,
 }
 
 #[derive(serde::Deserialize)]
@@ -77,19 +93,64 @@ async fn make_order(
     ctx: State<AppContext>,
     Json(req): Json<OrderBody<NewOrder>>,
 ) -> Result<Json<OrderBody<Order>>> {
-    let mut total = 0;
+    if req.order.items.is_empty() {
+        return Err(Error::unprocessable_entity([(
+            "items",
+            "must have at least one item",
+        )]));
+    }
+
+    if req.order.items.iter().any(|item| item.quantity <= 0) {
+        return Err(Error::unprocessable_entity([(
+            "quantity",
+            "must be at least 1",
+        )]));
+    }
+
+    let mut total: i32 = 0;
     let mut items = Vec::new();
+    let mut loyalty_items = Vec::new();
     let mut tx = ctx.db.begin().await?;
+
+    let active = sqlx::query_scalar!(
+        r#"select exists(select 1 from restaurant where restaurant_id = $1 and status = 'active') as "active!""#,
+        req.order.restaurant_id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    if !active {
+        return Err(Error::unprocessable_entity([(
+            "restaurant_id",
+            "restaurant is not accepting orders",
+        )]));
+    }
+
+    check_open(&mut tx, req.order.restaurant_id).await?;
+    check_not_blocked(&mut tx, req.order.restaurant_id, auth_user.user_id).await?;
+
     for item in &req.order.items {
-        // TODO: currently gives a 500 error if the item doesn't exist. fix this.
+        // Only the restaurant's own items, so they can't be ordered past the checks above.
         let db_item = sqlx::query!(
-            r#"select item_id, name, price from item where item_id = $1"#,
-            item.id
+            r#"select item_id, name, price from item where item_id = $1 and restaurant_id = $2 and available"#,
+            item.id,
+            req.order.restaurant_id
         )
-        .fetch_one(&mut *tx)
-        .await?;
-
-        total += db_item.price * item.quantity;
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or_else(|| {
+            Error::unprocessable_entity([(
+                "items",
+                format!("item {} is not available from this restaurant", item.id),
+            )])
+        })?;
+
+        total = db_item
+            .price
+            .checked_mul(item.quantity)
+            .and_then(|price| total.checked_add(price))
+            .ok_or_else(|| Error::unprocessable_entity([("quantity", "is too large")]))?;
+        loyalty_items.push((db_item.item_id, db_item.price, item.quantity));
         items.push(Item {
             name: db_item.name,
             price: db_item.price,
@@ -116,6 +177,38 @@ async fn make_order(
         .await?;
     }
 
+    let loyalty = redeem(
+        &mut tx,
+        auth_user.user_id,
+        req.order.restaurant_id,
+        order.order_id,
+        &loyalty_items,
+        total,
+        &req.order.redeem,
+    )
+    .await?;
+
+    // Orders a discount covers entirely have nothing left to pay.
+    let status = sqlx::query_scalar!(
+        r#"
+            update "order"
+            set total = total - $1,
+                loyalty_discount = $1,
+                loyalty_points = $2,
+                loyalty_stamps = $3,
+                status = case when $1 > 0 and total > 0 and total - $1 = 0 then 'paid' else status end,
+                order_placed_time = case when $1 > 0 and total > 0 and total - $1 = 0 then now() end
+            where order_id = $4
+            returning status
+        "#,
+        loyalty.discount,
+        loyalty.points,
+        loyalty.stamps,
+        order.order_id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
     tx.commit().await?;
 
     Ok(Json(OrderBody {
@@ -125,9 +218,11 @@ async fn make_order(
             restaurant_name: get_restaurant_name(req.order.restaurant_id, &ctx).await?,
             user_id: auth_user.user_id,
             user_name: get_username(auth_user.user_id, &ctx).await?,
+            customer: None,
             items,
-            total,
-            status: "payment_pending".into(),
+            total: total - loyalty.discount,
+            loyalty_discount: loyalty.discount,
+            status,
             created_at: order.created_at,
             order_placed_time: None,
             order_completed_time: None,
@@ -150,16 +245,16 @@ fn calc_xverify(parts: &[&str], index: &str) -> String {
 }
 
 #[derive(Serialize, Deserialize)]
-enum PaymentStatus {
+pub(super) enum PaymentStatus {
     Paid,
     Pending,
     Failed,
 }
 
 #[derive(Serialize, Deserialize)]
-struct Payment {
-    status: PaymentStatus,
-    url: Option<String>,
+pub(super) struct Payment {
+    pub status: PaymentStatus,
+    pub url: Option<String>,
 }
 
 async fn get_payment_session(
@@ -167,12 +262,21 @@ async fn get_payment_session(
     Path(order_id): Path<uuid::Uuid>,
     ctx: State<AppContext>,
 ) -> Result<Json<Payment>> {
+    let mut tx = ctx.db.begin().await?;
+
+    // Held until the payment URL is saved, so the order can't be paid from the wallet in the meantime.
     let order = sqlx::query!(
-        r#"select total, status, payment_url, restaurant_id from "order" where order_id = $1"#,
-        order_id
+        r#"
+            select total, status, payment_url, restaurant_id from "order"
+            where order_id = $1 and user_id = $2
+            for update
+        "#,
+        order_id,
+        auth_user.user_id
     )
-    .fetch_one(&ctx.db)
-    .await?;
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(Error::NotFound)?;
 
     if order.status == "payment_failed" {
         return Ok(Json(Payment {
@@ -191,7 +295,6 @@ async fn get_payment_session(
     let merchant_info = get_restaurant_phonpe_details(order.restaurant_id, &ctx).await?;
 
     let oid = order_id.to_string().replace("-", "");
-    let user_id = auth_user.user_id.to_string().replace("-", "");
 
     match order.payment_url {
         Some(url) => {
@@ -199,12 +302,14 @@ async fn get_payment_session(
             match status {
                 PaymentStatus::Paid => {
                     sqlx::query!(
-                        r#"update "order" set status = 'paid', order_placed_time = $1 where order_id = $2"#,
+                        r#"update "order" set status = 'paid', order_placed_time = $1 where order_id = $2 and status = 'payment_pending'"#,
                         Utc::now(),
                         order_id
                     )
-                    .execute(&ctx.db)
+                    .execute(&mut *tx)
                     .await?;
+                    tx.commit().await?;
+
                     Ok(Json(Payment {
                         status: PaymentStatus::Paid,
                         url: None,
@@ -216,11 +321,15 @@ async fn get_payment_session(
                 })),
                 PaymentStatus::Failed => {
                     sqlx::query!(
-                        r#"update "order" set status = 'payment_failed' where order_id = $1"#,
+                        r#"update "order" set status = 'payment_failed' where order_id = $1 and status = 'payment_pending'"#,
                         order_id
                     )
-                    .execute(&ctx.db)
+                    .execute(&mut *tx)
                     .await?;
+
+                    loyalty::reverse(&mut tx, order_id).await?;
+                    tx.commit().await?;
+
                     Ok(Json(Payment {
                         status: PaymentStatus::Failed,
                         url: Some(url),
@@ -229,77 +338,116 @@ async fn get_payment_session(
             }
         }
         None => {
-            let data = json!({
-              "merchantId": merchant_info.id,
-              "merchantTransactionId": oid,
-              "merchantUserId": user_id,
-              "amount": order.total.to_string()+"00",
-              "redirectUrl": "https://khaogalli.me/static/payments.html",
-              "redirectMode": "REDIRECT",
-              "callbackUrl": "https://webhook.site/callback-url",
-              "paymentInstrument": {
-                "type": "PAY_PAGE"
-              }
-            });
-
-            let request_data = data
-                .to_base64()
-                .context("failed to convert json data to base64")?;
-
-            let request = json!({
-                "request": request_data
-            });
-
-            let client = Client::new();
-
-            let xverify = calc_xverify(
-                &[&request_data, "/pg/v1/pay", &merchant_info.key],
-                &merchant_info.key_id,
-            );
-
-            let response = client
-                .post(format!("{}/pg/v1/pay", HOST))
-                .header("Content-Type", "application/json")
-                .header("X-VERIFY", xverify)
-                .json(&request)
-                .send()
-                .await
-                .context("failed to make phonepe api call")?;
-
-            if !response.status().is_success() {
-                return Err(anyhow::anyhow!(format!(
-                    "got error status while calling phonepe: {}",
-                    response.text().await.context("couldnte get text")?
-                ))
-                .into());
-            }
-
-            let response: serde_json::Value =
-                response.json().await.context("failed to parse response")?;
-
-            info!("response: {:?}", response);
-
-            let url = response["data"]["instrumentResponse"]["redirectInfo"]["url"]
-                .as_str()
-                .context("weird url")?;
+            let url = create_payment(
+                &merchant_info,
+                order_id,
+                auth_user.user_id,
+                order.total.into(),
+            )
+            .await?;
 
-            sqlx::query!(
-                r#"update "order" set payment_url = $1 where order_id = $2"#,
+            let updated = sqlx::query!(
+                r#"
+                    update "order" set payment_url = $1
+                    where order_id = $2 and status = 'payment_pending' and payment_url is null
+                "#,
                 url,
                 order_id
             )
-            .execute(&ctx.db)
-            .await?;
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+
+            // Paid some other way after all, the PhonePe page must not be used.
+            if updated == 0 {
+                return Err(Error::unprocessable_entity([(
+                    "order",
+                    "is no longer waiting for payment",
+                )]));
+            }
+
+            tx.commit().await?;
 
             Ok(Json(Payment {
                 status: PaymentStatus::Pending,
-                url: Some(url.into()),
+                url: Some(url),
             }))
         }
     }
 }
 
-async fn verify_payment(oid: String, merchant_info: PhonepeMerchant) -> Result<PaymentStatus> {
+/// Start a PhonePe payment of `amount` rupees to `merchant_info`, returning the URL of the page to pay on.
+///
+/// `transaction_id` identifies the payment to [`verify_payment`] later.
+pub(super) async fn create_payment(
+    merchant_info: &PhonepeMerchant,
+    transaction_id: uuid::Uuid,
+    user_id: uuid::Uuid,
+    amount: i64,
+) -> Result<String> {
+    let oid = transaction_id.to_string().replace("-", "");
+    let user_id = user_id.to_string().replace("-", "");
+
+    let data = json!({
+      "merchantId": merchant_info.id,
+      "merchantTransactionId": oid,
+      "merchantUserId": user_id,
+      "amount": amount.to_string()+"00",
+      "redirectUrl": "https://khaogalli.me/static/payments.html",
+      "redirectMode": "REDIRECT",
+      "callbackUrl": "https://webhook.site/callback-url",
+      "paymentInstrument": {
+        "type": "PAY_PAGE"
+      }
+    });
+
+    let request_data = data
+        .to_base64()
+        .context("failed to convert json data to base64")?;
+
+    let request = json!({
+        "request": request_data
+    });
+
+    let client = Client::new();
+
+    let xverify = calc_xverify(
+        &[&request_data, "/pg/v1/pay", &merchant_info.key],
+        &merchant_info.key_id,
+    );
+
+    let response = client
+        .post(format!("{}/pg/v1/pay", HOST))
+        .header("Content-Type", "application/json")
+        .header("X-VERIFY", xverify)
+        .json(&request)
+        .send()
+        .await
+        .context("failed to make phonepe api call")?;
+
+    if !response.status().is_success() {
+        return Err(anyhow::anyhow!(format!(
+            "got error status while calling phonepe: {}",
+            response.text().await.context("couldnte get text")?
+        ))
+        .into());
+    }
+
+    let response: serde_json::Value = response.json().await.context("failed to parse response")?;
+
+    info!("response: {:?}", response);
+
+    let url = response["data"]["instrumentResponse"]["redirectInfo"]["url"]
+        .as_str()
+        .context("weird url")?;
+
+    Ok(url.into())
+}
+
+pub(super) async fn verify_payment(
+    oid: String,
+    merchant_info: PhonepeMerchant,
+) -> Result<PaymentStatus> {
     let client = Client::new();
     let api_url = format!("{}/pg/v1/status/{}/{}", HOST, merchant_info.id, oid);
     let xverify = calc_xverify(
@@ -365,10 +513,12 @@ async fn get_items(order_id: uuid::Uuid, ctx: &State<AppContext>) -> Result<Vec<
 }
 
 async fn complete_order(
-    auth_restaurant: AuthRestaurant,
+    auth_restaurant: AuthIntegration,
     ctx: State<AppContext>,
     Path(order_id): Path<uuid::Uuid>,
 ) -> Result<()> {
+    auth_restaurant.require(Permission::CompleteOrders)?;
+
     let mut tx = ctx.db.begin().await?;
 
     let x = sqlx::query!(
@@ -381,15 +531,28 @@ async fn complete_order(
                  and status = 'paid' 
                returning user_id"#,
         order_id,
-        auth_restaurant.restaurant_id
+        auth_restaurant.restaurant_id()
     )
     .fetch_one(&mut *tx)
     .await?;
+
+    record_action(
+        &mut *tx,
+        &auth_restaurant,
+        "complete_order",
+        Some(order_id),
+        json!({}),
+    )
+    .await?;
+
+    loyalty::earn(&mut tx, order_id).await?;
+    reward_referral(&mut tx, order_id).await?;
+
     tx.commit().await?;
     new_notification(
         ctx,
         Notification {
-            sender_id: Some(auth_restaurant.restaurant_id),
+            sender_id: Some(auth_restaurant.restaurant_id()),
             recipient_id: Some(x.user_id),
             title: "Order Completed".into(),
             body: format!(
@@ -424,7 +587,7 @@ async fn get_orders_user(
     ctx: State<AppContext>,
 ) -> Result<Vec<Order>> {
     let db_orders = sqlx::query!(
-        r#"select order_id, restaurant_id, total, status, created_at, order_placed_time, order_completed_time, time_taken from "order" where user_id = $1 and created_at > now() - interval '1 day' * $2 and status in ('completed','paid', 'cancelled')"#,
+        r#"select order_id, restaurant_id, total, loyalty_discount, status, created_at, order_placed_time, order_completed_time, time_taken from "order" where user_id = $1 and created_at > now() - interval '1 day' * $2 and status in ('completed','paid', 'cancelled')"#,
         auth_user.user_id,
         days as f64
     )
@@ -458,8 +621,10 @@ async fn get_orders_user(
             restaurant_name: get_restaurant_name(order.restaurant_id, &ctx).await?,
             user_id: auth_user.user_id,
             user_name: get_username(auth_user.user_id, &ctx).await?,
+            customer: None,
             items,
             total: order.total,
+            loyalty_discount: order.loyalty_discount,
             status: order.status,
             created_at: order.created_at,
             order_placed_time: order.order_placed_time,
@@ -472,14 +637,53 @@ async fn get_orders_user(
     Ok(orders)
 }
 
+/// Every order a user has ever made, whatever its status, for their data export.
+pub(super) async fn all_orders_user(
+    user_id: uuid::Uuid,
+    ctx: &State<AppContext>,
+) -> Result<Vec<Order>> {
+    let db_orders = sqlx::query!(
+        r#"select order_id, restaurant_id, total, loyalty_discount, status, created_at, order_placed_time, order_completed_time, time_taken from "order" where user_id = $1 order by created_at"#,
+        user_id
+    )
+    .fetch_all(&ctx.db)
+    .await?;
+
+    let mut orders = Vec::with_capacity(db_orders.len());
+
+    for order in db_orders {
+        orders.push(Order {
+            id: order.order_id,
+            restaurant_id: order.restaurant_id,
+            restaurant_name: get_restaurant_name(order.restaurant_id, ctx).await?,
+            user_id,
+            user_name: get_username(user_id, ctx).await?,
+            customer: None,
+            items: get_items(order.order_id, ctx).await?,
+            total: order.total,
+            loyalty_discount: order.loyalty_discount,
+            status: order.status,
+            created_at: order.created_at,
+            order_placed_time: order.order_placed_time,
+            order_completed_time: order.order_completed_time,
+            time_taken: order.time_taken,
+            avg_wait_time: None,
+        });
+    }
+
+    Ok(orders)
+}
+
 async fn get_orders_restaurant(
-    auth_restaurant: AuthRestaurant,
+    auth_restaurant: AuthIntegration,
     days: i32,
     ctx: State<AppContext>,
 ) -> Result<Vec<Order>> {
+    auth_restaurant.require(Permission::ViewOrders)?;
+
     let db_orders = sqlx::query!(
-        r#"select order_id, user_id, total, status, created_at, order_placed_time, order_completed_time, time_taken from "order" where restaurant_id = $1 and created_at > now() - interval '1 day' * $2 and status in ('completed','paid')"#,
-        auth_restaurant.restaurant_id,
+        r#"select order_id, user_id, total, loyalty_discount, status, created_at, order_placed_time, order_completed_time, time_taken from "order" where restaurant_id = $1 and created_at > now() - interval '1 day' * $2 and status in ('completed','paid')"#,
+        auth_restaurant.restaurant_id(),
         days as f64
     )
     .fetch_all(&ctx.db)
@@ -491,12 +695,14 @@ async fn get_orders_restaurant(
         let items = get_items(order.order_id, &ctx).await?;
         orders.push(Order {
             id: order.order_id,
-            restaurant_id: auth_restaurant.restaurant_id,
-            restaurant_name: get_restaurant_name(auth_restaurant.restaurant_id, &ctx).await?,
+            restaurant_id: auth_restaurant.restaurant_id(),
+            restaurant_name: get_restaurant_name(auth_restaurant.restaurant_id(), &ctx).await?,
             user_id: order.user_id,
             user_name: get_username(order.user_id, &ctx).await?,
+            customer: Some(customer(&ctx.db, order.user_id).await?),
             items,
             total: order.total,
+            loyalty_discount: order.loyalty_discount,
             status: order.status,
             created_at: order.created_at,
             order_placed_time: order.order_placed_time,
@@ -513,14 +719,15 @@ async fn cancel_order(
     auth: Auth,
     Path(order_id): Path<uuid::Uuid>,
     ctx: State<AppContext>,
+    client: ClientInfo,
 ) -> Result<Json<bool>> {
     // allow user to cancel only if order is not completed and less than 1 minute old
     // and allow restaurant to cancel any time before completing
 
     match auth {
-        Auth::User(auth_user) => cancel_order_user(auth_user, order_id, ctx).await,
+        Auth::User(auth_user) => cancel_order_user(auth_user, order_id, ctx, &client).await,
         Auth::Restaurant(auth_restaurant) => {
-            cancel_order_restaurant(auth_restaurant, order_id, ctx).await
+            cancel_order_restaurant(auth_restaurant, order_id, ctx, &client).await
         }
     }
 }
@@ -529,9 +736,10 @@ async fn cancel_order_user(
     auth_user: AuthUser,
     order_id: uuid::Uuid,
     ctx: State<AppContext>,
+    client: &ClientInfo,
 ) -> Result<Json<bool>> {
     let order = sqlx::query!(
-        r#"select status, order_placed_time from "order" where order_id = $1 and user_id = $2"#,
+        r#"select status, order_placed_time, restaurant_id from "order" where order_id = $1 and user_id = $2"#,
         order_id,
         auth_user.user_id
     )
@@ -553,25 +761,49 @@ async fn cancel_order_user(
         return Ok(Json(false));
     }
 
-    sqlx::query!(
-        r#"update "order" set status = 'cancelled' where order_id = $1"#,
+    let mut tx = ctx.db.begin().await?;
+
+    // The order may have been completed or cancelled by the restaurant since it was read.
+    let Some(cancelled) = sqlx::query!(
+        r#"update "order" set status = 'cancelled' where order_id = $1 and status = 'paid' returning user_id, restaurant_id"#,
         order_id
     )
-    .execute(&ctx.db)
-    .await?;
+    .fetch_optional(&mut *tx)
+    .await?
+    else {
+        return Ok(Json(false));
+    };
+
+    count_cancellation(&mut tx, cancelled.restaurant_id, cancelled.user_id).await?;
+
+    refund_order(&mut tx, order_id).await?;
+    loyalty::reverse(&mut tx, order_id).await?;
+
+    AuditEvent::new("order_cancelled", &auth_user)
+        .user(auth_user.user_id)
+        .restaurant(order.restaurant_id)
+        .target(order_id)
+        .changes(json!({ "status": change(&order.status, "cancelled") }))
+        .record(&mut *tx, client)
+        .await?;
+
+    tx.commit().await?;
 
     Ok(Json(true))
 }
 
 async fn cancel_order_restaurant(
-    auth_restaurant: AuthRestaurant,
+    auth_restaurant: AuthIntegration,
     order_id: uuid::Uuid,
     ctx: State<AppContext>,
+    client: &ClientInfo,
 ) -> Result<Json<bool>> {
+    auth_restaurant.require(Permission::CancelOrders)?;
+
     let order = sqlx::query!(
         r#"select status from "order" where order_id = $1 and restaurant_id = $2"#,
         order_id,
-        auth_restaurant.restaurant_id
+        auth_restaurant.restaurant_id()
     )
     .fetch_one(&ctx.db)
     .await?;
@@ -580,17 +812,45 @@ async fn cancel_order_restaurant(
         return Ok(Json(false));
     }
 
-    let x = sqlx::query!(
-        r#"update "order" set status = 'cancelled' where order_id = $1 returning user_id"#,
+    let mut tx = ctx.db.begin().await?;
+
+    // The order may have been completed or cancelled by the user since it was read.
+    let Some(x) = sqlx::query!(
+        r#"update "order" set status = 'cancelled' where order_id = $1 and status = 'paid' returning user_id, restaurant_id"#,
         order_id
     )
-    .fetch_one(&ctx.db)
+    .fetch_optional(&mut *tx)
+    .await?
+    else {
+        return Ok(Json(false));
+    };
+
+    refund_order(&mut tx, order_id).await?;
+    loyalty::reverse(&mut tx, order_id).await?;
+
+    record_action(
+        &mut *tx,
+        &auth_restaurant,
+        "cancel_order",
+        Some(order_id),
+        json!({}),
+    )
     .await?;
 
+    AuditEvent::new("order_cancelled", &auth_restaurant)
+        .restaurant(auth_restaurant.restaurant_id())
+        .user(x.user_id)
+        .target(order_id)
+        .changes(json!({ "status": change(&order.status, "cancelled") }))
+        .record(&mut *tx, client)
+        .await?;
+
+    tx.commit().await?;
+
     new_notification(
         ctx,
         Notification {
-            sender_id: Some(auth_restaurant.restaurant_id),
+            sender_id: Some(auth_restaurant.restaurant_id()),
             recipient_id: Some(x.user_id),
             title: "Order Cancelled".into(),
             body: format!(
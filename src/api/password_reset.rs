@@ -0,0 +1,328 @@
+use axum::extract::State;
+use axum::routing::post;
+use axum::{Json, Router};
+use chrono::Utc;
+use rand::Rng;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use sqlx::query;
+use uuid::Uuid;
+
+use crate::api::audit::{Actor, AuditEvent, SECRET_CHANGED};
+use crate::api::login_throttle::throttle;
+use crate::api::messages::Message;
+use crate::api::sessions::{end_sessions, SessionOwner};
+use crate::api::util::{hash_password, ClientInfo};
+use crate::api::{AppContext, Error, Result};
+
+/// How long a reset code can be used for after it was requested.
+const RESET_CODE_LENGTH: chrono::Duration = chrono::Duration::minutes(15);
+
+/// Wrong guesses allowed before a reset code stops working.
+const MAX_RESET_ATTEMPTS: i32 = 5;
+
+/// Codes that can be sent to one account before it is throttled, see [`throttle`].
+const MAX_ACCOUNT_REQUESTS: i32 = 3;
+
+/// Codes that can be requested from one address before it is throttled,
+/// higher than for accounts since many people can share an address.
+const MAX_ADDRESS_REQUESTS: i32 = 20;
+
+pub(crate) fn router() -> Router<AppContext> {
+    Router::new()
+        .route("/api/password_reset", post(request_reset))
+        .route("/api/password_reset/confirm", post(confirm_reset))
+}
+
+#[derive(Clone, Copy, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Account {
+    User,
+    Restaurant,
+}
+
+impl Account {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::User => "user",
+            Self::Restaurant => "restaurant",
+        }
+    }
+}
+
+/// The account a reset is for, looked up by username.
+struct ResetTarget {
+    account: Account,
+    id: Uuid,
+    email: Option<String>,
+}
+
+impl ResetTarget {
+    async fn find(ctx: &AppContext, account: Account, username: &str) -> Result<Option<Self>> {
+        Ok(match account {
+            Account::User => query!(
+                r#"select user_id, email from "user" where username = $1"#,
+                username
+            )
+            .fetch_optional(&ctx.db)
+            .await?
+            .map(|row| Self {
+                account,
+                id: row.user_id,
+                email: row.email,
+            }),
+            Account::Restaurant => query!(
+                r#"select restaurant_id, email from restaurant where username = $1"#,
+                username
+            )
+            .fetch_optional(&ctx.db)
+            .await?
+            .map(|row| Self {
+                account,
+                id: row.restaurant_id,
+                email: row.email,
+            }),
+        })
+    }
+
+    /// The `user_id` and `restaurant_id` columns of this account's `password_reset` rows.
+    fn columns(&self) -> (Option<Uuid>, Option<Uuid>) {
+        match self.account {
+            Account::User => (Some(self.id), None),
+            Account::Restaurant => (None, Some(self.id)),
+        }
+    }
+
+    fn session_owner(&self) -> SessionOwner {
+        match self.account {
+            Account::User => SessionOwner::User(self.id),
+            Account::Restaurant => SessionOwner::Restaurant(self.id),
+        }
+    }
+}
+
+pub(super) fn generate_code() -> String {
+    format!("{:08}", rand::thread_rng().gen_range(0..100_000_000))
+}
+
+pub(super) fn hash_code(code: &str) -> String {
+    hex::encode(Sha256::digest(code.as_bytes()))
+}
+
+fn invalid_code() -> Error {
+    Error::unprocessable_entity([("code", "invalid or expired reset code")])
+}
+
+#[derive(serde::Deserialize)]
+struct RequestReset {
+    account: Account,
+    username: String,
+}
+
+/// Send a reset code to the email address of an account.
+///
+/// This always succeeds unless throttled, so it can't be used to find out which usernames exist or have an email address.
+async fn request_reset(
+    ctx: State<AppContext>,
+    client: ClientInfo,
+    Json(req): Json<RequestReset>,
+) -> Result<()> {
+    // Counted whether or not the account exists, like failed logins, so throttling doesn't give that away.
+    if let Some(ip) = client.ip {
+        throttle(
+            &ctx,
+            &format!("password_reset:ip:{}", ip),
+            MAX_ADDRESS_REQUESTS,
+        )
+        .await?;
+    }
+
+    throttle(
+        &ctx,
+        &format!(
+            "password_reset:{}:{}",
+            req.account.as_str(),
+            req.username.to_lowercase()
+        ),
+        MAX_ACCOUNT_REQUESTS,
+    )
+    .await?;
+
+    let Some(target) = ResetTarget::find(&ctx, req.account, &req.username).await? else {
+        log::debug!(
+            "password reset requested for unknown account {:?}",
+            req.username
+        );
+        return Ok(());
+    };
+
+    let Some(ref email) = target.email else {
+        log::debug!(
+            "password reset requested for {:?}, which has no email",
+            req.username
+        );
+        return Ok(());
+    };
+
+    let code = generate_code();
+    let (user_id, restaurant_id) = target.columns();
+
+    let mut tx = ctx.db.begin().await?;
+
+    // Only the most recent code is ever valid.
+    query!(
+        r#"
+            update password_reset set used_at = now()
+            where user_id is not distinct from $1
+              and restaurant_id is not distinct from $2
+              and used_at is null
+        "#,
+        user_id,
+        restaurant_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    query!(
+        r#"
+            insert into password_reset (user_id, restaurant_id, code_hash, expires_at)
+            values ($1, $2, $3, $4)
+        "#,
+        user_id,
+        restaurant_id,
+        hash_code(&code),
+        Utc::now() + RESET_CODE_LENGTH
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    let message = Message {
+        to: email.clone(),
+        subject: "Your password reset code".into(),
+        body: format!(
+            "Use the code {} to reset the password of {}. It expires in {} minutes.\n\n\
+             If you didn't ask to reset your password, you can ignore this message.",
+            code,
+            req.username,
+            RESET_CODE_LENGTH.num_minutes()
+        ),
+    };
+
+    // Sent in the background so that the response time doesn't give away whether the account exists.
+    let messages = ctx.messages.clone();
+    tokio::spawn(async move {
+        if let Err(e) = messages.send(message).await {
+            log::error!("failed to send password reset code: {:?}", e);
+        }
+    });
+
+    Ok(())
+}
+
+#[derive(serde::Deserialize)]
+struct ConfirmReset {
+    account: Account,
+    username: String,
+    code: String,
+    new_password: String,
+}
+
+async fn confirm_reset(
+    ctx: State<AppContext>,
+    client: ClientInfo,
+    Json(req): Json<ConfirmReset>,
+) -> Result<()> {
+    // Checked first so a weak password doesn't use up an attempt.
+    ctx.password_policy
+        .check("new_password", &req.new_password)?;
+
+    let target = ResetTarget::find(&ctx, req.account, &req.username)
+        .await?
+        .ok_or_else(invalid_code)?;
+
+    let (user_id, restaurant_id) = target.columns();
+
+    let mut tx = ctx.db.begin().await?;
+
+    let reset = query!(
+        r#"
+            select password_reset_id, code_hash
+            from password_reset
+            where user_id is not distinct from $1
+              and restaurant_id is not distinct from $2
+              and used_at is null
+              and expires_at > now()
+            order by created_at desc
+            limit 1
+            for update
+        "#,
+        user_id,
+        restaurant_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or_else(invalid_code)?;
+
+    if reset.code_hash != hash_code(&req.code) {
+        log::debug!("wrong password reset code for {:?}", req.username);
+        query!(
+            r#"
+                update password_reset
+                set attempts = attempts + 1,
+                    used_at = case when attempts + 1 >= $2 then now() end
+                where password_reset_id = $1
+            "#,
+            reset.password_reset_id,
+            MAX_RESET_ATTEMPTS
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        return Err(invalid_code());
+    }
+
+    let hash = hash_password(&ctx, req.new_password).await?;
+
+    match target.account {
+        Account::User => {
+            query!(
+                r#"update "user" set password_hash = $1 where user_id = $2"#,
+                hash,
+                target.id
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        Account::Restaurant => {
+            query!(
+                r#"update restaurant set password_hash = $1 where restaurant_id = $2"#,
+                hash,
+                target.id
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+    }
+
+    query!(
+        r#"update password_reset set used_at = now() where password_reset_id = $1"#,
+        reset.password_reset_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    // Whoever reset the password wasn't signed in, all we know is that they had the code.
+    AuditEvent::new("password_reset", Actor::Anonymous)
+        .user(user_id)
+        .restaurant(restaurant_id)
+        .changes(json!({ "password": SECRET_CHANGED }))
+        .record(&mut *tx, &client)
+        .await?;
+
+    tx.commit().await?;
+
+    // Whoever knew the old password may still be logged in.
+    end_sessions(&ctx, target.session_owner(), None).await
+}
@@ -0,0 +1,506 @@
+use std::sync::Arc;
+
+use axum::extract::State;
+use axum::routing::post;
+use axum::{Json, Router};
+use chrono::Utc;
+use rand::Rng;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use sqlx::query;
+use uuid::Uuid;
+
+use crate::api::audit::{change, AuditEvent};
+use crate::api::auth::AuthUser;
+use crate::api::login_throttle::LoginAttempt;
+use crate::api::sms::{Sms, SmsGateway};
+use crate::api::users::{start_user_session, User, UserBody};
+use crate::api::util::ClientInfo;
+use crate::api::{AppContext, Error, Result, ResultExt};
+
+/// How long a code can be used for after it was sent.
+const CODE_LENGTH: chrono::Duration = chrono::Duration::minutes(5);
+
+/// Wrong guesses allowed before a code stops working.
+const MAX_CODE_ATTEMPTS: i32 = 5;
+
+/// How long to wait before another code can be sent to the same number.
+const RESEND_INTERVAL: chrono::Duration = chrono::Duration::seconds(60);
+
+/// Codes that can be sent to one number, and requested from one address, within [`RATE_WINDOW`].
+/// More per address since many people can share one.
+const MAX_CODES_PER_NUMBER: i64 = 5;
+const MAX_CODES_PER_ADDRESS: i64 = 20;
+const RATE_WINDOW: chrono::Duration = chrono::Duration::hours(1);
+
+pub(crate) fn router() -> Router<AppContext> {
+    Router::new()
+        .route("/api/users/phone/login_code", post(request_login_code))
+        .route("/api/users/phone/login", post(login_with_code))
+        .route(
+            "/api/users/phone",
+            post(request_verify_code).delete(remove_phone_number),
+        )
+        .route("/api/users/phone/verify", post(verify_phone_number))
+}
+
+/// What a code lets its holder do, the `purpose` column of `phone_otp`.
+#[derive(Clone, Copy)]
+enum Purpose {
+    Login,
+    Verify,
+}
+
+impl Purpose {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Login => "login",
+            Self::Verify => "verify",
+        }
+    }
+}
+
+/// Bring a phone number into E.164 format, ignoring the spaces, dashes and brackets people write them with.
+fn normalize_phone_number(phone_number: &str) -> Result<String> {
+    let normalized: String = phone_number
+        .chars()
+        .filter(|c| !matches!(c, ' ' | '-' | '(' | ')'))
+        .collect();
+
+    let valid = normalized.strip_prefix('+').is_some_and(|digits| {
+        (8..=15).contains(&digits.len())
+            && !digits.starts_with('0')
+            && digits.chars().all(|c| c.is_ascii_digit())
+    });
+
+    if !valid {
+        return Err(Error::unprocessable_entity([(
+            "phone_number",
+            "must be in international format, e.g. +919876543210",
+        )]));
+    }
+
+    Ok(normalized)
+}
+
+fn generate_code() -> String {
+    format!("{:06}", rand::thread_rng().gen_range(0..1_000_000))
+}
+
+fn hash_code(code: &str) -> String {
+    hex::encode(Sha256::digest(code.as_bytes()))
+}
+
+/// The gateway codes are sent through, without which phone numbers can't be used.
+fn sms_gateway(ctx: &AppContext) -> Result<Arc<dyn SmsGateway>> {
+    ctx.sms.clone().ok_or_else(|| {
+        log::debug!("no SMS gateway is configured");
+        Error::NotFound
+    })
+}
+
+fn invalid_code() -> Error {
+    Error::unprocessable_entity([("code", "invalid or expired code")])
+}
+
+/// Fail with [`Error::TooManyRequests`] if too many codes have recently been sent to
+/// `phone_number` or requested from the client's address.
+async fn check_rate_limits(
+    ctx: &AppContext,
+    phone_number: &str,
+    client: &ClientInfo,
+) -> Result<()> {
+    let since = Utc::now() - RATE_WINDOW;
+
+    let number = query!(
+        r#"
+            select count(*) as "count!", min(created_at) as first, max(created_at) as last
+            from phone_otp
+            where phone_number = $1 and created_at > $2
+        "#,
+        phone_number,
+        since
+    )
+    .fetch_one(&ctx.db)
+    .await?;
+
+    if let Some(last) = number
+        .last
+        .filter(|last| *last + RESEND_INTERVAL > Utc::now())
+    {
+        return Err(Error::TooManyRequests {
+            retry_after: retry_after(last + RESEND_INTERVAL),
+        });
+    }
+
+    if let Some(first) = number
+        .first
+        .filter(|_| number.count >= MAX_CODES_PER_NUMBER)
+    {
+        log::debug!("too many codes sent to {}", phone_number);
+        return Err(Error::TooManyRequests {
+            retry_after: retry_after(first + RATE_WINDOW),
+        });
+    }
+
+    let Some(ip) = client.ip else {
+        return Ok(());
+    };
+
+    let address = query!(
+        r#"
+            select count(*) as "count!", min(created_at) as first
+            from phone_otp
+            where ip = $1 and created_at > $2
+        "#,
+        ip.to_string(),
+        since
+    )
+    .fetch_one(&ctx.db)
+    .await?;
+
+    if let Some(first) = address
+        .first
+        .filter(|_| address.count >= MAX_CODES_PER_ADDRESS)
+    {
+        log::debug!("too many codes requested from {}", ip);
+        return Err(Error::TooManyRequests {
+            retry_after: retry_after(first + RATE_WINDOW),
+        });
+    }
+
+    Ok(())
+}
+
+fn retry_after(at: chrono::DateTime<Utc>) -> u64 {
+    (at - Utc::now()).num_seconds().max(1) as u64
+}
+
+/// Replace any outstanding code for `phone_number` with a new one,
+/// and send it if there is a user it is for.
+///
+/// A code is recorded even when there is nobody to send it to, so that it counts towards the rate limits
+/// the same way and the response doesn't give away whether the number is in use.
+async fn send_code(
+    ctx: &AppContext,
+    gateway: Arc<dyn SmsGateway>,
+    purpose: Purpose,
+    phone_number: &str,
+    user_id: Option<Uuid>,
+    client: &ClientInfo,
+) -> Result<()> {
+    let code = generate_code();
+
+    let mut tx = ctx.db.begin().await?;
+
+    // Only the most recent code is ever valid.
+    query!(
+        r#"
+            update phone_otp set used_at = now()
+            where phone_number = $1 and purpose = $2 and used_at is null
+        "#,
+        phone_number,
+        purpose.as_str()
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    query!(
+        r#"
+            insert into phone_otp (phone_number, purpose, user_id, code_hash, ip, expires_at)
+            values ($1, $2, $3, $4, $5, $6)
+        "#,
+        phone_number,
+        purpose.as_str(),
+        user_id,
+        hash_code(&code),
+        client.ip.map(|ip| ip.to_string()),
+        Utc::now() + CODE_LENGTH
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    if user_id.is_none() {
+        log::debug!("{} code requested for unused number", purpose.as_str());
+        return Ok(());
+    }
+
+    let sms = Sms {
+        to: phone_number.to_owned(),
+        body: format!(
+            "Your code is {}. It expires in {} minutes. Don't share it with anyone.",
+            code,
+            CODE_LENGTH.num_minutes()
+        ),
+    };
+
+    // Sent in the background so that the response time doesn't give away whether the number is in use.
+    tokio::spawn(async move {
+        if let Err(e) = gateway.send(sms).await {
+            log::error!("failed to send SMS code: {:?}", e);
+        }
+    });
+
+    Ok(())
+}
+
+/// Use up the current code for `phone_number` if `code` matches it, returning the user it was sent to.
+///
+/// Returns `None` for a wrong, expired or unknown code, counting wrong guesses against the code.
+async fn use_code(
+    ctx: &AppContext,
+    purpose: Purpose,
+    phone_number: &str,
+    code: &str,
+) -> Result<Option<Uuid>> {
+    let mut tx = ctx.db.begin().await?;
+
+    let Some(otp) = query!(
+        r#"
+            select phone_otp_id, user_id, code_hash
+            from phone_otp
+            where phone_number = $1
+              and purpose = $2
+              and used_at is null
+              and expires_at > now()
+            order by created_at desc
+            limit 1
+            for update
+        "#,
+        phone_number,
+        purpose.as_str()
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    else {
+        return Ok(None);
+    };
+
+    if otp.code_hash != hash_code(code) {
+        log::debug!("wrong {} code for {}", purpose.as_str(), phone_number);
+        query!(
+            r#"
+                update phone_otp
+                set attempts = attempts + 1,
+                    used_at = case when attempts + 1 >= $2 then now() end
+                where phone_otp_id = $1
+            "#,
+            otp.phone_otp_id,
+            MAX_CODE_ATTEMPTS
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        return Ok(None);
+    }
+
+    query!(
+        r#"update phone_otp set used_at = now() where phone_otp_id = $1"#,
+        otp.phone_otp_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(otp.user_id)
+}
+
+#[derive(serde::Deserialize)]
+struct RequestCode {
+    phone_number: String,
+}
+
+/// Send a login code to a verified phone number.
+///
+/// This succeeds whether or not a user has the number, so it can't be used to find out which numbers are in use.
+async fn request_login_code(
+    ctx: State<AppContext>,
+    client: ClientInfo,
+    Json(req): Json<RequestCode>,
+) -> Result<()> {
+    let gateway = sms_gateway(&ctx)?;
+    let phone_number = normalize_phone_number(&req.phone_number)?;
+    check_rate_limits(&ctx, &phone_number, &client).await?;
+
+    let user_id = sqlx::query_scalar!(
+        r#"select user_id from "user" where phone_number = $1"#,
+        phone_number
+    )
+    .fetch_optional(&ctx.db)
+    .await?;
+
+    send_code(
+        &ctx,
+        gateway,
+        Purpose::Login,
+        &phone_number,
+        user_id,
+        &client,
+    )
+    .await
+}
+
+#[derive(serde::Deserialize)]
+struct CodeLogin {
+    phone_number: String,
+    code: String,
+}
+
+async fn login_with_code(
+    ctx: State<AppContext>,
+    client: ClientInfo,
+    Json(req): Json<CodeLogin>,
+) -> Result<Json<UserBody<User>>> {
+    sms_gateway(&ctx)?;
+    let phone_number = normalize_phone_number(&req.phone_number)?;
+    let attempt = LoginAttempt::start(&ctx, "phone", &phone_number, &client).await?;
+
+    let Some(user_id) = use_code(&ctx, Purpose::Login, &phone_number, &req.code).await? else {
+        return Err(attempt.failed(&ctx).await);
+    };
+
+    attempt.succeeded(&ctx).await?;
+
+    let suspended = sqlx::query_scalar!(
+        r#"select suspended_at is not null as "suspended!" from "user" where user_id = $1"#,
+        user_id
+    )
+    .fetch_one(&ctx.db)
+    .await?;
+
+    if suspended {
+        log::debug!("user {} is suspended", user_id);
+        return Err(Error::Forbidden);
+    }
+
+    Ok(Json(start_user_session(&ctx, user_id, &client).await?))
+}
+
+fn phone_number_taken() -> Error {
+    Error::unprocessable_entity([("phone_number", "phone number taken")])
+}
+
+/// Send a code to a phone number the user wants to add, which they confirm at `/api/users/phone/verify`.
+async fn request_verify_code(
+    auth_user: AuthUser,
+    ctx: State<AppContext>,
+    client: ClientInfo,
+    Json(req): Json<RequestCode>,
+) -> Result<()> {
+    let gateway = sms_gateway(&ctx)?;
+    let phone_number = normalize_phone_number(&req.phone_number)?;
+
+    let owner = sqlx::query_scalar!(
+        r#"select user_id from "user" where phone_number = $1"#,
+        phone_number
+    )
+    .fetch_optional(&ctx.db)
+    .await?;
+
+    if owner.is_some_and(|owner| owner != auth_user.user_id) {
+        return Err(phone_number_taken());
+    }
+
+    check_rate_limits(&ctx, &phone_number, &client).await?;
+
+    send_code(
+        &ctx,
+        gateway,
+        Purpose::Verify,
+        &phone_number,
+        Some(auth_user.user_id),
+        &client,
+    )
+    .await
+}
+
+#[derive(serde::Deserialize)]
+struct VerifyPhoneNumber {
+    phone_number: String,
+    code: String,
+}
+
+/// Set the user's phone number once they have proven they have it.
+async fn verify_phone_number(
+    auth_user: AuthUser,
+    ctx: State<AppContext>,
+    client: ClientInfo,
+    Json(req): Json<VerifyPhoneNumber>,
+) -> Result<()> {
+    sms_gateway(&ctx)?;
+    let phone_number = normalize_phone_number(&req.phone_number)?;
+
+    let user_id = use_code(&ctx, Purpose::Verify, &phone_number, &req.code).await?;
+
+    // A code sent for someone else's number is as good as a wrong one.
+    if user_id != Some(auth_user.user_id) {
+        return Err(invalid_code());
+    }
+
+    let mut tx = ctx.db.begin().await?;
+
+    let old_phone_number = sqlx::query_scalar!(
+        r#"select phone_number from "user" where user_id = $1 for update"#,
+        auth_user.user_id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    query!(
+        r#"update "user" set phone_number = $1, phone_verified_at = now() where user_id = $2"#,
+        phone_number,
+        auth_user.user_id
+    )
+    .execute(&mut *tx)
+    .await
+    .on_constraint("user_phone_number_key", |_| phone_number_taken())?;
+
+    AuditEvent::new("phone_number_changed", &auth_user)
+        .user(auth_user.user_id)
+        .changes(json!({ "phone_number": change(old_phone_number, &phone_number) }))
+        .record(&mut *tx, &client)
+        .await?;
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+async fn remove_phone_number(
+    auth_user: AuthUser,
+    ctx: State<AppContext>,
+    client: ClientInfo,
+) -> Result<()> {
+    let mut tx = ctx.db.begin().await?;
+
+    let old_phone_number = sqlx::query_scalar!(
+        r#"select phone_number from "user" where user_id = $1 for update"#,
+        auth_user.user_id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    let Some(old_phone_number) = old_phone_number else {
+        return Ok(());
+    };
+
+    query!(
+        r#"update "user" set phone_number = null, phone_verified_at = null where user_id = $1"#,
+        auth_user.user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    AuditEvent::new("phone_number_changed", &auth_user)
+        .user(auth_user.user_id)
+        .changes(json!({ "phone_number": change(old_phone_number, None::<String>) }))
+        .record(&mut *tx, &client)
+        .await?;
+
+    tx.commit().await?;
+
+    Ok(())
+}
@@ -0,0 +1,230 @@
+use sqlx::{query, PgConnection, PgExecutor};
+use uuid::Uuid;
+
+use crate::api::{Error, Result, ResultExt};
+
+const MAX_DISPLAY_NAME_LENGTH: usize = 50;
+const MAX_HOSTEL_LENGTH: usize = 50;
+const MAX_ROOM_LENGTH: usize = 10;
+const MIN_ROLL_NUMBER_LENGTH: usize = 4;
+const MAX_ROLL_NUMBER_LENGTH: usize = 20;
+
+/// Details about the user beyond their login, which restaurants may see on their orders.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub(super) struct Profile {
+    display_name: Option<String>,
+    hostel: Option<String>,
+    room: Option<String>,
+    roll_number: Option<String>,
+    privacy: Privacy,
+}
+
+/// Which details restaurants can see. The phone number is the verified one, see `/api/users/phone`.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub(super) struct Privacy {
+    share_display_name: bool,
+    share_phone_number: bool,
+    /// Shares the room along with the hostel.
+    share_hostel: bool,
+    share_roll_number: bool,
+}
+
+/// Changes to a profile through `PATCH /api/users`. Empty strings clear a detail.
+#[derive(serde::Deserialize)]
+pub(super) struct UpdateProfile {
+    display_name: Option<String>,
+    hostel: Option<String>,
+    room: Option<String>,
+    roll_number: Option<String>,
+    privacy: Option<Privacy>,
+}
+
+/// What a user shares with restaurants, shown on their orders.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub(super) struct Customer {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    display_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    phone_number: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hostel: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    room: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    roll_number: Option<String>,
+}
+
+pub(super) async fn profile(db: impl PgExecutor<'_>, user_id: Uuid) -> Result<Profile> {
+    let user = query!(
+        r#"
+            select display_name, hostel, room, roll_number,
+                   share_display_name, share_phone_number, share_hostel, share_roll_number
+            from "user" where user_id = $1
+        "#,
+        user_id
+    )
+    .fetch_one(db)
+    .await?;
+
+    Ok(Profile {
+        display_name: user.display_name,
+        hostel: user.hostel,
+        room: user.room,
+        roll_number: user.roll_number,
+        privacy: Privacy {
+            share_display_name: user.share_display_name,
+            share_phone_number: user.share_phone_number,
+            share_hostel: user.share_hostel,
+            share_roll_number: user.share_roll_number,
+        },
+    })
+}
+
+/// The details the user lets restaurants see.
+pub(super) async fn customer(db: impl PgExecutor<'_>, user_id: Uuid) -> Result<Customer> {
+    let customer = sqlx::query_as!(
+        Customer,
+        r#"
+            select case when share_display_name then display_name end as display_name,
+                   case when share_phone_number then phone_number end as phone_number,
+                   case when share_hostel then hostel end as hostel,
+                   case when share_hostel then room end as room,
+                   case when share_roll_number then roll_number end as roll_number
+            from "user" where user_id = $1
+        "#,
+        user_id
+    )
+    .fetch_one(db)
+    .await?;
+
+    Ok(customer)
+}
+
+/// Trim `value`, returning `None` to clear the detail if that leaves it empty,
+/// or an error if it is longer than `max_length` or has control characters in it.
+fn clean(field: &'static str, value: &str, max_length: usize) -> Result<Option<String>> {
+    let value = value.trim();
+
+    if value.chars().count() > max_length {
+        return Err(Error::unprocessable_entity([(
+            field,
+            format!("must be at most {} characters", max_length),
+        )]));
+    }
+
+    if value.chars().any(char::is_control) {
+        return Err(Error::unprocessable_entity([(
+            field,
+            "can't contain control characters",
+        )]));
+    }
+
+    Ok((!value.is_empty()).then(|| value.to_owned()))
+}
+
+/// Like [`clean`], for details like room and roll numbers that are only letters, digits, '-' and '/', uppercased.
+fn clean_number(field: &'static str, value: &str, max_length: usize) -> Result<Option<String>> {
+    let value = clean(field, value, max_length)?;
+
+    if let Some(ref value) = value {
+        if !value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '/')
+        {
+            return Err(Error::unprocessable_entity([(
+                field,
+                "can only contain letters, digits, '-' and '/'",
+            )]));
+        }
+    }
+
+    Ok(value.map(|value| value.to_uppercase()))
+}
+
+fn clean_roll_number(roll_number: &str) -> Result<Option<String>> {
+    let roll_number = clean_number("roll_number", roll_number, MAX_ROLL_NUMBER_LENGTH)?;
+
+    if roll_number
+        .as_ref()
+        .is_some_and(|roll_number| roll_number.len() < MIN_ROLL_NUMBER_LENGTH)
+    {
+        return Err(Error::unprocessable_entity([(
+            "roll_number",
+            format!("must be at least {} characters", MIN_ROLL_NUMBER_LENGTH),
+        )]));
+    }
+
+    Ok(roll_number)
+}
+
+/// Apply `update` to the user's profile, in the same transaction as the rest of `PATCH /api/users`.
+pub(super) async fn update_profile(
+    tx: &mut PgConnection,
+    user_id: Uuid,
+    update: &UpdateProfile,
+) -> Result<()> {
+    if let Some(ref display_name) = update.display_name {
+        query!(
+            r#"update "user" set display_name = $1 where user_id = $2"#,
+            clean("display_name", display_name, MAX_DISPLAY_NAME_LENGTH)?,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    if let Some(ref hostel) = update.hostel {
+        let hostel = clean("hostel", hostel, MAX_HOSTEL_LENGTH)?;
+
+        // A room means nothing without its hostel.
+        query!(
+            r#"update "user" set hostel = $1::text, room = case when $1 is null then null else room end where user_id = $2"#,
+            hostel,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    if let Some(ref room) = update.room {
+        query!(
+            r#"update "user" set room = $1 where user_id = $2"#,
+            clean_number("room", room, MAX_ROOM_LENGTH)?,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await
+        .on_constraint("user_room_needs_hostel", |_| {
+            Error::unprocessable_entity([("room", "needs a hostel")])
+        })?;
+    }
+
+    if let Some(ref roll_number) = update.roll_number {
+        query!(
+            r#"update "user" set roll_number = $1 where user_id = $2"#,
+            clean_roll_number(roll_number)?,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    if let Some(ref privacy) = update.privacy {
+        query!(
+            r#"
+                update "user"
+                set share_display_name = $1, share_phone_number = $2, share_hostel = $3, share_roll_number = $4
+                where user_id = $5
+            "#,
+            privacy.share_display_name,
+            privacy.share_phone_number,
+            privacy.share_hostel,
+            privacy.share_roll_number,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    Ok(())
+}
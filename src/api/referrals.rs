@@ -0,0 +1,207 @@
+use axum::extract::State;
+use axum::routing::get;
+use axum::{Json, Router};
+use chrono::{DateTime, Utc};
+use sqlx::{query, PgConnection};
+use uuid::Uuid;
+
+use crate::api::auth::AuthUser;
+use crate::api::wallet;
+use crate::api::{AppContext, Error, Result};
+
+/// Wallet credit, in rupees, both the referrer and the referee get.
+const REFERRAL_REWARD: i64 = 50;
+
+/// The smallest order total that earns the rewards, so they can't be had for an order of chutney.
+const MIN_QUALIFYING_ORDER: i32 = 100;
+
+/// Referrals a user is rewarded for; people they invite after that are still rewarded themselves.
+const MAX_REWARDED_REFERRALS: i64 = 10;
+
+pub(crate) fn router() -> Router<AppContext> {
+    Router::new().route("/api/users/referrals", get(get_referrals))
+}
+
+/// Find the user a referral code entered at sign up belongs to.
+pub(super) async fn find_referrer(tx: &mut PgConnection, code: &str) -> Result<Uuid> {
+    sqlx::query_scalar!(
+        r#"
+            select user_id from "user"
+            where referral_code = $1 and deleted_at is null and suspended_at is null
+        "#,
+        code.trim().to_uppercase()
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or_else(|| Error::unprocessable_entity([("referral_code", "no such referral code")]))
+}
+
+/// Record that `referrer_id` invited the new user `referee_id`, in the same transaction as creating them.
+pub(super) async fn create_referral(
+    tx: &mut PgConnection,
+    referrer_id: Uuid,
+    referee_id: Uuid,
+) -> Result<()> {
+    query!(
+        r#"insert into referral (referrer_id, referee_id) values ($1, $2)"#,
+        referrer_id,
+        referee_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    Ok(())
+}
+
+/// Reward the referee and their referrer if `order_id` is the referee's first qualifying order,
+/// in the same transaction as completing it.
+pub(super) async fn reward_referral(tx: &mut PgConnection, order_id: Uuid) -> Result<()> {
+    let order = query!(
+        r#"select user_id, total from "order" where order_id = $1"#,
+        order_id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    if order.total < MIN_QUALIFYING_ORDER {
+        return Ok(());
+    }
+
+    // Locks the referral, so two orders completing at once can't both be rewarded.
+    let Some(referral) = query!(
+        r#"
+            select referral_id, referrer_id from referral
+            where referee_id = $1 and status = 'pending'
+            for update
+        "#,
+        order.user_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    else {
+        return Ok(());
+    };
+
+    // Locks the referrer, so their referrals completing at once are counted one at a time.
+    let referrer = query!(
+        r#"
+            select deleted_at is null and suspended_at is null as "active!" from "user"
+            where user_id = $1
+            for update
+        "#,
+        referral.referrer_id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    let rewarded = sqlx::query_scalar!(
+        r#"select count(*) as "count!" from referral where referrer_id = $1 and referrer_rewarded"#,
+        referral.referrer_id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    let referrer_rewarded = referrer.active && rewarded < MAX_REWARDED_REFERRALS;
+
+    wallet::reward(
+        tx,
+        order.user_id,
+        REFERRAL_REWARD,
+        "Reward for joining through a referral",
+    )
+    .await?;
+
+    if referrer_rewarded {
+        wallet::reward(
+            tx,
+            referral.referrer_id,
+            REFERRAL_REWARD,
+            "Reward for referring a friend",
+        )
+        .await?;
+    }
+
+    query!(
+        r#"
+            update referral
+            set status = 'rewarded', order_id = $1, referrer_rewarded = $2, rewarded_at = now()
+            where referral_id = $3
+        "#,
+        order_id,
+        referrer_rewarded,
+        referral.referral_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    log::info!(
+        "rewarded referral {} (referrer rewarded: {})",
+        referral.referral_id,
+        referrer_rewarded
+    );
+    Ok(())
+}
+
+#[derive(serde::Serialize)]
+pub(super) struct Referrals {
+    /// The code to share, entered as `referral_code` when signing up.
+    code: String,
+    /// Wallet credit each side gets, in rupees.
+    reward: i64,
+    min_qualifying_order: i32,
+    /// How many more referrals the user will be rewarded for.
+    rewards_left: i64,
+    referrals: Vec<Referral>,
+}
+
+#[derive(serde::Serialize)]
+struct Referral {
+    username: String,
+    /// `pending` until their first qualifying order is completed, then `rewarded`.
+    status: String,
+    /// Whether the user was rewarded for this referral, which they aren't past the cap.
+    referrer_rewarded: bool,
+    created_at: DateTime<Utc>,
+    rewarded_at: Option<DateTime<Utc>>,
+}
+
+async fn get_referrals(auth_user: AuthUser, ctx: State<AppContext>) -> Result<Json<Referrals>> {
+    Ok(Json(referrals(&ctx, auth_user.user_id).await?))
+}
+
+/// The user's referral code and everyone they have invited with it, newest first.
+pub(super) async fn referrals(ctx: &AppContext, user_id: Uuid) -> Result<Referrals> {
+    let code = sqlx::query_scalar!(
+        r#"select referral_code from "user" where user_id = $1"#,
+        user_id
+    )
+    .fetch_one(&ctx.db)
+    .await?;
+
+    let referrals = sqlx::query_as!(
+        Referral,
+        r#"
+            select u.username, r.status, r.referrer_rewarded, r.created_at, r.rewarded_at
+            from referral r
+            join "user" u on u.user_id = r.referee_id
+            where r.referrer_id = $1
+            order by r.created_at desc
+        "#,
+        user_id
+    )
+    .fetch_all(&ctx.db)
+    .await?;
+
+    let rewarded = referrals
+        .iter()
+        .filter(|referral| referral.referrer_rewarded)
+        .count() as i64;
+
+    Ok(Referrals {
+        code,
+        reward: REFERRAL_REWARD,
+        min_qualifying_order: MIN_QUALIFYING_ORDER,
+        rewards_left: (MAX_REWARDED_REFERRALS - rewarded).max(0),
+        referrals,
+    })
+}
@@ -7,13 +7,27 @@ use chrono::{DateTime, Utc};
 use image::imageops::FilterType::Nearest;
 use image::ImageFormat;
 use serde::Deserialize;
-use sqlx::{query, query_scalar};
+use serde_json::json;
+use sqlx::{query, query_scalar, PgExecutor};
+use uuid::Uuid;
 
-use crate::api::auth::{Auth, AuthRestaurant, AuthUser};
-use crate::api::util::{hash_password, image_from_base64, verify_password};
+use crate::api::audit::{change, AuditEvent, SECRET_CHANGED};
+use crate::api::auth::{
+    mfa_pending_token, Auth, AuthIntegration, AuthRestaurant, AuthUser, Permission, PrincipalKind,
+    StaffRole,
+};
+use crate::api::diet::{
+    allergen_strs, dietary_preferences, parse_allergens, Allergen, Diet, DietaryPreferences,
+};
+use crate::api::email_change::{self, request_change};
+use crate::api::login_throttle::LoginAttempt;
+use crate::api::mfa::{begin_enrolment, restaurant_mfa_required, MfaAccount, TotpSetup};
+use crate::api::opening_hours::{schedule, set_default_hours};
+use crate::api::sessions::{create_session, end_session, end_sessions, SessionOwner};
+use crate::api::util::{hash_password, image_from_base64, verify_password, ClientInfo};
 use crate::api::{Error, Result, ResultExt};
 use anyhow::Context;
-use axum::extract::{Path, State};
+use axum::extract::{Path, Query, State};
 use axum::routing::{delete, get, post};
 use axum::{Json, Router};
 
@@ -23,9 +37,12 @@ pub(crate) fn router() -> Router<AppContext> {
     Router::new()
         .route("/api/restaurants/list", get(get_restaurants))
         .route("/api/restaurants/login", post(login_restaurant))
+        .route("/api/restaurants/logout", post(logout_restaurant))
         .route(
             "/api/restaurants",
-            get(get_current_restaurant).patch(update_restaurant),
+            get(get_current_restaurant)
+                .post(register_restaurant)
+                .patch(update_restaurant),
         )
         .route("/api/restaurants/menu/:restaurant_id", get(get_menu))
         .route("/api/restaurants/upload_image", post(upload_image))
@@ -39,32 +56,116 @@ pub(crate) fn router() -> Router<AppContext> {
 }
 
 #[derive(serde::Serialize, serde::Deserialize)]
-struct RestaurantBody<T> {
+pub(super) struct RestaurantBody<T> {
     restaurant: T,
 }
 
 #[derive(serde::Serialize, serde::Deserialize)]
-struct Restaurant {
+pub(super) struct Restaurant {
     id: uuid::Uuid,
     username: String,
     name: String,
-    token: String,
-    open_time: DateTime<Utc>,
-    close_time: DateTime<Utc>,
+    email: Option<String>,
+    /// Only returned when a new session is started, see `/api/sessions/refresh` for renewals.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    refresh_token: Option<String>,
+    /// The timezone of the restaurant's opening hours, see `/api/restaurants/opening_hours`.
+    timezone: String,
+    /// `pending_approval` until an administrator approves a restaurant that registered itself.
+    status: String,
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
+/// Whoever did something on behalf of a restaurant.
+pub(super) struct RestaurantActor {
+    restaurant_id: Uuid,
+    staff_id: Option<Uuid>,
+    api_key_id: Option<Uuid>,
+}
+
+impl From<&AuthRestaurant> for RestaurantActor {
+    fn from(auth_restaurant: &AuthRestaurant) -> Self {
+        Self {
+            restaurant_id: auth_restaurant.restaurant_id,
+            staff_id: auth_restaurant.staff_id,
+            api_key_id: None,
+        }
+    }
+}
+
+impl From<&AuthIntegration> for RestaurantActor {
+    fn from(auth: &AuthIntegration) -> Self {
+        match auth {
+            AuthIntegration::Restaurant(auth_restaurant) => auth_restaurant.into(),
+            AuthIntegration::ApiKey(auth_api_key) => Self {
+                restaurant_id: auth_api_key.restaurant_id,
+                staff_id: None,
+                api_key_id: Some(auth_api_key.api_key_id),
+            },
+        }
+    }
+}
+
+/// Record something done on behalf of a restaurant, attributed to whoever is signed in.
+pub(super) async fn record_action(
+    db: impl PgExecutor<'_>,
+    actor: impl Into<RestaurantActor>,
+    action: &str,
+    target_id: Option<Uuid>,
+    details: serde_json::Value,
+) -> Result<()> {
+    let actor = actor.into();
+
+    query!(
+        r#"
+            insert into restaurant_action (restaurant_id, staff_id, api_key_id, action, target_id, details)
+            values ($1, $2, $3, $4, $5, $6)
+        "#,
+        actor.restaurant_id,
+        actor.staff_id,
+        actor.api_key_id,
+        action,
+        target_id,
+        details
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+#[derive(serde::Serialize)]
 struct Menu<T> {
     menu: Vec<T>,
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(serde::Serialize)]
 struct Item {
     id: uuid::Uuid,
     name: String,
     description: String,
     price: i32,
     available: bool,
+    /// Whether the user viewing the menu has favourited the item, always false for restaurants.
+    is_favourite: bool,
+    /// None if the restaurant hasn't labelled the item.
+    diet: Option<Diet>,
+    /// None if the restaurant hasn't said which allergens the item contains.
+    allergens: Option<Vec<Allergen>>,
+    /// Why the item doesn't suit the dietary preferences the menu was filtered by, see [`DietaryPreferences::conflicts`].
+    conflicts: Vec<&'static str>,
+}
+
+/// Dietary preferences to check a menu against, instead of the user's saved ones.
+#[derive(Deserialize)]
+struct MenuFilter {
+    diet: Option<Diet>,
+    /// Comma separated allergens, e.g. `nuts,dairy`.
+    avoid: Option<String>,
+    /// Leave out items with conflicts, rather than only flagging them.
+    #[serde(default)]
+    hide_conflicts: bool,
 }
 
 #[derive(serde::Serialize)]
@@ -77,14 +178,21 @@ struct RestaurantInfo {
     id: uuid::Uuid,
     name: String,
     pending_orders: i64,
-    open_time: DateTime<Utc>,
-    close_time: DateTime<Utc>,
+    is_open_now: bool,
+    /// When the restaurant next opens, if it's closed and opens within two weeks.
+    next_opening: Option<DateTime<Utc>>,
+    is_favourite: bool,
 }
 
-async fn get_restaurants(_user: AuthUser, ctx: State<AppContext>) -> Result<Json<Restaurants>> {
+async fn get_restaurants(auth_user: AuthUser, ctx: State<AppContext>) -> Result<Json<Restaurants>> {
     let mut tx = ctx.db.begin().await?;
     let records = sqlx::query!(
-        r#"select restaurant_id as "id!", name, open_time as "open_time!: chrono::DateTime<Utc>", close_time as "close_time!: chrono::DateTime<Utc>" from restaurant"#
+        r#"
+            select restaurant_id as "id!", name,
+                   exists(select 1 from favourite_restaurant f where f.restaurant_id = restaurant.restaurant_id and f.user_id = $1) as "is_favourite!"
+            from restaurant where status = 'active'
+        "#,
+        auth_user.user_id
     )
     .fetch_all(&mut *tx)
     .await?;
@@ -99,54 +207,264 @@ async fn get_restaurants(_user: AuthUser, ctx: State<AppContext>) -> Result<Json
         .await?
         .context("unexpected option none")?;
 
+        let (is_open_now, next_opening) = schedule(&mut tx, restaurant.id).await?.status();
+
         restaurants.push(RestaurantInfo {
             id: restaurant.id,
             name: restaurant.name,
             pending_orders,
-            open_time: restaurant.open_time,
-            close_time: restaurant.close_time,
+            is_open_now,
+            next_opening,
+            is_favourite: restaurant.is_favourite,
         })
     }
 
     Ok(Json(Restaurants { restaurants }))
 }
 
+/// Pending restaurants can log in to set up their menu and staff before they are approved.
+pub(super) fn can_log_in(status: &str) -> bool {
+    matches!(status, "active" | "pending_approval")
+}
+
+/// Check the details a new restaurant is created with, reporting every problem at once.
+pub(super) fn validate_new_restaurant(
+    username: &str,
+    name: &str,
+    email: Option<&str>,
+) -> Result<()> {
+    let mut errors = Vec::new();
+
+    if !(3..=32).contains(&username.chars().count()) {
+        errors.push(("username", "must be between 3 and 32 characters"));
+    }
+    if !username
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+    {
+        errors.push((
+            "username",
+            "may only contain letters, digits, '.', '_' and '-'",
+        ));
+    }
+    if name.trim().is_empty() || name.chars().count() > 64 {
+        errors.push(("name", "must be between 1 and 64 characters"));
+    }
+    if email.is_some_and(|email| !email.contains('@')) {
+        errors.push(("email", "is not an email address"));
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::unprocessable_entity(errors))
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct NewRestaurant {
+    username: String,
+    name: String,
+    password: String,
+    /// Where password reset codes are sent.
+    email: Option<String>,
+    phonepe: PhonepeDetails,
+}
+
+/// The PhonePe merchant account a restaurant's orders are paid into.
+#[derive(serde::Deserialize)]
+pub(super) struct PhonepeDetails {
+    pub id: String,
+    pub key: String,
+    pub key_id: String,
+}
+
+/// Sign up a new restaurant, which isn't listed or able to take orders until an administrator approves it.
+async fn register_restaurant(
+    ctx: State<AppContext>,
+    Json(req): Json<RestaurantBody<NewRestaurant>>,
+) -> Result<Json<RestaurantBody<Restaurant>>> {
+    let req = req.restaurant;
+    validate_new_restaurant(&req.username, &req.name, req.email.as_deref())?;
+    ctx.password_policy.check("password", &req.password)?;
+
+    let hash = hash_password(&ctx, req.password).await?;
+
+    let mut tx = ctx.db.begin().await?;
+
+    let restaurant = sqlx::query!(
+        r#"
+            insert into restaurant
+                (username, name, password_hash, email, phonepe_id, phonepe_key, phonepe_key_id, status)
+            values ($1, $2, $3, $4, $5, $6, $7, 'pending_approval')
+            returning restaurant_id, timezone, status
+        "#,
+        req.username,
+        req.name,
+        hash,
+        req.email,
+        req.phonepe.id,
+        req.phonepe.key,
+        req.phonepe.key_id
+    )
+    .fetch_one(&mut *tx)
+    .await
+    .on_constraint("restaurant_username_key", |_| {
+        Error::unprocessable_entity([("username", "username taken")])
+    })
+    .on_constraint("restaurant_name_key", |_| {
+        Error::unprocessable_entity([("name", "name taken")])
+    })
+    .on_constraint("restaurant_email_key", |_| {
+        Error::unprocessable_entity([("email", "email taken")])
+    })?;
+
+    set_default_hours(&mut tx, restaurant.restaurant_id).await?;
+
+    tx.commit().await?;
+
+    log::info!(
+        "restaurant {:?} registered and is waiting for approval",
+        req.username
+    );
+
+    // No session yet, logging in takes care of second factors.
+    Ok(Json(RestaurantBody {
+        restaurant: Restaurant {
+            id: restaurant.restaurant_id,
+            username: req.username,
+            name: req.name,
+            email: req.email,
+            token: None,
+            refresh_token: None,
+            timezone: restaurant.timezone,
+            status: restaurant.status,
+        },
+    }))
+}
+
 #[derive(serde::Deserialize)]
 struct LoginRestaurant {
     username: String,
     password: String,
 }
 
+/// The response to a password login, which may need a second factor to finish, see `/api/restaurants/login/mfa`.
+#[derive(serde::Serialize)]
+#[serde(untagged)]
+enum RestaurantLogin {
+    Session(RestaurantBody<Restaurant>),
+    MfaRequired {
+        mfa_token: String,
+        /// Returned when the restaurant has to enrol before it can finish logging in.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        totp_setup: Option<TotpSetup>,
+    },
+}
+
 async fn login_restaurant(
     ctx: State<AppContext>,
+    client: ClientInfo,
     Json(req): Json<RestaurantBody<LoginRestaurant>>,
-) -> Result<Json<RestaurantBody<Restaurant>>> {
+) -> Result<Json<RestaurantLogin>> {
+    let attempt =
+        LoginAttempt::start(&ctx, "restaurant", &req.restaurant.username, &client).await?;
+
     let restaurant = sqlx::query!(
         r#"
-            select restaurant_id, username, name, password_hash, open_time, close_time
+            select restaurant_id, username, password_hash, status, totp_enabled_at
             from "restaurant" where username = $1
         "#,
         req.restaurant.username,
     )
     .fetch_optional(&ctx.db)
-    .await?
-    .ok_or_else(|| Error::unprocessable_entity([("username", "does not exist")]))?;
+    .await?;
 
-    verify_password(req.restaurant.password, restaurant.password_hash).await?;
+    let (restaurant, new_hash) = attempt
+        .verify(&ctx, restaurant, req.restaurant.password, |restaurant| {
+            &restaurant.password_hash
+        })
+        .await?;
 
-    Ok(Json(RestaurantBody {
+    if let Some(hash) = new_hash {
+        query!(
+            r#"update restaurant set password_hash = $1 where restaurant_id = $2"#,
+            hash,
+            restaurant.restaurant_id
+        )
+        .execute(&ctx.db)
+        .await?;
+    }
+
+    if !can_log_in(&restaurant.status) {
+        log::debug!(
+            "restaurant {} is {}",
+            restaurant.restaurant_id,
+            restaurant.status
+        );
+        return Err(Error::Forbidden);
+    }
+
+    if restaurant.totp_enabled_at.is_some() {
+        return Ok(Json(RestaurantLogin::MfaRequired {
+            mfa_token: mfa_pending_token(&ctx, PrincipalKind::Restaurant, restaurant.restaurant_id),
+            totp_setup: None,
+        }));
+    }
+
+    if restaurant_mfa_required(&ctx.db).await? {
+        let account = MfaAccount::Restaurant(restaurant.restaurant_id);
+        let setup = begin_enrolment(&ctx.db, account, &restaurant.username).await?;
+
+        return Ok(Json(RestaurantLogin::MfaRequired {
+            mfa_token: mfa_pending_token(&ctx, PrincipalKind::Restaurant, restaurant.restaurant_id),
+            totp_setup: Some(setup),
+        }));
+    }
+
+    Ok(Json(RestaurantLogin::Session(
+        start_restaurant_session(&ctx, restaurant.restaurant_id, &client).await?,
+    )))
+}
+
+/// Start a session for a restaurant that has proven who it is.
+pub(super) async fn start_restaurant_session(
+    ctx: &AppContext,
+    restaurant_id: Uuid,
+    client: &ClientInfo,
+) -> Result<RestaurantBody<Restaurant>> {
+    let restaurant = sqlx::query!(
+        r#"
+            select username, name, email, timezone, status
+            from "restaurant" where restaurant_id = $1
+        "#,
+        restaurant_id,
+    )
+    .fetch_one(&ctx.db)
+    .await?;
+
+    let session = create_session(ctx, SessionOwner::Restaurant(restaurant_id), client).await?;
+
+    Ok(RestaurantBody {
         restaurant: Restaurant {
-            id: restaurant.restaurant_id,
-            token: AuthRestaurant {
-                restaurant_id: restaurant.restaurant_id,
-            }
-            .to_jwt(&ctx),
+            id: restaurant_id,
+            token: Some(
+                AuthRestaurant {
+                    restaurant_id,
+                    session_id: session.session_id,
+                    staff_id: None,
+                    role: StaffRole::Owner,
+                }
+                .to_jwt(ctx),
+            ),
+            refresh_token: Some(session.refresh_token),
             username: restaurant.username,
             name: restaurant.name,
-            open_time: restaurant.open_time,
-            close_time: restaurant.close_time,
+            email: restaurant.email,
+            timezone: restaurant.timezone,
+            status: restaurant.status,
         },
-    }))
+    })
 }
 
 async fn get_current_restaurant(
@@ -154,7 +472,7 @@ async fn get_current_restaurant(
     ctx: State<AppContext>,
 ) -> Result<Json<RestaurantBody<Restaurant>>> {
     let restaurant = sqlx::query!(
-        r#"select username, name, open_time, close_time from "restaurant" where restaurant_id = $1"#,
+        r#"select username, name, email, timezone, status from "restaurant" where restaurant_id = $1"#,
         auth_restaurant.restaurant_id
     )
     .fetch_one(&ctx.db)
@@ -163,11 +481,13 @@ async fn get_current_restaurant(
     Ok(Json(RestaurantBody {
         restaurant: Restaurant {
             id: auth_restaurant.restaurant_id,
-            token: auth_restaurant.to_jwt(&ctx),
+            token: None,
+            refresh_token: None,
             username: restaurant.username,
             name: restaurant.name,
-            open_time: restaurant.open_time,
-            close_time: restaurant.close_time,
+            email: restaurant.email,
+            timezone: restaurant.timezone,
+            status: restaurant.status,
         },
     }))
 }
@@ -177,8 +497,10 @@ struct UpdateRestaurant {
     username: Option<String>,
     update_pass: Option<UpdatePass>,
     name: Option<String>,
-    open_time: Option<DateTime<Utc>>,
-    close_time: Option<DateTime<Utc>>,
+    /// Only replaces the current email once confirmed with the code sent to it, see `/api/restaurants/email/confirm`.
+    email: Option<String>,
+    /// The current password, required to change the email.
+    password: Option<String>,
 }
 
 #[derive(serde::Deserialize)]
@@ -190,27 +512,37 @@ struct UpdatePass {
 async fn update_restaurant(
     auth_restaurant: AuthRestaurant,
     ctx: State<AppContext>,
+    client: ClientInfo,
     Json(req): Json<RestaurantBody<UpdateRestaurant>>,
 ) -> Result<Json<RestaurantBody<Restaurant>>> {
+    auth_restaurant.require(Permission::ManageRestaurant)?;
+
+    let password_changed = req.restaurant.update_pass.is_some();
     let mut tx = ctx.db.begin().await?;
 
     let restaurant = sqlx::query!(
-        r#"select username, name, password_hash, open_time, close_time from "restaurant" where restaurant_id = $1"#,
+        r#"select username, name, email, password_hash, timezone, status from "restaurant" where restaurant_id = $1"#,
         auth_restaurant.restaurant_id
     )
     .fetch_one(&mut *tx)
     .await?;
 
     if let Some(update_pass) = req.restaurant.update_pass {
-        verify_password(update_pass.old_password, restaurant.password_hash)
-            .await
-            .map_err(|e| match e {
-                Error::Unauthorized => {
-                    Error::unprocessable_entity([("old_password", "old password is incorrect")])
-                }
-                _ => e,
-            })?;
-        let new_hash = hash_password(update_pass.new_password).await?;
+        verify_password(
+            &ctx,
+            update_pass.old_password,
+            restaurant.password_hash.clone(),
+        )
+        .await
+        .map_err(|e| match e {
+            Error::Unauthorized => {
+                Error::unprocessable_entity([("old_password", "old password is incorrect")])
+            }
+            _ => e,
+        })?;
+        ctx.password_policy
+            .check("new_password", &update_pass.new_password)?;
+        let new_hash = hash_password(&ctx, update_pass.new_password).await?;
 
         sqlx::query!(
             r#"update "restaurant" set password_hash = $1 where restaurant_id = $2"#,
@@ -219,19 +551,33 @@ async fn update_restaurant(
         )
         .execute(&mut *tx)
         .await?;
+
+        AuditEvent::new("password_changed", &auth_restaurant)
+            .restaurant(auth_restaurant.restaurant_id)
+            .changes(json!({ "password": SECRET_CHANGED }))
+            .record(&mut *tx, &client)
+            .await?;
     }
 
     if let Some(ref username) = req.restaurant.username {
         sqlx::query!(
-            r#"update "user" set username = $1 where user_id = $2"#,
+            r#"update "restaurant" set username = $1 where restaurant_id = $2"#,
             username,
             auth_restaurant.restaurant_id
         )
         .execute(&mut *tx)
         .await
-        .on_constraint("user_username_key", |_| {
+        .on_constraint("restaurant_username_key", |_| {
             Error::unprocessable_entity([("username", "username taken")])
         })?;
+
+        if *username != restaurant.username {
+            AuditEvent::new("username_changed", &auth_restaurant)
+                .restaurant(auth_restaurant.restaurant_id)
+                .changes(json!({ "username": change(&restaurant.username, username) }))
+                .record(&mut *tx, &client)
+                .await?;
+        }
     }
 
     if let Some(ref name) = req.restaurant.name {
@@ -244,52 +590,140 @@ async fn update_restaurant(
         .await?;
     }
 
-    if let Some(open_time) = req.restaurant.open_time {
-        sqlx::query!(
-            r#"update "restaurant" set open_time = $1 where restaurant_id = $2"#,
-            open_time,
-            auth_restaurant.restaurant_id
-        )
-        .execute(&mut *tx)
-        .await?;
+    let mut email_confirmation = None;
+
+    if let Some(ref email) = req.restaurant.email {
+        // A stolen session alone mustn't be enough to take over the account through a password reset.
+        let password = req.restaurant.password.clone().ok_or_else(|| {
+            Error::unprocessable_entity([("password", "is required to change the email")])
+        })?;
+        verify_password(&ctx, password, restaurant.password_hash)
+            .await
+            .map_err(|e| match e {
+                Error::Unauthorized => {
+                    Error::unprocessable_entity([("password", "password is incorrect")])
+                }
+                _ => e,
+            })?;
+
+        let account = email_change::Account::Restaurant(auth_restaurant.restaurant_id);
+        email_confirmation = Some(request_change(&ctx, &mut tx, account, email).await?);
     }
 
-    if let Some(close_time) = req.restaurant.close_time {
-        sqlx::query!(
-            r#"update "restaurant" set close_time = $1 where restaurant_id = $2"#,
-            close_time,
-            auth_restaurant.restaurant_id
+    record_action(
+        &mut *tx,
+        &auth_restaurant,
+        "update_restaurant",
+        None,
+        json!({
+            "username": req.restaurant.username,
+            "name": req.restaurant.name,
+            "email": req.restaurant.email,
+            "password_changed": password_changed,
+        }),
+    )
+    .await?;
+
+    tx.commit().await?;
+
+    if let Some(message) = email_confirmation {
+        email_change::send(&ctx, message);
+    }
+
+    // Anyone holding the old password may already have a session; log them out.
+    if password_changed {
+        end_sessions(
+            &ctx,
+            SessionOwner::Restaurant(auth_restaurant.restaurant_id),
+            Some(auth_restaurant.session_id),
         )
-        .execute(&mut *tx)
         .await?;
     }
 
-    tx.commit().await?;
-
     Ok(Json(RestaurantBody {
         restaurant: Restaurant {
             id: auth_restaurant.restaurant_id,
-            token: auth_restaurant.to_jwt(&ctx),
+            token: None,
+            refresh_token: None,
             username: req.restaurant.username.unwrap_or(restaurant.username),
             name: req.restaurant.name.unwrap_or(restaurant.name),
-            open_time: req.restaurant.open_time.unwrap_or(restaurant.open_time),
-            close_time: req.restaurant.close_time.unwrap_or(restaurant.close_time),
+            email: restaurant.email,
+            timezone: restaurant.timezone,
+            status: restaurant.status,
         },
     }))
 }
 
+async fn logout_restaurant(auth_restaurant: AuthRestaurant, ctx: State<AppContext>) -> Result<()> {
+    end_session(&ctx, auth_restaurant.session_id).await
+}
+
 async fn get_menu(
-    _auth: Auth,
+    auth: Auth,
     Path(restaurant_id): Path<uuid::Uuid>,
     State(ctx): State<AppContext>,
+    Query(filter): Query<MenuFilter>,
 ) -> Result<Json<Menu<Item>>> {
-    let items = sqlx::query_as!(
-        Item,
-        r#"select item_id as "id!", name, description, price, available from item where restaurant_id = $1 ORDER BY created_at"#,
-        restaurant_id
+    let user_id = match auth {
+        Auth::User(ref auth_user) => Some(auth_user.user_id),
+        Auth::Restaurant(_) => None,
+    };
+
+    let mut preferences = match user_id {
+        Some(user_id) => dietary_preferences(&ctx.db, user_id).await?,
+        None => DietaryPreferences::default(),
+    };
+
+    if filter.diet.is_some() {
+        preferences.diet = filter.diet;
+    }
+
+    if let Some(avoid) = filter.avoid {
+        preferences.avoid = avoid
+            .split(',')
+            .map(str::trim)
+            .filter(|allergen| !allergen.is_empty())
+            .map(|allergen| {
+                allergen.parse().map_err(|()| {
+                    Error::unprocessable_entity([(
+                        "avoid",
+                        format!("unknown allergen {}", allergen),
+                    )])
+                })
+            })
+            .collect::<Result<_>>()?;
+    }
+
+    let items = sqlx::query!(
+        r#"
+            select item_id, name, description, price, available, diet, allergens,
+                   exists(select 1 from favourite_item f where f.item_id = item.item_id and f.user_id = $2) as "is_favourite!"
+            from item where restaurant_id = $1 ORDER BY created_at
+        "#,
+        restaurant_id,
+        user_id
     )
     .fetch_all(&ctx.db)
-    .await?;
+    .await?
+    .into_iter()
+    .map(|item| {
+        let diet = item.diet.and_then(|diet| diet.parse().ok());
+        let allergens = item.allergens.as_deref().map(parse_allergens);
+
+        Item {
+            id: item.item_id,
+            name: item.name,
+            description: item.description,
+            price: item.price,
+            available: item.available,
+            is_favourite: item.is_favourite,
+            conflicts: preferences.conflicts(diet, allergens.as_deref()),
+            diet,
+            allergens,
+        }
+    })
+    .filter(|item| !filter.hide_conflicts || item.conflicts.is_empty())
+    .collect();
 
     Ok(Json(Menu { menu: items }))
 }
@@ -318,6 +752,8 @@ async fn upload_image(
     State(ctx): State<AppContext>,
     Json(req): Json<ImageUpload>,
 ) -> Result<()> {
+    auth_restaurant.require(Permission::ManageRestaurant)?;
+
     let image = image_from_base64(&req.image)?;
     let image = image.resize(1000, 1000, Nearest);
     let mut cursor = Cursor::new(Vec::new());
@@ -325,14 +761,20 @@ async fn upload_image(
         .write_to(&mut cursor, ImageFormat::Jpeg)
         .context("failed to encode image")?;
 
+    let mut tx = ctx.db.begin().await?;
+
     query!(
         "update restaurant set image = $1 where restaurant_id = $2",
         cursor.into_inner(),
         auth_restaurant.restaurant_id
     )
-    .execute(&ctx.db)
+    .execute(&mut *tx)
     .await
     .context("failed to upload image")?;
+
+    record_action(&mut *tx, &auth_restaurant, "upload_image", None, json!({})).await?;
+
+    tx.commit().await?;
     Ok(())
 }
 
@@ -375,15 +817,31 @@ struct UpdatedItem {
     price: Option<i32>,
     description: Option<String>,
     available: Option<bool>,
+    diet: Option<Diet>,
+    /// Replaces the item's allergens.
+    allergens: Option<Vec<Allergen>>,
 }
 
 async fn update_item(
-    auth_restaurant: AuthRestaurant,
+    auth_restaurant: AuthIntegration,
     State(ctx): State<AppContext>,
+    client: ClientInfo,
     Json(req): Json<ItemBody<UpdatedItem>>,
 ) -> Result<()> {
+    auth_restaurant.require(Permission::ManageMenu)?;
+
     let mut tx = ctx.db.begin().await?;
 
+    let details = json!({
+        "image_changed": req.item.image.is_some(),
+        "name": req.item.name,
+        "price": req.item.price,
+        "description": req.item.description,
+        "available": req.item.available,
+        "diet": req.item.diet,
+        "allergens": req.item.allergens,
+    });
+
     if let Some(image) = req.item.image {
         let image = image_from_base64(&image)?;
         let image = image.resize(70, 70, Nearest);
@@ -395,7 +853,7 @@ async fn update_item(
             r#"update item set image = $1 where item_id = $2 AND restaurant_id = $3 "#,
             cursor.into_inner(),
             req.item.id,
-            auth_restaurant.restaurant_id
+            auth_restaurant.restaurant_id()
         )
         .execute(&mut *tx)
         .await?;
@@ -406,21 +864,35 @@ async fn update_item(
             r#"update item set name = $1 where item_id = $2 AND restaurant_id = $3 "#,
             name,
             req.item.id,
-            auth_restaurant.restaurant_id
+            auth_restaurant.restaurant_id()
         )
         .execute(&mut *tx)
         .await?;
     }
 
     if let Some(price) = req.item.price {
-        query!(
-            r#"update item set price = $1 where item_id = $2 AND restaurant_id = $3 "#,
+        let old_price = query_scalar!(
+            r#"
+                update item set price = $1
+                from (select item_id, price from item where item_id = $2 and restaurant_id = $3 for update) old
+                where item.item_id = old.item_id
+                returning old.price
+            "#,
             price,
             req.item.id,
-            auth_restaurant.restaurant_id
+            auth_restaurant.restaurant_id()
         )
-        .execute(&mut *tx)
+        .fetch_optional(&mut *tx)
         .await?;
+
+        if let Some(old_price) = old_price.filter(|&old_price| old_price != price) {
+            AuditEvent::new("item_price_changed", &auth_restaurant)
+                .restaurant(auth_restaurant.restaurant_id())
+                .target(req.item.id)
+                .changes(json!({ "price": change(old_price, price) }))
+                .record(&mut *tx, &client)
+                .await?;
+        }
     }
 
     if let Some(description) = req.item.description {
@@ -428,7 +900,7 @@ async fn update_item(
             r#"update item set description = $1 where item_id = $2 AND restaurant_id = $3 "#,
             description,
             req.item.id,
-            auth_restaurant.restaurant_id
+            auth_restaurant.restaurant_id()
         )
         .execute(&mut *tx)
         .await?;
@@ -439,28 +911,76 @@ async fn update_item(
             r#"update item set available = $1 where item_id = $2 AND restaurant_id = $3 "#,
             available,
             req.item.id,
-            auth_restaurant.restaurant_id
+            auth_restaurant.restaurant_id()
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    if let Some(diet) = req.item.diet {
+        query!(
+            r#"update item set diet = $1 where item_id = $2 AND restaurant_id = $3 "#,
+            diet.as_str(),
+            req.item.id,
+            auth_restaurant.restaurant_id()
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    if let Some(allergens) = req.item.allergens {
+        query!(
+            r#"update item set allergens = $1 where item_id = $2 AND restaurant_id = $3 "#,
+            &allergen_strs(&allergens),
+            req.item.id,
+            auth_restaurant.restaurant_id()
         )
         .execute(&mut *tx)
         .await?;
     }
 
+    record_action(
+        &mut *tx,
+        &auth_restaurant,
+        "update_item",
+        Some(req.item.id),
+        details,
+    )
+    .await?;
+
     tx.commit().await?;
     Ok(())
 }
 
 async fn delete_item(
-    auth_restaurant: AuthRestaurant,
+    auth_restaurant: AuthIntegration,
     Path(id): Path<uuid::Uuid>,
     State(ctx): State<AppContext>,
 ) -> Result<()> {
-    query!(
-        r#"delete from item where item_id = $1 AND restaurant_id = $2"#,
+    auth_restaurant.require(Permission::ManageMenu)?;
+
+    let mut tx = ctx.db.begin().await?;
+
+    let item = query!(
+        r#"delete from item where item_id = $1 AND restaurant_id = $2 returning name"#,
         id,
-        auth_restaurant.restaurant_id
+        auth_restaurant.restaurant_id()
     )
-    .execute(&ctx.db)
+    .fetch_optional(&mut *tx)
     .await?;
+
+    if let Some(item) = item {
+        record_action(
+            &mut *tx,
+            &auth_restaurant,
+            "delete_item",
+            Some(id),
+            json!({ "name": item.name }),
+        )
+        .await?;
+    }
+
+    tx.commit().await?;
     Ok(())
 }
 
@@ -470,21 +990,34 @@ pub struct AddItem {
     description: String,
     price: i32,
     image: Option<String>,
+    diet: Option<Diet>,
+    /// None if it isn't known which allergens the item contains, which isn't the same as none.
+    allergens: Option<Vec<Allergen>>,
 }
 
 async fn add_item(
-    auth_restaurant: AuthRestaurant,
+    auth_restaurant: AuthIntegration,
     State(ctx): State<AppContext>,
     Json(req): Json<ItemBody<AddItem>>,
 ) -> Result<()> {
+    auth_restaurant.require(Permission::ManageMenu)?;
+
+    let allergens = req.item.allergens.as_deref().map(allergen_strs);
+
     let mut tx = ctx.db.begin().await?;
 
     let record = query!(
-        r#"insert into item (restaurant_id, name, description, price) values ($1, $2, $3, $4) returning item_id"#,
-        auth_restaurant.restaurant_id,
+        r#"
+            insert into item (restaurant_id, name, description, price, diet, allergens)
+            values ($1, $2, $3, $4, $5, $6)
+            returning item_id
+        "#,
+        auth_restaurant.restaurant_id(),
         req.item.name,
         req.item.description,
         req.item.price,
+        req.item.diet.map(Diet::as_str),
+        allergens.as_deref(),
     )
     .fetch_one(&mut *tx)
     .await?;
@@ -500,12 +1033,21 @@ async fn add_item(
             r#"update item set image = $1 where item_id = $2 AND restaurant_id = $3 "#,
             cursor.into_inner(),
             record.item_id,
-            auth_restaurant.restaurant_id
+            auth_restaurant.restaurant_id()
         )
         .execute(&mut *tx)
         .await?;
     };
 
+    record_action(
+        &mut *tx,
+        &auth_restaurant,
+        "add_item",
+        Some(record.item_id),
+        json!({ "name": req.item.name, "price": req.item.price }),
+    )
+    .await?;
+
     tx.commit().await?;
     Ok(())
 }
@@ -0,0 +1,411 @@
+use axum::extract::{Path, State};
+use axum::routing::{delete, get, post};
+use axum::{Json, Router};
+use base64::prelude::*;
+use chrono::{DateTime, Utc};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use sqlx::query;
+use uuid::Uuid;
+
+use crate::api::audit::{Actor, AuditEvent};
+use crate::api::auth::{Principal, PrincipalKind};
+use crate::api::util::ClientInfo;
+use crate::api::{AppContext, Error, Result};
+
+/// How long a session may sit unused before its refresh token stops working.
+/// Every refresh slides the expiry forward by this much.
+const REFRESH_TOKEN_LENGTH: chrono::Duration = chrono::Duration::days(30);
+
+pub(crate) fn router() -> Router<AppContext> {
+    Router::new()
+        .route("/api/sessions", get(get_sessions))
+        .route("/api/sessions/refresh", post(refresh_session))
+        .route("/api/sessions/:id", delete(revoke_session))
+}
+
+/// Who a session belongs to.
+pub(in crate::api) enum SessionOwner {
+    User(Uuid),
+    Restaurant(Uuid),
+    Admin(Uuid),
+    Staff(Uuid),
+}
+
+/// The owner columns of a `session` row, exactly one of which is set.
+#[derive(Default)]
+struct OwnerColumns {
+    user_id: Option<Uuid>,
+    restaurant_id: Option<Uuid>,
+    admin_id: Option<Uuid>,
+    staff_id: Option<Uuid>,
+}
+
+impl SessionOwner {
+    fn columns(&self) -> OwnerColumns {
+        match *self {
+            Self::User(user_id) => OwnerColumns {
+                user_id: Some(user_id),
+                ..Default::default()
+            },
+            Self::Restaurant(restaurant_id) => OwnerColumns {
+                restaurant_id: Some(restaurant_id),
+                ..Default::default()
+            },
+            Self::Admin(admin_id) => OwnerColumns {
+                admin_id: Some(admin_id),
+                ..Default::default()
+            },
+            Self::Staff(staff_id) => OwnerColumns {
+                staff_id: Some(staff_id),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+impl From<&Principal> for SessionOwner {
+    fn from(principal: &Principal) -> Self {
+        match principal.kind {
+            PrincipalKind::User => Self::User(principal.id),
+            PrincipalKind::Restaurant => Self::Restaurant(principal.id),
+            PrincipalKind::Admin => Self::Admin(principal.id),
+            PrincipalKind::Staff => Self::Staff(principal.id),
+        }
+    }
+}
+
+pub(in crate::api) struct NewSession {
+    pub session_id: Uuid,
+    pub refresh_token: String,
+}
+
+/// Start a new session for `owner`, returning the refresh token the client should keep.
+///
+/// Every login ends up here, so this is where they are recorded in the audit log.
+pub(in crate::api) async fn create_session(
+    ctx: &AppContext,
+    owner: SessionOwner,
+    client: &ClientInfo,
+) -> Result<NewSession> {
+    let event = match owner {
+        SessionOwner::User(user_id) => AuditEvent::new("login", Actor::User(user_id)).user(user_id),
+        SessionOwner::Restaurant(restaurant_id) => {
+            AuditEvent::new("login", Actor::Restaurant(restaurant_id)).restaurant(restaurant_id)
+        }
+        SessionOwner::Admin(admin_id) => AuditEvent::new("login", Actor::Admin(admin_id)),
+        SessionOwner::Staff(staff_id) => AuditEvent::new("login", Actor::Staff(staff_id))
+            .restaurant(
+                sqlx::query_scalar!(
+                    r#"select restaurant_id from staff where staff_id = $1"#,
+                    staff_id
+                )
+                .fetch_one(&ctx.db)
+                .await?,
+            ),
+    };
+
+    let owner = owner.columns();
+    let secret = generate_secret();
+
+    let mut tx = ctx.db.begin().await?;
+
+    let session_id = sqlx::query_scalar!(
+        r#"
+            insert into session
+                (user_id, restaurant_id, admin_id, staff_id, refresh_token_hash, user_agent, expires_at)
+            values ($1, $2, $3, $4, $5, $6, $7)
+            returning session_id
+        "#,
+        owner.user_id,
+        owner.restaurant_id,
+        owner.admin_id,
+        owner.staff_id,
+        hash_secret(&secret),
+        client.user_agent,
+        Utc::now() + REFRESH_TOKEN_LENGTH
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    event.target(session_id).record(&mut *tx, client).await?;
+
+    tx.commit().await?;
+
+    Ok(NewSession {
+        session_id,
+        refresh_token: format_refresh_token(session_id, &secret),
+    })
+}
+
+/// Reject the request unless `session_id` refers to a session that is neither revoked nor expired.
+pub(in crate::api) async fn ensure_session_active(
+    ctx: &AppContext,
+    session_id: Uuid,
+) -> Result<()> {
+    let active = sqlx::query_scalar!(
+        r#"
+            select exists(
+                select 1 from session
+                where session_id = $1 and revoked_at is null and expires_at > now()
+            ) as "active!"
+        "#,
+        session_id
+    )
+    .fetch_one(&ctx.db)
+    .await?;
+
+    if !active {
+        log::debug!("session {} is revoked or expired", session_id);
+        return Err(Error::Unauthorized);
+    }
+
+    Ok(())
+}
+
+/// Revoke a single session, e.g. on logout.
+pub(in crate::api) async fn end_session(ctx: &AppContext, session_id: Uuid) -> Result<()> {
+    query!(
+        r#"update session set revoked_at = now() where session_id = $1 and revoked_at is null"#,
+        session_id
+    )
+    .execute(&ctx.db)
+    .await?;
+
+    Ok(())
+}
+
+/// Revoke every session of `owner` except `keep`,
+/// e.g. all other devices after a password change, or everything when an account is suspended.
+pub(in crate::api) async fn end_sessions(
+    ctx: &AppContext,
+    owner: SessionOwner,
+    keep: Option<Uuid>,
+) -> Result<()> {
+    let owner = owner.columns();
+
+    query!(
+        r#"
+            update session set revoked_at = now()
+            where user_id is not distinct from $1
+              and restaurant_id is not distinct from $2
+              and admin_id is not distinct from $3
+              and staff_id is not distinct from $4
+              and session_id is distinct from $5
+              and revoked_at is null
+        "#,
+        owner.user_id,
+        owner.restaurant_id,
+        owner.admin_id,
+        owner.staff_id,
+        keep
+    )
+    .execute(&ctx.db)
+    .await?;
+
+    Ok(())
+}
+
+fn generate_secret() -> String {
+    let mut secret = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut secret);
+    BASE64_URL_SAFE_NO_PAD.encode(secret)
+}
+
+fn hash_secret(secret: &str) -> String {
+    hex::encode(Sha256::digest(secret.as_bytes()))
+}
+
+/// Refresh tokens are `<session id>.<secret>`, so that a stale secret still identifies
+/// the session it was stolen from.
+fn format_refresh_token(session_id: Uuid, secret: &str) -> String {
+    format!("{}.{}", session_id.simple(), secret)
+}
+
+fn parse_refresh_token(refresh_token: &str) -> Option<(Uuid, &str)> {
+    let (session_id, secret) = refresh_token.split_once('.')?;
+    Some((Uuid::parse_str(session_id).ok()?, secret))
+}
+
+#[derive(serde::Deserialize)]
+struct RefreshSession {
+    refresh_token: String,
+}
+
+#[derive(serde::Serialize)]
+struct Tokens {
+    token: String,
+    refresh_token: String,
+}
+
+async fn refresh_session(
+    ctx: State<AppContext>,
+    Json(req): Json<RefreshSession>,
+) -> Result<Json<Tokens>> {
+    let (session_id, secret) =
+        parse_refresh_token(&req.refresh_token).ok_or(Error::Unauthorized)?;
+
+    let mut tx = ctx.db.begin().await?;
+
+    let session = query!(
+        r#"
+            select user_id, restaurant_id, admin_id, staff_id, refresh_token_hash, expires_at, revoked_at
+            from session where session_id = $1
+            for update
+        "#,
+        session_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(Error::Unauthorized)?;
+
+    if session.revoked_at.is_some() || session.expires_at < Utc::now() {
+        log::debug!(
+            "refresh attempted on revoked or expired session {}",
+            session_id
+        );
+        return Err(Error::Unauthorized);
+    }
+
+    if session.refresh_token_hash != hash_secret(secret) {
+        // The secret was valid for this session once but has since been rotated,
+        // so either the client or an attacker is replaying an old token.
+        // We can't tell which, so the whole session goes.
+        log::warn!(
+            "refresh token reuse detected, revoking session {}",
+            session_id
+        );
+        query!(
+            r#"update session set revoked_at = now() where session_id = $1"#,
+            session_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        return Err(Error::Unauthorized);
+    }
+
+    let new_secret = generate_secret();
+    query!(
+        r#"
+            update session
+            set refresh_token_hash = $1, last_used_at = now(), expires_at = $2
+            where session_id = $3
+        "#,
+        hash_secret(&new_secret),
+        Utc::now() + REFRESH_TOKEN_LENGTH,
+        session_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    let (kind, id) = match (
+        session.user_id,
+        session.restaurant_id,
+        session.admin_id,
+        session.staff_id,
+    ) {
+        (Some(user_id), None, None, None) => (PrincipalKind::User, user_id),
+        (None, Some(restaurant_id), None, None) => (PrincipalKind::Restaurant, restaurant_id),
+        (None, None, Some(admin_id), None) => (PrincipalKind::Admin, admin_id),
+        (None, None, None, Some(staff_id)) => (PrincipalKind::Staff, staff_id),
+        _ => return Err(anyhow::anyhow!("session {} has no single owner", session_id).into()),
+    };
+
+    let token = Principal {
+        kind,
+        id,
+        session_id,
+    }
+    .to_jwt(&ctx);
+
+    Ok(Json(Tokens {
+        token,
+        refresh_token: format_refresh_token(session_id, &new_secret),
+    }))
+}
+
+#[derive(serde::Serialize)]
+struct Sessions {
+    sessions: Vec<Session>,
+}
+
+#[derive(serde::Serialize)]
+struct Session {
+    id: Uuid,
+    user_agent: Option<String>,
+    created_at: DateTime<Utc>,
+    last_used_at: DateTime<Utc>,
+    /// Whether this is the session the request was made with.
+    current: bool,
+}
+
+async fn get_sessions(principal: Principal, ctx: State<AppContext>) -> Result<Json<Sessions>> {
+    let owner = SessionOwner::from(&principal).columns();
+
+    let sessions = query!(
+        r#"
+            select session_id, user_agent, created_at, last_used_at
+            from session
+            where user_id is not distinct from $1
+              and restaurant_id is not distinct from $2
+              and admin_id is not distinct from $3
+              and staff_id is not distinct from $4
+              and revoked_at is null
+              and expires_at > now()
+            order by last_used_at desc
+        "#,
+        owner.user_id,
+        owner.restaurant_id,
+        owner.admin_id,
+        owner.staff_id
+    )
+    .fetch_all(&ctx.db)
+    .await?
+    .into_iter()
+    .map(|row| Session {
+        id: row.session_id,
+        user_agent: row.user_agent,
+        created_at: row.created_at,
+        last_used_at: row.last_used_at,
+        current: row.session_id == principal.session_id,
+    })
+    .collect();
+
+    Ok(Json(Sessions { sessions }))
+}
+
+async fn revoke_session(
+    principal: Principal,
+    Path(session_id): Path<Uuid>,
+    ctx: State<AppContext>,
+) -> Result<()> {
+    let owner = SessionOwner::from(&principal).columns();
+
+    let result = query!(
+        r#"
+            update session set revoked_at = now()
+            where session_id = $1
+              and user_id is not distinct from $2
+              and restaurant_id is not distinct from $3
+              and admin_id is not distinct from $4
+              and staff_id is not distinct from $5
+              and revoked_at is null
+        "#,
+        session_id,
+        owner.user_id,
+        owner.restaurant_id,
+        owner.admin_id,
+        owner.staff_id
+    )
+    .execute(&ctx.db)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(Error::NotFound);
+    }
+
+    Ok(())
+}
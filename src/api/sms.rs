@@ -0,0 +1,50 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+
+use crate::config::{Config, SmsGatewayKind};
+
+/// A text message to a phone number, such as a login code.
+pub(crate) struct Sms {
+    /// The phone number to deliver to, in E.164 format.
+    pub to: String,
+    pub body: String,
+}
+
+/// Delivers [`Sms`]es, see `sms_gateway` in the config.
+///
+/// To use a real provider, implement this for it and add it to [`SmsGatewayKind`].
+#[async_trait]
+pub(crate) trait SmsGateway: Send + Sync {
+    async fn send(&self, sms: Sms) -> anyhow::Result<()>;
+}
+
+/// The configured gateway, or `None` if text messages can't be sent.
+pub(crate) fn from_config(config: &Config) -> anyhow::Result<Option<Arc<dyn SmsGateway>>> {
+    Ok(match config.sms_gateway {
+        None => None,
+        Some(SmsGatewayKind::Log) => {
+            log::warn!("writing text messages to the log instead of sending them, don't use this in production");
+            Some(Arc::new(LogGateway))
+        }
+    })
+}
+
+/// Writes every message to the log instead of sending it,
+/// so flows like phone login can be exercised without an SMS provider.
+///
+/// Digits are hidden, so that logs never contain a code that could be used to log in.
+struct LogGateway;
+
+#[async_trait]
+impl SmsGateway for LogGateway {
+    async fn send(&self, sms: Sms) -> anyhow::Result<()> {
+        let body: String = sms
+            .body
+            .chars()
+            .map(|c| if c.is_ascii_digit() { '#' } else { c })
+            .collect();
+        log::info!("SMS to {}: {}", sms.to, body);
+        Ok(())
+    }
+}
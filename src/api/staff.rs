@@ -0,0 +1,495 @@
+use axum::extract::{Path, Query, State};
+use axum::routing::{delete, get, patch, post};
+use axum::{Json, Router};
+use chrono::{DateTime, Utc};
+use serde_json::json;
+use sqlx::query;
+use uuid::Uuid;
+
+use crate::api::audit::{AuditEvent, SECRET_CHANGED};
+use crate::api::auth::{mfa_pending_token, AuthRestaurant, Permission, PrincipalKind, StaffRole};
+use crate::api::login_throttle::LoginAttempt;
+use crate::api::mfa::{begin_enrolment, clear_totp, staff_mfa_required, MfaAccount, TotpSetup};
+use crate::api::restaurants::{can_log_in, record_action};
+use crate::api::sessions::{create_session, end_sessions, SessionOwner};
+use crate::api::util::{hash_password, ClientInfo};
+use crate::api::{AppContext, Error, Result, ResultExt};
+
+pub(crate) fn router() -> Router<AppContext> {
+    Router::new()
+        .route("/api/restaurants/staff/login", post(login_staff))
+        .route("/api/restaurants/staff", get(get_staff).post(create_staff))
+        .route(
+            "/api/restaurants/staff/:id",
+            patch(update_staff).delete(deactivate_staff),
+        )
+        .route("/api/restaurants/staff/:id/mfa", delete(reset_staff_mfa))
+        .route("/api/restaurants/staff/actions", get(get_actions))
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub(super) struct StaffBody<T> {
+    staff: T,
+}
+
+#[derive(serde::Serialize)]
+pub(super) struct Staff {
+    id: Uuid,
+    restaurant_id: Uuid,
+    username: String,
+    name: String,
+    role: StaffRole,
+    /// Only returned when a new session is started, see `/api/sessions/refresh` for renewals.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    refresh_token: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct LoginStaff {
+    username: String,
+    password: String,
+}
+
+/// The response to a password login, which may need a second factor to finish, see `/api/restaurants/staff/login/mfa`.
+#[derive(serde::Serialize)]
+#[serde(untagged)]
+enum StaffLogin {
+    Session(StaffBody<Staff>),
+    MfaRequired {
+        mfa_token: String,
+        /// Returned when the staff member has to enrol before they can finish logging in.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        totp_setup: Option<TotpSetup>,
+    },
+}
+
+async fn login_staff(
+    ctx: State<AppContext>,
+    client: ClientInfo,
+    Json(req): Json<StaffBody<LoginStaff>>,
+) -> Result<Json<StaffLogin>> {
+    let attempt = LoginAttempt::start(&ctx, "staff", &req.staff.username, &client).await?;
+
+    let staff = query!(
+        r#"
+            select staff_id, staff.username, staff.password_hash, deactivated_at,
+                   staff.totp_enabled_at, restaurant.status as restaurant_status
+            from staff join restaurant using (restaurant_id)
+            where staff.username = $1
+        "#,
+        req.staff.username
+    )
+    .fetch_optional(&ctx.db)
+    .await?;
+
+    let (staff, new_hash) = attempt
+        .verify(&ctx, staff, req.staff.password, |staff| {
+            &staff.password_hash
+        })
+        .await?;
+
+    if let Some(hash) = new_hash {
+        query!(
+            r#"update staff set password_hash = $1 where staff_id = $2"#,
+            hash,
+            staff.staff_id
+        )
+        .execute(&ctx.db)
+        .await?;
+    }
+
+    if staff.deactivated_at.is_some() || !can_log_in(&staff.restaurant_status) {
+        log::debug!(
+            "staff {} or their restaurant is no longer active",
+            staff.staff_id
+        );
+        return Err(Error::Forbidden);
+    }
+
+    if staff.totp_enabled_at.is_some() {
+        return Ok(Json(StaffLogin::MfaRequired {
+            mfa_token: mfa_pending_token(&ctx, PrincipalKind::Staff, staff.staff_id),
+            totp_setup: None,
+        }));
+    }
+
+    if staff_mfa_required(&ctx.db, staff.staff_id).await? {
+        let account = MfaAccount::Staff(staff.staff_id);
+        let setup = begin_enrolment(&ctx.db, account, &staff.username).await?;
+
+        return Ok(Json(StaffLogin::MfaRequired {
+            mfa_token: mfa_pending_token(&ctx, PrincipalKind::Staff, staff.staff_id),
+            totp_setup: Some(setup),
+        }));
+    }
+
+    Ok(Json(StaffLogin::Session(
+        start_staff_session(&ctx, staff.staff_id, &client).await?,
+    )))
+}
+
+/// Start a session for a member of staff who has proven who they are.
+pub(super) async fn start_staff_session(
+    ctx: &AppContext,
+    staff_id: Uuid,
+    client: &ClientInfo,
+) -> Result<StaffBody<Staff>> {
+    let staff = query!(
+        r#"
+            select restaurant_id, username, name, role
+            from staff where staff_id = $1
+        "#,
+        staff_id
+    )
+    .fetch_one(&ctx.db)
+    .await?;
+
+    let role = staff.role.parse()?;
+    let session = create_session(ctx, SessionOwner::Staff(staff_id), client).await?;
+
+    Ok(StaffBody {
+        staff: Staff {
+            id: staff_id,
+            restaurant_id: staff.restaurant_id,
+            token: Some(
+                AuthRestaurant {
+                    restaurant_id: staff.restaurant_id,
+                    session_id: session.session_id,
+                    staff_id: Some(staff_id),
+                    role,
+                }
+                .to_jwt(ctx),
+            ),
+            refresh_token: Some(session.refresh_token),
+            username: staff.username,
+            name: staff.name,
+            role,
+        },
+    })
+}
+
+#[derive(serde::Serialize)]
+struct StaffList {
+    staff: Vec<Staff>,
+}
+
+async fn get_staff(
+    auth_restaurant: AuthRestaurant,
+    ctx: State<AppContext>,
+) -> Result<Json<StaffList>> {
+    auth_restaurant.require(Permission::ManageStaff)?;
+
+    let staff = query!(
+        r#"
+            select staff_id, username, name, role from staff
+            where restaurant_id = $1 and deactivated_at is null
+            order by created_at
+        "#,
+        auth_restaurant.restaurant_id
+    )
+    .fetch_all(&ctx.db)
+    .await?
+    .into_iter()
+    .map(|row| {
+        Ok(Staff {
+            id: row.staff_id,
+            restaurant_id: auth_restaurant.restaurant_id,
+            username: row.username,
+            name: row.name,
+            role: row.role.parse()?,
+            token: None,
+            refresh_token: None,
+        })
+    })
+    .collect::<Result<_>>()?;
+
+    Ok(Json(StaffList { staff }))
+}
+
+#[derive(serde::Deserialize)]
+struct NewStaff {
+    username: String,
+    name: String,
+    password: String,
+    role: StaffRole,
+}
+
+async fn create_staff(
+    auth_restaurant: AuthRestaurant,
+    ctx: State<AppContext>,
+    Json(req): Json<StaffBody<NewStaff>>,
+) -> Result<Json<StaffBody<Staff>>> {
+    auth_restaurant.require(Permission::ManageStaff)?;
+
+    ctx.password_policy.check("password", &req.staff.password)?;
+    let hash = hash_password(&ctx, req.staff.password).await?;
+
+    let mut tx = ctx.db.begin().await?;
+
+    let staff_id = sqlx::query_scalar!(
+        r#"
+            insert into staff (restaurant_id, username, name, password_hash, role)
+            values ($1, $2, $3, $4, $5)
+            returning staff_id
+        "#,
+        auth_restaurant.restaurant_id,
+        req.staff.username,
+        req.staff.name,
+        hash,
+        req.staff.role.as_str()
+    )
+    .fetch_one(&mut *tx)
+    .await
+    .on_constraint("staff_username_key", |_| {
+        Error::unprocessable_entity([("username", "username taken")])
+    })?;
+
+    record_action(
+        &mut *tx,
+        &auth_restaurant,
+        "create_staff",
+        Some(staff_id),
+        json!({ "username": req.staff.username, "role": req.staff.role }),
+    )
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(Json(StaffBody {
+        staff: Staff {
+            id: staff_id,
+            restaurant_id: auth_restaurant.restaurant_id,
+            username: req.staff.username,
+            name: req.staff.name,
+            role: req.staff.role,
+            token: None,
+            refresh_token: None,
+        },
+    }))
+}
+
+#[derive(serde::Deserialize)]
+struct UpdateStaff {
+    name: Option<String>,
+    role: Option<StaffRole>,
+    password: Option<String>,
+}
+
+async fn update_staff(
+    auth_restaurant: AuthRestaurant,
+    ctx: State<AppContext>,
+    client: ClientInfo,
+    Path(staff_id): Path<Uuid>,
+    Json(req): Json<StaffBody<UpdateStaff>>,
+) -> Result<()> {
+    auth_restaurant.require(Permission::ManageStaff)?;
+
+    let password_hash = match req.staff.password {
+        Some(password) => {
+            ctx.password_policy.check("password", &password)?;
+            Some(hash_password(&ctx, password).await?)
+        }
+        None => None,
+    };
+
+    let mut tx = ctx.db.begin().await?;
+
+    let result = query!(
+        r#"
+            update staff
+            set name = coalesce($1, name),
+                role = coalesce($2, role),
+                password_hash = coalesce($3, password_hash)
+            where staff_id = $4 and restaurant_id = $5 and deactivated_at is null
+        "#,
+        req.staff.name,
+        req.staff.role.map(StaffRole::as_str),
+        password_hash,
+        staff_id,
+        auth_restaurant.restaurant_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(Error::NotFound);
+    }
+
+    record_action(
+        &mut *tx,
+        &auth_restaurant,
+        "update_staff",
+        Some(staff_id),
+        json!({
+            "name": req.staff.name,
+            "role": req.staff.role,
+            "password_changed": password_hash.is_some(),
+        }),
+    )
+    .await?;
+
+    if password_hash.is_some() {
+        AuditEvent::new("password_changed", &auth_restaurant)
+            .restaurant(auth_restaurant.restaurant_id)
+            .target(staff_id)
+            .changes(json!({ "password": SECRET_CHANGED }))
+            .record(&mut *tx, &client)
+            .await?;
+    }
+
+    tx.commit().await?;
+
+    if password_hash.is_some() {
+        end_sessions(&ctx, SessionOwner::Staff(staff_id), None).await?;
+    }
+
+    Ok(())
+}
+
+async fn deactivate_staff(
+    auth_restaurant: AuthRestaurant,
+    ctx: State<AppContext>,
+    Path(staff_id): Path<Uuid>,
+) -> Result<()> {
+    auth_restaurant.require(Permission::ManageStaff)?;
+
+    let mut tx = ctx.db.begin().await?;
+
+    let result = query!(
+        r#"
+            update staff set deactivated_at = now()
+            where staff_id = $1 and restaurant_id = $2 and deactivated_at is null
+        "#,
+        staff_id,
+        auth_restaurant.restaurant_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(Error::NotFound);
+    }
+
+    record_action(
+        &mut *tx,
+        &auth_restaurant,
+        "deactivate_staff",
+        Some(staff_id),
+        json!({}),
+    )
+    .await?;
+
+    tx.commit().await?;
+
+    end_sessions(&ctx, SessionOwner::Staff(staff_id), None).await
+}
+
+/// Turn off a staff member's second factor, for when they have lost both their authenticator and
+/// recovery codes. They have to enrol again on their next login if their role requires it.
+async fn reset_staff_mfa(
+    auth_restaurant: AuthRestaurant,
+    ctx: State<AppContext>,
+    Path(staff_id): Path<Uuid>,
+) -> Result<()> {
+    auth_restaurant.require(Permission::ManageStaff)?;
+
+    let mut tx = ctx.db.begin().await?;
+
+    query!(
+        r#"
+            select staff_id from staff
+            where staff_id = $1 and restaurant_id = $2 and deactivated_at is null
+            for update
+        "#,
+        staff_id,
+        auth_restaurant.restaurant_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    clear_totp(&mut tx, MfaAccount::Staff(staff_id)).await?;
+
+    record_action(
+        &mut *tx,
+        &auth_restaurant,
+        "reset_staff_mfa",
+        Some(staff_id),
+        json!({}),
+    )
+    .await?;
+
+    tx.commit().await?;
+
+    end_sessions(&ctx, SessionOwner::Staff(staff_id), None).await
+}
+
+#[derive(serde::Deserialize)]
+struct ActionsQuery {
+    staff_id: Option<Uuid>,
+    limit: Option<i64>,
+}
+
+#[derive(serde::Serialize)]
+struct Actions {
+    actions: Vec<Action>,
+}
+
+#[derive(serde::Serialize)]
+struct Action {
+    id: Uuid,
+    /// `None` if the restaurant's own account or an API key did it.
+    staff_id: Option<Uuid>,
+    staff_name: Option<String>,
+    /// Set if it was done with an API key.
+    api_key_id: Option<Uuid>,
+    api_key_name: Option<String>,
+    action: String,
+    target_id: Option<Uuid>,
+    details: serde_json::Value,
+    created_at: DateTime<Utc>,
+}
+
+async fn get_actions(
+    auth_restaurant: AuthRestaurant,
+    ctx: State<AppContext>,
+    Query(req): Query<ActionsQuery>,
+) -> Result<Json<Actions>> {
+    auth_restaurant.require(Permission::ManageStaff)?;
+
+    let actions = query!(
+        r#"
+            select a.restaurant_action_id, a.staff_id, s.name as "staff_name?",
+                   a.api_key_id, k.name as "api_key_name?",
+                   a.action, a.target_id, a.details, a.created_at
+            from restaurant_action a
+            left join staff s using (staff_id)
+            left join api_key k using (api_key_id)
+            where a.restaurant_id = $1 and ($2::uuid is null or a.staff_id = $2)
+            order by a.created_at desc
+            limit $3
+        "#,
+        auth_restaurant.restaurant_id,
+        req.staff_id,
+        req.limit.unwrap_or(100).clamp(1, 1000)
+    )
+    .fetch_all(&ctx.db)
+    .await?
+    .into_iter()
+    .map(|row| Action {
+        id: row.restaurant_action_id,
+        staff_id: row.staff_id,
+        staff_name: row.staff_name,
+        api_key_id: row.api_key_id,
+        api_key_name: row.api_key_name,
+        action: row.action,
+        target_id: row.target_id,
+        details: row.details,
+        created_at: row.created_at,
+    })
+    .collect();
+
+    Ok(Json(Actions { actions }))
+}
@@ -10,7 +10,7 @@ use chrono_tz::Asia::Kolkata;
 use sqlx::types::chrono;
 use sqlx::{Pool, Postgres};
 
-use crate::api::auth::AuthRestaurant;
+use crate::api::auth::{AuthIntegration, Permission};
 use crate::api::{AppContext, Result};
 
 pub(crate) fn router() -> Router<AppContext> {
@@ -35,20 +35,23 @@ struct RestaurantStats {
 }
 
 async fn get_restaurant_stats(
-    auth_restaurant: AuthRestaurant,
+    auth_restaurant: AuthIntegration,
     ctx: State<AppContext>,
 ) -> Result<Json<RestaurantStats>> {
-    let total_orders = total_orders_restaurant(&ctx.db, auth_restaurant.restaurant_id).await?;
-    let total_revenue = total_revenue_restaurant(&ctx.db, auth_restaurant.restaurant_id).await?;
-    let item_frequency = item_frequency_restaurant(&ctx.db, auth_restaurant.restaurant_id).await?;
+    auth_restaurant.require(Permission::ViewStats)?;
+
+    let total_orders = total_orders_restaurant(&ctx.db, auth_restaurant.restaurant_id()).await?;
+    let total_revenue = total_revenue_restaurant(&ctx.db, auth_restaurant.restaurant_id()).await?;
+    let item_frequency =
+        item_frequency_restaurant(&ctx.db, auth_restaurant.restaurant_id()).await?;
     let orders_per_hour_by_day =
-        orders_per_hour_by_day_restaurant(&ctx.db, auth_restaurant.restaurant_id).await?;
+        orders_per_hour_by_day_restaurant(&ctx.db, auth_restaurant.restaurant_id()).await?;
     let top_3_breakfast_items =
-        top_items_by_meal_period(&ctx.db, auth_restaurant.restaurant_id, "breakfast").await?;
+        top_items_by_meal_period(&ctx.db, auth_restaurant.restaurant_id(), "breakfast").await?;
     let top_3_lunch_items =
-        top_items_by_meal_period(&ctx.db, auth_restaurant.restaurant_id, "lunch").await?;
+        top_items_by_meal_period(&ctx.db, auth_restaurant.restaurant_id(), "lunch").await?;
     let top_3_dinner_items =
-        top_items_by_meal_period(&ctx.db, auth_restaurant.restaurant_id, "dinner").await?;
+        top_items_by_meal_period(&ctx.db, auth_restaurant.restaurant_id(), "dinner").await?;
 
     Ok(Json(RestaurantStats {
         total_orders,
@@ -182,7 +185,7 @@ async fn orders_per_hour_by_day_restaurant(
 }
 
 #[derive(serde::Serialize)]
-struct UserStats {
+pub(super) struct UserStats {
     total_orders: i64,
     total_spent: i64,
     orders_per_hour_by_day: [[i64; 24]; 7],
@@ -193,17 +196,21 @@ async fn get_user_stats(
     auth_user: crate::api::auth::AuthUser,
     ctx: State<AppContext>,
 ) -> Result<Json<UserStats>> {
-    let total_orders = total_orders_user(&ctx.db, auth_user.user_id).await?;
-    let total_spent = total_spent_user(&ctx.db, auth_user.user_id).await?;
-    let orders_per_hour_by_day = orders_per_hour_by_day_user(&ctx.db, auth_user.user_id).await?;
-    let orders_per_day = orders_per_day_user(&ctx.db, auth_user.user_id).await?;
+    Ok(Json(user_stats(&ctx.db, auth_user.user_id).await?))
+}
 
-    Ok(Json(UserStats {
+pub(super) async fn user_stats(db: &Pool<Postgres>, user_id: uuid::Uuid) -> Result<UserStats> {
+    let total_orders = total_orders_user(db, user_id).await?;
+    let total_spent = total_spent_user(db, user_id).await?;
+    let orders_per_hour_by_day = orders_per_hour_by_day_user(db, user_id).await?;
+    let orders_per_day = orders_per_day_user(db, user_id).await?;
+
+    Ok(UserStats {
         total_orders,
         total_spent,
         orders_per_hour_by_day,
         orders_per_day,
-    }))
+    })
 }
 
 async fn total_orders_user(db: &Pool<Postgres>, user_id: uuid::Uuid) -> Result<i64> {
@@ -308,23 +315,25 @@ struct DateRange {
     end: DateTime<Utc>,
 }
 async fn get_custom_restaurants_stats(
-    auth_restaurant: AuthRestaurant,
+    auth_restaurant: AuthIntegration,
     ctx: State<AppContext>,
     Json(req): Json<DateRange>,
 ) -> Result<Json<RestaurantStatsCustom>> {
+    auth_restaurant.require(Permission::ViewStats)?;
+
     let DateRange { start, end } = req;
 
     let total_orders =
-        total_orders_custom(&ctx.db, auth_restaurant.restaurant_id, start, end).await?;
+        total_orders_custom(&ctx.db, auth_restaurant.restaurant_id(), start, end).await?;
     let total_revenue =
-        total_revenue_custom(&ctx.db, auth_restaurant.restaurant_id, start, end).await?;
+        total_revenue_custom(&ctx.db, auth_restaurant.restaurant_id(), start, end).await?;
     let item_frequency =
-        item_frequency_custom(&ctx.db, auth_restaurant.restaurant_id, start, end).await?;
+        item_frequency_custom(&ctx.db, auth_restaurant.restaurant_id(), start, end).await?;
     let orders_by_day =
-        orders_by_day_custom(&ctx.db, auth_restaurant.restaurant_id, start, end).await?;
+        orders_by_day_custom(&ctx.db, auth_restaurant.restaurant_id(), start, end).await?;
     let top_3_breakfast_items = top_items_by_meal_period_custom(
         &ctx.db,
-        auth_restaurant.restaurant_id,
+        auth_restaurant.restaurant_id(),
         "breakfast",
         start,
         end,
@@ -332,7 +341,7 @@ async fn get_custom_restaurants_stats(
     .await?;
     let top_3_lunch_items = top_items_by_meal_period_custom(
         &ctx.db,
-        auth_restaurant.restaurant_id,
+        auth_restaurant.restaurant_id(),
         "lunch",
         start,
         end,
@@ -340,7 +349,7 @@ async fn get_custom_restaurants_stats(
     .await?;
     let top_3_dinner_items = top_items_by_meal_period_custom(
         &ctx.db,
-        auth_restaurant.restaurant_id,
+        auth_restaurant.restaurant_id(),
         "dinner",
         start,
         end,
@@ -2,18 +2,34 @@ use std::io::Cursor;
 
 use anyhow::Context;
 use axum::extract::{Path, State};
-use axum::http::header::CONTENT_TYPE;
+use axum::http::header::{CONTENT_DISPOSITION, CONTENT_TYPE};
 use axum::http::StatusCode;
 use axum::response::{AppendHeaders, IntoResponse, Response};
 use axum::routing::{get, post, put};
 use axum::{Json, Router};
+use base64::prelude::*;
+use chrono::{DateTime, Utc};
 use image::imageops::FilterType::Nearest;
 use image::ImageFormat;
 use serde::Deserialize;
+use serde_json::json;
 use sqlx::query;
 
+use crate::api::audit::{change, AuditEvent, SECRET_CHANGED};
 use crate::api::auth::AuthUser;
-use crate::api::util::{hash_password, image_from_base64, verify_password};
+use crate::api::diet::{dietary_preferences, DietaryPreferences};
+use crate::api::email_change::{self, request_change};
+use crate::api::favourites::{favourites, Favourites};
+use crate::api::login_throttle::LoginAttempt;
+use crate::api::loyalty::{self, LoyaltyBalance};
+use crate::api::notifications::{user_notifications, NotificationUser};
+use crate::api::orders::{all_orders_user, Order};
+use crate::api::profile::{profile, update_profile, Profile, UpdateProfile};
+use crate::api::referrals::{create_referral, find_referrer, referrals, Referrals};
+use crate::api::sessions::{create_session, end_session, end_sessions, SessionOwner};
+use crate::api::stats::{user_stats, UserStats};
+use crate::api::util::{hash_password, image_from_base64, verify_password, ClientInfo};
+use crate::api::wallet::{statement, wallet_balance, Statement};
 use crate::api::Result;
 use crate::api::{AppContext, Error, ResultExt};
 
@@ -21,14 +37,20 @@ pub(crate) fn router() -> Router<AppContext> {
     Router::new()
         .route("/api/users", post(create_user))
         .route("/api/users/login", post(login_user))
-        .route("/api/users", get(get_current_user).patch(update_user))
+        .route("/api/users/logout", post(logout_user))
+        .route(
+            "/api/users",
+            get(get_current_user).patch(update_user).delete(delete_user),
+        )
+        .route("/api/users/export", get(export_user))
         .route("/api/users/upload_image", post(upload_image))
         .route("/api/users/image/:id", get(get_image))
         .route("/api/users/expo_push_token", put(update_push_token))
+        .route("/api/users/push_devices", get(get_push_devices))
 }
 
 #[derive(serde::Serialize, serde::Deserialize)]
-struct UserBody<T> {
+pub(super) struct UserBody<T> {
     user: T,
 }
 
@@ -36,38 +58,79 @@ struct UserBody<T> {
 struct NewUser {
     username: String,
     password: String,
+    /// Where password reset codes are sent.
+    email: Option<String>,
+    /// Another user's code, see `/api/users/referrals`.
+    referral_code: Option<String>,
 }
 
 #[derive(serde::Serialize, serde::Deserialize)]
-struct User {
+pub(super) struct User {
     id: uuid::Uuid,
-    token: String,
+    /// Only returned when a new session is started, see `/api/sessions/refresh` for renewals.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    refresh_token: Option<String>,
     username: String,
-    expo_push_token: Option<String>,
+    email: Option<String>,
+    /// Verified through `/api/users/phone/verify`.
+    phone_number: Option<String>,
+    profile: Profile,
 }
 
 async fn create_user(
     ctx: State<AppContext>,
+    client: ClientInfo,
     Json(req): Json<UserBody<NewUser>>,
 ) -> Result<Json<UserBody<User>>> {
-    let hash = hash_password(req.user.password).await?;
+    ctx.password_policy.check("password", &req.user.password)?;
+    let hash = hash_password(&ctx, req.user.password).await?;
+    let mut tx = ctx.db.begin().await?;
+
+    let referrer_id = match req.user.referral_code {
+        Some(ref code) => Some(find_referrer(&mut tx, code).await?),
+        None => None,
+    };
+
     let user_id = sqlx::query_scalar!(
-        r#"insert into "user" (username, password_hash) values ($1, $2) returning user_id"#,
+        r#"insert into "user" (username, password_hash, email) values ($1, $2, $3) returning user_id"#,
         req.user.username,
-        hash
+        hash,
+        req.user.email
     )
-    .fetch_one(&ctx.db)
+    .fetch_one(&mut *tx)
     .await
     .on_constraint("user_username_key", |_| {
         Error::unprocessable_entity([("username", "username taken")])
+    })
+    .on_constraint("user_email_key", |_| {
+        Error::unprocessable_entity([("email", "email taken")])
     })?;
 
+    if let Some(referrer_id) = referrer_id {
+        create_referral(&mut tx, referrer_id, user_id).await?;
+    }
+
+    tx.commit().await?;
+
+    let session = create_session(&ctx, SessionOwner::User(user_id), &client).await?;
+
     Ok(Json(UserBody {
         user: User {
             id: user_id,
-            token: AuthUser { user_id }.to_jwt(&ctx),
+            token: Some(
+                AuthUser {
+                    user_id,
+                    session_id: session.session_id,
+                }
+                .to_jwt(&ctx),
+            ),
+            refresh_token: Some(session.refresh_token),
             username: req.user.username,
-            expo_push_token: None,
+            email: req.user.email,
+            phone_number: None,
+            profile: profile(&ctx.db, user_id).await?,
         },
     }))
 }
@@ -80,32 +143,75 @@ struct LoginUser {
 
 async fn login_user(
     ctx: State<AppContext>,
+    client: ClientInfo,
     Json(req): Json<UserBody<LoginUser>>,
 ) -> Result<Json<UserBody<User>>> {
+    let attempt = LoginAttempt::start(&ctx, "user", &req.user.username, &client).await?;
+
     let user = sqlx::query!(
         r#"
-            select user_id, username, password_hash, expo_push_token
-            from "user" where username = $1
+            select user_id, password_hash, suspended_at from "user"
+            where username = $1 and deleted_at is null
         "#,
         req.user.username,
     )
     .fetch_optional(&ctx.db)
-    .await?
-    .ok_or_else(|| Error::unprocessable_entity([("username", "does not exist")]))?;
+    .await?;
 
-    verify_password(req.user.password, user.password_hash).await?;
+    let (user, new_hash) = attempt
+        .verify(&ctx, user, req.user.password, |user| &user.password_hash)
+        .await?;
 
-    Ok(Json(UserBody {
+    if let Some(hash) = new_hash {
+        query!(
+            r#"update "user" set password_hash = $1 where user_id = $2"#,
+            hash,
+            user.user_id
+        )
+        .execute(&ctx.db)
+        .await?;
+    }
+
+    if user.suspended_at.is_some() {
+        log::debug!("user {} is suspended", user.user_id);
+        return Err(Error::Forbidden);
+    }
+
+    Ok(Json(start_user_session(&ctx, user.user_id, &client).await?))
+}
+
+/// Start a session for a user that has proven who they are.
+pub(super) async fn start_user_session(
+    ctx: &AppContext,
+    user_id: uuid::Uuid,
+    client: &ClientInfo,
+) -> Result<UserBody<User>> {
+    let user = sqlx::query!(
+        r#"select username, email, phone_number from "user" where user_id = $1"#,
+        user_id
+    )
+    .fetch_one(&ctx.db)
+    .await?;
+
+    let session = create_session(ctx, SessionOwner::User(user_id), client).await?;
+
+    Ok(UserBody {
         user: User {
-            id: user.user_id,
-            token: AuthUser {
-                user_id: user.user_id,
-            }
-            .to_jwt(&ctx),
+            id: user_id,
+            token: Some(
+                AuthUser {
+                    user_id,
+                    session_id: session.session_id,
+                }
+                .to_jwt(ctx),
+            ),
+            refresh_token: Some(session.refresh_token),
             username: user.username,
-            expo_push_token: user.expo_push_token,
+            email: user.email,
+            phone_number: user.phone_number,
+            profile: profile(&ctx.db, user_id).await?,
         },
-    }))
+    })
 }
 
 async fn get_current_user(
@@ -113,7 +219,7 @@ async fn get_current_user(
     ctx: State<AppContext>,
 ) -> Result<Json<UserBody<User>>> {
     let user = sqlx::query!(
-        r#"select username, expo_push_token from "user" where user_id = $1"#,
+        r#"select username, email, phone_number from "user" where user_id = $1"#,
         auth_user.user_id
     )
     .fetch_one(&ctx.db)
@@ -122,9 +228,12 @@ async fn get_current_user(
     Ok(Json(UserBody {
         user: User {
             id: auth_user.user_id,
-            token: auth_user.to_jwt(&ctx),
+            token: None,
+            refresh_token: None,
             username: user.username,
-            expo_push_token: user.expo_push_token,
+            email: user.email,
+            phone_number: user.phone_number,
+            profile: profile(&ctx.db, auth_user.user_id).await?,
         },
     }))
 }
@@ -132,7 +241,12 @@ async fn get_current_user(
 #[derive(serde::Deserialize)]
 struct UpdateUser {
     username: Option<String>,
+    /// Only replaces the current email once confirmed with the code sent to it, see `/api/users/email/confirm`.
+    email: Option<String>,
+    /// The current password, required to change the email.
+    password: Option<String>,
     update_pass: Option<UpdatePass>,
+    profile: Option<UpdateProfile>,
 }
 
 #[derive(serde::Deserialize)]
@@ -144,19 +258,21 @@ struct UpdatePass {
 async fn update_user(
     auth_user: AuthUser,
     ctx: State<AppContext>,
+    client: ClientInfo,
     Json(req): Json<UserBody<UpdateUser>>,
 ) -> Result<Json<UserBody<User>>> {
+    let password_changed = req.user.update_pass.is_some();
     let mut tx = ctx.db.begin().await?;
 
     let user = sqlx::query!(
-        r#"select username, password_hash, expo_push_token from "user" where user_id = $1"#,
+        r#"select username, email, phone_number, password_hash from "user" where user_id = $1"#,
         auth_user.user_id
     )
     .fetch_one(&mut *tx)
     .await?;
 
     if let Some(update_pass) = req.user.update_pass {
-        verify_password(update_pass.old_password, user.password_hash)
+        verify_password(&ctx, update_pass.old_password, user.password_hash.clone())
             .await
             .map_err(|e| match e {
                 Error::Unauthorized => {
@@ -164,7 +280,9 @@ async fn update_user(
                 }
                 _ => e,
             })?;
-        let new_hash = hash_password(update_pass.new_password).await?;
+        ctx.password_policy
+            .check("new_password", &update_pass.new_password)?;
+        let new_hash = hash_password(&ctx, update_pass.new_password).await?;
 
         sqlx::query!(
             r#"update "user" set password_hash = $1 where user_id = $2"#,
@@ -173,6 +291,12 @@ async fn update_user(
         )
         .execute(&mut *tx)
         .await?;
+
+        AuditEvent::new("password_changed", &auth_user)
+            .user(auth_user.user_id)
+            .changes(json!({ "password": SECRET_CHANGED }))
+            .record(&mut *tx, &client)
+            .await?;
     }
 
     if let Some(ref username) = req.user.username {
@@ -186,20 +310,278 @@ async fn update_user(
         .on_constraint("user_username_key", |_| {
             Error::unprocessable_entity([("username", "username taken")])
         })?;
+
+        if *username != user.username {
+            AuditEvent::new("username_changed", &auth_user)
+                .user(auth_user.user_id)
+                .changes(json!({ "username": change(&user.username, username) }))
+                .record(&mut *tx, &client)
+                .await?;
+        }
     }
 
+    let mut email_confirmation = None;
+
+    if let Some(ref email) = req.user.email {
+        // A stolen session alone mustn't be enough to take over the account through a password reset.
+        let password = req.user.password.clone().ok_or_else(|| {
+            Error::unprocessable_entity([("password", "is required to change the email")])
+        })?;
+        verify_password(&ctx, password, user.password_hash)
+            .await
+            .map_err(|e| match e {
+                Error::Unauthorized => {
+                    Error::unprocessable_entity([("password", "password is incorrect")])
+                }
+                _ => e,
+            })?;
+
+        email_confirmation = Some(
+            request_change(
+                &ctx,
+                &mut tx,
+                email_change::Account::User(auth_user.user_id),
+                email,
+            )
+            .await?,
+        );
+    }
+
+    if let Some(ref update) = req.user.profile {
+        update_profile(&mut tx, auth_user.user_id, update).await?;
+    }
+
+    let profile = profile(&mut *tx, auth_user.user_id).await?;
+
     tx.commit().await?;
 
+    if let Some(message) = email_confirmation {
+        email_change::send(&ctx, message);
+    }
+
+    // Anyone holding the old password may already have a session; log them out.
+    if password_changed {
+        end_sessions(
+            &ctx,
+            SessionOwner::User(auth_user.user_id),
+            Some(auth_user.session_id),
+        )
+        .await?;
+    }
+
     Ok(Json(UserBody {
         user: User {
             id: auth_user.user_id,
-            token: auth_user.to_jwt(&ctx),
+            token: None,
+            refresh_token: None,
             username: req.user.username.unwrap_or(user.username),
-            expo_push_token: user.expo_push_token,
+            email: user.email,
+            phone_number: user.phone_number,
+            profile,
         },
     }))
 }
 
+/// Delete the user's account.
+///
+/// Their orders are kept for the restaurants' accounts, so instead of removing the user we strip
+/// everything that identifies them and free their username, email and phone number for others.
+async fn delete_user(
+    auth_user: AuthUser,
+    ctx: State<AppContext>,
+    client: ClientInfo,
+) -> Result<()> {
+    let mut tx = ctx.db.begin().await?;
+
+    // Deleting the account would leave the money stranded in its wallet.
+    if wallet_balance(&mut *tx, auth_user.user_id).await? > 0 {
+        return Err(Error::unprocessable_entity([(
+            "wallet",
+            "must be empty before deleting the account",
+        )]));
+    }
+
+    // The password hash can't be null, and an empty one matches no password.
+    query!(
+        r#"
+            update "user"
+            set username = 'deleted-' || user_id,
+                password_hash = '',
+                email = null,
+                email_verified_at = null,
+                phone_number = null,
+                phone_verified_at = null,
+                image = null,
+                display_name = null,
+                hostel = null,
+                room = null,
+                roll_number = null,
+                diet = null,
+                avoid_allergens = '{}',
+                deleted_at = now()
+            where user_id = $1
+        "#,
+        auth_user.user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    query!(
+        r#"delete from user_identity where user_id = $1"#,
+        auth_user.user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    query!(
+        r#"delete from password_reset where user_id = $1"#,
+        auth_user.user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    query!(
+        r#"delete from email_change where user_id = $1"#,
+        auth_user.user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    query!(
+        r#"delete from favourite_restaurant where user_id = $1"#,
+        auth_user.user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    query!(
+        r#"delete from favourite_item where user_id = $1"#,
+        auth_user.user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    query!(
+        r#"delete from loyalty_balance where user_id = $1"#,
+        auth_user.user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    query!(
+        r#"delete from push_device where user_id = $1"#,
+        auth_user.user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    query!(
+        r#"delete from phone_otp where user_id = $1"#,
+        auth_user.user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    query!(
+        r#"delete from notification where recipient_id = $1"#,
+        auth_user.user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    AuditEvent::new("account_deleted", &auth_user)
+        .user(auth_user.user_id)
+        .record(&mut *tx, &client)
+        .await?;
+
+    tx.commit().await?;
+
+    end_sessions(&ctx, SessionOwner::User(auth_user.user_id), None).await
+}
+
+#[derive(serde::Serialize)]
+struct UserExport {
+    exported_at: DateTime<Utc>,
+    user: ExportedUser,
+    orders: Vec<Order>,
+    notifications: Vec<NotificationUser>,
+    favourites: Favourites,
+    loyalty: Vec<LoyaltyBalance>,
+    referrals: Referrals,
+    wallet: Statement,
+    stats: UserStats,
+}
+
+#[derive(serde::Serialize)]
+struct ExportedUser {
+    id: uuid::Uuid,
+    username: String,
+    email: Option<String>,
+    phone_number: Option<String>,
+    push_devices: Vec<PushDevice>,
+    profile: Profile,
+    dietary_preferences: DietaryPreferences,
+    /// Base64 encoded JPEG.
+    image: Option<String>,
+    created_at: DateTime<Utc>,
+}
+
+/// Everything we keep about the user, as a JSON file to download.
+async fn export_user(auth_user: AuthUser, ctx: State<AppContext>) -> Result<Response> {
+    let user = query!(
+        r#"
+            select username, email, phone_number, image, created_at
+            from "user" where user_id = $1
+        "#,
+        auth_user.user_id
+    )
+    .fetch_one(&ctx.db)
+    .await?;
+
+    let export = UserExport {
+        exported_at: Utc::now(),
+        user: ExportedUser {
+            id: auth_user.user_id,
+            username: user.username,
+            email: user.email,
+            phone_number: user.phone_number,
+            push_devices: push_devices(&ctx, auth_user.user_id).await?,
+            profile: profile(&ctx.db, auth_user.user_id).await?,
+            dietary_preferences: dietary_preferences(&ctx.db, auth_user.user_id).await?,
+            image: user.image.map(|image| BASE64_STANDARD.encode(image)),
+            created_at: user.created_at,
+        },
+        orders: all_orders_user(auth_user.user_id, &ctx).await?,
+        notifications: user_notifications(&ctx, auth_user.user_id).await?,
+        favourites: favourites(&ctx, auth_user.user_id).await?,
+        loyalty: loyalty::balances(&ctx, auth_user.user_id).await?,
+        referrals: referrals(&ctx, auth_user.user_id).await?,
+        wallet: statement(&ctx, auth_user.user_id, i64::MAX, 0).await?,
+        stats: user_stats(&ctx.db, auth_user.user_id).await?,
+    };
+
+    Ok((
+        AppendHeaders([(
+            CONTENT_DISPOSITION,
+            "attachment; filename=\"kg-export.json\"",
+        )]),
+        Json(export),
+    )
+        .into_response())
+}
+
+async fn logout_user(auth_user: AuthUser, ctx: State<AppContext>) -> Result<()> {
+    // Whoever uses the device next shouldn't get this user's notifications.
+    query!(
+        r#"delete from push_device where session_id = $1"#,
+        auth_user.session_id
+    )
+    .execute(&ctx.db)
+    .await?;
+
+    end_session(&ctx, auth_user.session_id).await
+}
+
 pub(super) async fn get_username(user_id: uuid::Uuid, ctx: &State<AppContext>) -> Result<String> {
     let username =
         sqlx::query_scalar!(r#"select username from "user" where user_id = $1"#, user_id)
@@ -250,19 +632,102 @@ async fn get_image(Path(id): Path<uuid::Uuid>, State(ctx): State<AppContext>) ->
 #[derive(Deserialize)]
 struct ExpoPushToken {
     expo_push_token: String,
+    /// e.g. `ios` or `android`.
+    platform: Option<String>,
+    app_version: Option<String>,
 }
 
+/// Register the device the user is logged in on for push notifications, or mark it as seen again.
+///
+/// Apps should call this on every start, since the device is unregistered when its session ends.
 async fn update_push_token(
     auth_user: AuthUser,
     State(ctx): State<AppContext>,
+    client: ClientInfo,
     Json(req): Json<ExpoPushToken>,
 ) -> Result<()> {
+    let mut tx = ctx.db.begin().await?;
+
+    let previous_user_id = sqlx::query_scalar!(
+        r#"select user_id from push_device where expo_push_token = $1 for update"#,
+        req.expo_push_token
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    // A device someone else was logged in on now belongs to this user.
     query!(
-        r#"update "user" set expo_push_token = $1 where user_id = $2"#,
+        r#"
+            insert into push_device (user_id, session_id, expo_push_token, platform, app_version)
+            values ($1, $2, $3, $4, $5)
+            on conflict (expo_push_token) do update
+            set user_id = excluded.user_id,
+                session_id = excluded.session_id,
+                platform = excluded.platform,
+                app_version = excluded.app_version,
+                last_seen_at = now()
+        "#,
+        auth_user.user_id,
+        auth_user.session_id,
         req.expo_push_token,
-        auth_user.user_id
+        req.platform,
+        req.app_version
     )
-    .execute(&ctx.db)
+    .execute(&mut *tx)
     .await?;
+
+    if previous_user_id != Some(auth_user.user_id) {
+        AuditEvent::new("push_device_registered", &auth_user)
+            .user(auth_user.user_id)
+            .changes(json!({
+                "expo_push_token": SECRET_CHANGED,
+                "platform": change(None::<String>, &req.platform),
+            }))
+            .record(&mut *tx, &client)
+            .await?;
+    }
+
+    tx.commit().await?;
     Ok(())
 }
+
+#[derive(serde::Serialize)]
+struct PushDevices {
+    push_devices: Vec<PushDevice>,
+}
+
+#[derive(serde::Serialize)]
+struct PushDevice {
+    id: uuid::Uuid,
+    expo_push_token: String,
+    platform: Option<String>,
+    app_version: Option<String>,
+    last_seen_at: DateTime<Utc>,
+    created_at: DateTime<Utc>,
+}
+
+async fn get_push_devices(
+    auth_user: AuthUser,
+    ctx: State<AppContext>,
+) -> Result<Json<PushDevices>> {
+    Ok(Json(PushDevices {
+        push_devices: push_devices(&ctx, auth_user.user_id).await?,
+    }))
+}
+
+async fn push_devices(ctx: &AppContext, user_id: uuid::Uuid) -> Result<Vec<PushDevice>> {
+    let push_devices = sqlx::query_as!(
+        PushDevice,
+        r#"
+            select push_device_id as id, expo_push_token, platform, app_version, last_seen_at, created_at
+            from push_device
+            where user_id = $1
+            order by last_seen_at desc
+        "#,
+        user_id
+    )
+    .fetch_all(&ctx.db)
+    .await?;
+
+    Ok(push_devices)
+}
@@ -1,31 +1,103 @@
+use std::collections::HashSet;
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::OnceLock;
+
 use anyhow::Context;
-use argon2::password_hash::SaltString;
-use argon2::{Argon2, PasswordHash};
+use argon2::password_hash::{PasswordHasher, SaltString};
+use argon2::{Algorithm, Argon2, KeyId, Params, ParamsBuilder, PasswordHash, Version};
+use async_trait::async_trait;
+use axum::extract::{ConnectInfo, FromRequestParts};
+use axum::http::header::USER_AGENT;
+use axum::http::request::Parts;
 use base64::prelude::*;
 use image::DynamicImage;
 
+use crate::api::AppContext;
 use crate::api::Error;
 use crate::api::Result;
+use crate::config::Config;
+
+/// Stored in the parameters of hashes made with `password_pepper`, so we know to use it when verifying them.
+const PEPPER_KEY_ID: &[u8] = b"pepper";
+
+/// The Argon2 instance new hashes are made with, see the `argon2_*` and `password_pepper` options in the config.
+fn hasher(config: &Config) -> anyhow::Result<Argon2<'_>> {
+    let mut params = ParamsBuilder::new();
+    params
+        .m_cost(config.argon2_memory_kib)
+        .t_cost(config.argon2_iterations)
+        .p_cost(config.argon2_parallelism);
+
+    if config.password_pepper.is_some() {
+        params.keyid(KeyId::new(PEPPER_KEY_ID).map_err(|e| anyhow::anyhow!("{}", e))?);
+    }
+
+    let params = params
+        .build()
+        .map_err(|e| anyhow::anyhow!("invalid Argon2 parameters: {}", e))?;
+
+    Ok(match config.password_pepper {
+        Some(ref pepper) => Argon2::new_with_secret(
+            pepper.as_bytes(),
+            Algorithm::Argon2id,
+            Version::V0x13,
+            params,
+        )
+        .map_err(|e| anyhow::anyhow!("invalid password pepper: {}", e))?,
+        None => Argon2::new(Algorithm::Argon2id, Version::V0x13, params),
+    })
+}
+
+/// The Argon2 instance to verify `hash` with, the rest of its parameters are read from the hash itself.
+fn verifier<'a>(config: &'a Config, hash: &PasswordHash) -> anyhow::Result<Argon2<'a>> {
+    let params =
+        Params::try_from(hash).map_err(|e| anyhow::anyhow!("invalid password hash: {}", e))?;
+
+    if params.keyid() != PEPPER_KEY_ID {
+        return Ok(Argon2::default());
+    }
+
+    let pepper = config
+        .password_pepper
+        .as_deref()
+        .context("password hash was made with a pepper, but password_pepper is not set")?;
+
+    Argon2::new_with_secret(
+        pepper.as_bytes(),
+        Algorithm::default(),
+        Version::default(),
+        Params::default(),
+    )
+    .map_err(|e| anyhow::anyhow!("invalid password pepper: {}", e))
+}
+
+pub(crate) async fn hash_password(ctx: &AppContext, password: String) -> Result<String> {
+    let config = ctx.config.clone();
 
-pub(crate) async fn hash_password(password: String) -> Result<String> {
     tokio::task::spawn_blocking(move || -> Result<String> {
         let salt = SaltString::generate(rand::thread_rng());
-        Ok(
-            PasswordHash::generate(Argon2::default(), password, salt.as_salt())
-                .map_err(|e| anyhow::anyhow!("failed to generate password hash: {}", e))?
-                .to_string(),
-        )
+        Ok(hasher(&config)?
+            .hash_password(password.as_bytes(), salt.as_salt())
+            .map_err(|e| anyhow::anyhow!("failed to generate password hash: {}", e))?
+            .to_string())
     })
     .await
     .context("panic in generating password hash")?
 }
 
-pub(crate) async fn verify_password(password: String, password_hash: String) -> Result<()> {
+pub(crate) async fn verify_password(
+    ctx: &AppContext,
+    password: String,
+    password_hash: String,
+) -> Result<()> {
+    let config = ctx.config.clone();
+
     tokio::task::spawn_blocking(move || -> Result<()> {
         let hash = PasswordHash::new(&password_hash)
             .map_err(|e| anyhow::anyhow!("invalid password hash: {}", e))?;
 
-        hash.verify_password(&[&Argon2::default()], password)
+        hash.verify_password(&[&verifier(&config, &hash)?], password)
             .map_err(|e| match e {
                 argon2::password_hash::Error::Password => Error::Unauthorized,
                 _ => anyhow::anyhow!("failed to verify password hash: {}", e).into(),
@@ -35,6 +107,90 @@ pub(crate) async fn verify_password(password: String, password_hash: String) ->
     .context("panic in verifying password hash")?
 }
 
+/// Whether `password_hash` was made differently from new hashes, e.g. before the Argon2 parameters were raised,
+/// and should be replaced the next time we have the password.
+pub(crate) fn password_needs_rehash(config: &Config, password_hash: &str) -> bool {
+    let Ok(hash) = PasswordHash::new(password_hash) else {
+        return false;
+    };
+    let Ok(params) = Params::try_from(&hash) else {
+        return false;
+    };
+
+    hash.algorithm != Algorithm::Argon2id.ident()
+        || hash.version != Some(Version::V0x13 as u32)
+        || params.m_cost() != config.argon2_memory_kib
+        || params.t_cost() != config.argon2_iterations
+        || params.p_cost() != config.argon2_parallelism
+        || (params.keyid() == PEPPER_KEY_ID) != config.password_pepper.is_some()
+}
+
+/// Spend as long as [`verify_password`] would, for when there is no account to check against.
+pub(crate) async fn dummy_verify_password(ctx: &AppContext, password: String) -> Result<()> {
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+
+    let hash = match DUMMY_HASH.get() {
+        Some(hash) => hash.clone(),
+        None => {
+            let hash = hash_password(ctx, "dummy password".into()).await?;
+            DUMMY_HASH.get_or_init(|| hash).clone()
+        }
+    };
+
+    match verify_password(ctx, password, hash).await {
+        Err(Error::Unauthorized) | Ok(()) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Rules for new passwords, see `password_min_length` and `password_blocklist` in the config.
+pub(crate) struct PasswordPolicy {
+    min_length: usize,
+    /// Lowercased, so variations in case don't get around it.
+    blocklist: HashSet<String>,
+}
+
+impl PasswordPolicy {
+    pub fn from_config(config: &Config) -> anyhow::Result<Self> {
+        // Catch bad Argon2 parameters on startup rather than on the first login.
+        hasher(config)?;
+
+        let blocklist = match config.password_blocklist {
+            Some(ref path) => std::fs::read_to_string(path)
+                .with_context(|| format!("failed to read {}", path.display()))?
+                .lines()
+                .map(|line| line.trim().to_lowercase())
+                .filter(|line| !line.is_empty())
+                .collect(),
+            None => HashSet::new(),
+        };
+
+        Ok(Self {
+            min_length: config.password_min_length,
+            blocklist,
+        })
+    }
+
+    /// Check a password someone has chosen, reporting any problem against `field` of the request.
+    pub fn check(&self, field: &'static str, password: &str) -> Result<()> {
+        if password.chars().count() < self.min_length {
+            return Err(Error::unprocessable_entity([(
+                field,
+                format!("must be at least {} characters", self.min_length),
+            )]));
+        }
+
+        if self.blocklist.contains(&password.to_lowercase()) {
+            return Err(Error::unprocessable_entity([(
+                field,
+                "is too common, choose a different password",
+            )]));
+        }
+
+        Ok(())
+    }
+}
+
 pub(crate) fn image_from_base64(image: &str) -> Result<DynamicImage> {
     let data = BASE64_STANDARD
         .decode(image)
@@ -43,3 +199,192 @@ pub(crate) fn image_from_base64(image: &str) -> Result<DynamicImage> {
     let image = image::load_from_memory(&data).context("falied to decode image")?;
     Ok(image)
 }
+
+/// Details about the client making a request, recorded against the sessions it starts.
+#[derive(Clone)]
+pub(crate) struct ClientInfo {
+    pub user_agent: Option<String>,
+    /// The address the request came from, see `trust_x_forwarded_for` in the config.
+    pub ip: Option<IpAddr>,
+}
+
+#[async_trait]
+impl FromRequestParts<AppContext> for ClientInfo {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        ctx: &AppContext,
+    ) -> Result<Self, Self::Rejection> {
+        let user_agent = parts
+            .headers
+            .get(USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_owned);
+
+        let peer_ip = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip());
+
+        // The last entry is the one added by our own proxy, anything before it could be made up by the client.
+        let forwarded_ip = || {
+            parts
+                .headers
+                .get_all("x-forwarded-for")
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(','))
+                .last()
+                .and_then(|ip| ip.trim().parse().ok())
+        };
+
+        let ip = if ctx.config.trust_x_forwarded_for {
+            forwarded_ip().or(peer_ip)
+        } else {
+            peer_ip
+        };
+
+        Ok(Self { user_agent, ip })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use clap::Parser;
+
+    use super::*;
+
+    fn config(args: &[&str]) -> Config {
+        let mut config = Config::parse_from(
+            [
+                "kg",
+                "--database-url",
+                "postgres://",
+                "--hmac-key",
+                "secret",
+            ]
+            .iter()
+            .chain(args),
+        );
+        // Don't let a pepper from the environment leak into the tests that don't set one.
+        if !args.contains(&"--password-pepper") {
+            config.password_pepper = None;
+        }
+        config
+    }
+
+    fn hash(config: &Config) -> String {
+        let salt = SaltString::generate(rand::thread_rng());
+        hasher(config)
+            .unwrap()
+            .hash_password(b"correct horse battery staple", salt.as_salt())
+            .unwrap()
+            .to_string()
+    }
+
+    fn policy(min_length: usize, blocklist: &[&str]) -> PasswordPolicy {
+        PasswordPolicy {
+            min_length,
+            blocklist: blocklist
+                .iter()
+                .map(|password| password.to_string())
+                .collect(),
+        }
+    }
+
+    fn rejected(result: Result<()>) -> Vec<Cow<'static, str>> {
+        match result {
+            Err(Error::UnprocessableEntity { mut errors }) => errors.remove("password").unwrap(),
+            _ => panic!("password was not rejected"),
+        }
+    }
+
+    #[test]
+    fn password_shorter_than_min_length_is_rejected() {
+        let policy = policy(8, &[]);
+
+        assert_eq!(
+            rejected(policy.check("password", "1234567")),
+            ["must be at least 8 characters"]
+        );
+        assert!(policy.check("password", "12345678").is_ok());
+    }
+
+    #[test]
+    fn password_length_counts_characters_not_bytes() {
+        let policy = policy(4, &[]);
+
+        assert!(policy.check("password", "ééé").is_err());
+        assert!(policy.check("password", "éééé").is_ok());
+    }
+
+    #[test]
+    fn blocklisted_password_is_rejected_in_any_case() {
+        let policy = policy(4, &["password1"]);
+
+        for password in ["password1", "Password1", "PASSWORD1"] {
+            assert_eq!(
+                rejected(policy.check("password", password)),
+                ["is too common, choose a different password"]
+            );
+        }
+        assert!(policy.check("password", "password2").is_ok());
+    }
+
+    #[test]
+    fn blocklist_file_is_lowercased_and_trimmed() {
+        let path = std::env::temp_dir().join(format!("blocklist-{}", rand::random::<u64>()));
+        std::fs::write(&path, "  Hunter22 \n\nletmein1\n").unwrap();
+        let policy =
+            PasswordPolicy::from_config(&config(&["--password-blocklist", path.to_str().unwrap()]))
+                .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(policy.check("password", "hunter22").is_err());
+        assert!(policy.check("password", "LETMEIN1").is_err());
+        assert!(policy.check("password", "hunter23").is_ok());
+    }
+
+    #[test]
+    fn hash_with_current_params_does_not_need_rehash() {
+        let config = config(&[]);
+
+        assert!(!password_needs_rehash(&config, &hash(&config)));
+    }
+
+    #[test]
+    fn hash_with_old_params_needs_rehash() {
+        let old = hash(&config(&["--argon2-iterations", "1"]));
+
+        assert!(password_needs_rehash(
+            &config(&["--argon2-iterations", "2"]),
+            &old
+        ));
+        assert!(password_needs_rehash(
+            &config(&["--argon2-iterations", "1", "--argon2-memory-kib", "8192"]),
+            &old
+        ));
+        assert!(password_needs_rehash(
+            &config(&["--argon2-iterations", "1", "--argon2-parallelism", "2"]),
+            &old
+        ));
+    }
+
+    #[test]
+    fn adding_or_removing_pepper_needs_rehash() {
+        let unpeppered = config(&[]);
+        let peppered = config(&["--password-pepper", "pepper"]);
+
+        assert!(password_needs_rehash(&peppered, &hash(&unpeppered)));
+        assert!(password_needs_rehash(&unpeppered, &hash(&peppered)));
+        assert!(!password_needs_rehash(&peppered, &hash(&peppered)));
+    }
+
+    #[test]
+    fn unparseable_hash_does_not_need_rehash() {
+        assert!(!password_needs_rehash(&config(&[]), "not a hash"));
+    }
+}
@@ -0,0 +1,765 @@
+use axum::extract::{Path, Query, State};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use chrono::{DateTime, Utc};
+use serde_json::json;
+use sqlx::{query, PgConnection, PgExecutor};
+use uuid::Uuid;
+
+use crate::api::admin::{record_action, Page};
+use crate::api::audit::{change, AuditEvent};
+use crate::api::auth::{AuthAdmin, AuthUser};
+use crate::api::orders::{create_payment, verify_payment, Payment, PaymentStatus};
+use crate::api::restaurants::PhonepeMerchant;
+use crate::api::util::ClientInfo;
+use crate::api::{AppContext, Error, Result};
+
+/// The smallest and largest top-ups, in rupees.
+const MIN_TOP_UP: i64 = 10;
+const MAX_TOP_UP: i64 = 5000;
+
+pub(crate) fn router() -> Router<AppContext> {
+    Router::new()
+        .route("/api/wallet", get(get_wallet))
+        .route("/api/wallet/statement", get(get_statement))
+        .route("/api/wallet/top_ups", post(create_top_up))
+        .route("/api/wallet/top_ups/:id", get(get_top_up))
+        .route(
+            "/api/orders/pay_with_wallet/:order_id",
+            post(pay_with_wallet),
+        )
+        .route(
+            "/api/admin/users/:id/wallet/adjustments",
+            post(adjust_wallet),
+        )
+        .route("/api/admin/wallet/check", get(get_ledger_check))
+}
+
+/// An account in the ledger, see `0024_wallet.sql`.
+#[derive(Clone, Copy)]
+enum Account {
+    /// What the platform owes a user.
+    Wallet(Uuid),
+    /// What the platform owes a restaurant for orders paid from wallets.
+    Restaurant(Uuid),
+    /// Money received through PhonePe.
+    Phonepe,
+    /// The other side of corrections made by administrators.
+    Adjustments,
+    /// Where rewards, e.g. for referrals, are paid from.
+    Rewards,
+}
+
+impl Account {
+    /// Find the account's id, opening it if this is the first time it is used.
+    async fn id(self, tx: &mut PgConnection) -> Result<Uuid> {
+        let id = match self {
+            Self::Wallet(user_id) => {
+                query!(
+                    r#"
+                        insert into ledger_account (kind, user_id) values ('wallet', $1)
+                        on conflict (user_id) where kind = 'wallet' do nothing
+                    "#,
+                    user_id
+                )
+                .execute(&mut *tx)
+                .await?;
+
+                sqlx::query_scalar!(
+                    r#"select ledger_account_id from ledger_account where kind = 'wallet' and user_id = $1"#,
+                    user_id
+                )
+                .fetch_one(&mut *tx)
+                .await?
+            }
+            Self::Restaurant(restaurant_id) => {
+                query!(
+                    r#"
+                        insert into ledger_account (kind, restaurant_id) values ('restaurant', $1)
+                        on conflict (restaurant_id) where kind = 'restaurant' do nothing
+                    "#,
+                    restaurant_id
+                )
+                .execute(&mut *tx)
+                .await?;
+
+                sqlx::query_scalar!(
+                    r#"select ledger_account_id from ledger_account where kind = 'restaurant' and restaurant_id = $1"#,
+                    restaurant_id
+                )
+                .fetch_one(&mut *tx)
+                .await?
+            }
+            Self::Phonepe => {
+                sqlx::query_scalar!(
+                    r#"select ledger_account_id from ledger_account where kind = 'phonepe'"#
+                )
+                .fetch_one(&mut *tx)
+                .await?
+            }
+            Self::Adjustments => {
+                sqlx::query_scalar!(
+                    r#"select ledger_account_id from ledger_account where kind = 'adjustments'"#
+                )
+                .fetch_one(&mut *tx)
+                .await?
+            }
+            Self::Rewards => {
+                sqlx::query_scalar!(
+                    r#"select ledger_account_id from ledger_account where kind = 'rewards'"#
+                )
+                .fetch_one(&mut *tx)
+                .await?
+            }
+        };
+
+        Ok(id)
+    }
+}
+
+/// A ledger transaction about to be posted.
+struct Transaction<'a> {
+    kind: &'static str,
+    order_id: Option<Uuid>,
+    admin_id: Option<Uuid>,
+    description: &'a str,
+    /// Amounts to add to each account, which must sum to zero.
+    entries: &'a [(Account, i64)],
+}
+
+impl Transaction<'_> {
+    /// Post the transaction, which the database checks balances when `tx` commits.
+    async fn post(self, tx: &mut PgConnection) -> Result<Uuid> {
+        debug_assert_eq!(
+            self.entries.iter().map(|(_, amount)| amount).sum::<i64>(),
+            0
+        );
+
+        let transaction_id = sqlx::query_scalar!(
+            r#"
+                insert into ledger_transaction (kind, order_id, admin_id, description)
+                values ($1, $2, $3, $4)
+                returning ledger_transaction_id
+            "#,
+            self.kind,
+            self.order_id,
+            self.admin_id,
+            self.description
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        for &(account, amount) in self.entries {
+            let account_id = account.id(tx).await?;
+
+            query!(
+                r#"
+                    insert into ledger_entry (ledger_transaction_id, ledger_account_id, amount)
+                    values ($1, $2, $3)
+                "#,
+                transaction_id,
+                account_id,
+                amount
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        Ok(transaction_id)
+    }
+}
+
+/// The merchant top-ups are paid to, see `wallet_phonepe_id` in the config.
+fn wallet_merchant(ctx: &AppContext) -> Result<PhonepeMerchant> {
+    let config = &ctx.config;
+
+    match (
+        &config.wallet_phonepe_id,
+        &config.wallet_phonepe_key,
+        &config.wallet_phonepe_key_id,
+    ) {
+        (Some(id), Some(key), Some(key_id)) => Ok(PhonepeMerchant {
+            id: id.clone(),
+            key: key.clone(),
+            key_id: key_id.clone(),
+        }),
+        _ => {
+            log::debug!("wallet top-ups are not configured");
+            Err(Error::NotFound)
+        }
+    }
+}
+
+/// The user's wallet balance in rupees, zero if they have never used it.
+pub(super) async fn wallet_balance(db: impl PgExecutor<'_>, user_id: Uuid) -> Result<i64> {
+    let balance = sqlx::query_scalar!(
+        r#"select balance from ledger_account where kind = 'wallet' and user_id = $1"#,
+        user_id
+    )
+    .fetch_optional(db)
+    .await?;
+
+    Ok(balance.unwrap_or(0))
+}
+
+#[derive(serde::Serialize)]
+struct Wallet {
+    balance: i64,
+}
+
+async fn get_wallet(auth_user: AuthUser, ctx: State<AppContext>) -> Result<Json<Wallet>> {
+    Ok(Json(Wallet {
+        balance: wallet_balance(&ctx.db, auth_user.user_id).await?,
+    }))
+}
+
+#[derive(serde::Serialize)]
+pub(super) struct Statement {
+    entries: Vec<StatementEntry>,
+}
+
+#[derive(serde::Serialize)]
+struct StatementEntry {
+    id: Uuid,
+    /// `top_up`, `debit`, `refund`, `adjustment` or `reward`.
+    kind: String,
+    description: String,
+    order_id: Option<Uuid>,
+    /// Positive for money into the wallet, negative for money out of it.
+    amount: i64,
+    balance_after: i64,
+    created_at: DateTime<Utc>,
+}
+
+/// The entries of the user's wallet, newest first.
+pub(super) async fn statement(
+    ctx: &AppContext,
+    user_id: Uuid,
+    limit: i64,
+    offset: i64,
+) -> Result<Statement> {
+    let entries = sqlx::query_as!(
+        StatementEntry,
+        r#"
+            select e.ledger_entry_id as id, t.kind, t.description, t.order_id, e.amount, e.balance_after,
+                   e.created_at
+            from ledger_entry e
+            join ledger_account a using (ledger_account_id)
+            join ledger_transaction t using (ledger_transaction_id)
+            where a.kind = 'wallet' and a.user_id = $1
+            order by e.created_at desc
+            limit $2 offset $3
+        "#,
+        user_id,
+        limit,
+        offset
+    )
+    .fetch_all(&ctx.db)
+    .await?;
+
+    Ok(Statement { entries })
+}
+
+async fn get_statement(
+    auth_user: AuthUser,
+    ctx: State<AppContext>,
+    Query(page): Query<Page>,
+) -> Result<Json<Statement>> {
+    Ok(Json(
+        statement(&ctx, auth_user.user_id, page.limit(), page.offset()).await?,
+    ))
+}
+
+#[derive(serde::Deserialize)]
+struct NewTopUp {
+    amount: i64,
+}
+
+#[derive(serde::Serialize)]
+struct TopUp {
+    id: Uuid,
+    amount: i64,
+    status: PaymentStatus,
+    /// Where to pay, while the payment is pending.
+    url: Option<String>,
+}
+
+/// Start topping up the wallet, returning the PhonePe page to pay on.
+async fn create_top_up(
+    auth_user: AuthUser,
+    ctx: State<AppContext>,
+    Json(req): Json<NewTopUp>,
+) -> Result<Json<TopUp>> {
+    let merchant = wallet_merchant(&ctx)?;
+
+    if !(MIN_TOP_UP..=MAX_TOP_UP).contains(&req.amount) {
+        return Err(Error::unprocessable_entity([(
+            "amount",
+            format!("must be between {} and {}", MIN_TOP_UP, MAX_TOP_UP),
+        )]));
+    }
+
+    let top_up_id = sqlx::query_scalar!(
+        r#"insert into wallet_top_up (user_id, amount) values ($1, $2) returning wallet_top_up_id"#,
+        auth_user.user_id,
+        req.amount
+    )
+    .fetch_one(&ctx.db)
+    .await?;
+
+    let url = create_payment(&merchant, top_up_id, auth_user.user_id, req.amount).await?;
+
+    query!(
+        r#"update wallet_top_up set payment_url = $1 where wallet_top_up_id = $2"#,
+        url,
+        top_up_id
+    )
+    .execute(&ctx.db)
+    .await?;
+
+    Ok(Json(TopUp {
+        id: top_up_id,
+        amount: req.amount,
+        status: PaymentStatus::Pending,
+        url: Some(url),
+    }))
+}
+
+/// Check on a top-up, crediting the wallet once PhonePe says it has been paid.
+async fn get_top_up(
+    auth_user: AuthUser,
+    ctx: State<AppContext>,
+    Path(top_up_id): Path<Uuid>,
+) -> Result<Json<TopUp>> {
+    let top_up = query!(
+        r#"
+            select amount, status, payment_url from wallet_top_up
+            where wallet_top_up_id = $1 and user_id = $2
+        "#,
+        top_up_id,
+        auth_user.user_id
+    )
+    .fetch_optional(&ctx.db)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    let response = |status, url| {
+        Ok(Json(TopUp {
+            id: top_up_id,
+            amount: top_up.amount,
+            status,
+            url,
+        }))
+    };
+
+    match (top_up.status.as_str(), top_up.payment_url) {
+        ("paid", _) => response(PaymentStatus::Paid, None),
+        // Starting the payment failed, so there is nothing to pay.
+        ("payment_failed", _) | (_, None) => response(PaymentStatus::Failed, None),
+        (_, Some(url)) => {
+            let oid = top_up_id.to_string().replace("-", "");
+
+            match verify_payment(oid, wallet_merchant(&ctx)?).await? {
+                PaymentStatus::Pending => response(PaymentStatus::Pending, Some(url)),
+                PaymentStatus::Failed => {
+                    query!(
+                        r#"
+                            update wallet_top_up set status = 'payment_failed'
+                            where wallet_top_up_id = $1 and status = 'payment_pending'
+                        "#,
+                        top_up_id
+                    )
+                    .execute(&ctx.db)
+                    .await?;
+
+                    response(PaymentStatus::Failed, None)
+                }
+                PaymentStatus::Paid => {
+                    credit_top_up(&ctx, auth_user.user_id, top_up_id).await?;
+                    response(PaymentStatus::Paid, None)
+                }
+            }
+        }
+    }
+}
+
+/// Credit a paid top-up to the wallet, unless a concurrent check already has.
+async fn credit_top_up(ctx: &AppContext, user_id: Uuid, top_up_id: Uuid) -> Result<()> {
+    let mut tx = ctx.db.begin().await?;
+
+    let top_up = query!(
+        r#"select amount, status from wallet_top_up where wallet_top_up_id = $1 for update"#,
+        top_up_id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    if top_up.status != "payment_pending" {
+        return Ok(());
+    }
+
+    let transaction_id = Transaction {
+        kind: "top_up",
+        order_id: None,
+        admin_id: None,
+        description: "Top-up through PhonePe",
+        entries: &[
+            (Account::Phonepe, -top_up.amount),
+            (Account::Wallet(user_id), top_up.amount),
+        ],
+    }
+    .post(&mut tx)
+    .await?;
+
+    query!(
+        r#"
+            update wallet_top_up set status = 'paid', ledger_transaction_id = $1
+            where wallet_top_up_id = $2
+        "#,
+        transaction_id,
+        top_up_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    log::info!("credited top-up {} of {}", top_up_id, top_up.amount);
+    Ok(())
+}
+
+/// Pay for an order from the wallet, instead of going through PhonePe with `/api/orders/payment/:order_id`.
+async fn pay_with_wallet(
+    auth_user: AuthUser,
+    ctx: State<AppContext>,
+    Path(order_id): Path<Uuid>,
+) -> Result<Json<Payment>> {
+    let mut tx = ctx.db.begin().await?;
+
+    let order = query!(
+        r#"
+            select restaurant_id, total, status, payment_url from "order"
+            where order_id = $1 and user_id = $2
+            for update
+        "#,
+        order_id,
+        auth_user.user_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(Error::NotFound)?;
+
+    if order.status != "payment_pending" {
+        return Err(Error::unprocessable_entity([(
+            "order",
+            "is not waiting for payment",
+        )]));
+    }
+
+    // The user may already have paid on the PhonePe page, and we'd take the money twice.
+    if order.payment_url.is_some() {
+        return Err(Error::unprocessable_entity([(
+            "order",
+            "is already being paid through PhonePe",
+        )]));
+    }
+
+    let total = i64::from(order.total);
+
+    // Nothing to pay, or a total that would take money from the restaurant.
+    if total <= 0 {
+        return Err(Error::unprocessable_entity([(
+            "order",
+            "has nothing to pay",
+        )]));
+    }
+
+    // Locks the wallet, so two orders can't both spend the same money.
+    let balance = sqlx::query_scalar!(
+        r#"select balance from ledger_account where kind = 'wallet' and user_id = $1 for update"#,
+        auth_user.user_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .unwrap_or(0);
+
+    if balance < total {
+        return Err(Error::unprocessable_entity([(
+            "balance",
+            "is not enough to pay for this order",
+        )]));
+    }
+
+    Transaction {
+        kind: "debit",
+        order_id: Some(order_id),
+        admin_id: None,
+        description: "Order payment",
+        entries: &[
+            (Account::Wallet(auth_user.user_id), -total),
+            (Account::Restaurant(order.restaurant_id), total),
+        ],
+    }
+    .post(&mut tx)
+    .await?;
+
+    query!(
+        r#"update "order" set status = 'paid', order_placed_time = now() where order_id = $1"#,
+        order_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(Json(Payment {
+        status: PaymentStatus::Paid,
+        url: None,
+    }))
+}
+
+/// Give the money back if the order was paid from a wallet, in the same transaction as cancelling it.
+///
+/// Does nothing for orders paid through PhonePe.
+pub(super) async fn refund_order(tx: &mut PgConnection, order_id: Uuid) -> Result<()> {
+    let debit = query!(
+        r#"
+            select a.kind, a.user_id, a.restaurant_id, e.amount
+            from ledger_entry e
+            join ledger_account a using (ledger_account_id)
+            join ledger_transaction t using (ledger_transaction_id)
+            where t.order_id = $1 and t.kind = 'debit'
+        "#,
+        order_id
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    if debit.is_empty() {
+        return Ok(());
+    }
+
+    let mut entries = Vec::with_capacity(debit.len());
+
+    for entry in debit {
+        let account = match (entry.kind.as_str(), entry.user_id, entry.restaurant_id) {
+            ("wallet", Some(user_id), _) => Account::Wallet(user_id),
+            ("restaurant", _, Some(restaurant_id)) => Account::Restaurant(restaurant_id),
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "unexpected {} account in the payment of order {}",
+                    entry.kind,
+                    order_id
+                )
+                .into())
+            }
+        };
+
+        entries.push((account, -entry.amount));
+    }
+
+    Transaction {
+        kind: "refund",
+        order_id: Some(order_id),
+        admin_id: None,
+        description: "Refund for cancelled order",
+        entries: &entries,
+    }
+    .post(tx)
+    .await?;
+
+    Ok(())
+}
+
+/// Credit `amount` rupees to the user's wallet as a reward, in the same transaction as whatever earned it.
+pub(super) async fn reward(
+    tx: &mut PgConnection,
+    user_id: Uuid,
+    amount: i64,
+    description: &str,
+) -> Result<()> {
+    Transaction {
+        kind: "reward",
+        order_id: None,
+        admin_id: None,
+        description,
+        entries: &[
+            (Account::Rewards, -amount),
+            (Account::Wallet(user_id), amount),
+        ],
+    }
+    .post(tx)
+    .await?;
+
+    Ok(())
+}
+
+#[derive(serde::Deserialize)]
+struct Adjustment {
+    /// Positive to add to the wallet, negative to take from it.
+    amount: i64,
+    reason: String,
+}
+
+/// Correct a user's balance, e.g. to pay back a top-up that was taken by PhonePe but never credited.
+async fn adjust_wallet(
+    auth_admin: AuthAdmin,
+    ctx: State<AppContext>,
+    client: ClientInfo,
+    Path(user_id): Path<Uuid>,
+    Json(req): Json<Adjustment>,
+) -> Result<Json<Wallet>> {
+    if req.amount == 0 {
+        return Err(Error::unprocessable_entity([(
+            "amount",
+            "must not be zero",
+        )]));
+    }
+
+    if req.reason.trim().is_empty() {
+        return Err(Error::unprocessable_entity([("reason", "can't be blank")]));
+    }
+
+    let mut tx = ctx.db.begin().await?;
+
+    let exists = sqlx::query_scalar!(
+        r#"select exists(select 1 from "user" where user_id = $1) as "exists!""#,
+        user_id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    if !exists {
+        return Err(Error::NotFound);
+    }
+
+    let old_balance = wallet_balance(&mut *tx, user_id).await?;
+
+    if old_balance + req.amount < 0 {
+        return Err(Error::unprocessable_entity([(
+            "amount",
+            "would leave the wallet overdrawn",
+        )]));
+    }
+
+    Transaction {
+        kind: "adjustment",
+        order_id: None,
+        admin_id: Some(auth_admin.admin_id),
+        description: &req.reason,
+        entries: &[
+            (Account::Adjustments, -req.amount),
+            (Account::Wallet(user_id), req.amount),
+        ],
+    }
+    .post(&mut tx)
+    .await?;
+
+    let balance = old_balance + req.amount;
+
+    record_action(
+        &mut tx,
+        &auth_admin,
+        "adjust_wallet",
+        Some(user_id),
+        json!({ "amount": req.amount, "reason": req.reason }),
+    )
+    .await?;
+
+    AuditEvent::new("wallet_adjusted", &auth_admin)
+        .user(user_id)
+        .changes(json!({ "balance": change(old_balance, balance) }))
+        .record(&mut *tx, &client)
+        .await?;
+
+    tx.commit().await?;
+
+    Ok(Json(Wallet { balance }))
+}
+
+#[derive(serde::Serialize)]
+struct LedgerCheck {
+    consistent: bool,
+    /// Accounts whose balance isn't the sum of their entries.
+    mismatched_accounts: Vec<MismatchedAccount>,
+    /// Transactions whose entries don't sum to zero.
+    unbalanced_transactions: Vec<Uuid>,
+    /// The sum of every account's balance, which should be zero.
+    total_balance: i64,
+}
+
+#[derive(serde::Serialize)]
+struct MismatchedAccount {
+    id: Uuid,
+    kind: String,
+    user_id: Option<Uuid>,
+    restaurant_id: Option<Uuid>,
+    balance: i64,
+    entries_total: i64,
+}
+
+async fn check_ledger(ctx: &AppContext) -> Result<LedgerCheck> {
+    let mismatched_accounts = sqlx::query_as!(
+        MismatchedAccount,
+        r#"
+            select a.ledger_account_id as id, a.kind, a.user_id, a.restaurant_id, a.balance,
+                   coalesce(sum(e.amount), 0)::bigint as "entries_total!"
+            from ledger_account a
+            left join ledger_entry e using (ledger_account_id)
+            group by a.ledger_account_id
+            having a.balance <> coalesce(sum(e.amount), 0)
+        "#
+    )
+    .fetch_all(&ctx.db)
+    .await?;
+
+    let unbalanced_transactions = sqlx::query_scalar!(
+        r#"
+            select ledger_transaction_id
+            from ledger_entry
+            group by ledger_transaction_id
+            having sum(amount) <> 0
+        "#
+    )
+    .fetch_all(&ctx.db)
+    .await?;
+
+    let total_balance = sqlx::query_scalar!(
+        r#"select coalesce(sum(balance), 0)::bigint as "total!" from ledger_account"#
+    )
+    .fetch_one(&ctx.db)
+    .await?;
+
+    Ok(LedgerCheck {
+        consistent: mismatched_accounts.is_empty()
+            && unbalanced_transactions.is_empty()
+            && total_balance == 0,
+        mismatched_accounts,
+        unbalanced_transactions,
+        total_balance,
+    })
+}
+
+/// Check that every balance is the sum of its entries and that every transaction balances.
+async fn get_ledger_check(
+    _auth_admin: AuthAdmin,
+    ctx: State<AppContext>,
+) -> Result<Json<LedgerCheck>> {
+    Ok(Json(check_ledger(&ctx).await?))
+}
+
+/// Run the ledger checks on startup, so inconsistencies show up in the logs without anyone asking.
+pub(super) async fn check_ledger_on_startup(ctx: &AppContext) -> Result<()> {
+    let check = check_ledger(ctx).await?;
+
+    if !check.consistent {
+        log::error!(
+            "wallet ledger is inconsistent: {} mismatched accounts, {} unbalanced transactions, total balance {}",
+            check.mismatched_accounts.len(),
+            check.unbalanced_transactions.len(),
+            check.total_balance
+        );
+    }
+
+    Ok(())
+}
@@ -2,7 +2,7 @@ use anyhow::Context;
 use clap::Parser;
 use kg_rust::api;
 use kg_rust::config::Config;
-use sqlx::postgres::{PgPool, PgPoolOptions};
+use sqlx::postgres::PgPoolOptions;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {